@@ -71,4 +71,13 @@ impl Key {
     pub fn is_blob(&self) -> bool {
         self.as_ref().starts_with(BLOB_PREFIX)
     }
+
+    /// The digest of the object stored under this key, if it is a blob key.
+    pub fn blob_digest(&self) -> Option<&[u8]> {
+        if self.is_blob() {
+            Some(&self.as_ref()[BLOB_PREFIX.len()..])
+        } else {
+            None
+        }
+    }
 }
@@ -1,13 +1,15 @@
-use std::{fmt, mem, str, collections::HashMap, io::{self, BufRead, Cursor, Read, Write}, path::Path,
-          sync::RwLock};
+use std::{fmt, fs, mem, str, collections::{HashMap, HashSet},
+          io::{self, BufRead, Cursor, Read, Write}, path::{Path, PathBuf}, sync::RwLock};
 
 use attaca::{canonical, Init, Open, digest::{Sha3Digest, prelude::*},
+             pack::{Pack, PackEntry, PackWriter, TransferEncoding},
              store::{RawHandle, prelude::*}};
 use capnp::{message, serialize_packed};
 use failure::*;
-use futures::{future::FutureResult, prelude::*};
+use futures::{stream, future::FutureResult, prelude::*};
 use leb128;
-use leveldb::{database::Database, kv::KV, options::{Options, ReadOptions, WriteOptions}};
+use leveldb::{compaction::Compaction, database::Database, iterator::{Iterable, LevelDBIterator},
+              kv::KV, options::{Options, ReadOptions, WriteOptions}};
 use url::Url;
 use uuid::Uuid;
 
@@ -77,7 +79,7 @@ impl Open for LevelDbBackend {
 
     fn open_path(path: &Path) -> Result<Self, Error> {
         let db = Database::open(&path, Options::new())?;
-        Self::new(db)
+        Self::new(db, path)
     }
 }
 
@@ -104,7 +106,7 @@ impl Init for LevelDbBackend {
         )?;
         let uuid = Uuid::new_v4();
         db.put(WriteOptions::new(), &Key::uuid(), uuid.as_bytes())?;
-        Self::new(db)
+        Self::new(db, path)
     }
 }
 
@@ -156,6 +158,7 @@ impl Iterator for LevelDbContent {
 struct Inner {
     uuid: Uuid,
     db: Database<Key>,
+    path: PathBuf,
 
     ids: HashMap<Sha3Digest, RawHandle>,
     handles: HashMap<RawHandle, Sha3Digest>,
@@ -165,6 +168,7 @@ impl fmt::Debug for Inner {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Inner")
             .field("db", &"Database")
+            .field("path", &self.path)
             .field("ids", &self.ids)
             .field("handles", &self.handles)
             .finish()
@@ -177,13 +181,14 @@ pub struct LevelDbBackend {
 }
 
 impl LevelDbBackend {
-    fn new(db: Database<Key>) -> Result<Self, Error> {
+    fn new(db: Database<Key>, path: &Path) -> Result<Self, Error> {
         let uuid = Uuid::from_bytes(&db.get(ReadOptions::new(), &Key::uuid())?.unwrap())?;
 
         Ok(Self {
             inner: RwLock::new(Inner {
                 uuid,
                 db,
+                path: path.to_owned(),
 
                 ids: HashMap::new(),
                 handles: HashMap::new(),
@@ -273,6 +278,20 @@ impl LevelDbBackend {
         })
     }
 
+    fn do_size(&self, id: RawHandle) -> Result<Option<u64>, Error> {
+        let inner = self.inner.read().unwrap();
+        let digest = inner.handles[&id];
+        let raw = inner
+            .db
+            .get(ReadOptions::new(), &Key::blob(digest.as_bytes()))?
+            .expect("bad ID!");
+
+        // The length prefix is all we need; neither the blob nor the refs after it are decoded.
+        let size = leb128::read::unsigned(&mut &raw[..])?; // `C.length || C`
+
+        Ok(Some(size))
+    }
+
     fn do_id(&self, id: RawHandle) -> Result<Sha3Digest, Error> {
         Ok(self.inner.read().unwrap().handles[&id])
     }
@@ -361,6 +380,208 @@ impl LevelDbBackend {
 
         Ok(())
     }
+
+    fn do_stats(&self) -> Result<Option<StoreStats>, Error> {
+        let inner = self.inner.read().unwrap();
+        let mut stats = StoreStats::default();
+
+        // Every object lives under its own key, so a full scan of the blob keyspace sees each
+        // exactly once.
+        for (key, value) in inner.db.iter(ReadOptions::new()) {
+            if key.is_blob() {
+                stats.loose_objects += 1;
+                stats.loose_bytes += (key.as_ref().len() + value.len()) as u64;
+            }
+        }
+
+        Ok(Some(stats))
+    }
+
+    fn do_pack(
+        &self,
+        signature: DigestSignature,
+        encoding: TransferEncoding,
+        root: RawHandle,
+        have: Vec<Vec<u8>>,
+    ) -> Result<Pack, Error> {
+        ensure!(
+            signature == Sha3Digest::SIGNATURE,
+            "unsupported digest {:?}",
+            signature
+        );
+
+        enum Visit {
+            Enter(RawHandle),
+            Exit(RawHandle, Vec<RawHandle>),
+        }
+
+        // Where an object ended up: bases are only numbered ahead of entries once we know how
+        // many of them there are.
+        #[derive(Clone, Copy)]
+        enum Slot {
+            Base(usize),
+            Entry(usize),
+        }
+
+        let have = have.into_iter().collect::<HashSet<_>>();
+
+        // Walk the object graph depth-first, ordering each object only once all of its children
+        // have been, so that every reference in the pack points backwards. Objects the receiver
+        // has become bases, and are not descended into. Only handles are kept, not data: each
+        // object is loaded again as it is encoded, so that the whole pack's data is never held
+        // in memory besides the encoded pack itself.
+        let mut bases = Vec::new();
+        let mut order = Vec::new();
+        let mut slots = HashMap::new();
+        let mut stack = vec![Visit::Enter(root)];
+
+        while let Some(visit) = stack.pop() {
+            match visit {
+                Visit::Enter(id) => {
+                    if slots.contains_key(&id) {
+                        continue;
+                    }
+
+                    let digest = self.do_id(id)?.as_bytes().to_vec();
+                    if have.contains(&digest) {
+                        slots.insert(id, Slot::Base(bases.len()));
+                        bases.push(digest);
+                        continue;
+                    }
+
+                    let refs = self.do_load(id)?.collect::<Vec<_>>();
+                    stack.push(Visit::Exit(id, refs.clone()));
+                    stack.extend(refs.into_iter().rev().map(Visit::Enter));
+                }
+                Visit::Exit(id, refs) => {
+                    if slots.contains_key(&id) {
+                        continue;
+                    }
+
+                    let refs = refs.iter().map(|child| slots[child]).collect::<Vec<_>>();
+                    slots.insert(id, Slot::Entry(order.len()));
+                    order.push((id, refs));
+                }
+            }
+        }
+
+        let base_count = bases.len();
+        let mut writer = PackWriter::new(signature, encoding, bases, order.len() as u64)?;
+        for (id, refs) in order {
+            let mut blob = Vec::new();
+            self.do_load(id)?.read_to_end(&mut blob)?;
+            writer.push(&PackEntry {
+                digest: self.do_id(id)?.as_bytes().to_vec(),
+                blob,
+                refs: refs.into_iter()
+                    .map(|slot| match slot {
+                        Slot::Base(i) => i,
+                        Slot::Entry(i) => base_count + i,
+                    })
+                    .collect(),
+            })?;
+        }
+
+        writer.finish()
+    }
+
+    fn do_contains_digests(
+        &self,
+        signature: DigestSignature,
+        digests: Vec<Vec<u8>>,
+    ) -> Result<Option<Vec<bool>>, Error> {
+        ensure!(
+            signature == Sha3Digest::SIGNATURE,
+            "unsupported digest {:?}",
+            signature
+        );
+
+        // Unlike `resolve_digest`, this doesn't reserve handles for digests we don't need.
+        let inner = self.inner.read().unwrap();
+        let contained = digests
+            .iter()
+            .map(|digest| {
+                Ok(inner
+                    .db
+                    .get(ReadOptions::new(), &Key::blob(digest))?
+                    .is_some())
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Some(contained))
+    }
+
+    fn do_list_digests(&self, signature: DigestSignature) -> Result<Vec<Vec<u8>>, Error> {
+        ensure!(
+            signature == Sha3Digest::SIGNATURE,
+            "unsupported digest {:?}",
+            signature
+        );
+
+        // Only the keys are needed, so there's no need to read any payloads.
+        let inner = self.inner.read().unwrap();
+        let digests = inner
+            .db
+            .keys_iter(ReadOptions::new())
+            .filter_map(|key| key.blob_digest().map(<[u8]>::to_vec))
+            .collect();
+
+        Ok(digests)
+    }
+
+    fn do_list_digests_with_prefix(
+        &self,
+        signature: DigestSignature,
+        prefix: &[u8],
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        ensure!(
+            signature == Sha3Digest::SIGNATURE,
+            "unsupported digest {:?}",
+            signature
+        );
+
+        // Blob keys are ordered by digest, so the matching digests are a contiguous range.
+        let start = Key::blob(prefix);
+        let inner = self.inner.read().unwrap();
+        let digests = inner
+            .db
+            .keys_iter(ReadOptions::new())
+            .from(&start)
+            .take_while(|key| key.as_ref().starts_with(start.as_ref()))
+            .filter_map(|key| key.blob_digest().map(<[u8]>::to_vec))
+            .collect();
+
+        Ok(digests)
+    }
+
+    fn do_compact(&self) -> Result<Option<CompactStats>, Error> {
+        // LevelDB keeps all of its files directly inside the database directory.
+        fn disk_usage(path: &Path) -> Result<u64, Error> {
+            let mut total = 0;
+            for entry in fs::read_dir(path)? {
+                let metadata = entry?.metadata()?;
+                if metadata.is_file() {
+                    total += metadata.len();
+                }
+            }
+            Ok(total)
+        }
+
+        let inner = self.inner.read().unwrap();
+        let before_bytes = disk_usage(&inner.path)?;
+
+        // No key starts with `0xFF`, so this range covers the entire keyspace.
+        inner
+            .db
+            .compact(&Key::Borrowed(b""), &Key::Borrowed(b"\xFF"));
+
+        let after_bytes = disk_usage(&inner.path)?;
+
+        Ok(Some(CompactStats {
+            before_bytes,
+            after_bytes,
+        }))
+    }
 }
 
 impl Backend for LevelDbBackend {
@@ -432,4 +653,70 @@ impl Backend for LevelDbBackend {
     ) -> Self::FutureSwapBranches {
         self.do_swap_branches(previous, new).into_future()
     }
+
+    type FutureStats = FutureResult<Option<StoreStats>, Error>;
+
+    fn stats(&self) -> Self::FutureStats {
+        self.do_stats().into_future()
+    }
+
+    fn pack_encodings(&self) -> &'static [TransferEncoding] {
+        &[TransferEncoding::Deflate, TransferEncoding::Plain]
+    }
+
+    type FuturePack = FutureResult<Pack, Error>;
+
+    fn pack(
+        &self,
+        signature: DigestSignature,
+        encoding: TransferEncoding,
+        root: RawHandle,
+        have: Vec<Vec<u8>>,
+    ) -> Self::FuturePack {
+        self.do_pack(signature, encoding, root, have).into_future()
+    }
+
+    type FutureContainsDigests = FutureResult<Option<Vec<bool>>, Error>;
+
+    fn contains_digests(
+        &self,
+        signature: DigestSignature,
+        digests: Vec<Vec<u8>>,
+    ) -> Self::FutureContainsDigests {
+        self.do_contains_digests(signature, digests).into_future()
+    }
+
+    type StreamDigests = Box<Stream<Item = Vec<u8>, Error = Error>>;
+
+    fn list_digests(&self, signature: DigestSignature) -> Option<Self::StreamDigests> {
+        let digests = self.do_list_digests(signature)
+            .into_future()
+            .map(stream::iter_ok)
+            .flatten_stream();
+        Some(Box::new(digests))
+    }
+
+    fn list_digests_with_prefix(
+        &self,
+        signature: DigestSignature,
+        prefix: &[u8],
+    ) -> Option<Self::StreamDigests> {
+        let digests = self.do_list_digests_with_prefix(signature, prefix)
+            .into_future()
+            .map(stream::iter_ok)
+            .flatten_stream();
+        Some(Box::new(digests))
+    }
+
+    type FutureCompact = FutureResult<Option<CompactStats>, Error>;
+
+    fn compact(&self) -> Self::FutureCompact {
+        self.do_compact().into_future()
+    }
+
+    type FutureSize = FutureResult<Option<u64>, Error>;
+
+    fn size(&self, id: RawHandle) -> Self::FutureSize {
+        self.do_size(id).into_future()
+    }
 }
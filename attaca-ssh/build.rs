@@ -0,0 +1,9 @@
+extern crate capnpc;
+
+fn main() {
+    capnpc::CompilerCommand::new()
+        .src_prefix("schema")
+        .file("schema/transport.capnp")
+        .run()
+        .expect("schema compiler command");
+}
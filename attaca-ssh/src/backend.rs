@@ -0,0 +1,571 @@
+use std::{env, fmt, collections::HashMap, io::{self, BufReader, Cursor, Read, Write},
+          net::TcpStream, path::Path, process::{Child, Command, Stdio}, sync::Mutex};
+
+use attaca::{canonical, Open, digest::{prelude::*, Sha3Digest}, pack::{Pack, TransferEncoding},
+             store::{RawHandle, prelude::*}};
+use failure::*;
+use futures::{future::{self, FutureResult}, prelude::*, stream::Empty};
+use url::{percent_encoding::percent_decode, Url};
+
+use protocol::{Object, Request, Response, PROTOCOL_VERSION};
+
+/// The ssh program to run, if not `ssh`. It is run as
+/// `$ATTACA_SSH [-p PORT] -- [USER@]HOST COMMAND`.
+pub const SSH_VAR: &'static str = "ATTACA_SSH";
+
+/// The command which runs subito on the remote host, if not `subito`. It is run through the remote
+/// user's shell, so it may include arguments.
+pub const REMOTE_COMMAND_VAR: &'static str = "ATTACA_REMOTE_SUBITO";
+
+const DEFAULT_SSH: &'static str = "ssh";
+const DEFAULT_REMOTE_COMMAND: &'static str = "subito";
+
+/// Quote a string for a POSIX shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r#"'\''"#))
+}
+
+/// The `[user@]host` for ssh to connect to. Neither part may start with a `-`, which ssh would take
+/// as an option (such as `-oProxyCommand=...`) rather than somewhere to connect to.
+fn ssh_destination(url: &Url) -> Result<String, Error> {
+    let host = url.host_str()
+        .ok_or_else(|| format_err!("SSH URL {} has no host!", url))?;
+    let user = percent_decode(url.username().as_bytes()).decode_utf8()?;
+    ensure!(
+        !host.starts_with('-') && !user.starts_with('-'),
+        "Refusing SSH URL {}: a user or host may not start with '-'",
+        url
+    );
+
+    match user.as_ref() {
+        "" => Ok(host.to_owned()),
+        user => Ok(format!("{}@{}", user, host)),
+    }
+}
+
+fn env_or(name: &str, default: &str) -> String {
+    match env::var(name) {
+        Ok(ref value) if !value.trim().is_empty() => value.clone(),
+        _ => default.to_owned(),
+    }
+}
+
+#[derive(Debug)]
+pub struct SshBuilder {
+    blob: Vec<u8>,
+    refs: Vec<RawHandle>,
+}
+
+impl Write for SshBuilder {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.blob.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Write::flush(&mut self.blob)
+    }
+}
+
+impl Extend<RawHandle> for SshBuilder {
+    fn extend<I>(&mut self, iterable: I)
+    where
+        I: IntoIterator<Item = RawHandle>,
+    {
+        self.refs.extend(iterable);
+    }
+}
+
+#[derive(Debug)]
+pub struct SshContent {
+    blob: Cursor<Vec<u8>>,
+    refs: <Vec<RawHandle> as IntoIterator>::IntoIter,
+}
+
+impl Read for SshContent {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.blob.read(buf)
+    }
+}
+
+impl Iterator for SshContent {
+    type Item = RawHandle;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.refs.next()
+    }
+}
+
+struct Connection {
+    /// The ssh process, for connections made over ssh.
+    child: Option<Child>,
+
+    // This is an `Option` only so that it can be closed before waiting for the child to exit.
+    writer: Option<Box<Write + Send>>,
+    reader: BufReader<Box<Read + Send>>,
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("child", &self.child)
+            .finish()
+    }
+}
+
+impl Connection {
+    fn ssh(url: &Url) -> Result<Self, Error> {
+        let destination = ssh_destination(url)?;
+        let path = percent_decode(url.path().as_bytes()).decode_utf8()?;
+
+        let ssh = env_or(SSH_VAR, DEFAULT_SSH);
+        let remote_command = format!(
+            "{} serve {}",
+            env_or(REMOTE_COMMAND_VAR, DEFAULT_REMOTE_COMMAND),
+            shell_quote(&path)
+        );
+
+        let mut command = Command::new(&ssh);
+        if let Some(port) = url.port() {
+            command.arg("-p").arg(port.to_string());
+        }
+        // `--` ends ssh's options, so that nothing after it is read as one.
+        let mut child = command
+            .arg("--")
+            .arg(&destination)
+            .arg(&remote_command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .with_context(|_| format!("Error running {:?}", ssh))?;
+
+        Ok(Connection {
+            writer: Some(Box::new(child.stdin.take().unwrap())),
+            reader: BufReader::new(Box::new(child.stdout.take().unwrap())),
+            child: Some(child),
+        })
+    }
+
+    fn tcp(url: &Url) -> Result<Self, Error> {
+        let host = url.host_str()
+            .ok_or_else(|| format_err!("URL {} has no host!", url))?;
+        let port = url.port()
+            .ok_or_else(|| format_err!("URL {} has no port!", url))?;
+        let stream = TcpStream::connect((host, port))
+            .with_context(|_| format!("Error connecting to {}:{}", host, port))?;
+
+        Ok(Connection {
+            reader: BufReader::new(Box::new(stream.try_clone()?)),
+            writer: Some(Box::new(stream)),
+            child: None,
+        })
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Response, Error> {
+        request.write(self.writer.as_mut().unwrap())?;
+        match Response::read(&mut self.reader)? {
+            Response::Error(message) => Err(format_err!("Remote error: {}", message)),
+            response => Ok(response),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Closing our end tells the server to stop.
+        self.writer.take();
+        if let Some(ref mut child) = self.child {
+            let _ = child.wait();
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    connection: Connection,
+
+    ids: HashMap<Sha3Digest, RawHandle>,
+    handles: HashMap<RawHandle, Sha3Digest>,
+}
+
+impl Inner {
+    fn reserve(&mut self, digest: Sha3Digest) -> RawHandle {
+        if let Some(&id) = self.ids.get(&digest) {
+            return id;
+        }
+
+        let id = RawHandle(self.ids.len() as u64);
+        self.ids.insert(digest, id);
+        self.handles.insert(id, digest);
+        id
+    }
+}
+
+/// A store belonging to a repository on another host, reached by running `subito serve` there
+/// over ssh. Authentication is left entirely to the ssh client.
+///
+/// URLs take the form `ssh://[USER@]HOST[:PORT]/PATH`, where `PATH` is the path to the
+/// repository on the remote host. A repository served with `subito serve --listen` can instead be
+/// reached directly at `attaca+tcp://HOST:PORT`; such connections are not authenticated.
+#[derive(Debug)]
+pub struct SshBackend {
+    uuid: [u8; 16],
+    inner: Mutex<Inner>,
+}
+
+impl Open for SshBackend {
+    const SCHEMES: &'static [&'static str] = &["ssh", "attaca+tcp"];
+
+    fn open(url_str: &str) -> Result<Self, Error> {
+        let url = Url::parse(url_str)?;
+        let connection = match url.scheme() {
+            "ssh" => Connection::ssh(&url)?,
+            "attaca+tcp" => Connection::tcp(&url)?,
+            _ => bail!("Unsupported URL scheme!"),
+        };
+        Self::handshake(&url, connection)
+    }
+
+    fn open_path(_path: &Path) -> Result<Self, Error> {
+        bail!("SSH stores can only be opened by URL!")
+    }
+}
+
+impl SshBackend {
+    fn handshake(url: &Url, mut connection: Connection) -> Result<Self, Error> {
+        let uuid = match connection
+            .request(&Request::Hello(PROTOCOL_VERSION))
+            .with_context(|_| format!("Error connecting to {}", url))?
+        {
+            Response::Hello(_, uuid) => uuid,
+            other => bail!("Unexpected response to hello: {:?}", other),
+        };
+
+        Ok(Self {
+            uuid,
+            inner: Mutex::new(Inner {
+                connection,
+                ids: HashMap::new(),
+                handles: HashMap::new(),
+            }),
+        })
+    }
+
+    fn do_finish(&self, builder: SshBuilder) -> Result<RawHandle, Error> {
+        let mut inner = self.inner.lock().unwrap();
+        let object = Object {
+            blob: builder.blob,
+            refs: builder.refs.iter().map(|id| inner.handles[id]).collect(),
+        };
+
+        // Check the remote's answer, since every handle we give out is trusted to name the object
+        // it was reserved for.
+        let mut hasher = Sha3Digest::writer();
+        canonical::encode(&mut hasher, &object.blob, &object.refs)?;
+        let expected = hasher.finish();
+
+        let response = inner.connection.request(&Request::Finish(object))?;
+        match response {
+            Response::Digest(digest) => {
+                ensure!(
+                    digest == expected,
+                    "Remote stored an object under the wrong digest!"
+                );
+                Ok(inner.reserve(digest))
+            }
+            other => bail!("Unexpected response to finish: {:?}", other),
+        }
+    }
+
+    fn do_load(&self, id: RawHandle) -> Result<SshContent, Error> {
+        let mut inner = self.inner.lock().unwrap();
+        let digest = inner.handles[&id];
+
+        let response = inner.connection.request(&Request::Load(digest))?;
+        match response {
+            Response::Object(object) => {
+                let refs = object
+                    .refs
+                    .into_iter()
+                    .map(|digest| inner.reserve(digest))
+                    .collect::<Vec<_>>();
+                Ok(SshContent {
+                    blob: Cursor::new(object.blob),
+                    refs: refs.into_iter(),
+                })
+            }
+            other => bail!("Unexpected response to load: {:?}", other),
+        }
+    }
+
+    fn do_size(&self, id: RawHandle) -> Result<Option<u64>, Error> {
+        let mut inner = self.inner.lock().unwrap();
+        let digest = inner.handles[&id];
+
+        let response = inner.connection.request(&Request::Size(digest))?;
+        match response {
+            Response::Size(size) => Ok(Some(size)),
+            other => bail!("Unexpected response to size: {:?}", other),
+        }
+    }
+
+    fn do_id(&self, id: RawHandle) -> Result<Sha3Digest, Error> {
+        Ok(self.inner.lock().unwrap().handles[&id])
+    }
+
+    fn do_digest(&self, signature: DigestSignature, id: RawHandle) -> Result<Sha3Digest, Error> {
+        ensure!(signature == Sha3Digest::SIGNATURE, "bad digest");
+
+        self.do_id(id)
+    }
+
+    fn do_contains(&self, digests: Vec<Sha3Digest>) -> Result<Vec<bool>, Error> {
+        let mut inner = self.inner.lock().unwrap();
+        let count = digests.len();
+
+        let response = inner.connection.request(&Request::ContainsDigests(digests))?;
+        match response {
+            Response::ContainsDigests(contained) => {
+                ensure!(
+                    contained.len() == count,
+                    "Remote answered a membership query with the wrong number of results"
+                );
+                Ok(contained)
+            }
+            other => bail!("Unexpected response to membership query: {:?}", other),
+        }
+    }
+
+    fn do_resolve_id(&self, digest: &Sha3Digest) -> Result<Option<RawHandle>, Error> {
+        if self.do_contains(vec![*digest])?[0] {
+            Ok(Some(self.inner.lock().unwrap().reserve(*digest)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn do_resolve_digest(
+        &self,
+        signature: DigestSignature,
+        bytes: &[u8],
+    ) -> Result<Option<RawHandle>, Error> {
+        ensure!(
+            signature == Sha3Digest::SIGNATURE,
+            "unsupported digest {:?}",
+            signature
+        );
+
+        self.do_resolve_id(&Sha3Digest::from_bytes(bytes))
+    }
+
+    fn do_load_branches(&self) -> Result<HashMap<String, RawHandle>, Error> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let response = inner.connection.request(&Request::LoadBranches)?;
+        match response {
+            Response::Branches(branches) => Ok(branches
+                .into_iter()
+                .map(|(name, digest)| (name, inner.reserve(digest)))
+                .collect()),
+            other => bail!("Unexpected response to loading branches: {:?}", other),
+        }
+    }
+
+    fn do_swap_branches(
+        &self,
+        previous: HashMap<String, RawHandle>,
+        new: HashMap<String, RawHandle>,
+    ) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        let previous = previous
+            .into_iter()
+            .map(|(name, id)| (name, inner.handles[&id]))
+            .collect();
+        let new = new.into_iter()
+            .map(|(name, id)| (name, inner.handles[&id]))
+            .collect();
+
+        let response = inner
+            .connection
+            .request(&Request::SwapBranches(previous, new))?;
+        match response {
+            Response::Swapped => Ok(()),
+            other => bail!("Unexpected response to swapping branches: {:?}", other),
+        }
+    }
+
+    fn do_contains_digests(
+        &self,
+        signature: DigestSignature,
+        digests: Vec<Vec<u8>>,
+    ) -> Result<Option<Vec<bool>>, Error> {
+        ensure!(
+            signature == Sha3Digest::SIGNATURE,
+            "unsupported digest {:?}",
+            signature
+        );
+
+        let digests = digests
+            .iter()
+            .map(|bytes| Sha3Digest::from_bytes(bytes))
+            .collect();
+        Ok(Some(self.do_contains(digests)?))
+    }
+}
+
+impl Backend for SshBackend {
+    fn uuid(&self) -> [u8; 16] {
+        self.uuid
+    }
+
+    type Builder = SshBuilder;
+    type FutureFinish = FutureResult<RawHandle, Error>;
+
+    fn builder(&self) -> Self::Builder {
+        SshBuilder {
+            blob: Vec::new(),
+            refs: Vec::new(),
+        }
+    }
+
+    fn finish(&self, builder: Self::Builder) -> Self::FutureFinish {
+        self.do_finish(builder).into_future()
+    }
+
+    type Content = SshContent;
+    type FutureContent = FutureResult<Self::Content, Error>;
+
+    fn load(&self, id: RawHandle) -> Self::FutureContent {
+        self.do_load(id).into_future()
+    }
+
+    type Id = Sha3Digest;
+    type FutureId = FutureResult<Self::Id, Error>;
+
+    fn id(&self, id: RawHandle) -> Self::FutureId {
+        self.do_id(id).into_future()
+    }
+
+    type Digest = Sha3Digest;
+    type FutureDigest = FutureResult<Self::Digest, Error>;
+
+    fn digest(&self, signature: DigestSignature, id: RawHandle) -> Self::FutureDigest {
+        self.do_digest(signature, id).into_future()
+    }
+
+    type FutureResolveId = FutureResult<Option<RawHandle>, Error>;
+
+    fn resolve_id(&self, digest: &Sha3Digest) -> Self::FutureResolveId {
+        self.do_resolve_id(digest).into_future()
+    }
+
+    type FutureResolveDigest = FutureResult<Option<RawHandle>, Error>;
+
+    fn resolve_digest(
+        &self,
+        signature: DigestSignature,
+        bytes: &[u8],
+    ) -> Self::FutureResolveDigest {
+        self.do_resolve_digest(signature, bytes).into_future()
+    }
+
+    type FutureLoadBranches = FutureResult<HashMap<String, RawHandle>, Error>;
+
+    fn load_branches(&self) -> Self::FutureLoadBranches {
+        self.do_load_branches().into_future()
+    }
+
+    type FutureSwapBranches = FutureResult<(), Error>;
+
+    fn swap_branches(
+        &self,
+        previous: HashMap<String, RawHandle>,
+        new: HashMap<String, RawHandle>,
+    ) -> Self::FutureSwapBranches {
+        self.do_swap_branches(previous, new).into_future()
+    }
+
+    type FutureStats = FutureResult<Option<StoreStats>, Error>;
+
+    fn stats(&self) -> Self::FutureStats {
+        future::ok(None)
+    }
+
+    // Objects are copied one at a time, so that only those missing on the other end are sent.
+    fn pack_encodings(&self) -> &'static [TransferEncoding] {
+        &[]
+    }
+
+    type FuturePack = FutureResult<Pack, Error>;
+
+    fn pack(
+        &self,
+        _signature: DigestSignature,
+        _encoding: TransferEncoding,
+        _root: RawHandle,
+        _have: Vec<Vec<u8>>,
+    ) -> Self::FuturePack {
+        future::err(format_err!("SSH stores cannot produce packs"))
+    }
+
+    type FutureContainsDigests = FutureResult<Option<Vec<bool>>, Error>;
+
+    fn contains_digests(
+        &self,
+        signature: DigestSignature,
+        digests: Vec<Vec<u8>>,
+    ) -> Self::FutureContainsDigests {
+        self.do_contains_digests(signature, digests).into_future()
+    }
+
+    type StreamDigests = Empty<Vec<u8>, Error>;
+
+    fn list_digests(&self, _signature: DigestSignature) -> Option<Self::StreamDigests> {
+        None
+    }
+
+    fn list_digests_with_prefix(
+        &self,
+        _signature: DigestSignature,
+        _prefix: &[u8],
+    ) -> Option<Self::StreamDigests> {
+        None
+    }
+
+    type FutureCompact = FutureResult<Option<CompactStats>, Error>;
+
+    fn compact(&self) -> Self::FutureCompact {
+        future::ok(None)
+    }
+
+    type FutureSize = FutureResult<Option<u64>, Error>;
+
+    fn size(&self, id: RawHandle) -> Self::FutureSize {
+        self.do_size(id).into_future()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ssh_destination_user_and_host() {
+        let url = Url::parse("ssh://alice@example.com:2222/srv/repo").unwrap();
+        assert_eq!(ssh_destination(&url).unwrap(), "alice@example.com");
+
+        let url = Url::parse("ssh://example.com/srv/repo").unwrap();
+        assert_eq!(ssh_destination(&url).unwrap(), "example.com");
+    }
+
+    #[test]
+    fn ssh_destination_rejects_options() {
+        for url in &[
+            "ssh://-oProxyCommand=touch%20pwned@example.com/repo",
+            "ssh://-oProxyCommand=touch/repo",
+        ] {
+            let url = Url::parse(url).unwrap();
+            assert!(ssh_destination(&url).is_err(), "{} was accepted", url);
+        }
+    }
+}
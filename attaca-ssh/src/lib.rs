@@ -0,0 +1,26 @@
+//! A backend for stores on other hosts, reached over SSH.
+//!
+//! The client end is `SshBackend`, which runs `subito serve` on the remote host through the
+//! system's ssh client and speaks to it over the child process's stdin and stdout. The server end
+//! is `serve`, which answers requests against any local `Store`. See `protocol` for the messages
+//! exchanged.
+
+extern crate attaca;
+extern crate capnp;
+#[macro_use]
+extern crate failure;
+extern crate futures_await as futures;
+extern crate url;
+
+#[allow(dead_code)]
+mod transport_capnp {
+    include!(concat!(env!("OUT_DIR"), "/transport_capnp.rs"));
+}
+
+mod backend;
+mod serve;
+
+pub mod protocol;
+
+pub use backend::*;
+pub use serve::{serve, Access};
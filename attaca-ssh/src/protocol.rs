@@ -0,0 +1,383 @@
+//! The messages exchanged between an `SshBackend` and `serve` on the other end of the connection.
+//!
+//! Every message is a packed capnp message, sent as a frame: its length in bytes as a big-endian
+//! `u32`, followed by the message itself. The client sends one request and waits for its response
+//! before sending the next, so there is never more than one message in flight in either direction.
+//! Objects are always named by their SHA-3 digests, since handles are local to each side.
+
+use std::io::{Read, Write};
+
+use attaca::digest::{prelude::*, Sha3Digest};
+use capnp::{data_list, message, serialize_packed, struct_list};
+use failure::*;
+
+use transport_capnp::*;
+
+/// Bumped whenever the messages change incompatibly.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Frames larger than this are refused, so that a corrupt length can't exhaust memory.
+pub const MAX_FRAME_SIZE: usize = 1 << 28;
+
+/// An object's content and the digests of the objects it references.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Object {
+    pub blob: Vec<u8>,
+    pub refs: Vec<Sha3Digest>,
+}
+
+/// Branch names and the digests of the commits they point to.
+pub type Branches = Vec<(String, Sha3Digest)>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Open the conversation, giving the client's protocol version.
+    Hello(u32),
+    Load(Sha3Digest),
+    Finish(Object),
+    ContainsDigests(Vec<Sha3Digest>),
+    LoadBranches,
+    SwapBranches(Branches, Branches),
+    /// Ask for the length of an object's blob without fetching it.
+    Size(Sha3Digest),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// The request failed; the message is meant for the user.
+    Error(String),
+    /// The server's protocol version and the UUID of its store.
+    Hello(u32, [u8; 16]),
+    Object(Object),
+    Digest(Sha3Digest),
+    ContainsDigests(Vec<bool>),
+    Branches(Branches),
+    Swapped,
+    Size(u64),
+}
+
+fn write_frame<W, A>(writer: &mut W, message: &message::Builder<A>) -> Result<(), Error>
+where
+    W: Write,
+    A: message::Allocator,
+{
+    let mut buf = Vec::new();
+    serialize_packed::write_message(&mut buf, message)?;
+    ensure!(
+        buf.len() <= MAX_FRAME_SIZE,
+        "Message of {} bytes is too large to send",
+        buf.len()
+    );
+
+    let len = buf.len() as u32;
+    writer.write_all(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8])?;
+    writer.write_all(&buf)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let mut len_bytes = [0; 4];
+    reader.read_exact(&mut len_bytes)?;
+    let len = len_bytes
+        .iter()
+        .fold(0usize, |len, &byte| (len << 8) | byte as usize);
+    ensure!(
+        len <= MAX_FRAME_SIZE,
+        "Refusing to read a message of {} bytes",
+        len
+    );
+
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf)?;
+
+    Ok(buf)
+}
+
+fn decode_digest(bytes: &[u8]) -> Result<Sha3Digest, Error> {
+    ensure!(
+        bytes.len() == Sha3Digest::SIGNATURE.size,
+        "Malformed digest: expected {} bytes, got {}",
+        Sha3Digest::SIGNATURE.size,
+        bytes.len()
+    );
+
+    Ok(Sha3Digest::from_bytes(bytes))
+}
+
+fn decode_digests(digests_reader: data_list::Reader) -> Result<Vec<Sha3Digest>, Error> {
+    digests_reader
+        .iter()
+        .map(|bytes| decode_digest(bytes?))
+        .collect()
+}
+
+fn encode_digests(mut digests_builder: data_list::Builder, digests: &[Sha3Digest]) {
+    for (i, digest) in digests.iter().enumerate() {
+        digests_builder.set(i as u32, digest.as_bytes());
+    }
+}
+
+fn decode_object(object_reader: object::Reader) -> Result<Object, Error> {
+    Ok(Object {
+        blob: object_reader.get_blob()?.to_vec(),
+        refs: decode_digests(object_reader.get_refs()?)?,
+    })
+}
+
+fn encode_object(mut object_builder: object::Builder, object: &Object) {
+    object_builder.set_blob(&object.blob);
+    encode_digests(
+        object_builder.init_refs(object.refs.len() as u32),
+        &object.refs,
+    );
+}
+
+fn decode_branches(branches_reader: struct_list::Reader<branch::Owned>) -> Result<Branches, Error> {
+    branches_reader
+        .iter()
+        .map(|branch_reader| {
+            let name = String::from(branch_reader.get_name()?);
+            let digest = decode_digest(branch_reader.get_digest()?)?;
+            Ok((name, digest))
+        })
+        .collect()
+}
+
+fn encode_branches(mut branches_builder: struct_list::Builder<branch::Owned>, branches: &Branches) {
+    for (i, &(ref name, ref digest)) in branches.iter().enumerate() {
+        let mut branch_builder = branches_builder.borrow().get(i as u32);
+        branch_builder.set_name(name);
+        branch_builder.set_digest(digest.as_bytes());
+    }
+}
+
+impl Request {
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        let frame = read_frame(reader)?;
+        let message_reader =
+            serialize_packed::read_message(&mut &frame[..], message::ReaderOptions::new())?;
+        let request_reader = message_reader.get_root::<request::Reader>()?;
+
+        let request = match request_reader.which()? {
+            request::Hello(version) => Request::Hello(version),
+            request::Load(digest) => Request::Load(decode_digest(digest?)?),
+            request::Finish(object) => Request::Finish(decode_object(object?)?),
+            request::ContainsDigests(digests) => {
+                Request::ContainsDigests(decode_digests(digests?)?)
+            }
+            request::LoadBranches(()) => Request::LoadBranches,
+            request::SwapBranches(swap) => {
+                let swap_reader = swap?;
+                Request::SwapBranches(
+                    decode_branches(swap_reader.get_previous()?)?,
+                    decode_branches(swap_reader.get_new()?)?,
+                )
+            }
+            request::Size(digest) => Request::Size(decode_digest(digest?)?),
+        };
+
+        Ok(request)
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        let mut message = message::Builder::new_default();
+
+        {
+            let mut request_builder = message.init_root::<request::Builder>();
+            match *self {
+                Request::Hello(version) => request_builder.set_hello(version),
+                Request::Load(ref digest) => request_builder.set_load(digest.as_bytes()),
+                Request::Finish(ref object) => encode_object(request_builder.init_finish(), object),
+                Request::ContainsDigests(ref digests) => encode_digests(
+                    request_builder.init_contains_digests(digests.len() as u32),
+                    digests,
+                ),
+                Request::LoadBranches => request_builder.set_load_branches(()),
+                Request::SwapBranches(ref previous, ref new) => {
+                    let mut swap_builder = request_builder.init_swap_branches();
+                    encode_branches(
+                        swap_builder.borrow().init_previous(previous.len() as u32),
+                        previous,
+                    );
+                    encode_branches(swap_builder.init_new(new.len() as u32), new);
+                }
+                Request::Size(ref digest) => request_builder.set_size(digest.as_bytes()),
+            }
+        }
+
+        write_frame(writer, &message)
+    }
+}
+
+impl Response {
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        let frame = read_frame(reader)?;
+        let message_reader =
+            serialize_packed::read_message(&mut &frame[..], message::ReaderOptions::new())?;
+        let response_reader = message_reader.get_root::<response::Reader>()?;
+
+        let response = match response_reader.which()? {
+            response::Error(message) => Response::Error(String::from(message?)),
+            response::Hello(hello) => {
+                let hello_reader = hello?;
+                let uuid_bytes = hello_reader.get_uuid()?;
+                ensure!(uuid_bytes.len() == 16, "Malformed store UUID");
+                let mut uuid = [0; 16];
+                uuid.copy_from_slice(uuid_bytes);
+                Response::Hello(hello_reader.get_version(), uuid)
+            }
+            response::Object(object) => Response::Object(decode_object(object?)?),
+            response::Digest(digest) => Response::Digest(decode_digest(digest?)?),
+            response::ContainsDigests(contained) => {
+                Response::ContainsDigests(contained?.iter().collect())
+            }
+            response::Branches(branches) => Response::Branches(decode_branches(branches?)?),
+            response::Swapped(()) => Response::Swapped,
+            response::Size(size) => Response::Size(size),
+        };
+
+        Ok(response)
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        let mut message = message::Builder::new_default();
+
+        {
+            let mut response_builder = message.init_root::<response::Builder>();
+            match *self {
+                Response::Error(ref message) => response_builder.set_error(message),
+                Response::Hello(version, ref uuid) => {
+                    let mut hello_builder = response_builder.init_hello();
+                    hello_builder.set_version(version);
+                    hello_builder.set_uuid(uuid);
+                }
+                Response::Object(ref object) => {
+                    encode_object(response_builder.init_object(), object)
+                }
+                Response::Digest(ref digest) => response_builder.set_digest(digest.as_bytes()),
+                Response::ContainsDigests(ref contained) => {
+                    let mut contained_builder =
+                        response_builder.init_contains_digests(contained.len() as u32);
+                    for (i, &is_contained) in contained.iter().enumerate() {
+                        contained_builder.set(i as u32, is_contained);
+                    }
+                }
+                Response::Branches(ref branches) => encode_branches(
+                    response_builder.init_branches(branches.len() as u32),
+                    branches,
+                ),
+                Response::Swapped => response_builder.set_swapped(()),
+                Response::Size(size) => response_builder.set_size(size),
+            }
+        }
+
+        write_frame(writer, &message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn digest(byte: u8) -> Sha3Digest {
+        Sha3Digest::from_bytes(&[byte; 32])
+    }
+
+    fn round_trip_request(request: Request) {
+        let mut buf = Vec::new();
+        request.write(&mut buf).unwrap();
+        assert_eq!(Request::read(&mut Cursor::new(buf)).unwrap(), request);
+    }
+
+    fn round_trip_response(response: Response) {
+        let mut buf = Vec::new();
+        response.write(&mut buf).unwrap();
+        assert_eq!(Response::read(&mut Cursor::new(buf)).unwrap(), response);
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        round_trip_request(Request::Hello(PROTOCOL_VERSION));
+        round_trip_request(Request::Load(digest(1)));
+        round_trip_request(Request::Finish(Object {
+            blob: b"hello".to_vec(),
+            refs: vec![digest(2), digest(3)],
+        }));
+        round_trip_request(Request::ContainsDigests(vec![digest(4), digest(5)]));
+        round_trip_request(Request::LoadBranches);
+        round_trip_request(Request::SwapBranches(
+            vec![("refs/heads/master".to_owned(), digest(6))],
+            vec![
+                ("refs/heads/master".to_owned(), digest(7)),
+                ("refs/heads/topic".to_owned(), digest(6)),
+            ],
+        ));
+        round_trip_request(Request::Size(digest(8)));
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        round_trip_response(Response::Error("no such object".to_owned()));
+        round_trip_response(Response::Hello(PROTOCOL_VERSION, [9; 16]));
+        round_trip_response(Response::Object(Object {
+            blob: Vec::new(),
+            refs: vec![digest(1)],
+        }));
+        round_trip_response(Response::Digest(digest(2)));
+        round_trip_response(Response::ContainsDigests(vec![true, false, true]));
+        round_trip_response(Response::Branches(Vec::new()));
+        round_trip_response(Response::Swapped);
+        round_trip_response(Response::Size(1 << 40));
+    }
+
+    #[test]
+    fn messages_are_read_one_at_a_time() {
+        let mut buf = Vec::new();
+        Request::LoadBranches.write(&mut buf).unwrap();
+        Request::Load(digest(1)).write(&mut buf).unwrap();
+
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(Request::read(&mut cursor).unwrap(), Request::LoadBranches);
+        assert_eq!(Request::read(&mut cursor).unwrap(), Request::Load(digest(1)));
+    }
+
+    #[test]
+    fn short_digests_are_rejected() {
+        let mut message = message::Builder::new_default();
+        message
+            .init_root::<request::Builder>()
+            .set_load(&[0; 4]);
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &message).unwrap();
+
+        assert!(Request::read(&mut Cursor::new(buf)).is_err());
+    }
+
+    #[test]
+    fn frames_are_length_prefixed() {
+        let mut buf = Vec::new();
+        Request::LoadBranches.write(&mut buf).unwrap();
+
+        let len = read_frame(&mut Cursor::new(&buf)).unwrap().len();
+        assert_eq!(buf.len(), 4 + len);
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        let buf = vec![0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0];
+        assert!(Request::read(&mut Cursor::new(buf)).is_err());
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let mut buf = Vec::new();
+        Request::Load(digest(1)).write(&mut buf).unwrap();
+        buf.pop();
+
+        assert!(Request::read(&mut Cursor::new(buf)).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::{collections::HashMap, io::{BufRead, Read, Write}};
+
+use attaca::{digest::{prelude::*, Sha3Digest}, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+
+use protocol::{Branches, Object, Request, Response, PROTOCOL_VERSION};
+
+/// What clients of `serve` may do to the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Clients may fetch objects, check which objects exist and list branches.
+    ReadOnly,
+
+    /// Clients may also send objects and update branches.
+    ReadWrite,
+}
+
+fn resolve<B: Backend>(store: &Store<B>, digest: Sha3Digest) -> Result<Handle<B>, Error> {
+    store
+        .resolve_digest(digest)
+        .wait()?
+        .ok_or_else(|| format_err!("No such object: {:?}", digest))
+}
+
+fn resolve_branches<B: Backend>(
+    store: &Store<B>,
+    branches: Branches,
+) -> Result<HashMap<String, Handle<B>>, Error> {
+    branches
+        .into_iter()
+        .map(|(name, digest)| Ok((name, resolve(store, digest)?)))
+        .collect()
+}
+
+fn respond<B: Backend>(
+    store: &Store<B>,
+    access: Access,
+    request: Request,
+) -> Result<Response, Error> {
+    match request {
+        Request::Finish(_) | Request::SwapBranches(..) => ensure!(
+            access == Access::ReadWrite,
+            "This repository is served read-only"
+        ),
+        _ => {}
+    }
+
+    let response = match request {
+        Request::Hello(version) => {
+            ensure!(
+                version == PROTOCOL_VERSION,
+                "Protocol version mismatch: the client speaks version {}, but this server only \
+                 speaks version {}",
+                version,
+                PROTOCOL_VERSION
+            );
+            Response::Hello(PROTOCOL_VERSION, store.uuid())
+        }
+        Request::Load(digest) => {
+            let mut content = resolve(store, digest)?.load().wait()?;
+            let mut blob = Vec::new();
+            content.read_to_end(&mut blob)?;
+            let refs = content
+                .map(|handle| handle.digest::<Sha3Digest>().wait())
+                .collect::<Result<_, _>>()?;
+            Response::Object(Object { blob, refs })
+        }
+        Request::Finish(object) => {
+            let mut builder = store.builder();
+            builder.write_all(&object.blob)?;
+            for digest in object.refs {
+                builder.push(resolve(store, digest)?);
+            }
+            let handle = builder.finish().wait()?;
+            Response::Digest(handle.digest::<Sha3Digest>().wait()?)
+        }
+        Request::ContainsDigests(digests) => {
+            Response::ContainsDigests(store.contains_digests(digests).wait()?)
+        }
+        Request::LoadBranches => {
+            let branches = store
+                .load_branches()
+                .wait()?
+                .into_iter()
+                .map(|(name, handle)| Ok((name, handle.digest::<Sha3Digest>().wait()?)))
+                .collect::<Result<_, Error>>()?;
+            Response::Branches(branches)
+        }
+        Request::SwapBranches(previous, new) => {
+            let previous = resolve_branches(store, previous)?;
+            let new = resolve_branches(store, new)?;
+            store.swap_branches(previous, new).wait()?;
+            Response::Swapped
+        }
+        Request::Size(digest) => Response::Size(resolve(store, digest)?.size().wait()?),
+    };
+
+    Ok(response)
+}
+
+/// Serve `store` to a single `SshBackend`, reading requests from `reader` and writing responses
+/// to `writer` until the client hangs up. Requests which fail, including those not allowed by
+/// `access`, are answered with the error, and the conversation carries on; only errors reading or
+/// writing messages end it.
+pub fn serve<B, R, W>(
+    store: &Store<B>,
+    access: Access,
+    mut reader: R,
+    mut writer: W,
+) -> Result<(), Error>
+where
+    B: Backend,
+    R: BufRead,
+    W: Write,
+{
+    while !reader.fill_buf()?.is_empty() {
+        let request = Request::read(&mut reader).context("Error reading request")?;
+        let response = respond(store, access, request).unwrap_or_else(|err| {
+            let causes = err.causes().map(|cause| cause.to_string()).collect::<Vec<_>>();
+            Response::Error(causes.join(": "))
+        });
+        response
+            .write(&mut writer)
+            .context("Error writing response")?;
+    }
+
+    Ok(())
+}
@@ -1,10 +1,10 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{fmt, collections::HashMap, sync::{Arc, Mutex}};
 
 use failure::*;
-use futures::prelude::*;
+use futures::{future::{self, Either}, prelude::*};
 use im::List;
 
-use object::{ObjectRef, TreeBuilder};
+use object::{ObjectRef, Tree, TreeBuilder, TreeRef};
 use path::ObjectPath;
 use store::prelude::*;
 
@@ -131,15 +131,88 @@ impl<B: Backend> Operation<B> {
     }
 }
 
+/// Trees which have already been sent to a store, so that a tree built more than once (say, for
+/// several identical copies of a directory) is only encoded and sent the first time. Since trees
+/// are compared by their entries, a tree found here has the same digest as the one that was sent.
+/// Clones share the same set of trees.
+pub struct SentTrees<B: Backend> {
+    sent: Arc<Mutex<HashMap<Tree<Handle<B>>, TreeRef<Handle<B>>>>>,
+}
+
+impl<B: Backend> fmt::Debug for SentTrees<B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SentTrees")
+            .field("sent", &self.sent.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl<B: Backend> Clone for SentTrees<B> {
+    fn clone(&self) -> Self {
+        Self {
+            sent: self.sent.clone(),
+        }
+    }
+}
+
+impl<B: Backend> Default for SentTrees<B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<B: Backend> SentTrees<B> {
+    pub fn new() -> Self {
+        Self {
+            sent: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Send a tree to the store, unless an identical tree has already been sent.
+    pub fn send(
+        &self,
+        tree: &Tree<Handle<B>>,
+        store: &Store<B>,
+    ) -> impl Future<Item = TreeRef<Handle<B>>, Error = Error> {
+        if let Some(tree_ref) = self.sent.lock().unwrap().get(tree) {
+            return Either::A(future::ok(tree_ref.clone()));
+        }
+
+        let sent = self.sent.clone();
+        let tree = tree.clone();
+        Either::B(tree.send(store).map(move |tree_ref| {
+            sent.lock().unwrap().insert(tree, tree_ref.clone());
+            tree_ref
+        }))
+    }
+}
+
+/// A set of additions and deletions to apply to a tree in one pass.
+///
+/// A subtree which the batch leaves with no entries (for instance, because every file in it was
+/// deleted) is pruned from its parent by default, exactly as though the subtree had been deleted,
+/// so the same contents always give the same tree digest no matter how they were arrived at. Use
+/// `keep_empty_trees` to keep such subtrees as empty trees instead. Either way, trees added whole
+/// are taken as they are.
 #[derive(Debug, Clone)]
 pub struct Batch<B: Backend> {
     root: HashMap<Arc<String>, Node<B>>,
+    keep_empty_trees: bool,
 }
 
 impl<B: Backend> Batch<B> {
     pub fn new() -> Self {
         Self {
             root: HashMap::new(),
+            keep_empty_trees: false,
+        }
+    }
+
+    /// Whether subtrees left empty by the batch are kept as empty trees rather than pruned.
+    pub fn keep_empty_trees(self, keep_empty_trees: bool) -> Self {
+        Self {
+            keep_empty_trees,
+            ..self
         }
     }
 
@@ -155,7 +228,10 @@ impl<B: Backend> Batch<B> {
         let root = await!(Node::do_insert(head, tail, value, self.root))
             .context("Error while inserting operation into batch trie")?;
 
-        Ok(Self { root })
+        Ok(Self {
+            root,
+            keep_empty_trees: self.keep_empty_trees,
+        })
     }
 
     #[async]
@@ -164,7 +240,20 @@ impl<B: Backend> Batch<B> {
         store: Store<B>,
         tree_builder: TreeBuilder<Handle<B>>,
     ) -> Result<TreeBuilder<Handle<B>>, Error> {
-        Ok(await!(self.into_iter().run(store, tree_builder))?)
+        Ok(await!(self.run_deduplicated(store, tree_builder, SentTrees::new()))?)
+    }
+
+    /// Like `run`, but skipping any subtrees which have already been sent according to `sent`.
+    /// Share a `SentTrees` between several batches to avoid resending trees common to them.
+    #[async]
+    pub fn run_deduplicated(
+        self,
+        store: Store<B>,
+        tree_builder: TreeBuilder<Handle<B>>,
+        sent: SentTrees<B>,
+    ) -> Result<TreeBuilder<Handle<B>>, Error> {
+        let keep_empty_trees = self.keep_empty_trees;
+        Ok(await!(self.into_iter().run(store, tree_builder, sent, keep_empty_trees))?)
     }
 }
 
@@ -204,6 +293,8 @@ impl<B: Backend> BatchIter<B> {
         self,
         store: Store<B>,
         mut tree_builder: TreeBuilder<Handle<B>>,
+        sent: SentTrees<B>,
+        keep_empty_trees: bool,
     ) -> Result<TreeBuilder<Handle<B>>, Error> {
         for (name, batched_op) in self {
             match batched_op {
@@ -222,10 +313,15 @@ impl<B: Backend> BatchIter<B> {
                         _ => TreeBuilder::new(),
                     };
 
-                    let child_built = await!(batch_iter.run(store.clone(), child_builder))?;
+                    let child_built = await!(batch_iter.run(
+                        store.clone(),
+                        child_builder,
+                        sent.clone(),
+                        keep_empty_trees
+                    ))?;
 
-                    if !child_built.is_empty() {
-                        let child_ref = await!(child_built.as_tree().send(&store))?;
+                    if keep_empty_trees || !child_built.is_empty() {
+                        let child_ref = await!(sent.send(child_built.as_tree(), &store))?;
                         tree_builder.insert(
                             Arc::try_unwrap(name).unwrap_or_else(|arcd| (*arcd).clone()),
                             ObjectRef::Tree(child_ref),
@@ -238,3 +334,122 @@ impl<B: Backend> BatchIter<B> {
         Ok(tree_builder)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::BTreeMap, io::Write};
+
+    use digest::Sha3Digest;
+    use memory::MemoryBackend;
+    use object::{SmallBuilder, SmallRef};
+    use store::dummy::*;
+
+    use proptest::prelude::*;
+
+    fn path(s: &str) -> ObjectPath {
+        ObjectPath::from_path(s).unwrap()
+    }
+
+    /// Stage two files in a subdirectory and one beside it, then unstage both files in the
+    /// subdirectory.
+    fn stage_then_unstage_subdir(
+        batch: Batch<DummyBackend>,
+        handle: &Handle<DummyBackend>,
+    ) -> Batch<DummyBackend> {
+        let file = || ObjectRef::Small(SmallRef::new(0, handle.clone()));
+        let ops = vec![
+            Operation::Add(path("dir/a"), file()),
+            Operation::Add(path("dir/b"), file()),
+            Operation::Add(path("top"), file()),
+            Operation::Delete(path("dir/a")),
+            Operation::Delete(path("dir/b")),
+        ];
+
+        ops.into_iter().fold(batch, |batch, op| batch.add(op).wait().unwrap())
+    }
+
+    fn store_and_handle() -> BoxedStrategy<(Store<DummyBackend>, Handle<DummyBackend>)> {
+        Just(Store::default())
+            .prop_flat_map(|store| (Just(store.clone()), dummy_handle(store)))
+            .boxed()
+    }
+
+    fn send_small<B: Backend>(store: &Store<B>, content: u8) -> ObjectRef<Handle<B>> {
+        let mut small_builder = SmallBuilder::new();
+        small_builder.write_all(&[content]).unwrap();
+        ObjectRef::Small(small_builder.as_small().send(store).wait().unwrap())
+    }
+
+    /// Directories each holding a single file, some of them identical.
+    fn arb_dirs() -> BoxedStrategy<BTreeMap<String, u8>> {
+        prop::collection::btree_map("[a-z]{1,4}", 0u8..3, 1..8).boxed()
+    }
+
+    proptest! {
+        #[test]
+        fn deduplicated_trees_keep_their_digests(ref dirs in arb_dirs()) {
+            // Build the same directories twice, the second time sharing every tree sent the first.
+            let store = Store::new(MemoryBackend::new());
+            let sent = SentTrees::new();
+            let mut roots = Vec::new();
+            for _ in 0..2 {
+                let batch = dirs.iter().fold(Batch::new(), |batch, (dir, &content)| {
+                    let file = send_small(&store, content);
+                    let op = Operation::Add(path(&format!("{}/f", dir)), file);
+                    batch.add(op).wait().unwrap()
+                });
+                let built = batch
+                    .run_deduplicated(store.clone(), TreeBuilder::new(), sent.clone())
+                    .wait()
+                    .unwrap();
+                roots.push(built.into_tree().send(&store).wait().unwrap());
+            }
+
+            // Build them again by hand in a fresh store, sending every tree.
+            let fresh = Store::new(MemoryBackend::new());
+            let mut root_builder = TreeBuilder::new();
+            for (dir, &content) in dirs {
+                let mut dir_builder = TreeBuilder::new();
+                dir_builder.insert("f".to_owned(), send_small(&fresh, content));
+                let dir_ref = dir_builder.into_tree().send(&fresh).wait().unwrap();
+                root_builder.insert(dir.clone(), ObjectRef::Tree(dir_ref));
+            }
+            let expected_ref = root_builder.into_tree().send(&fresh).wait().unwrap();
+            let expected = expected_ref.as_inner().digest::<Sha3Digest>().wait().unwrap();
+
+            for root in roots {
+                assert_eq!(root.as_inner().digest::<Sha3Digest>().wait().unwrap(), expected);
+            }
+        }
+
+        #[test]
+        fn emptied_subtree_is_pruned((ref store, ref handle) in store_and_handle()) {
+            let batch = stage_then_unstage_subdir(Batch::new(), handle);
+            let built = batch.run(store.clone(), TreeBuilder::new()).wait().unwrap();
+
+            assert!(built.contains_key("top"));
+            assert!(!built.contains_key("dir"));
+        }
+
+        #[test]
+        fn emptied_subtree_is_kept_if_asked((ref store, ref handle) in store_and_handle()) {
+            // The dummy backend can't store anything, so pretend the empty tree was sent already.
+            let sent = SentTrees::new();
+            let empty_ref = TreeRef::new(handle.clone());
+            sent.sent
+                .lock()
+                .unwrap()
+                .insert(TreeBuilder::new().as_tree().clone(), empty_ref.clone());
+
+            let batch = stage_then_unstage_subdir(Batch::new().keep_empty_trees(true), handle);
+            let built = batch
+                .run_deduplicated(store.clone(), TreeBuilder::new(), sent)
+                .wait()
+                .unwrap();
+
+            assert!(built.contains_key("top"));
+            assert_eq!(built.get("dir"), Some(&ObjectRef::Tree(empty_ref)));
+        }
+    }
+}
@@ -1,56 +1,192 @@
-use std::{fmt, collections::HashMap, sync::Arc};
+use std::{fmt, collections::{BTreeMap, HashMap}, sync::{Arc, Weak}};
 
 use failure::{Compat, Error, ResultExt};
 use futures::{future::Shared, prelude::*};
 use im::List;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
 use object::{FutureTree, ObjectRef, Tree, TreeRef};
 use path::ObjectPath;
 use store::prelude::*;
 
+/// The number of fetched trees a `Hierarchy` keeps in memory unless told otherwise.
+pub const DEFAULT_CACHE_CAPACITY: usize = 4096;
+
+/// Counters for the tree cache of a `Hierarchy`, for tuning its capacity.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups which passed through a tree which was already fetched or being fetched.
+    pub hits: u64,
+    /// Lookups which had to fetch a tree from the store.
+    pub misses: u64,
+    /// Trees dropped from memory to stay within capacity.
+    pub evictions: u64,
+    /// Trees currently held in memory.
+    pub resident: usize,
+}
+
+/// A lazily loaded view of a tree and everything beneath it. Trees are fetched as paths through
+/// them are looked up, and kept in memory up to a fixed number of trees; past that, the least
+/// recently used trees are dropped and will simply be fetched again if needed.
 #[derive(Debug)]
 pub struct Hierarchy<B: Backend> {
     root: Option<Arc<RwLock<Node<B>>>>,
+    cache: Arc<Mutex<TreeCache<B>>>,
 }
 
 impl<B: Backend> Clone for Hierarchy<B> {
     fn clone(&self) -> Self {
         Self {
             root: self.root.clone(),
+            cache: self.cache.clone(),
         }
     }
 }
 
 impl<B: Backend> From<TreeRef<Handle<B>>> for Hierarchy<B> {
     fn from(tree_ref: TreeRef<Handle<B>>) -> Self {
+        Self::with_capacity(tree_ref, DEFAULT_CACHE_CAPACITY)
+    }
+}
+
+impl<B: Backend> Hierarchy<B> {
+    pub fn new() -> Self {
+        Self {
+            root: None,
+            cache: Arc::new(Mutex::new(TreeCache::new(DEFAULT_CACHE_CAPACITY))),
+        }
+    }
+
+    /// Create a hierarchy which keeps at most `capacity` fetched trees in memory.
+    pub fn with_capacity(tree_ref: TreeRef<Handle<B>>, capacity: usize) -> Self {
         Self {
             root: Some(Arc::new(RwLock::new(Node {
                 objref: ObjectRef::Tree(tree_ref),
                 state: NodeState::UnPolled,
             }))),
+            cache: Arc::new(Mutex::new(TreeCache::new(capacity))),
         }
     }
-}
-
-impl<B: Backend> Hierarchy<B> {
-    pub fn new() -> Self {
-        Self { root: None }
-    }
 
     pub fn get(
         &self,
         path: ObjectPath,
     ) -> impl Future<Item = Option<ObjectRef<Handle<B>>>, Error = Error> {
         let root = self.root.clone();
+        let cache = self.cache.clone();
 
         async_block! {
             match root {
-                Some(node) => Ok(await!(Node::get(node, path.inner))?),
+                Some(node) => Ok(await!(Node::get(node, path.inner, cache))?),
                 None => Ok(None),
             }
         }
     }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.lock().stats()
+    }
+}
+
+/// Tracks which nodes hold fetched trees, in order of use, and unloads the least recently used
+/// ones when there are too many. Nodes are identified by address; holding a `Weak` to each keeps
+/// the allocation, and so the address, from being reused while it is tracked.
+struct TreeCache<B: Backend> {
+    capacity: usize,
+    clock: u64,
+    resident: HashMap<usize, (u64, Weak<RwLock<Node<B>>>)>,
+    lru: BTreeMap<u64, usize>,
+    stats: CacheStats,
+}
+
+impl<B: Backend> fmt::Debug for TreeCache<B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TreeCache")
+            .field("capacity", &self.capacity)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl<B: Backend> TreeCache<B> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            clock: 0,
+            resident: HashMap::new(),
+            lru: BTreeMap::new(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    fn key(node: &Arc<RwLock<Node<B>>>) -> usize {
+        &**node as *const RwLock<Node<B>> as usize
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            resident: self.resident.len(),
+            ..self.stats
+        }
+    }
+
+    fn miss(&mut self) {
+        self.stats.misses += 1;
+    }
+
+    /// Record a use of a node whose tree is loaded or being loaded.
+    fn hit(&mut self, node: &Arc<RwLock<Node<B>>>) {
+        self.stats.hits += 1;
+
+        let key = Self::key(node);
+        if let Some(&mut (ref mut tick, _)) = self.resident.get_mut(&key) {
+            self.lru.remove(tick);
+            self.clock += 1;
+            *tick = self.clock;
+            self.lru.insert(self.clock, key);
+        }
+    }
+
+    /// Record that a node's tree has been loaded, evicting others if we are over capacity.
+    fn insert(&mut self, node: &Arc<RwLock<Node<B>>>) {
+        let key = Self::key(node);
+        self.clock += 1;
+        if let Some((old_tick, _)) = self.resident
+            .insert(key, (self.clock, Arc::downgrade(node)))
+        {
+            self.lru.remove(&old_tick);
+        }
+        self.lru.insert(self.clock, key);
+
+        // Nodes which are locked can't be unloaded right now; give up on them for this round
+        // rather than spinning.
+        let mut attempts = self.resident.len();
+        while self.resident.len() > self.capacity && attempts > 0 {
+            attempts -= 1;
+
+            let (tick, key) = match self.lru.iter().next() {
+                Some((&tick, &key)) => (tick, key),
+                None => break,
+            };
+            self.lru.remove(&tick);
+            let (_, weak) = self.resident.remove(&key).unwrap();
+
+            // If the node is already gone, an ancestor was evicted before it.
+            if let Some(node) = weak.upgrade() {
+                match node.try_write() {
+                    Some(mut guard) => {
+                        guard.state = NodeState::UnPolled;
+                        self.stats.evictions += 1;
+                    }
+                    None => {
+                        self.clock += 1;
+                        self.resident.insert(key, (self.clock, weak));
+                        self.lru.insert(self.clock, key);
+                    }
+                }
+            }
+        }
+    }
 }
 
 struct CompatFutureTree<B: Backend>(FutureTree<B>);
@@ -154,7 +290,9 @@ impl<B: Backend> Node<B> {
     fn get(
         this: Arc<RwLock<Self>>,
         path: List<String>,
+        cache: Arc<Mutex<TreeCache<B>>>,
     ) -> Result<Option<ObjectRef<Handle<B>>>, Error> {
+        let full_path = path.clone();
         match path.uncons() {
             None => Ok(Some(this.read().objref.clone())),
             Some((head, tail)) => {
@@ -176,11 +314,17 @@ impl<B: Backend> Node<B> {
                     }
                 };
 
+                match primary {
+                    Primary::UnPolled(_) => cache.lock().miss(),
+                    Primary::NotReady(_) | Primary::Ready(_) => cache.lock().hit(&this),
+                }
+
                 match primary.update_if_unpolled(&this, &head) {
                     // Poll the shared future to completion, then race to emplace the result.
                     Some(Secondary::NotReady(shared)) => {
                         let shared_tree = await!(shared)?;
 
+                        let mut won = false;
                         let future_node = {
                             // See if we're the node who wins the replacement race.
                             let guard = match this.try_write() {
@@ -199,6 +343,7 @@ impl<B: Backend> Node<B> {
                                         })
                                         .collect();
                                     guard.state = NodeState::Ready(hash_map);
+                                    won = true;
                                     guard.downgrade()
                                 }
                                 // We lose - wait for a read lock.
@@ -209,16 +354,25 @@ impl<B: Backend> Node<B> {
                             match guard.state {
                                 NodeState::Ready(ref hash_map) => hash_map
                                     .get(&head)
-                                    .map(|node| Self::get(node.clone(), tail)),
+                                    .map(|node| Self::get(node.clone(), tail, cache.clone())),
 
-                                // Impossible! We just polled everything to completion.
-                                NodeState::UnPolled | NodeState::NotReady(_) => unreachable!(),
+                                // We just polled everything to completion, so the tree must have
+                                // been evicted in the meantime. Start over.
+                                NodeState::UnPolled | NodeState::NotReady(_) => {
+                                    Some(Self::get(this.clone(), full_path, cache.clone()))
+                                }
                             }
                         };
 
+                        // Only track the node once its lock has been released, so that it can't
+                        // get in the way of its own eviction.
+                        if won {
+                            cache.lock().insert(&this);
+                        }
+
                         Ok(await!(future_node)?.and_then(|x| x))
                     }
-                    Some(Secondary::Ready(node)) => Ok(await!(Self::get(node, tail))?),
+                    Some(Secondary::Ready(node)) => Ok(await!(Self::get(node, tail, cache))?),
                     None => Ok(None),
                 }
             }
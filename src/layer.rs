@@ -0,0 +1,320 @@
+//! Decorators for backends.
+//!
+//! A `Layer` wraps a `Backend` in another `Backend` which may intercept any of its operations -
+//! for example, to cache objects, gather metrics, or transform object payloads on their way to
+//! and from storage. Layers compose by wrapping one layered backend in another, and since a
+//! layered backend is just another `Backend`, it can be handed to `Store::new` like any other:
+//!
+//! ```ignore
+//! let backend = LevelDbBackend::open_path(path)?.layer(CacheLayer::new(1024));
+//! let store = Store::new(backend);
+//! ```
+
+use std::{io::{self, Read}, collections::{BTreeMap, HashMap}, sync::Arc, vec};
+
+use failure::Error;
+use futures::prelude::*;
+use parking_lot::Mutex;
+
+use digest::prelude::*;
+use pack::TransferEncoding;
+use store::{Backend, RawHandle};
+
+/// A decorator which wraps a backend of type `B` in another backend.
+pub trait Layer<B: Backend> {
+    type Backend: Backend;
+
+    fn wrap(self, inner: B) -> Self::Backend;
+}
+
+/// Two layers applied one after the other: `Inner` wraps the backend first, and `Outer` wraps
+/// the result.
+impl<B, Inner, Outer> Layer<B> for (Inner, Outer)
+where
+    B: Backend,
+    Inner: Layer<B>,
+    Outer: Layer<Inner::Backend>,
+{
+    type Backend = Outer::Backend;
+
+    fn wrap(self, inner: B) -> Self::Backend {
+        self.1.wrap(self.0.wrap(inner))
+    }
+}
+
+/// Extension trait for wrapping backends in layers.
+pub trait BackendExt: Backend + Sized {
+    fn layer<L: Layer<Self>>(self, layer: L) -> L::Backend {
+        layer.wrap(self)
+    }
+}
+
+impl<B: Backend> BackendExt for B {}
+
+/// The default number of objects kept by a `CacheLayer`.
+pub const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+/// A read-through, in-memory cache of recently loaded objects. Objects are evicted least
+/// recently used first once more than `capacity` of them are cached.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheLayer {
+    capacity: usize,
+}
+
+impl Default for CacheLayer {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_CAPACITY)
+    }
+}
+
+impl CacheLayer {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity }
+    }
+}
+
+impl<B: Backend> Layer<B> for CacheLayer {
+    type Backend = CachedBackend<B>;
+
+    fn wrap(self, inner: B) -> Self::Backend {
+        CachedBackend {
+            inner,
+            cache: Arc::new(Mutex::new(ObjectCache::new(self.capacity))),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CachedObject {
+    data: Vec<u8>,
+    refs: Vec<RawHandle>,
+}
+
+#[derive(Debug)]
+struct ObjectCache {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<RawHandle, (u64, Arc<CachedObject>)>,
+    lru: BTreeMap<u64, RawHandle>,
+}
+
+impl ObjectCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, id: RawHandle) -> Option<Arc<CachedObject>> {
+        let tick = self.tick;
+        match self.entries.get_mut(&id) {
+            Some(&mut (ref mut last_used, ref object)) => {
+                self.lru.remove(last_used);
+                self.lru.insert(tick, id);
+                *last_used = tick;
+                self.tick += 1;
+                Some(object.clone())
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, id: RawHandle, object: Arc<CachedObject>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if let Some((last_used, _)) = self.entries.insert(id, (self.tick, object)) {
+            self.lru.remove(&last_used);
+        }
+        self.lru.insert(self.tick, id);
+        self.tick += 1;
+
+        while self.entries.len() > self.capacity {
+            let oldest = *self.lru.keys().next().unwrap();
+            let evicted = self.lru.remove(&oldest).unwrap();
+            self.entries.remove(&evicted);
+        }
+    }
+}
+
+/// The content of an object loaded through a `CachedBackend`.
+#[derive(Debug)]
+pub struct CachedContent {
+    object: Arc<CachedObject>,
+    offset: usize,
+    refs: vec::IntoIter<RawHandle>,
+}
+
+impl Read for CachedContent {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let n = (&self.object.data[self.offset..]).read(buf)?;
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+impl Iterator for CachedContent {
+    type Item = RawHandle;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.refs.next()
+    }
+}
+
+impl CachedContent {
+    fn new(object: Arc<CachedObject>) -> Self {
+        let refs = object.refs.clone().into_iter();
+        Self {
+            object,
+            offset: 0,
+            refs,
+        }
+    }
+}
+
+/// A backend wrapped by a `CacheLayer`.
+#[derive(Debug)]
+pub struct CachedBackend<B: Backend> {
+    inner: B,
+    cache: Arc<Mutex<ObjectCache>>,
+}
+
+impl<B: Backend> Backend for CachedBackend<B> {
+    fn uuid(&self) -> [u8; 16] {
+        self.inner.uuid()
+    }
+
+    type Builder = B::Builder;
+    type FutureFinish = B::FutureFinish;
+    fn builder(&self) -> Self::Builder {
+        self.inner.builder()
+    }
+    fn finish(&self, builder: Self::Builder) -> Self::FutureFinish {
+        self.inner.finish(builder)
+    }
+
+    type Content = CachedContent;
+    type FutureContent = Box<Future<Item = Self::Content, Error = Error>>;
+    fn load(&self, id: RawHandle) -> Self::FutureContent {
+        if let Some(object) = self.cache.lock().get(id) {
+            return Box::new(Ok(CachedContent::new(object)).into_future());
+        }
+
+        let cache = self.cache.clone();
+        Box::new(self.inner.load(id).and_then(move |mut content| {
+            let mut data = Vec::new();
+            content.read_to_end(&mut data)?;
+            let refs = content.collect();
+            let object = Arc::new(CachedObject { data, refs });
+            cache.lock().insert(id, object.clone());
+            Ok(CachedContent::new(object))
+        }))
+    }
+
+    type Id = B::Id;
+    type FutureId = B::FutureId;
+    fn id(&self, id: RawHandle) -> Self::FutureId {
+        self.inner.id(id)
+    }
+
+    type Digest = B::Digest;
+    type FutureDigest = B::FutureDigest;
+    fn digest(&self, signature: DigestSignature, id: RawHandle) -> Self::FutureDigest {
+        self.inner.digest(signature, id)
+    }
+
+    type FutureResolveId = B::FutureResolveId;
+    fn resolve_id(&self, bytes: &Self::Id) -> Self::FutureResolveId {
+        self.inner.resolve_id(bytes)
+    }
+
+    type FutureResolveDigest = B::FutureResolveDigest;
+    fn resolve_digest(
+        &self,
+        signature: DigestSignature,
+        bytes: &[u8],
+    ) -> Self::FutureResolveDigest {
+        self.inner.resolve_digest(signature, bytes)
+    }
+
+    type FutureLoadBranches = B::FutureLoadBranches;
+    fn load_branches(&self) -> Self::FutureLoadBranches {
+        self.inner.load_branches()
+    }
+
+    type FutureSwapBranches = B::FutureSwapBranches;
+    fn swap_branches(
+        &self,
+        previous: HashMap<String, RawHandle>,
+        new: HashMap<String, RawHandle>,
+    ) -> Self::FutureSwapBranches {
+        self.inner.swap_branches(previous, new)
+    }
+
+    type FutureStats = B::FutureStats;
+    fn stats(&self) -> Self::FutureStats {
+        self.inner.stats()
+    }
+
+    fn pack_encodings(&self) -> &'static [TransferEncoding] {
+        self.inner.pack_encodings()
+    }
+
+    type FuturePack = B::FuturePack;
+    fn pack(
+        &self,
+        signature: DigestSignature,
+        encoding: TransferEncoding,
+        root: RawHandle,
+        have: Vec<Vec<u8>>,
+    ) -> Self::FuturePack {
+        self.inner.pack(signature, encoding, root, have)
+    }
+
+    type FutureContainsDigests = B::FutureContainsDigests;
+    fn contains_digests(
+        &self,
+        signature: DigestSignature,
+        digests: Vec<Vec<u8>>,
+    ) -> Self::FutureContainsDigests {
+        self.inner.contains_digests(signature, digests)
+    }
+
+    type StreamDigests = B::StreamDigests;
+    fn list_digests(&self, signature: DigestSignature) -> Option<Self::StreamDigests> {
+        self.inner.list_digests(signature)
+    }
+
+    fn list_digests_with_prefix(
+        &self,
+        signature: DigestSignature,
+        prefix: &[u8],
+    ) -> Option<Self::StreamDigests> {
+        self.inner.list_digests_with_prefix(signature, prefix)
+    }
+
+    type FutureCompact = B::FutureCompact;
+    fn compact(&self) -> Self::FutureCompact {
+        self.inner.compact()
+    }
+
+    type FutureSize = Box<Future<Item = Option<u64>, Error = Error>>;
+    fn size(&self, id: RawHandle) -> Self::FutureSize {
+        if let Some(object) = self.cache.lock().get(id) {
+            return Box::new(Ok(Some(object.data.len() as u64)).into_future());
+        }
+
+        Box::new(self.inner.size(id))
+    }
+}
+
+impl<B: Backend> CachedBackend<B> {
+    /// The backend this cache wraps.
+    pub fn get_ref(&self) -> &B {
+        &self.inner
+    }
+}
@@ -12,10 +12,14 @@ compile_error!(
 #[macro_use]
 extern crate proptest;
 
+extern crate argon2;
+extern crate chacha20poly1305;
 extern crate chrono;
 #[macro_use]
 extern crate failure;
+extern crate flate2;
 extern crate futures_await as futures;
+extern crate getrandom;
 extern crate hex;
 #[macro_use]
 extern crate im;
@@ -32,8 +36,13 @@ pub mod batch;
 pub mod canonical;
 pub mod digest;
 pub mod hierarchy;
+pub mod layer;
+pub mod limit;
+pub mod memory;
 pub mod object;
+pub mod pack;
 pub mod path;
+pub mod seal;
 pub mod split;
 pub mod store;
 
@@ -0,0 +1,109 @@
+//! Throttling of object transfers between stores.
+
+use std::{fmt, io::{self, Read}, sync::Arc, thread, time::{Duration, Instant}};
+
+use parking_lot::Mutex;
+
+/// A token-bucket rate limiter. Clones share the same bucket, so a single limiter handed to
+/// several concurrent transfers bounds their combined rate rather than each one's.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    bucket: Option<Arc<Mutex<Bucket>>>,
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("rate", &self.bucket.as_ref().map(|bucket| bucket.lock().rate))
+            .finish()
+    }
+}
+
+struct Bucket {
+    /// Bytes per second.
+    rate: u64,
+    /// Available bytes. This goes negative when callers have reserved more than is available, and
+    /// they then wait for the bucket to refill.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `bytes_per_second` on average, with bursts of up to a second's
+    /// worth. A rate of zero means no limit.
+    pub fn new(bytes_per_second: u64) -> Self {
+        if bytes_per_second == 0 {
+            return Self::unlimited();
+        }
+
+        Self {
+            bucket: Some(Arc::new(Mutex::new(Bucket {
+                rate: bytes_per_second,
+                tokens: bytes_per_second as f64,
+                last_refill: Instant::now(),
+            }))),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self { bucket: None }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.bucket.is_none()
+    }
+
+    /// Block the current thread until `bytes` more may be transferred.
+    pub fn consume(&self, bytes: u64) {
+        let bucket = match self.bucket {
+            Some(ref bucket) => bucket,
+            None => return,
+        };
+
+        let wait = {
+            let mut bucket = bucket.lock();
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill);
+            let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+            bucket.tokens =
+                (bucket.tokens + elapsed_secs * bucket.rate as f64).min(bucket.rate as f64);
+            bucket.last_refill = now;
+
+            // Reserve our share now, even if it puts the bucket in debt, so that concurrent
+            // callers queue up behind us instead of racing for the same tokens.
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens >= 0.0 {
+                None
+            } else {
+                let secs = -bucket.tokens / bucket.rate as f64;
+                Some(Duration::new(secs as u64, (secs.fract() * 1e9) as u32))
+            }
+        };
+
+        if let Some(duration) = wait {
+            thread::sleep(duration);
+        }
+    }
+}
+
+/// A reader which charges a `RateLimiter` for everything read through it, as it is read.
+#[derive(Debug)]
+pub struct LimitedReader<R> {
+    inner: R,
+    limiter: RateLimiter,
+}
+
+impl<R: Read> LimitedReader<R> {
+    pub fn new(inner: R, limiter: RateLimiter) -> Self {
+        Self { inner, limiter }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.limiter.consume(read as u64);
+        Ok(read)
+    }
+}
@@ -0,0 +1,439 @@
+//! A backend which keeps everything in memory.
+//!
+//! Nothing is ever written to disk, and everything is lost when the backend is dropped, so this is
+//! only good for tests and benchmarks: it measures the cost of building, hashing and walking
+//! objects without the cost of any particular storage engine. Objects are addressed by SHA-3
+//! digests computed exactly as a persistent backend would compute them, so the digests of commits
+//! and trees built here match those built anywhere else.
+//!
+//! A backend created with `MemoryBackend::digests_only` goes further, throwing away the contents
+//! of every object once its digest is known. It is a cheap way to find out what an object's digest
+//! would be without storing it.
+
+use std::{io::{self, Read, Write}, collections::HashMap, sync::{Arc, RwLock}, vec};
+
+use failure::Error;
+use futures::{stream, future::{self, FutureResult}, prelude::*};
+use uuid::Uuid;
+
+use canonical;
+use digest::{Sha3Digest, prelude::*};
+use pack::{Pack, TransferEncoding};
+use store::{Backend, CompactStats, RawHandle, StoreStats};
+
+#[derive(Debug, Default)]
+pub struct MemoryBuilder {
+    blob: Vec<u8>,
+    refs: Vec<RawHandle>,
+}
+
+impl Write for MemoryBuilder {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.blob.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Write::flush(&mut self.blob)
+    }
+}
+
+impl Extend<RawHandle> for MemoryBuilder {
+    fn extend<I>(&mut self, iterable: I)
+    where
+        I: IntoIterator<Item = RawHandle>,
+    {
+        self.refs.extend(iterable);
+    }
+}
+
+/// The content of an object. Blobs are shared with the backend rather than copied out of it.
+#[derive(Debug)]
+pub struct MemoryContent {
+    blob: Arc<Vec<u8>>,
+    position: usize,
+    refs: vec::IntoIter<RawHandle>,
+}
+
+impl Read for MemoryContent {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let read = (&self.blob[self.position..]).read(buf)?;
+        self.position += read;
+        Ok(read)
+    }
+}
+
+impl Iterator for MemoryContent {
+    type Item = RawHandle;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.refs.next()
+    }
+}
+
+#[derive(Debug)]
+struct Object {
+    digest: Sha3Digest,
+    blob: Arc<Vec<u8>>,
+    refs: Vec<RawHandle>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Every object, indexed by its handle.
+    objects: Vec<Object>,
+    ids: HashMap<Sha3Digest, RawHandle>,
+    branches: HashMap<String, RawHandle>,
+}
+
+/// A backend which holds its objects and branches in memory. See the module documentation.
+#[derive(Debug)]
+pub struct MemoryBackend {
+    uuid: [u8; 16],
+    keep_contents: bool,
+    inner: RwLock<Inner>,
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryBackend {
+    /// Create an empty backend. Each one is a separate store, with its own UUID.
+    pub fn new() -> Self {
+        Self {
+            uuid: *Uuid::new_v4().as_bytes(),
+            keep_contents: true,
+            inner: RwLock::new(Inner::default()),
+        }
+    }
+
+    /// Create an empty backend which keeps only the digests of the objects sent to it. Their
+    /// contents are discarded, and loading any object fails.
+    pub fn digests_only() -> Self {
+        Self {
+            keep_contents: false,
+            ..Self::new()
+        }
+    }
+
+    fn do_finish(&self, builder: MemoryBuilder) -> Result<RawHandle, Error> {
+        let MemoryBuilder { blob, refs } = builder;
+        let ref_digests = {
+            let inner = self.inner.read().unwrap();
+            refs.iter()
+                .map(|id| inner.objects[id.0 as usize].digest)
+                .collect::<Vec<_>>()
+        };
+
+        let mut hasher = Sha3Digest::writer();
+        canonical::encode(&mut hasher, &blob, &ref_digests)?;
+        let digest = hasher.finish();
+
+        let mut inner = self.inner.write().unwrap();
+        if let Some(&id) = inner.ids.get(&digest) {
+            return Ok(id);
+        }
+
+        let id = RawHandle(inner.objects.len() as u64);
+        let blob = if self.keep_contents { blob } else { Vec::new() };
+        inner.objects.push(Object {
+            digest,
+            blob: Arc::new(blob),
+            refs,
+        });
+        inner.ids.insert(digest, id);
+
+        Ok(id)
+    }
+
+    fn do_load(&self, id: RawHandle) -> Result<MemoryContent, Error> {
+        ensure!(
+            self.keep_contents,
+            "This in-memory backend keeps only digests, not contents"
+        );
+
+        let inner = self.inner.read().unwrap();
+        let object = &inner.objects[id.0 as usize];
+
+        Ok(MemoryContent {
+            blob: object.blob.clone(),
+            position: 0,
+            refs: object.refs.clone().into_iter(),
+        })
+    }
+
+    fn do_id(&self, id: RawHandle) -> Sha3Digest {
+        self.inner.read().unwrap().objects[id.0 as usize].digest
+    }
+
+    fn do_resolve_digest(
+        &self,
+        signature: DigestSignature,
+        bytes: &[u8],
+    ) -> Result<Option<RawHandle>, Error> {
+        ensure!(
+            signature == Sha3Digest::SIGNATURE,
+            "unsupported digest {:?}",
+            signature
+        );
+
+        let digest = Sha3Digest::from_bytes(bytes);
+        Ok(self.inner.read().unwrap().ids.get(&digest).cloned())
+    }
+
+    fn do_swap_branches(
+        &self,
+        previous: HashMap<String, RawHandle>,
+        new: HashMap<String, RawHandle>,
+    ) -> Result<(), Error> {
+        let mut inner = self.inner.write().unwrap();
+        ensure!(inner.branches == previous, "compare failed");
+        inner.branches = new;
+
+        Ok(())
+    }
+
+    fn do_contains_digests(
+        &self,
+        signature: DigestSignature,
+        digests: Vec<Vec<u8>>,
+    ) -> Result<Option<Vec<bool>>, Error> {
+        ensure!(
+            signature == Sha3Digest::SIGNATURE,
+            "unsupported digest {:?}",
+            signature
+        );
+
+        let inner = self.inner.read().unwrap();
+        let contained = digests
+            .iter()
+            .map(|bytes| inner.ids.contains_key(&Sha3Digest::from_bytes(bytes)))
+            .collect();
+
+        Ok(Some(contained))
+    }
+
+    fn do_list_digests(&self, signature: DigestSignature) -> Result<Vec<Vec<u8>>, Error> {
+        ensure!(
+            signature == Sha3Digest::SIGNATURE,
+            "unsupported digest {:?}",
+            signature
+        );
+
+        let inner = self.inner.read().unwrap();
+        let digests = inner
+            .objects
+            .iter()
+            .map(|object| object.digest.as_bytes().to_vec())
+            .collect();
+
+        Ok(digests)
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn uuid(&self) -> [u8; 16] {
+        self.uuid
+    }
+
+    type Builder = MemoryBuilder;
+    type FutureFinish = FutureResult<RawHandle, Error>;
+
+    fn builder(&self) -> Self::Builder {
+        MemoryBuilder::default()
+    }
+
+    fn finish(&self, builder: Self::Builder) -> Self::FutureFinish {
+        self.do_finish(builder).into_future()
+    }
+
+    type Content = MemoryContent;
+    type FutureContent = FutureResult<Self::Content, Error>;
+
+    fn load(&self, id: RawHandle) -> Self::FutureContent {
+        self.do_load(id).into_future()
+    }
+
+    type Id = Sha3Digest;
+    type FutureId = FutureResult<Self::Id, Error>;
+
+    fn id(&self, id: RawHandle) -> Self::FutureId {
+        future::ok(self.do_id(id))
+    }
+
+    type Digest = Sha3Digest;
+    type FutureDigest = FutureResult<Self::Digest, Error>;
+
+    fn digest(&self, signature: DigestSignature, id: RawHandle) -> Self::FutureDigest {
+        if signature == Sha3Digest::SIGNATURE {
+            future::ok(self.do_id(id))
+        } else {
+            future::err(format_err!("unsupported digest {:?}", signature))
+        }
+    }
+
+    type FutureResolveId = FutureResult<Option<RawHandle>, Error>;
+
+    fn resolve_id(&self, digest: &Sha3Digest) -> Self::FutureResolveId {
+        future::ok(self.inner.read().unwrap().ids.get(digest).cloned())
+    }
+
+    type FutureResolveDigest = FutureResult<Option<RawHandle>, Error>;
+
+    fn resolve_digest(
+        &self,
+        signature: DigestSignature,
+        bytes: &[u8],
+    ) -> Self::FutureResolveDigest {
+        self.do_resolve_digest(signature, bytes).into_future()
+    }
+
+    type FutureLoadBranches = FutureResult<HashMap<String, RawHandle>, Error>;
+
+    fn load_branches(&self) -> Self::FutureLoadBranches {
+        future::ok(self.inner.read().unwrap().branches.clone())
+    }
+
+    type FutureSwapBranches = FutureResult<(), Error>;
+
+    fn swap_branches(
+        &self,
+        previous: HashMap<String, RawHandle>,
+        new: HashMap<String, RawHandle>,
+    ) -> Self::FutureSwapBranches {
+        self.do_swap_branches(previous, new).into_future()
+    }
+
+    type FutureStats = FutureResult<Option<StoreStats>, Error>;
+
+    fn stats(&self) -> Self::FutureStats {
+        let inner = self.inner.read().unwrap();
+        let stats = StoreStats {
+            loose_objects: inner.objects.len() as u64,
+            loose_bytes: inner
+                .objects
+                .iter()
+                .map(|object| object.blob.len() as u64)
+                .sum(),
+        };
+
+        future::ok(Some(stats))
+    }
+
+    fn pack_encodings(&self) -> &'static [TransferEncoding] {
+        &[]
+    }
+
+    type FuturePack = FutureResult<Pack, Error>;
+
+    fn pack(
+        &self,
+        _signature: DigestSignature,
+        _encoding: TransferEncoding,
+        _root: RawHandle,
+        _have: Vec<Vec<u8>>,
+    ) -> Self::FuturePack {
+        future::err(format_err!("The in-memory backend does not produce packs"))
+    }
+
+    type FutureContainsDigests = FutureResult<Option<Vec<bool>>, Error>;
+
+    fn contains_digests(
+        &self,
+        signature: DigestSignature,
+        digests: Vec<Vec<u8>>,
+    ) -> Self::FutureContainsDigests {
+        self.do_contains_digests(signature, digests).into_future()
+    }
+
+    type StreamDigests = Box<Stream<Item = Vec<u8>, Error = Error>>;
+
+    fn list_digests(&self, signature: DigestSignature) -> Option<Self::StreamDigests> {
+        let digests = self.do_list_digests(signature)
+            .into_future()
+            .map(stream::iter_ok)
+            .flatten_stream();
+        Some(Box::new(digests))
+    }
+
+    fn list_digests_with_prefix(
+        &self,
+        signature: DigestSignature,
+        prefix: &[u8],
+    ) -> Option<Self::StreamDigests> {
+        let prefix = prefix.to_owned();
+        let digests = self.do_list_digests(signature)
+            .into_future()
+            .map(stream::iter_ok)
+            .flatten_stream()
+            .filter(move |digest| digest.starts_with(&prefix));
+        Some(Box::new(digests))
+    }
+
+    type FutureCompact = FutureResult<Option<CompactStats>, Error>;
+
+    fn compact(&self) -> Self::FutureCompact {
+        future::ok(None)
+    }
+
+    type FutureSize = FutureResult<Option<u64>, Error>;
+
+    fn size(&self, id: RawHandle) -> Self::FutureSize {
+        let inner = self.inner.read().unwrap();
+        future::ok(Some(inner.objects[id.0 as usize].blob.len() as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object::{ObjectRef, SmallBuilder, TreeBuilder};
+    use store::Store;
+
+    #[test]
+    fn identical_objects_share_a_handle() {
+        let store = Store::new(MemoryBackend::new());
+        let mut small_builder = SmallBuilder::new();
+        small_builder.write_all(b"hello").unwrap();
+        let small = small_builder.as_small().clone();
+
+        let first = small.send(&store).wait().unwrap();
+        let second = small.send(&store).wait().unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.fetch().wait().unwrap(), small);
+    }
+
+    #[test]
+    fn trees_round_trip() {
+        let store = Store::new(MemoryBackend::new());
+        let mut small_builder = SmallBuilder::new();
+        small_builder.write_all(b"contents").unwrap();
+        let small_ref = small_builder.as_small().send(&store).wait().unwrap();
+
+        let mut tree_builder = TreeBuilder::new();
+        tree_builder.insert("file".to_owned(), ObjectRef::Small(small_ref));
+        let tree = tree_builder.into_tree();
+        let tree_ref = tree.send(&store).wait().unwrap();
+
+        assert_eq!(tree_ref.fetch().wait().unwrap(), tree);
+    }
+
+    #[test]
+    fn digests_only_matches_digests() {
+        let full = Store::new(MemoryBackend::new());
+        let digests_only = Store::new(MemoryBackend::digests_only());
+        let mut small_builder = SmallBuilder::new();
+        small_builder.write_all(b"contents").unwrap();
+        let small = small_builder.as_small().clone();
+
+        let kept = small.send(&full).wait().unwrap();
+        let discarded = small.send(&digests_only).wait().unwrap();
+        assert_eq!(
+            kept.digest::<Sha3Digest>().wait().unwrap(),
+            discarded.digest::<Sha3Digest>().wait().unwrap()
+        );
+        assert!(discarded.fetch().wait().is_err());
+    }
+}
@@ -8,7 +8,8 @@ use ntriple::{self, Object, Predicate, Subject};
 
 use object::{Commit, CommitAuthor, CommitBuilder, CommitRef, Large, LargeRef, ObjectKind,
              ObjectRef, Small, SmallRef, Tree, TreeRef,
-             metadata::{ATTACA_COMMIT_MESSAGE, ATTACA_COMMIT_TIMESTAMP, FOAF_MBOX, FOAF_NAME}};
+             metadata::{ATTACA_COMMIT_COMMITTER_TIMESTAMP, ATTACA_COMMIT_HEADER,
+                        ATTACA_COMMIT_MESSAGE, ATTACA_COMMIT_TIMESTAMP, FOAF_MBOX, FOAF_NAME}};
 use store::prelude::*;
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
@@ -283,6 +284,14 @@ pub fn commit<B: Backend>(mut content: Content<B>) -> Result<Commit<Handle<B>>,
                         let timestamp = DateTime::parse_from_rfc2822(&object)?;
                         commit_builder.timestamp(timestamp);
                     }
+                    ATTACA_COMMIT_COMMITTER_TIMESTAMP => {
+                        let timestamp = DateTime::parse_from_rfc2822(&object)?;
+                        commit_builder.committer_timestamp(Some(timestamp));
+                    }
+                    header if header.starts_with(ATTACA_COMMIT_HEADER) => {
+                        let key = &header[ATTACA_COMMIT_HEADER.len()..];
+                        commit_builder.header(key.to_owned(), object);
+                    }
                     _ => bail!(
                         "Malformed commit metadata: invalid commit predicate <{}>",
                         iri
@@ -1,9 +1,11 @@
 use std::{ascii, usize, collections::{BTreeSet, HashMap}, io::Write};
 
+use chrono::{DateTime, Datelike, FixedOffset, Timelike};
 use failure::Error;
 
 use object::{Commit, Large, ObjectRef, Small, Tree,
-             metadata::{ATTACA_COMMIT_MESSAGE, ATTACA_COMMIT_TIMESTAMP, FOAF_MBOX, FOAF_NAME}};
+             metadata::{self, ATTACA_COMMIT_COMMITTER_TIMESTAMP, ATTACA_COMMIT_HEADER,
+                        ATTACA_COMMIT_MESSAGE, ATTACA_COMMIT_TIMESTAMP, FOAF_MBOX, FOAF_NAME}};
 use store::prelude::*;
 
 pub fn small<B: Backend>(builder: &mut Builder<B>, object: &Small) -> Result<(), Error> {
@@ -83,6 +85,39 @@ pub fn tree<B: Backend>(builder: &mut Builder<B>, object: &Tree<Handle<B>>) -> R
     Ok(())
 }
 
+const WEEKDAYS: [&'static str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+const MONTHS: [&'static str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"
+];
+
+/// Format a commit timestamp in RFC 2822 form, exactly as chrono 0.4.0's `to_rfc2822` does: the
+/// day of the month is padded with a space rather than a zero, and fractions of a second are
+/// dropped. Timestamps are part of commit digests, so the format is spelled out here rather than
+/// left to whichever version of chrono happens to be in use.
+pub fn timestamp(timestamp: &DateTime<FixedOffset>) -> String {
+    let offset = timestamp.offset().local_minus_utc() / 60;
+    let (sign, offset) = if offset < 0 {
+        ('-', -offset)
+    } else {
+        ('+', offset)
+    };
+
+    format!(
+        "{}, {:2} {} {:04} {:02}:{:02}:{:02} {}{:02}{:02}",
+        WEEKDAYS[timestamp.weekday().num_days_from_monday() as usize],
+        timestamp.day(),
+        MONTHS[timestamp.month0() as usize],
+        timestamp.year(),
+        timestamp.hour(),
+        timestamp.minute(),
+        // A leap second is written as second 60.
+        timestamp.second() + timestamp.nanosecond() / 1_000_000_000,
+        sign,
+        offset / 60,
+        offset % 60
+    )
+}
+
 // TODO: Robust RDF formatting/parsing - current breaks for non-ASCII strings:
 // https://github.com/sdleffler/attaca/issues/25
 pub fn commit<B: Backend>(
@@ -135,7 +170,29 @@ pub fn commit<B: Backend>(
     {
         let mut buf = Vec::new();
         write!(&mut buf, "_:this <{}> \"", ATTACA_COMMIT_TIMESTAMP)?;
-        buf.write_all(&rdf_literal(&object.as_timestamp().to_rfc2822()))?;
+        buf.write_all(&rdf_literal(&timestamp(object.as_timestamp())))?;
+        write!(&mut buf, "\" .\n")?;
+        ntriples.insert(buf);
+    }
+
+    if let Some(committer_timestamp) = object.committer_timestamp.as_ref() {
+        let mut buf = Vec::new();
+        write!(&mut buf, "_:this <{}> \"", ATTACA_COMMIT_COMMITTER_TIMESTAMP)?;
+        buf.write_all(&rdf_literal(&timestamp(committer_timestamp)))?;
+        write!(&mut buf, "\" .\n")?;
+        ntriples.insert(buf);
+    }
+
+    for (key, value) in object.as_headers() {
+        ensure!(
+            metadata::is_valid_header_key(key),
+            "Bad commit header key {:?}",
+            key
+        );
+
+        let mut buf = Vec::new();
+        write!(&mut buf, "_:this <{}{}> \"", ATTACA_COMMIT_HEADER, key)?;
+        buf.write_all(&rdf_literal(value))?;
         write!(&mut buf, "\" .\n")?;
         ntriples.insert(buf);
     }
@@ -0,0 +1,322 @@
+//! Golden digests.
+//!
+//! An object's digest is taken over its encoding, and commits refer to trees, trees to files and
+//! so on by digest, so any change to how objects are encoded changes the digest of everything
+//! built on top of them: commit IDs, signatures over commits, and whether two stores agree that
+//! they hold the same objects. These tests build a fixed set of objects and compare their digests
+//! with values recorded here, so that such a change can't go unnoticed. If one of them fails, the
+//! encoding has changed, and that calls for a migration rather than new golden values.
+//!
+//! The recorded digests were computed independently of this crate, from the encodings in `encode`
+//! and `canonical` and the hashsplitting in `split`.
+
+use std::io::Write;
+
+use hex;
+
+use super::*;
+use digest::Sha3Digest;
+use memory::MemoryBackend;
+use store::Store;
+
+type H = Handle<MemoryBackend>;
+
+const HELLO: &'static str = "62cbbd1f385ab7617e7907733ce876058e5682db3048dfea1d34aa40e272a1ea";
+const EMPTY: &'static str = "d9171588bd66db74762f6a67b2e3cc2eb8ac9a17ce0ca17e3b062b824102154d";
+const SNOWMAN: &'static str = "3aa22a8b733c911a191c6a1f7a16e11a587f5b7dff3ea94980e0fc6131ba0349";
+const BIG: &'static str = "ff9d1443d2565c23ee6c122faaea010b2b04e92a5cd1e01ee2c5141373053200";
+const SRC: &'static str = "f1e658acad3bfe85601c7dae8046fc8f43d497fe993ca074e622e9d911e34eec";
+const ROOT: &'static str = "7c50a8aae06fdc6cd5a049b63468ffca7b8376cfe3881533a004b18432f7679a";
+const INITIAL: &'static str = "df39f0059e12abca9d2e4796f212d75a783441514c27ea30efb9a926a6d5a0d6";
+const SECOND: &'static str = "9ae0d090f7f48b51c259dba02ec22662fb325d8aa0ca0bd9dcb0f9690585f9ed";
+const RECOMMITTED: &'static str =
+    "9f95a9163980966a8e7adae8395ab3370245a8b49ce233d06e0d5b58d264f10f";
+const SHARED: &'static str = "1490c28c41cbe278b021fa5256f84bba83aa8d6db148be70d942f832392aebf9";
+
+fn digest(object_ref: &ObjectRef<H>) -> String {
+    let digest_ref = object_ref.digest::<Sha3Digest>().wait().unwrap();
+    hex::encode(digest_ref.as_inner().as_bytes())
+}
+
+fn small(store: &Store<MemoryBackend>, data: &[u8]) -> ObjectRef<H> {
+    let mut builder = SmallBuilder::new();
+    builder.write_all(data).unwrap();
+    ObjectRef::Small(builder.as_small().send(store).wait().unwrap())
+}
+
+fn large(store: &Store<MemoryBackend>, chunks: &[&[u8]]) -> ObjectRef<H> {
+    let mut builder = LargeBuilder::new(1);
+    for chunk in chunks {
+        builder.push(small(store, chunk));
+    }
+    ObjectRef::Large(builder.as_large().send(store).wait().unwrap())
+}
+
+fn tree(store: &Store<MemoryBackend>, entries: Vec<(&str, ObjectRef<H>)>) -> ObjectRef<H> {
+    let mut builder = TreeBuilder::new();
+    for (name, object_ref) in entries {
+        builder.insert(name.to_owned(), object_ref);
+    }
+    ObjectRef::Tree(builder.as_tree().send(store).wait().unwrap())
+}
+
+/// The fixture tree:
+///
+/// ```text
+/// COPYING      "hello, world\n"
+/// README       "hello, world\n"
+/// big          "abc" + "defg", as a large object
+/// empty        ""
+/// empty_dir/
+/// naïve.txt    "☃\n"
+/// src/
+///     lib.rs   "pub fn answer() -> u32 {\n    42\n}\n"
+/// ```
+///
+/// Entries are inserted in alphabetical order, or the reverse, which mustn't matter.
+fn fixture(store: &Store<MemoryBackend>, reverse: bool) -> ObjectRef<H> {
+    let src = tree(
+        store,
+        vec![("lib.rs", small(store, b"pub fn answer() -> u32 {\n    42\n}\n"))],
+    );
+    let mut entries = vec![
+        ("COPYING", small(store, b"hello, world\n")),
+        ("README", small(store, b"hello, world\n")),
+        ("big", large(store, &[&b"abc"[..], &b"defg"[..]])),
+        ("empty", small(store, b"")),
+        ("empty_dir", tree(store, Vec::new())),
+        ("naïve.txt", small(store, "☃\n".as_bytes())),
+        ("src", src),
+    ];
+    if reverse {
+        entries.reverse();
+    }
+    tree(store, entries)
+}
+
+fn initial_commit(store: &Store<MemoryBackend>, subtree: TreeRef<H>) -> CommitRef<H> {
+    let mut builder = CommitBuilder::new();
+    builder
+        .subtree(subtree)
+        .timestamp(FixedOffset::east(3600).ymd(2018, 3, 6).and_hms(12, 0, 0))
+        .author(CommitAuthor {
+            name: Some("Jane Doe".to_owned()),
+            mbox: Some("jane@example.com".to_owned()),
+        })
+        .message("Initial commit".to_owned())
+        .header("co-author".to_owned(), "John Roe <john@example.com>".to_owned());
+    builder.into_commit().unwrap().send(store).wait().unwrap()
+}
+
+fn second_commit(
+    store: &Store<MemoryBackend>,
+    subtree: TreeRef<H>,
+    parents: Vec<CommitRef<H>>,
+) -> CommitRef<H> {
+    let mut builder = CommitBuilder::new();
+    builder
+        .subtree(subtree)
+        .parents(parents)
+        .timestamp(
+            FixedOffset::west(5 * 3600 + 1800)
+                .ymd(2018, 11, 23)
+                .and_hms_nano(9, 30, 15, 250_000_000),
+        )
+        .author(CommitAuthor {
+            name: Some("Jane Doe".to_owned()),
+            mbox: None,
+        })
+        .message("Second commit\n\nWith a body.".to_owned());
+    builder.into_commit().unwrap().send(store).wait().unwrap()
+}
+
+/// Deterministic pseudo-random bytes, from a 64-bit linear congruential generator.
+fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        })
+        .collect()
+}
+
+#[test]
+fn golden_blobs() {
+    let store = Store::new(MemoryBackend::new());
+    assert_eq!(digest(&small(&store, b"hello, world\n")), HELLO);
+    assert_eq!(digest(&small(&store, b"")), EMPTY);
+    assert_eq!(digest(&small(&store, "☃\n".as_bytes())), SNOWMAN);
+    assert_eq!(digest(&large(&store, &[&b"abc"[..], &b"defg"[..]])), BIG);
+}
+
+#[test]
+fn golden_trees() {
+    let store = Store::new(MemoryBackend::new());
+    let root = fixture(&store, false);
+    assert_eq!(digest(&root), ROOT);
+
+    let root_tree = match root {
+        ObjectRef::Tree(tree_ref) => tree_ref.fetch().wait().unwrap(),
+        _ => unreachable!(),
+    };
+    assert_eq!(digest(&root_tree["src"]), SRC);
+    // Neither the kind of an object nor its size is part of its own encoding; they're recorded
+    // by whatever refers to it. So an empty tree and an empty file are the same object.
+    assert_eq!(digest(&root_tree["empty_dir"]), EMPTY);
+}
+
+#[test]
+fn golden_trees_ignore_insertion_order() {
+    let store = Store::new(MemoryBackend::new());
+    assert_eq!(digest(&fixture(&store, true)), ROOT);
+}
+
+#[test]
+fn golden_commits() {
+    let store = Store::new(MemoryBackend::new());
+    let root = match fixture(&store, false) {
+        ObjectRef::Tree(tree_ref) => tree_ref,
+        _ => unreachable!(),
+    };
+    let src = match root.fetch().wait().unwrap()["src"] {
+        ObjectRef::Tree(ref tree_ref) => tree_ref.clone(),
+        _ => unreachable!(),
+    };
+
+    let initial = initial_commit(&store, root);
+    assert_eq!(digest(&ObjectRef::Commit(initial.clone())), INITIAL);
+
+    // Fractions of a second aren't encoded, so the second commit's quarter second is dropped.
+    let second = second_commit(&store, src, vec![initial]);
+    assert_eq!(digest(&ObjectRef::Commit(second)), SECOND);
+}
+
+#[test]
+fn golden_committer_timestamp() {
+    let store = Store::new(MemoryBackend::new());
+    let root = match fixture(&store, false) {
+        ObjectRef::Tree(tree_ref) => tree_ref,
+        _ => unreachable!(),
+    };
+    let src = src_of(&root);
+    let initial = initial_commit(&store, root);
+
+    // Without a committer timestamp of its own, a commit is encoded as it always was.
+    let second = second_commit(&store, src.clone(), vec![initial.clone()]);
+    let second = second.fetch().wait().unwrap();
+    assert!(!second.has_committer_timestamp());
+    assert_eq!(second.as_committer_timestamp(), second.as_timestamp());
+
+    let mut builder = second.diverge();
+    builder.committer_timestamp(Some(FixedOffset::east(0).ymd(2018, 11, 24).and_hms(10, 0, 0)));
+    let recommitted = builder.into_commit().unwrap().send(&store).wait().unwrap();
+    assert_eq!(digest(&ObjectRef::Commit(recommitted.clone())), RECOMMITTED);
+
+    let recommitted = recommitted.fetch().wait().unwrap();
+    assert_eq!(
+        recommitted.as_committer_timestamp(),
+        &FixedOffset::east(0).ymd(2018, 11, 24).and_hms(10, 0, 0)
+    );
+    assert_eq!(
+        recommitted.as_timestamp(),
+        &FixedOffset::west(5 * 3600 + 1800).ymd(2018, 11, 23).and_hms(9, 30, 15)
+    );
+}
+
+#[test]
+fn golden_commits_ignore_order_after_first_parent() {
+    let store = Store::new(MemoryBackend::new());
+    let root = match fixture(&store, false) {
+        ObjectRef::Tree(tree_ref) => tree_ref,
+        _ => unreachable!(),
+    };
+    let initial = initial_commit(&store, root.clone());
+    let second = second_commit(&store, root.clone(), vec![initial.clone()]);
+    let third = second_commit(&store, src_of(&root), vec![initial.clone()]);
+
+    let forward = second_commit(
+        &store,
+        root.clone(),
+        vec![initial.clone(), second.clone(), third.clone()],
+    );
+    let backward = second_commit(
+        &store,
+        root.clone(),
+        vec![initial.clone(), third.clone(), second.clone(), third.clone()],
+    );
+    assert_eq!(
+        digest(&ObjectRef::Commit(forward.clone())),
+        digest(&ObjectRef::Commit(backward))
+    );
+    assert_eq!(forward.fetch().wait().unwrap().as_parents()[0], initial);
+
+    // The first parent is what the commit was made on top of, so swapping it changes the commit.
+    let ours = second_commit(&store, root.clone(), vec![second.clone(), third.clone()]);
+    let theirs = second_commit(&store, root, vec![third, second.clone()]);
+    assert!(digest(&ObjectRef::Commit(ours.clone())) != digest(&ObjectRef::Commit(theirs)));
+    assert_eq!(ours.fetch().wait().unwrap().as_parents()[0], second);
+}
+
+fn src_of(root: &TreeRef<H>) -> TreeRef<H> {
+    match root.fetch().wait().unwrap()["src"] {
+        ObjectRef::Tree(ref tree_ref) => tree_ref.clone(),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn golden_timestamps() {
+    let cases = vec![
+        (
+            FixedOffset::east(3600).ymd(2018, 3, 6).and_hms(12, 0, 0),
+            "Tue,  6 Mar 2018 12:00:00 +0100",
+        ),
+        (
+            FixedOffset::west(5 * 3600 + 1800)
+                .ymd(2018, 11, 23)
+                .and_hms_nano(9, 30, 15, 250_000_000),
+            "Fri, 23 Nov 2018 09:30:15 -0530",
+        ),
+        (
+            FixedOffset::east(0).ymd(1970, 1, 1).and_hms(0, 0, 0),
+            "Thu,  1 Jan 1970 00:00:00 +0000",
+        ),
+    ];
+
+    for (timestamp, expected) in cases {
+        assert_eq!(encode::timestamp(&timestamp), expected);
+        assert_eq!(
+            DateTime::parse_from_rfc2822(expected).unwrap(),
+            timestamp.with_nanosecond(0).unwrap()
+        );
+    }
+}
+
+#[test]
+fn golden_share() {
+    let store = Store::new(MemoryBackend::new());
+
+    let data = noise(200_000, 0x5eed);
+    let shared = share(&data[..], store.clone()).wait().unwrap();
+    assert_eq!(digest(&shared), SHARED);
+
+    let large = match shared.fetch().wait().unwrap() {
+        Object::Large(large) => large,
+        _ => panic!("expected a large object"),
+    };
+    let sizes = large
+        .iter()
+        .map(|(range, _)| range.end - range.start)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        sizes,
+        vec![18293, 52037, 24408, 27697, 17908, 11271, 23442, 24944]
+    );
+
+    let hello = share(&b"hello, world\n"[..], store.clone()).wait().unwrap();
+    assert_eq!(hello.kind(), ObjectKind::Small);
+    assert_eq!(digest(&hello), HELLO);
+}
@@ -1,5 +1,31 @@
 pub const ATTACA_COMMIT_MESSAGE: &'static str = "http://attaca.io/ontology/#commitMessage";
 pub const ATTACA_COMMIT_TIMESTAMP: &'static str = "http://attaca.io/ontology/#commitTimestamp";
+pub const ATTACA_COMMIT_COMMITTER_TIMESTAMP: &'static str =
+    "http://attaca.io/ontology/#commitCommitterTimestamp";
+
+/// Prefix of the predicates of commit headers; the header's key follows it.
+pub const ATTACA_COMMIT_HEADER: &'static str = "http://attaca.io/ontology/header#";
+
+/// Reserved header holding a detached signature over the commit as encoded without this header.
+pub const HEADER_SIGNATURE: &'static str = "gpgsig";
+
+/// Reserved header holding a signed tag which was merged by this commit.
+pub const HEADER_MERGETAG: &'static str = "mergetag";
+
+/// Reserved header naming a further author of the commit, in the same `Name <mbox>` form as
+/// `--author`.
+pub const HEADER_CO_AUTHOR: &'static str = "co-author";
+
+/// Header keys beginning with this prefix are reserved for future use by attaca itself.
+pub const RESERVED_HEADER_PREFIX: &'static str = "attaca-";
+
+/// Whether `key` may be used as the key of a commit header. Keys are non-empty and made up of
+/// ASCII letters, digits, `-`, `_` and `.`, so that they can be embedded in a predicate IRI.
+pub fn is_valid_header_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+}
 
 pub const FOAF_MBOX: &'static str = "http://xmlns.com/foaf/spec/#term_mbox";
 pub const FOAF_NAME: &'static str = "http://xmlns.com/foaf/spec/#term_name";
@@ -2,12 +2,15 @@ pub mod decode;
 pub mod encode;
 pub mod metadata;
 
+#[cfg(test)]
+mod golden;
+
 use std::{mem, borrow::Borrow, collections::{btree_map, BTreeMap, Bound}, io::{self, Read, Write},
           ops::{Deref, DerefMut, Range}};
 
 use chrono::prelude::*;
 use failure::Error;
-use futures::{prelude::*, stream::FuturesOrdered};
+use futures::{future, prelude::*, stream::FuturesOrdered};
 
 use digest::prelude::*;
 use split::{Parameters, Splitter};
@@ -1249,14 +1252,33 @@ impl CommitAuthor {
     }
 }
 
+/// A commit: a snapshot of a tree, along with its parents and some metadata.
+///
+/// The first parent is special: it is the commit this one was made on top of, which history
+/// walks following first parents, `~N` and rebasing all rely on. The order of the other parents
+/// carries no meaning. `CommitBuilder` removes duplicate parents, and when a commit is sent to a
+/// store the parents after the first are sorted by their IDs (see `Commit::canonicalize`), so
+/// that the same logical commit always has the same digest.
+///
+/// A commit has two timestamps: the author timestamp, of when its changes were first made, and the
+/// committer timestamp, of when the commit itself was made. They differ once a commit has been
+/// amended or rebased. The committer timestamp is optional, and a commit without one (such as any
+/// made before it existed) was committed at its author timestamp.
+///
+/// Besides its author, timestamps and message, a commit may carry any number of headers: key/value
+/// pairs which attaca stores verbatim without interpreting them, so that features such as signing
+/// can attach data to commits without changing the encoding. Headers are part of the commit's
+/// digest. See `metadata` for which keys are reserved.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Commit<H> {
     subtree: TreeRef<H>,
     parents: Vec<CommitRef<H>>,
 
     timestamp: DateTime<FixedOffset>,
+    committer_timestamp: Option<DateTime<FixedOffset>>,
     author: CommitAuthor,
     message: Option<String>,
+    headers: BTreeMap<String, String>,
 }
 
 impl<H> Commit<H> {
@@ -1276,24 +1298,94 @@ impl<H> Commit<H> {
         &self.author
     }
 
+    /// The author timestamp.
     pub fn as_timestamp(&self) -> &DateTime<FixedOffset> {
         &self.timestamp
     }
 
+    /// The committer timestamp, falling back to the author timestamp if the commit doesn't have
+    /// one.
+    pub fn as_committer_timestamp(&self) -> &DateTime<FixedOffset> {
+        self.committer_timestamp.as_ref().unwrap_or(&self.timestamp)
+    }
+
+    /// Whether the commit has a committer timestamp of its own.
+    pub fn has_committer_timestamp(&self) -> bool {
+        self.committer_timestamp.is_some()
+    }
+
     pub fn as_message(&self) -> Option<&str> {
         self.message.as_ref().map(String::as_str)
     }
+
+    pub fn as_headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key).map(String::as_str)
+    }
 }
 
 impl<B: Backend> Commit<Handle<B>> {
+    /// Put this commit's parents into canonical order: the first parent first, then the rest
+    /// sorted by their IDs, with duplicates removed.
+    pub fn canonicalize(self) -> impl Future<Item = Self, Error = Error> {
+        let future_ids = future::join_all(
+            self.parents
+                .iter()
+                .map(|parent| parent.id())
+                .collect::<Vec<_>>(),
+        );
+
+        future_ids.map(move |ids| {
+            fn key<B: Backend>(id: &CommitRef<OwnedLocalId<B>>) -> &B::Id {
+                id.as_inner().borrow()
+            }
+
+            let Commit {
+                subtree,
+                parents,
+                timestamp,
+                committer_timestamp,
+                author,
+                message,
+                headers,
+            } = self;
+
+            let mut keyed: Vec<(CommitRef<OwnedLocalId<B>>, _)> = Vec::new();
+            for (id, parent) in ids.into_iter().zip(parents) {
+                if !keyed.iter().any(|&(ref seen, _)| key::<B>(seen) == key::<B>(&id)) {
+                    keyed.push((id, parent));
+                }
+            }
+            if keyed.len() > 2 {
+                keyed[1..].sort_by(|&(ref a, _), &(ref b, _)| key::<B>(a).cmp(key::<B>(b)));
+            }
+
+            Commit {
+                subtree,
+                parents: keyed.into_iter().map(|(_, parent)| parent).collect(),
+                timestamp,
+                committer_timestamp,
+                author,
+                message,
+                headers,
+            }
+        })
+    }
+
     pub fn send(&self, store: &Store<B>) -> FutureCommitHandle<B> {
-        let mut builder = store.builder();
-        FutureCommitHandle(Box::new(
-            encode::commit(&mut builder, self)
-                .map(|()| builder.finish())
-                .into_future()
-                .flatten(),
-        ))
+        let store = store.clone();
+        FutureCommitHandle(Box::new(self.clone().canonicalize().and_then(
+            move |commit| {
+                let mut builder = store.builder();
+                encode::commit(&mut builder, &commit)
+                    .map(|()| builder.finish())
+                    .into_future()
+                    .flatten()
+            },
+        )))
     }
 }
 
@@ -1302,8 +1394,10 @@ pub enum CommitBuilder<H> {
     Incomplete {
         parents: Vec<CommitRef<H>>,
         timestamp: DateTime<FixedOffset>,
+        committer_timestamp: Option<DateTime<FixedOffset>>,
         author: CommitAuthor,
         message: Option<String>,
+        headers: BTreeMap<String, String>,
     },
     Complete(Commit<H>),
 }
@@ -1322,8 +1416,10 @@ impl<H> Default for CommitBuilder<H> {
                 let local = Local::now();
                 local.with_timezone(local.offset())
             },
+            committer_timestamp: None,
             author: Default::default(),
             message: Default::default(),
+            headers: Default::default(),
         }
     }
 }
@@ -1357,9 +1453,33 @@ impl<H> CommitBuilder<H> {
         self
     }
 
+    /// Set a header, replacing any previous value for the same key. The key must satisfy
+    /// `metadata::is_valid_header_key`, or sending the commit will fail.
+    pub fn header(&mut self, key: String, value: String) -> &mut Self {
+        match *self {
+            CommitBuilder::Complete(ref mut commit) => commit.headers.insert(key, value),
+            CommitBuilder::Incomplete {
+                ref mut headers, ..
+            } => headers.insert(key, value),
+        };
+        self
+    }
+
+    pub fn remove_header(&mut self, key: &str) -> &mut Self {
+        match *self {
+            CommitBuilder::Complete(ref mut commit) => commit.headers.remove(key),
+            CommitBuilder::Incomplete {
+                ref mut headers, ..
+            } => headers.remove(key),
+        };
+        self
+    }
+
+    /// Set the parents of the commit. Duplicate parents are dropped.
     pub fn parents<I>(&mut self, iterable: I) -> &mut Self
     where
         I: IntoIterator<Item = CommitRef<H>>,
+        H: PartialEq,
     {
         {
             let parents = match *self {
@@ -1369,7 +1489,11 @@ impl<H> CommitBuilder<H> {
                 } => parents,
             };
             parents.clear();
-            parents.extend(iterable);
+            for parent in iterable {
+                if !parents.contains(&parent) {
+                    parents.push(parent);
+                }
+            }
         }
         self
     }
@@ -1393,12 +1517,16 @@ impl<H> CommitBuilder<H> {
                 author,
                 message,
                 timestamp,
+                committer_timestamp,
+                headers,
             } => Commit {
                 subtree: new_subtree,
                 parents,
                 timestamp,
+                committer_timestamp,
                 author,
                 message,
+                headers,
             },
         };
         *self = CommitBuilder::Complete(tmp);
@@ -1414,6 +1542,22 @@ impl<H> CommitBuilder<H> {
         }
         self
     }
+
+    /// Set the committer timestamp, or with `None`, have the commit committed at its author
+    /// timestamp.
+    pub fn committer_timestamp(
+        &mut self,
+        new_timestamp: Option<DateTime<FixedOffset>>,
+    ) -> &mut Self {
+        match *self {
+            CommitBuilder::Complete(ref mut commit) => commit.committer_timestamp = new_timestamp,
+            CommitBuilder::Incomplete {
+                ref mut committer_timestamp,
+                ..
+            } => *committer_timestamp = new_timestamp,
+        }
+        self
+    }
 }
 
 pub fn share<R: Read, B: Backend>(
@@ -1545,12 +1689,20 @@ mod tests {
                  name in prop::option::of("[ -~]*"),
                  mbox in prop::option::of("[ -~]*"),
                  timestamp in arb_timestamp(),
-                 message in prop::option::of("[ -~]*")) -> Commit<Handle<DummyBackend>> {
+                 committer_timestamp in prop::option::of(arb_timestamp()),
+                 message in prop::option::of("[ -~]*"),
+                 headers in prop::collection::vec(("[a-z][a-z0-9._-]{0,15}", "[ -~]*"), 0..4))
+                 -> Commit<Handle<DummyBackend>> {
             let mut builder = CommitBuilder::new();
             builder.subtree(subtree).parents(parents);
             builder.timestamp(timestamp);
+            builder.committer_timestamp(committer_timestamp);
             builder.author(CommitAuthor { name, mbox });
 
+            for (key, value) in headers {
+                builder.header(key, value);
+            }
+
             if let Some(msg) = message {
                 builder.message(msg);
             }
@@ -1614,5 +1766,31 @@ mod tests {
                      .unwrap();
              assert_eq!(commit, &battered_commit);
          }
+
+         #[test]
+         fn canonical_parent_order((ref commit, ref store) in
+                             Just(Store::default()).prop_flat_map(|store|
+                                (arb_commit(store.clone()), Just(store.clone())))) {
+             // Only the parents after the first are reordered; the first parent stays put.
+             let mut reversed_builder = commit.clone().diverge();
+             reversed_builder.parents(
+                 commit.as_parents().iter().take(1)
+                     .chain(commit.as_parents().iter().skip(1).rev())
+                     .cloned(),
+             );
+             let reversed = reversed_builder.into_commit().unwrap();
+             let canonical = commit.clone().canonicalize().wait().unwrap();
+             assert_eq!(canonical.as_parents().first(), commit.as_parents().first());
+
+             // Commits which encode identically have identical digests.
+             let encode_canonical = |commit: Commit<Handle<DummyBackend>>| {
+                 let canonical = commit.canonicalize().wait().unwrap();
+                 let mut builder = store.builder();
+                 super::encode::commit(&mut builder, &canonical).unwrap();
+                 super::decode::commit::<DummyBackend>(DummyContent::new(builder, store.clone()))
+                     .unwrap()
+             };
+             assert_eq!(encode_canonical(commit.clone()), encode_canonical(reversed));
+         }
     }
 }
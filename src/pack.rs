@@ -0,0 +1,643 @@
+//! Packs: sets of objects transferred between stores as a single unit, rather than one by one.
+//!
+//! A pack contains every object reachable from some root, ordered so that each object comes
+//! after all of the objects it refers to; references are recorded as indices of earlier entries.
+//! Each entry carries the digest the sending store claims for it, which is checked as the pack is
+//! unpacked.
+//!
+//! When the receiving store already has some of those objects, they can be left out: the pack
+//! lists their digests as its *bases*, which its entries refer to as they would to earlier
+//! entries, and which the receiving store must hold for the pack to be unpacked. Only packs
+//! without bases can be written out with `write_to`.
+//!
+//! Which `TransferEncoding` a pack is sent in is negotiated: the sending backend lists the
+//! encodings it can produce, and the first one which we can also decode is used. If there is none,
+//! objects are copied one at a time instead (see `store::transfer`).
+//!
+//! A pack can also be written out as a byte stream with `Pack::write_to` and read back with
+//! `Pack::read_from`, for moving objects between stores which can't talk to each other at all.
+
+use std::{cmp, collections::HashMap, fmt, io::{BufReader, Cursor, Read, Write}};
+
+use failure::*;
+use flate2::{Compression, read::DeflateDecoder, write::DeflateEncoder};
+use futures::prelude::*;
+use hex;
+use leb128;
+
+use canonical;
+use digest::prelude::*;
+use limit::{LimitedReader, RateLimiter};
+use store::{Backend, Handle, Store};
+
+/// Encodings which we are able to decode, in order of preference.
+pub const SUPPORTED_ENCODINGS: &'static [TransferEncoding] =
+    &[TransferEncoding::Deflate, TransferEncoding::Plain];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransferEncoding {
+    /// Entries are sent as-is.
+    Plain,
+
+    /// Entries are compressed as a single DEFLATE stream.
+    Deflate,
+}
+
+impl TransferEncoding {
+    /// Pick the first of the encodings offered by a backend which we are able to decode.
+    pub fn negotiate(offered: &[TransferEncoding]) -> Option<TransferEncoding> {
+        offered
+            .iter()
+            .cloned()
+            .find(|encoding| SUPPORTED_ENCODINGS.contains(encoding))
+    }
+
+    fn to_code(&self) -> u8 {
+        match *self {
+            TransferEncoding::Plain => 0,
+            TransferEncoding::Deflate => 1,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self, Error> {
+        match code {
+            0 => Ok(TransferEncoding::Plain),
+            1 => Ok(TransferEncoding::Deflate),
+            _ => bail!("Malformed pack: unknown encoding {}", code),
+        }
+    }
+}
+
+/// The most space reserved up front for a count read from a pack. Anything beyond this is only
+/// allocated as the data it counts actually turns up, so that a malformed pack can't make us
+/// allocate more than it contains.
+const MAX_PREALLOCATED: usize = 1024;
+
+/// A single object in a pack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackEntry {
+    /// The digest which the sending store claims for this object.
+    pub digest: Vec<u8>,
+    pub blob: Vec<u8>,
+    /// The objects this object refers to, each either one of the pack's bases or an entry which
+    /// comes before it. Bases are numbered first, so for a pack with `n` bases, `n + i` is the
+    /// `i`th entry.
+    pub refs: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pack {
+    signature: DigestSignature,
+    encoding: TransferEncoding,
+    bases: Vec<Vec<u8>>,
+    data: Vec<u8>,
+}
+
+/// Encodes a pack one entry at a time, so that only the encoded (and with `Deflate`, compressed)
+/// data is held in memory rather than every entry along with it. The number of entries has to be
+/// given up front, since it comes first in the encoded data.
+pub struct PackWriter {
+    signature: DigestSignature,
+    encoding: TransferEncoding,
+    bases: Vec<Vec<u8>>,
+    sink: PackSink,
+    count: u64,
+    written: u64,
+}
+
+enum PackSink {
+    Plain(Vec<u8>),
+    Deflate(DeflateEncoder<Vec<u8>>),
+}
+
+impl fmt::Debug for PackWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PackWriter")
+            .field("encoding", &self.encoding)
+            .field("count", &self.count)
+            .field("written", &self.written)
+            .finish()
+    }
+}
+
+impl PackWriter {
+    /// Start a pack of `count` entries. `bases` are the digests of objects which the entries
+    /// refer to but which are left out of the pack.
+    pub fn new(
+        signature: DigestSignature,
+        encoding: TransferEncoding,
+        bases: Vec<Vec<u8>>,
+        count: u64,
+    ) -> Result<Self, Error> {
+        ensure!(
+            bases.iter().all(|base| base.len() == signature.size),
+            "Pack base has a digest of the wrong size"
+        );
+
+        let mut sink = match encoding {
+            TransferEncoding::Plain => PackSink::Plain(Vec::new()),
+            TransferEncoding::Deflate => {
+                PackSink::Deflate(DeflateEncoder::new(Vec::new(), Compression::default()))
+            }
+        };
+        leb128::write::unsigned(sink.as_write(), count)?;
+
+        Ok(Self {
+            signature,
+            encoding,
+            bases,
+            sink,
+            count,
+            written: 0,
+        })
+    }
+
+    /// Encode the next entry, which may only refer to the bases and the entries before it.
+    pub fn push(&mut self, entry: &PackEntry) -> Result<(), Error> {
+        let i = self.written;
+        ensure!(i < self.count, "Pack has more entries than it was started with");
+        ensure!(
+            entry.digest.len() == self.signature.size,
+            "Pack entry {} has a digest of the wrong size",
+            i
+        );
+        ensure!(
+            entry.refs.iter().all(|&r| (r as u64) < self.bases.len() as u64 + i),
+            "Pack entry {} refers to an entry which does not precede it",
+            i
+        );
+
+        let out = self.sink.as_write();
+        out.write_all(&entry.digest)?;
+        leb128::write::unsigned(out, entry.blob.len() as u64)?;
+        out.write_all(&entry.blob)?;
+        leb128::write::unsigned(out, entry.refs.len() as u64)?;
+        for &r in &entry.refs {
+            leb128::write::unsigned(out, r as u64)?;
+        }
+        self.written += 1;
+
+        Ok(())
+    }
+
+    /// Finish the pack, once every entry it was started with has been pushed.
+    pub fn finish(self) -> Result<Pack, Error> {
+        ensure!(
+            self.written == self.count,
+            "Pack has {} entries but was started with {}",
+            self.written,
+            self.count
+        );
+
+        let data = match self.sink {
+            PackSink::Plain(buf) => buf,
+            PackSink::Deflate(encoder) => encoder.finish()?,
+        };
+
+        Ok(Pack {
+            signature: self.signature,
+            encoding: self.encoding,
+            bases: self.bases,
+            data,
+        })
+    }
+}
+
+impl PackSink {
+    fn as_write(&mut self) -> &mut Write {
+        match *self {
+            PackSink::Plain(ref mut buf) => buf,
+            PackSink::Deflate(ref mut encoder) => encoder,
+        }
+    }
+}
+
+impl Pack {
+    /// Encode a list of entries, ordered such that the last entry is the root of the pack. `bases`
+    /// are the digests of objects which the entries refer to but which are left out of the pack.
+    /// To encode entries as they are produced instead, use `PackWriter`.
+    pub fn encode(
+        signature: DigestSignature,
+        encoding: TransferEncoding,
+        bases: Vec<Vec<u8>>,
+        entries: &[PackEntry],
+    ) -> Result<Self, Error> {
+        let mut writer = PackWriter::new(signature, encoding, bases, entries.len() as u64)?;
+        for entry in entries {
+            writer.push(entry)?;
+        }
+
+        writer.finish()
+    }
+
+    /// Decode the entries of this pack. Their digests are not checked; see `unpack`.
+    pub fn decode(&self) -> Result<Vec<PackEntry>, Error> {
+        match self.encoding {
+            TransferEncoding::Plain => self.decode_from(&mut &self.data[..]),
+            TransferEncoding::Deflate => self.decode_from(&mut DeflateDecoder::new(&self.data[..])),
+        }
+    }
+
+    fn decode_from<R: Read>(&self, reader: &mut R) -> Result<Vec<PackEntry>, Error> {
+        let count = leb128::read::unsigned(reader)?;
+        let mut entries = Vec::with_capacity(cmp::min(count, MAX_PREALLOCATED as u64) as usize);
+        for i in 0..count as usize {
+            entries.push(read_entry(reader, self.signature.size, self.bases.len() + i)?);
+        }
+
+        Ok(entries)
+    }
+
+    /// Decode the entries of this pack one at a time, charging `limiter` for the encoded data as
+    /// it is read. Their digests are not checked; see `unpack_with_limit`.
+    pub fn into_entries(self, limiter: RateLimiter) -> Result<Entries, Error> {
+        let limited = LimitedReader::new(Cursor::new(self.data), limiter);
+        let mut reader: Box<Read> = match self.encoding {
+            TransferEncoding::Plain => Box::new(BufReader::new(limited)),
+            TransferEncoding::Deflate => Box::new(DeflateDecoder::new(limited)),
+        };
+        let remaining = leb128::read::unsigned(&mut reader)?;
+
+        Ok(Entries {
+            reader,
+            digest_size: self.signature.size,
+            preceding: self.bases.len(),
+            remaining,
+        })
+    }
+
+    /// The number of entries in this pack, read from the start of its data.
+    pub fn entry_count(&self) -> Result<u64, Error> {
+        let count = match self.encoding {
+            TransferEncoding::Plain => leb128::read::unsigned(&mut &self.data[..])?,
+            TransferEncoding::Deflate => {
+                leb128::read::unsigned(&mut DeflateDecoder::new(&self.data[..]))?
+            }
+        };
+
+        Ok(count)
+    }
+
+    pub fn signature(&self) -> DigestSignature {
+        self.signature
+    }
+
+    pub fn encoding(&self) -> TransferEncoding {
+        self.encoding
+    }
+
+    /// The digests of the objects which this pack's entries refer to but which it leaves out.
+    pub fn bases(&self) -> &[Vec<u8>] {
+        &self.bases
+    }
+
+    /// The size of the encoded pack, in bytes.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Write the encoded pack out: its encoding, the length of its data and then the data itself.
+    /// The digest signature is not written, so whoever reads the pack back has to know it. Packs
+    /// with bases can't be written out, since they aren't complete by themselves.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        ensure!(
+            self.bases.is_empty(),
+            "A pack which leaves out objects can't be written out"
+        );
+
+        writer.write_all(&[self.encoding.to_code()])?;
+        leb128::write::unsigned(writer, self.data.len() as u64)?;
+        writer.write_all(&self.data)?;
+
+        Ok(())
+    }
+
+    /// Read back a pack written by `write_to`, stopping at the end of it. Its entries are not
+    /// decoded until `decode` or `unpack`.
+    pub fn read_from<R: Read>(signature: DigestSignature, reader: &mut R) -> Result<Self, Error> {
+        let mut code = [0];
+        reader.read_exact(&mut code)?;
+        let encoding = TransferEncoding::from_code(code[0])?;
+
+        let len = leb128::read::unsigned(reader)?;
+        let mut data = Vec::new();
+        reader.take(len).read_to_end(&mut data)?;
+        ensure!(data.len() as u64 == len, "Malformed pack: truncated data");
+
+        Ok(Self {
+            signature,
+            encoding,
+            bases: Vec::new(),
+            data,
+        })
+    }
+}
+
+/// Read a single pack entry, which may refer to any of the `preceding` bases and entries.
+fn read_entry<R: Read>(
+    reader: &mut R,
+    digest_size: usize,
+    preceding: usize,
+) -> Result<PackEntry, Error> {
+    let mut digest = vec![0; digest_size];
+    reader.read_exact(&mut digest)?;
+
+    // The blob is read in as it arrives rather than into a buffer of the claimed length.
+    let blob_len = leb128::read::unsigned(reader)?;
+    let mut blob = Vec::new();
+    reader.take(blob_len).read_to_end(&mut blob)?;
+    ensure!(
+        blob.len() as u64 == blob_len,
+        "Malformed pack: an entry is truncated"
+    );
+
+    let ref_count = leb128::read::unsigned(reader)?;
+    let mut refs = Vec::with_capacity(cmp::min(ref_count, MAX_PREALLOCATED as u64) as usize);
+    for _ in 0..ref_count {
+        let r = leb128::read::unsigned(reader)?;
+        ensure!(
+            r < preceding as u64,
+            "Malformed pack: an entry refers to an entry which does not precede it"
+        );
+        refs.push(r as usize);
+    }
+
+    Ok(PackEntry { digest, blob, refs })
+}
+
+/// The entries of a pack, decoded one at a time as they are read. See `Pack::into_entries`.
+pub struct Entries {
+    reader: Box<Read>,
+    digest_size: usize,
+    /// The number of bases and entries before the next entry.
+    preceding: usize,
+    remaining: u64,
+}
+
+impl fmt::Debug for Entries {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Entries")
+            .field("reader", &"OPAQUE")
+            .field("preceding", &self.preceding)
+            .field("remaining", &self.remaining)
+            .finish()
+    }
+}
+
+impl Iterator for Entries {
+    type Item = Result<PackEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let entry = read_entry(&mut self.reader, self.digest_size, self.preceding);
+        self.remaining -= 1;
+        self.preceding += 1;
+        if entry.is_err() {
+            // Nothing after a malformed entry can be trusted.
+            self.remaining = 0;
+        }
+
+        Some(entry)
+    }
+}
+
+/// Pack every object reachable from any of `roots`, each object once, ordered so that every entry
+/// comes after the entries it refers to. Returns the pack along with the index of each root's
+/// entry.
+///
+/// The objects are walked twice: once to put them in order, keeping only their handles and
+/// references, and again to load each one and encode it, so that no more than one object's data
+/// is held in memory at a time besides the encoded pack.
+#[async]
+pub fn collect<D: Digest, B: Backend>(
+    encoding: TransferEncoding,
+    roots: Vec<Handle<B>>,
+) -> Result<(Pack, Vec<usize>), Error> {
+    enum Visit<B: Backend> {
+        Enter(Handle<B>),
+        Exit(Handle<B>, Vec<Handle<B>>),
+    }
+
+    let mut order = Vec::new();
+    let mut indices = HashMap::new();
+    let mut root_indices = Vec::with_capacity(roots.len());
+
+    for root in roots {
+        let mut stack = vec![Visit::Enter(root.clone())];
+        while let Some(visit) = stack.pop() {
+            match visit {
+                Visit::Enter(handle) => {
+                    if indices.contains_key(&handle) {
+                        continue;
+                    }
+
+                    let refs = await!(handle.load())?.collect::<Vec<_>>();
+                    stack.push(Visit::Exit(handle, refs.clone()));
+                    stack.extend(refs.into_iter().rev().map(Visit::Enter));
+                }
+                Visit::Exit(handle, refs) => {
+                    if indices.contains_key(&handle) {
+                        continue;
+                    }
+
+                    let refs = refs.iter().map(|child| indices[child]).collect::<Vec<_>>();
+                    indices.insert(handle.clone(), order.len());
+                    order.push((handle, refs));
+                }
+            }
+        }
+
+        root_indices.push(indices[&root]);
+    }
+
+    let mut writer = PackWriter::new(D::SIGNATURE, encoding, Vec::new(), order.len() as u64)?;
+    for (handle, refs) in order {
+        let mut blob = Vec::new();
+        await!(handle.load())?.read_to_end(&mut blob)?;
+        let digest = await!(handle.digest::<D>())?;
+        writer.push(&PackEntry {
+            digest: digest.as_bytes().to_vec(),
+            blob,
+            refs,
+        })?;
+    }
+
+    Ok((writer.finish()?, root_indices))
+}
+
+/// Write the objects in a pack into a store, verifying the digest of each, and return a handle to
+/// the root of the pack.
+pub fn unpack<D: Digest, B: Backend>(
+    pack: Pack,
+    target: Store<B>,
+) -> Box<Future<Item = Handle<B>, Error = Error>> {
+    unpack_with_limit::<D, B>(pack, target, RateLimiter::unlimited())
+}
+
+/// As `unpack`, but throttled by `limiter`, which is charged for the encoded data of the pack as
+/// it is read, interleaved with writing its objects into the store.
+#[async(boxed)]
+pub fn unpack_with_limit<D: Digest, B: Backend>(
+    pack: Pack,
+    target: Store<B>,
+    limiter: RateLimiter,
+) -> Result<Handle<B>, Error> {
+    let mut handles = await!(unpack_all_with_limit::<D, B>(pack, target, limiter))?;
+    handles.pop().ok_or_else(|| format_err!("Empty pack!"))
+}
+
+/// As `unpack`, but returning a handle to every entry of the pack, in order.
+pub fn unpack_all<D: Digest, B: Backend>(
+    pack: Pack,
+    target: Store<B>,
+) -> Box<Future<Item = Vec<Handle<B>>, Error = Error>> {
+    unpack_all_with_limit::<D, B>(pack, target, RateLimiter::unlimited())
+}
+
+/// As `unpack_with_limit`, but returning a handle to every entry of the pack, in order.
+#[async(boxed)]
+pub fn unpack_all_with_limit<D: Digest, B: Backend>(
+    pack: Pack,
+    target: Store<B>,
+    limiter: RateLimiter,
+) -> Result<Vec<Handle<B>>, Error> {
+    ensure!(
+        pack.signature() == D::SIGNATURE,
+        "Pack uses digest {} but {} was expected",
+        pack.signature().name,
+        D::SIGNATURE.name
+    );
+
+    let bases = pack.bases().to_vec();
+    let mut handles: Vec<Handle<B>> = Vec::with_capacity(bases.len());
+    let mut digests: Vec<D> = Vec::with_capacity(bases.len());
+
+    for base in &bases {
+        let digest = D::from_bytes(base);
+        let handle = await!(target.resolve_digest(digest.clone()))?.ok_or_else(|| {
+            format_err!(
+                "Pack depends on object {} which the store doesn't have",
+                hex::encode(base)
+            )
+        })?;
+        handles.push(handle);
+        digests.push(digest);
+    }
+
+    for entry_res in pack.into_entries(limiter)? {
+        let entry = entry_res?;
+        let ref_digests = entry
+            .refs
+            .iter()
+            .map(|&r| digests[r].clone())
+            .collect::<Vec<_>>();
+
+        let mut writer = D::writer();
+        canonical::encode(&mut writer, &entry.blob, &ref_digests)?;
+        let digest = writer.finish();
+
+        ensure!(
+            digest.as_bytes() == &entry.digest[..],
+            "Digest mismatch while unpacking: received {}, calculated {}",
+            hex::encode(&entry.digest),
+            hex::encode(digest.as_bytes())
+        );
+
+        let mut builder = target.builder();
+        builder.write_all(&entry.blob)?;
+        builder.extend(entry.refs.iter().map(|&r| handles[r].clone()));
+        handles.push(await!(builder.finish())?);
+        digests.push(digest);
+    }
+
+    Ok(handles.split_off(bases.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use digest::Sha3Digest;
+    use memory::MemoryBackend;
+    use object::{ObjectRef, SmallBuilder, TreeBuilder};
+
+    #[test]
+    fn collected_packs_round_trip_through_bytes() {
+        let source = Store::new(MemoryBackend::new());
+        let mut small_builder = SmallBuilder::new();
+        small_builder.write_all(b"shared").unwrap();
+        let small_ref = small_builder.as_small().send(&source).wait().unwrap();
+
+        let mut roots = Vec::new();
+        for name in &["a", "b"] {
+            let mut tree_builder = TreeBuilder::new();
+            tree_builder.insert(name.to_string(), ObjectRef::Small(small_ref.clone()));
+            let tree_ref = tree_builder.into_tree().send(&source).wait().unwrap();
+            roots.push(tree_ref.as_inner().clone());
+        }
+
+        let (pack, root_indices) =
+            collect::<Sha3Digest, _>(TransferEncoding::Deflate, roots.clone())
+                .wait()
+                .unwrap();
+        let entries = pack.decode().unwrap();
+        assert_eq!(entries.len(), 3);
+
+        let mut bytes = Vec::new();
+        pack.write_to(&mut bytes).unwrap();
+        let read = Pack::read_from(Sha3Digest::SIGNATURE, &mut &bytes[..]).unwrap();
+        assert_eq!(read.decode().unwrap(), entries);
+
+        let target = Store::new(MemoryBackend::new());
+        let handles = unpack_all::<Sha3Digest, _>(read, target).wait().unwrap();
+        for (root, &index) in roots.iter().zip(&root_indices) {
+            assert_eq!(
+                handles[index].digest::<Sha3Digest>().wait().unwrap(),
+                root.digest::<Sha3Digest>().wait().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn packs_with_bases_need_them_in_the_target() {
+        let source = Store::new(MemoryBackend::new());
+        let mut small_builder = SmallBuilder::new();
+        small_builder.write_all(b"shared").unwrap();
+        let small = small_builder.as_small().clone();
+        let small_ref = small.send(&source).wait().unwrap();
+
+        let mut tree_builder = TreeBuilder::new();
+        tree_builder.insert("a".to_owned(), ObjectRef::Small(small_ref));
+        let tree_ref = tree_builder.into_tree().send(&source).wait().unwrap();
+
+        let (pack, _) = collect::<Sha3Digest, _>(
+            TransferEncoding::Plain,
+            vec![tree_ref.as_inner().clone()],
+        ).wait()
+            .unwrap();
+        let mut entries = pack.decode().unwrap();
+        let tree_entry = entries.pop().unwrap();
+        let small_entry = entries.pop().unwrap();
+        assert_eq!(tree_entry.refs, vec![0]);
+
+        // The tree's reference to entry 0 now means base 0 instead.
+        let bases = vec![small_entry.digest.clone()];
+        let pack =
+            Pack::encode(Sha3Digest::SIGNATURE, TransferEncoding::Plain, bases, &[tree_entry])
+                .unwrap();
+        assert!(pack.write_to(&mut Vec::new()).is_err());
+
+        let empty = Store::new(MemoryBackend::new());
+        assert!(unpack::<Sha3Digest, _>(pack.clone(), empty).wait().is_err());
+
+        let target = Store::new(MemoryBackend::new());
+        small.send(&target).wait().unwrap();
+        let handle = unpack::<Sha3Digest, _>(pack, target).wait().unwrap();
+        assert_eq!(
+            handle.digest::<Sha3Digest>().wait().unwrap(),
+            tree_ref.as_inner().digest::<Sha3Digest>().wait().unwrap()
+        );
+    }
+}
@@ -14,6 +14,21 @@ impl fmt::Debug for ObjectPath {
     }
 }
 
+/// Paths are displayed with `/` as a separator regardless of platform, so that the displayed form
+/// is stable for machine consumption.
+impl fmt::Display for ObjectPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, s) in self.inner.iter().enumerate() {
+            if i > 0 {
+                f.write_str("/")?;
+            }
+            f.write_str(&s)?;
+        }
+
+        Ok(())
+    }
+}
+
 impl ObjectPath {
     pub fn new() -> Self {
         Self { inner: List::new() }
@@ -35,6 +50,15 @@ impl ObjectPath {
         }
     }
 
+    /// Whether `prefix` is this path or one of its ancestors.
+    pub fn starts_with(&self, prefix: &ObjectPath) -> bool {
+        prefix.depth() <= self.depth()
+            && self.inner
+                .iter()
+                .zip(prefix.inner.iter())
+                .all(|(this, that)| this == that)
+    }
+
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
         let inner_opt = path.as_ref()
             .iter()
@@ -0,0 +1,1094 @@
+//! Authenticated encryption of object payloads at rest.
+//!
+//! A `Sealer` holds a key derived from a passphrase with Argon2 and a per-repository salt, and
+//! encrypts payloads with ChaCha20-Poly1305. `SealLayer` wraps a backend so that every object
+//! payload written through it is sealed, and every payload read through it opened.
+//!
+//! The plaintext digest of each object is sealed in along with its data, so digests are still
+//! computed over plaintext and content addressing is unaffected. Nonces are derived from the key
+//! and the plaintext rather than drawn at random, so that identical objects seal to identical
+//! payloads and the wrapped backend still deduplicates them. The price is that anyone able to read
+//! the store can tell when two of its objects are the same.
+//!
+//! A sealed store keeps its salt and check value (its `SealParams`) unencrypted under a reserved
+//! branch, so that whoever opens it - as a remote, say - can tell that it is sealed and unlock it.
+//!
+//! The wrapped backend only knows objects by the digests of their sealed payloads, so a sealed
+//! store also keeps an index from plaintext digests to those, itself sealed, under another
+//! reserved branch. The index is a chain of segments, each listing some objects and referring to
+//! the segment before it; every object written adds a segment, and the first reader of a chain
+//! longer than one segment replaces it with a single segment listing everything. Looking up a
+//! digest therefore costs opening the index once, rather than every object in the store. A store
+//! sealed before the index existed has its objects indexed the first time it is written to or
+//! looked up in.
+//!
+//! Loading an object checks the digests of the objects it refers to along with its data, so that
+//! a reference changed in the wrapped backend is caught rather than followed.
+
+use std::{fmt, collections::{BTreeMap, HashMap}, io::{self, Cursor, Read, Write}, path::Path,
+          sync::Arc, vec};
+
+use argon2;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, aead::{Aead, NewAead}};
+use failure::Error;
+use futures::{future, stream, prelude::*};
+use getrandom;
+use parking_lot::Mutex;
+
+use Open;
+use canonical;
+use digest::{Sha3Digest, prelude::*};
+use layer::{BackendExt, Layer};
+use pack::{Pack, TransferEncoding};
+use store::{Backend, RawHandle};
+
+/// Length in bytes of the salt used for key derivation.
+pub const SALT_LEN: usize = 16;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// The number of bytes by which sealing a payload lengthens it.
+pub const OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+/// The branch under which a sealed store keeps its `SealParams`. It is outside of any namespace
+/// refs are kept in, and `SealedBackend` hides it.
+pub const PARAMS_BRANCH: &'static str = "attaca/seal";
+
+/// The branch under which a sealed store keeps the latest segment of its digest index. Like
+/// `PARAMS_BRANCH`, `SealedBackend` hides it.
+pub const INDEX_BRANCH: &'static str = "attaca/seal-index";
+
+/// How many times adding a segment to the digest index is tried before giving up, if the branches
+/// of the wrapped backend keep changing underneath it.
+const INDEX_RETRIES: usize = 16;
+
+/// Plaintext of the check value which proves that a passphrase is the right one.
+const CHECK_PLAINTEXT: &'static [u8] = b"attaca sealed store";
+
+/// Generate a fresh random salt for a new repository.
+pub fn generate_salt() -> Result<Vec<u8>, Error> {
+    let mut salt = vec![0; SALT_LEN];
+    getrandom::getrandom(&mut salt)
+        .map_err(|err| format_err!("Unable to generate a random salt: {}", err))?;
+    Ok(salt)
+}
+
+pub struct Sealer {
+    key: [u8; KEY_LEN],
+    cipher: ChaCha20Poly1305,
+}
+
+impl fmt::Debug for Sealer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sealer").field("key", &"OPAQUE").finish()
+    }
+}
+
+impl Sealer {
+    /// Derive a key from a passphrase and salt.
+    pub fn derive(passphrase: &[u8], salt: &[u8]) -> Result<Self, Error> {
+        ensure!(salt.len() == SALT_LEN, "Bad salt length {}", salt.len());
+
+        let config = argon2::Config {
+            hash_length: KEY_LEN as u32,
+            ..argon2::Config::default()
+        };
+        let hash = argon2::hash_raw(passphrase, salt, &config)
+            .map_err(|err| format_err!("Key derivation failed: {}", err))?;
+
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(&hash);
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+
+        Ok(Self { key, cipher })
+    }
+
+    /// A keyed digest of the plaintext, so that a nonce is only ever reused to seal the very same
+    /// plaintext again.
+    fn nonce(&self, plaintext: &[u8]) -> [u8; NONCE_LEN] {
+        let mut writer = Sha3Digest::writer();
+        writer.write_all(&self.key).unwrap();
+        writer.write_all(plaintext).unwrap();
+
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&writer.finish().as_bytes()[..NONCE_LEN]);
+        nonce
+    }
+
+    /// Encrypt a payload. The result is the nonce followed by the ciphertext and its tag.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = self.nonce(plaintext);
+        let ciphertext = self.cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| format_err!("Encryption failed"))?;
+
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypt a payload produced by `seal`, failing if it was sealed with a different key or has
+    /// been tampered with.
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        ensure!(sealed.len() >= OVERHEAD, "Sealed payload is truncated");
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| format_err!("Unable to decrypt payload: wrong key or corrupted data"))
+    }
+
+    /// Produce a check value to store alongside the salt, so that `verify` can later tell whether a
+    /// passphrase is correct before anything is read from the store.
+    pub fn check_value(&self) -> Result<Vec<u8>, Error> {
+        self.seal(CHECK_PLAINTEXT)
+    }
+
+    pub fn verify(&self, check: &[u8]) -> Result<(), Error> {
+        match self.open(check) {
+            Ok(ref plaintext) if plaintext.as_slice() == CHECK_PLAINTEXT => Ok(()),
+            _ => bail!("Wrong passphrase!"),
+        }
+    }
+}
+
+/// What is needed, along with the passphrase, to unlock a sealed store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SealParams {
+    pub salt: Vec<u8>,
+    pub check: Vec<u8>,
+}
+
+impl SealParams {
+    fn encode(&self) -> Vec<u8> {
+        let mut encoded = self.salt.clone();
+        encoded.extend_from_slice(&self.check);
+        encoded
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        ensure!(bytes.len() >= SALT_LEN, "Malformed seal parameters");
+
+        let (salt, check) = bytes.split_at(SALT_LEN);
+        Ok(Self {
+            salt: salt.to_vec(),
+            check: check.to_vec(),
+        })
+    }
+}
+
+/// A layer which seals object payloads with a `Sealer` on their way into storage and opens them on
+/// their way back out. See the module documentation.
+#[derive(Debug, Clone, Default)]
+pub struct SealLayer {
+    sealer: Option<Arc<Sealer>>,
+}
+
+impl SealLayer {
+    pub fn new(sealer: Sealer) -> Self {
+        Self {
+            sealer: Some(Arc::new(sealer)),
+        }
+    }
+
+    /// A layer which seals nothing, for stores which aren't sealed. Objects pass through it
+    /// unchanged.
+    pub fn none() -> Self {
+        Self::default()
+    }
+}
+
+impl<B: Backend<Digest = Sha3Digest>> Layer<B> for SealLayer {
+    type Backend = SealedBackend<B>;
+
+    fn wrap(self, inner: B) -> Self::Backend {
+        SealedBackend {
+            inner: Arc::new(inner),
+            sealer: self.sealer,
+            index: Arc::new(Mutex::new(DigestIndex::default())),
+        }
+    }
+}
+
+/// The plaintext digests of sealed objects, which the wrapped backend knows nothing of.
+#[derive(Debug, Default)]
+struct DigestIndex {
+    digests: HashMap<RawHandle, Sha3Digest>,
+    /// Ordered, so that digests with a given prefix can be found without scanning the whole index.
+    handles: BTreeMap<Sha3Digest, RawHandle>,
+
+    /// Whether every object in the wrapped backend has been indexed.
+    complete: bool,
+}
+
+impl DigestIndex {
+    fn insert(&mut self, id: RawHandle, digest: Sha3Digest) {
+        self.digests.insert(id, digest);
+        self.handles.insert(digest, id);
+    }
+}
+
+/// The builder of a `SealedBackend`. Objects are sealed whole, so they are buffered until they are
+/// finished.
+#[derive(Debug, Default)]
+pub struct SealedBuilder {
+    data: Vec<u8>,
+    refs: Vec<RawHandle>,
+}
+
+impl Write for SealedBuilder {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.data.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+impl Extend<RawHandle> for SealedBuilder {
+    fn extend<I: IntoIterator<Item = RawHandle>>(&mut self, iterable: I) {
+        self.refs.extend(iterable);
+    }
+}
+
+/// The content of an object loaded through a `SealedBackend`.
+#[derive(Debug)]
+pub struct SealedContent {
+    data: Cursor<Vec<u8>>,
+    refs: vec::IntoIter<RawHandle>,
+}
+
+impl Read for SealedContent {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.data.read(buf)
+    }
+}
+
+impl Iterator for SealedContent {
+    type Item = RawHandle;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.refs.next()
+    }
+}
+
+/// Read the whole of an object loaded from the wrapped backend.
+fn read_content<C>(mut content: C) -> Result<(Vec<u8>, Vec<RawHandle>), Error>
+where
+    C: Read + Iterator<Item = RawHandle>,
+{
+    let mut data = Vec::new();
+    content.read_to_end(&mut data)?;
+    let refs = content.collect();
+    Ok((data, refs))
+}
+
+/// Open a sealed payload, and split it into the digest of its object and the object's data.
+fn open_payload(sealer: &Sealer, sealed: &[u8]) -> Result<(Sha3Digest, Vec<u8>), Error> {
+    let mut payload = sealer.open(sealed)?;
+    ensure!(
+        payload.len() >= Sha3Digest::SIGNATURE.size,
+        "Sealed payload is truncated"
+    );
+
+    let data = payload.split_off(Sha3Digest::SIGNATURE.size);
+    Ok((Sha3Digest::from_bytes(&payload), data))
+}
+
+/// Write an object straight into the wrapped backend.
+fn write_inner<B: Backend>(
+    inner: &B,
+    data: &[u8],
+    refs: Vec<RawHandle>,
+) -> Result<B::FutureFinish, Error> {
+    let mut builder = inner.builder();
+    builder.write_all(data)?;
+    builder.extend(refs);
+    Ok(inner.finish(builder))
+}
+
+/// Find the plaintext digest of a sealed object, opening it if it isn't indexed yet.
+fn load_digest<B: Backend>(
+    inner: &B,
+    sealer: Arc<Sealer>,
+    index: Arc<Mutex<DigestIndex>>,
+    id: RawHandle,
+) -> Box<Future<Item = Sha3Digest, Error = Error>> {
+    if let Some(&digest) = index.lock().digests.get(&id) {
+        return Box::new(future::ok(digest));
+    }
+
+    Box::new(inner.load(id).and_then(move |content| {
+        let (sealed, _) = read_content(content)?;
+        let (digest, _) = open_payload(&sealer, &sealed)?;
+        index.lock().insert(id, digest);
+        Ok(digest)
+    }))
+}
+
+/// Load and open a sealed object, checking that its data and the objects it refers to are those
+/// its digest was calculated over.
+#[async]
+fn load_sealed<B: Backend<Digest = Sha3Digest>>(
+    inner: Arc<B>,
+    sealer: Arc<Sealer>,
+    index: Arc<Mutex<DigestIndex>>,
+    id: RawHandle,
+) -> Result<(Vec<u8>, Vec<RawHandle>), Error> {
+    // With the index complete, the digests of the objects referred to are looked up in it rather
+    // than by opening each of them.
+    await!(complete_index(inner.clone(), sealer.clone(), index.clone()))?;
+
+    let (sealed, refs) = read_content(await!(inner.load(id))?)?;
+    let (digest, data) = open_payload(&sealer, &sealed)?;
+    let ref_digests = await!(future::join_all(
+        refs.iter()
+            .map(|&child| load_digest(&*inner, sealer.clone(), index.clone(), child))
+            .collect::<Vec<_>>()
+    ))?;
+
+    let mut writer = Sha3Digest::writer();
+    canonical::encode(&mut writer, &data, &ref_digests)?;
+    ensure!(
+        writer.finish() == digest,
+        "Sealed object does not match its digest: its references have been tampered with"
+    );
+
+    index.lock().insert(id, digest);
+    Ok((data, refs))
+}
+
+#[async]
+fn index_all<B, S>(
+    inner: Arc<B>,
+    sealer: Arc<Sealer>,
+    index: Arc<Mutex<DigestIndex>>,
+    listed: S,
+) -> Result<(), Error>
+where
+    B: Backend,
+    S: Stream<Item = Vec<u8>, Error = Error> + 'static,
+{
+    // The parameters are the one object in a sealed store which isn't sealed.
+    let params = await!(inner.load_branches())?
+        .get(PARAMS_BRANCH)
+        .cloned();
+
+    #[async]
+    for inner_digest in listed {
+        let id = match await!(inner.resolve_digest(Sha3Digest::SIGNATURE, &inner_digest))? {
+            Some(id) => id,
+            None => continue,
+        };
+
+        if Some(id) != params {
+            await!(load_digest(&*inner, sealer.clone(), index.clone(), id))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Encode the entries of a segment of the digest index: the plaintext digest of each object,
+/// followed by the digest the wrapped backend knows it by.
+fn encode_segment(entries: &[(Sha3Digest, Sha3Digest)]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(entries.len() * 2 * Sha3Digest::SIGNATURE.size);
+    for &(ref digest, ref inner_digest) in entries {
+        encoded.extend_from_slice(digest.as_bytes());
+        encoded.extend_from_slice(inner_digest.as_bytes());
+    }
+    encoded
+}
+
+fn decode_segment(bytes: &[u8]) -> Result<Vec<(Sha3Digest, Sha3Digest)>, Error> {
+    let size = Sha3Digest::SIGNATURE.size;
+    ensure!(
+        bytes.len() % (2 * size) == 0,
+        "Malformed digest index segment"
+    );
+
+    Ok(bytes
+        .chunks(2 * size)
+        .map(|entry| {
+            (
+                Sha3Digest::from_bytes(&entry[..size]),
+                Sha3Digest::from_bytes(&entry[size..]),
+            )
+        })
+        .collect())
+}
+
+/// Read the digest index into `index`, following its chain of segments back from `head`. Returns
+/// the number of segments in the chain.
+#[async]
+fn load_segments<B: Backend<Digest = Sha3Digest>>(
+    inner: Arc<B>,
+    sealer: Arc<Sealer>,
+    index: Arc<Mutex<DigestIndex>>,
+    head: RawHandle,
+) -> Result<usize, Error> {
+    let mut segments = 0;
+    let mut next = Some(head);
+    while let Some(id) = next {
+        let (sealed, refs) = read_content(await!(inner.load(id))?)?;
+        for (digest, inner_digest) in decode_segment(&sealer.open(&sealed)?)? {
+            let inner_digest = inner_digest.as_bytes().to_vec();
+            let resolved = await!(inner.resolve_digest(Sha3Digest::SIGNATURE, &inner_digest))?;
+            if let Some(object_id) = resolved {
+                index.lock().insert(object_id, digest);
+            }
+        }
+
+        segments += 1;
+        next = refs.into_iter().next();
+    }
+
+    Ok(segments)
+}
+
+/// Add a segment listing `entries` to the digest index, retrying if the branches of the wrapped
+/// backend change before it can be recorded.
+#[async]
+fn append_segment<B: Backend<Digest = Sha3Digest>>(
+    inner: Arc<B>,
+    sealer: Arc<Sealer>,
+    entries: Vec<(Sha3Digest, Sha3Digest)>,
+) -> Result<(), Error> {
+    let sealed = sealer.seal(&encode_segment(&entries))?;
+    for _ in 0..INDEX_RETRIES {
+        let previous = await!(inner.load_branches())?;
+        let refs = previous.get(INDEX_BRANCH).cloned().into_iter().collect();
+        let id = await!(write_inner(&*inner, &sealed, refs)?)?;
+
+        let mut new = previous.clone();
+        new.insert(INDEX_BRANCH.to_owned(), id);
+        if await!(inner.swap_branches(previous, new)).is_ok() {
+            return Ok(());
+        }
+    }
+
+    bail!("Unable to update the digest index: the store's branches keep changing")
+}
+
+/// Replace the digest index which begins at `head` with a single segment listing everything in
+/// `index`. If the index has moved on from `head` in the meantime, it's left as it is.
+#[async]
+fn consolidate<B: Backend<Digest = Sha3Digest>>(
+    inner: Arc<B>,
+    sealer: Arc<Sealer>,
+    index: Arc<Mutex<DigestIndex>>,
+    head: Option<RawHandle>,
+) -> Result<(), Error> {
+    if await!(inner.load_branches())?.get(INDEX_BRANCH).cloned() != head {
+        return Ok(());
+    }
+
+    let indexed = index
+        .lock()
+        .digests
+        .iter()
+        .map(|(&id, &digest)| (id, digest))
+        .collect::<Vec<_>>();
+    let mut entries = Vec::with_capacity(indexed.len());
+    for (id, digest) in indexed {
+        entries.push((digest, await!(inner.digest(Sha3Digest::SIGNATURE, id))?));
+    }
+    let id = await!(write_inner(&*inner, &sealer.seal(&encode_segment(&entries))?, Vec::new())?)?;
+
+    let previous = await!(inner.load_branches())?;
+    if previous.get(INDEX_BRANCH).cloned() == head {
+        let mut new = previous.clone();
+        new.insert(INDEX_BRANCH.to_owned(), id);
+        // Losing a race with another writer only means the chain is consolidated later.
+        let _ = await!(inner.swap_branches(previous, new));
+    }
+
+    Ok(())
+}
+
+/// Make sure every object in the wrapped backend has its plaintext digest indexed, reading the
+/// digest index if there is one. Without one, every object is opened to index it, and the result
+/// recorded as the digest index; if the wrapped backend can't enumerate its objects, that can't be
+/// done, and this returns `false`.
+#[async]
+fn complete_index<B: Backend<Digest = Sha3Digest>>(
+    inner: Arc<B>,
+    sealer: Arc<Sealer>,
+    index: Arc<Mutex<DigestIndex>>,
+) -> Result<bool, Error> {
+    if index.lock().complete {
+        return Ok(true);
+    }
+
+    let head = await!(inner.load_branches())?.get(INDEX_BRANCH).cloned();
+    match head {
+        Some(head) => {
+            let segments =
+                await!(load_segments(inner.clone(), sealer.clone(), index.clone(), head))?;
+            index.lock().complete = true;
+            if segments > 1 {
+                await!(consolidate(inner, sealer, index, Some(head)))?;
+            }
+        }
+        None => {
+            let listed = match inner.list_digests(Sha3Digest::SIGNATURE) {
+                Some(listed) => listed,
+                None => return Ok(false),
+            };
+            await!(index_all(inner.clone(), sealer.clone(), index.clone(), listed))?;
+            index.lock().complete = true;
+            await!(consolidate(inner, sealer, index, None))?;
+        }
+    }
+
+    Ok(true)
+}
+
+/// Record a newly written object in the digest index.
+#[async]
+fn record_digest<B: Backend<Digest = Sha3Digest>>(
+    inner: Arc<B>,
+    sealer: Arc<Sealer>,
+    index: Arc<Mutex<DigestIndex>>,
+    id: RawHandle,
+    digest: Sha3Digest,
+) -> Result<(), Error> {
+    index.lock().insert(id, digest);
+
+    // Without a digest index yet, building one takes in the new object along with the rest.
+    if await!(inner.load_branches())?.contains_key(INDEX_BRANCH) {
+        let inner_digest = await!(inner.digest(Sha3Digest::SIGNATURE, id))?;
+        await!(append_segment(inner, sealer, vec![(digest, inner_digest)]))?;
+    } else {
+        await!(complete_index(inner, sealer, index))?;
+    }
+
+    Ok(())
+}
+
+/// A backend wrapped by a `SealLayer`.
+pub struct SealedBackend<B: Backend> {
+    inner: Arc<B>,
+    sealer: Option<Arc<Sealer>>,
+    index: Arc<Mutex<DigestIndex>>,
+}
+
+impl<B: Backend> fmt::Debug for SealedBackend<B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SealedBackend")
+            .field("inner", &"OPAQUE")
+            .field("sealer", &self.sealer)
+            .field("index", &self.index)
+            .finish()
+    }
+}
+
+impl<B: Backend<Digest = Sha3Digest>> SealedBackend<B> {
+    /// The backend this layer wraps.
+    pub fn get_ref(&self) -> &B {
+        &self.inner
+    }
+
+    /// The parameters the wrapped backend was sealed with, or `None` if it isn't sealed.
+    pub fn params(&self) -> Box<Future<Item = Option<SealParams>, Error = Error>> {
+        let inner = self.inner.clone();
+        let params = self.inner
+            .load_branches()
+            .and_then(move |branches| match branches.get(PARAMS_BRANCH) {
+                Some(&id) => future::Either::A(inner.load(id).and_then(|content| {
+                    let (data, _) = read_content(content)?;
+                    Ok(Some(SealParams::decode(&data)?))
+                })),
+                None => future::Either::B(future::ok(None)),
+            });
+
+        Box::new(params)
+    }
+
+    /// Record the parameters of a newly sealed store in it, so that it can be recognized as sealed
+    /// and unlocked when it is opened.
+    pub fn set_params(&self, params: &SealParams) -> Box<Future<Item = (), Error = Error>> {
+        let inner = self.inner.clone();
+        let written = write_inner(&*self.inner, &params.encode(), Vec::new())
+            .into_future()
+            .flatten()
+            .and_then(move |id| {
+                inner.load_branches().and_then(move |previous| {
+                    let mut new = previous.clone();
+                    new.insert(PARAMS_BRANCH.to_owned(), id);
+                    inner.swap_branches(previous, new)
+                })
+            });
+
+        Box::new(written)
+    }
+
+    /// List the plaintext digests beginning with `prefix`, completing the index first. Returns
+    /// `None` if the index can't be completed, since the wrapped backend can't enumerate its
+    /// objects and there's no digest index to read instead.
+    fn list_indexed(
+        &self,
+        sealer: Arc<Sealer>,
+        signature: DigestSignature,
+        prefix: Vec<u8>,
+    ) -> Option<Box<Stream<Item = Vec<u8>, Error = Error>>> {
+        if signature != Sha3Digest::SIGNATURE {
+            return None;
+        }
+        if !self.index.lock().complete && self.inner.list_digests(signature).is_none() {
+            return None;
+        }
+
+        let index = self.index.clone();
+        let indexed = complete_index(self.inner.clone(), sealer, self.index.clone());
+        let listed = indexed
+            .map(move |_| {
+                // Every digest beginning with `prefix` sorts at or after the prefix padded out with
+                // zeroes, and they all sort together.
+                let mut start = vec![0; Sha3Digest::SIGNATURE.size];
+                let len = prefix.len().min(start.len());
+                start[..len].copy_from_slice(&prefix[..len]);
+
+                let digests = index
+                    .lock()
+                    .handles
+                    .range(Sha3Digest::from_bytes(&start)..)
+                    .map(|(digest, _)| digest.as_bytes())
+                    .take_while(|bytes| bytes.starts_with(&prefix))
+                    .map(<[u8]>::to_vec)
+                    .collect::<Vec<_>>();
+                stream::iter_ok(digests)
+            })
+            .flatten_stream();
+        Some(Box::new(listed))
+    }
+
+    /// Wrap a backend which isn't sealed, passing its objects through unchanged. Fails if the
+    /// backend turns out to be sealed after all.
+    pub fn unsealed(inner: B) -> Result<Self, Error> {
+        let backend = inner.layer(SealLayer::none());
+        ensure!(
+            backend.params().wait()?.is_none(),
+            "This store is encrypted at rest; it can't be opened without its passphrase"
+        );
+
+        Ok(backend)
+    }
+}
+
+/// Opening a sealed backend directly opens it unsealed, and fails if the store turns out to be
+/// sealed after all. Sealed stores are opened by wrapping a backend in a `SealLayer` instead.
+impl<B: Backend<Digest = Sha3Digest> + Open> Open for SealedBackend<B> {
+    const SCHEMES: &'static [&'static str] = B::SCHEMES;
+
+    fn open(s: &str) -> Result<Self, Error> {
+        Self::unsealed(B::open(s)?)
+    }
+
+    fn open_path(path: &Path) -> Result<Self, Error> {
+        Self::unsealed(B::open_path(path)?)
+    }
+}
+
+impl<B: Backend<Digest = Sha3Digest>> Backend for SealedBackend<B> {
+    fn uuid(&self) -> [u8; 16] {
+        self.inner.uuid()
+    }
+
+    type Builder = SealedBuilder;
+    type FutureFinish = Box<Future<Item = RawHandle, Error = Error>>;
+    fn builder(&self) -> Self::Builder {
+        SealedBuilder::default()
+    }
+    fn finish(&self, builder: Self::Builder) -> Self::FutureFinish {
+        let SealedBuilder { data, refs } = builder;
+        let sealer = match self.sealer {
+            Some(ref sealer) => sealer.clone(),
+            None => return Box::new(write_inner(&*self.inner, &data, refs).into_future().flatten()),
+        };
+
+        let ref_digests = refs.iter()
+            .map(|&id| load_digest(&*self.inner, sealer.clone(), self.index.clone(), id))
+            .collect::<Vec<_>>();
+        let inner = self.inner.clone();
+        let index = self.index.clone();
+        let finished = future::join_all(ref_digests)
+            .and_then(move |ref_digests| {
+                let mut writer = Sha3Digest::writer();
+                canonical::encode(&mut writer, &data, &ref_digests)?;
+                let digest = writer.finish();
+
+                let mut payload = Vec::with_capacity(digest.as_bytes().len() + data.len());
+                payload.extend_from_slice(digest.as_bytes());
+                payload.extend_from_slice(&data);
+                let sealed = sealer.seal(&payload)?;
+
+                Ok(write_inner(&*inner, &sealed, refs)?.and_then(move |id| {
+                    record_digest(inner, sealer, index, id, digest).map(move |()| id)
+                }))
+            })
+            .flatten();
+
+        Box::new(finished)
+    }
+
+    type Content = SealedContent;
+    type FutureContent = Box<Future<Item = Self::Content, Error = Error>>;
+    fn load(&self, id: RawHandle) -> Self::FutureContent {
+        let loaded: Box<Future<Item = (Vec<u8>, Vec<RawHandle>), Error = Error>> =
+            match self.sealer {
+                Some(ref sealer) => Box::new(load_sealed(
+                    self.inner.clone(),
+                    sealer.clone(),
+                    self.index.clone(),
+                    id,
+                )),
+                None => Box::new(self.inner.load(id).and_then(read_content)),
+            };
+
+        Box::new(loaded.map(|(data, refs)| SealedContent {
+            data: Cursor::new(data),
+            refs: refs.into_iter(),
+        }))
+    }
+
+    // The wrapped backend's IDs would name sealed payloads, so objects are identified by their
+    // plaintext digests instead.
+    type Id = Sha3Digest;
+    type FutureId = Box<Future<Item = Sha3Digest, Error = Error>>;
+    fn id(&self, id: RawHandle) -> Self::FutureId {
+        self.digest(Sha3Digest::SIGNATURE, id)
+    }
+
+    type Digest = Sha3Digest;
+    type FutureDigest = Box<Future<Item = Self::Digest, Error = Error>>;
+    fn digest(&self, signature: DigestSignature, id: RawHandle) -> Self::FutureDigest {
+        match self.sealer {
+            Some(_) if signature != Sha3Digest::SIGNATURE => Box::new(future::err(format_err!(
+                "unsupported digest {:?}",
+                signature
+            ))),
+            Some(ref sealer) => load_digest(&*self.inner, sealer.clone(), self.index.clone(), id),
+            None => Box::new(self.inner.digest(signature, id)),
+        }
+    }
+
+    type FutureResolveId = Box<Future<Item = Option<RawHandle>, Error = Error>>;
+    fn resolve_id(&self, digest: &Sha3Digest) -> Self::FutureResolveId {
+        self.resolve_digest(Sha3Digest::SIGNATURE, digest.as_bytes())
+    }
+
+    type FutureResolveDigest = Box<Future<Item = Option<RawHandle>, Error = Error>>;
+    fn resolve_digest(
+        &self,
+        signature: DigestSignature,
+        bytes: &[u8],
+    ) -> Self::FutureResolveDigest {
+        let sealer = match self.sealer {
+            Some(ref sealer) => sealer.clone(),
+            None => return Box::new(self.inner.resolve_digest(signature, bytes)),
+        };
+        if signature != Sha3Digest::SIGNATURE || bytes.len() != signature.size {
+            return Box::new(future::err(format_err!("unsupported digest {:?}", signature)));
+        }
+
+        let digest = Sha3Digest::from_bytes(bytes);
+        if let Some(&id) = self.index.lock().handles.get(&digest) {
+            return Box::new(future::ok(Some(id)));
+        }
+
+        let index = self.index.clone();
+        let indexed = complete_index(self.inner.clone(), sealer, self.index.clone());
+        Box::new(indexed.map(move |_| index.lock().handles.get(&digest).cloned()))
+    }
+
+    type FutureLoadBranches = Box<Future<Item = HashMap<String, RawHandle>, Error = Error>>;
+    fn load_branches(&self) -> Self::FutureLoadBranches {
+        Box::new(self.inner.load_branches().map(|mut branches| {
+            branches.remove(PARAMS_BRANCH);
+            branches.remove(INDEX_BRANCH);
+            branches
+        }))
+    }
+
+    type FutureSwapBranches = Box<Future<Item = (), Error = Error>>;
+    fn swap_branches(
+        &self,
+        mut previous: HashMap<String, RawHandle>,
+        mut new: HashMap<String, RawHandle>,
+    ) -> Self::FutureSwapBranches {
+        // The parameters and digest index are hidden from `load_branches`, so they have to be put
+        // back before the swap or they would be lost.
+        let inner = self.inner.clone();
+        let swapped = self.inner.load_branches().and_then(move |branches| {
+            for reserved in &[PARAMS_BRANCH, INDEX_BRANCH] {
+                if let Some(&id) = branches.get(*reserved) {
+                    previous.insert(reserved.to_string(), id);
+                    new.insert(reserved.to_string(), id);
+                }
+            }
+            inner.swap_branches(previous, new)
+        });
+
+        Box::new(swapped)
+    }
+
+    type FutureStats = B::FutureStats;
+    fn stats(&self) -> Self::FutureStats {
+        self.inner.stats()
+    }
+
+    /// The wrapped backend could only pack sealed payloads, under digests of their ciphertext, so
+    /// objects are copied out of a sealed backend one at a time instead.
+    fn pack_encodings(&self) -> &'static [TransferEncoding] {
+        match self.sealer {
+            Some(_) => &[],
+            None => self.inner.pack_encodings(),
+        }
+    }
+
+    type FuturePack = Box<Future<Item = Pack, Error = Error>>;
+    fn pack(
+        &self,
+        signature: DigestSignature,
+        encoding: TransferEncoding,
+        root: RawHandle,
+        have: Vec<Vec<u8>>,
+    ) -> Self::FuturePack {
+        match self.sealer {
+            Some(_) => Box::new(future::err(format_err!("Sealed stores do not produce packs"))),
+            None => Box::new(self.inner.pack(signature, encoding, root, have)),
+        }
+    }
+
+    type FutureContainsDigests = Box<Future<Item = Option<Vec<bool>>, Error = Error>>;
+    fn contains_digests(
+        &self,
+        signature: DigestSignature,
+        digests: Vec<Vec<u8>>,
+    ) -> Self::FutureContainsDigests {
+        let sealer = match self.sealer {
+            Some(ref sealer) => sealer.clone(),
+            None => return Box::new(self.inner.contains_digests(signature, digests)),
+        };
+        if signature != Sha3Digest::SIGNATURE {
+            return Box::new(future::err(format_err!("unsupported digest {:?}", signature)));
+        }
+
+        let index = self.index.clone();
+        let indexed = complete_index(self.inner.clone(), sealer, self.index.clone());
+        Box::new(indexed.map(move |complete| {
+            if !complete {
+                return None;
+            }
+
+            let index = index.lock();
+            let contained = digests
+                .iter()
+                .map(|bytes| {
+                    bytes.len() == signature.size
+                        && index.handles.contains_key(&Sha3Digest::from_bytes(bytes))
+                })
+                .collect();
+            Some(contained)
+        }))
+    }
+
+    type StreamDigests = Box<Stream<Item = Vec<u8>, Error = Error>>;
+    fn list_digests(&self, signature: DigestSignature) -> Option<Self::StreamDigests> {
+        match self.sealer {
+            Some(ref sealer) => self.list_indexed(sealer.clone(), signature, Vec::new()),
+            None => self.inner
+                .list_digests(signature)
+                .map(|listed| Box::new(listed) as Self::StreamDigests),
+        }
+    }
+
+    fn list_digests_with_prefix(
+        &self,
+        signature: DigestSignature,
+        prefix: &[u8],
+    ) -> Option<Self::StreamDigests> {
+        match self.sealer {
+            Some(ref sealer) => self.list_indexed(sealer.clone(), signature, prefix.to_owned()),
+            None => self.inner
+                .list_digests_with_prefix(signature, prefix)
+                .map(|listed| Box::new(listed) as Self::StreamDigests),
+        }
+    }
+
+    type FutureCompact = B::FutureCompact;
+    fn compact(&self) -> Self::FutureCompact {
+        self.inner.compact()
+    }
+
+    type FutureSize = Box<Future<Item = Option<u64>, Error = Error>>;
+    fn size(&self, id: RawHandle) -> Self::FutureSize {
+        let sealed_overhead = match self.sealer {
+            Some(_) => (OVERHEAD + Sha3Digest::SIGNATURE.size) as u64,
+            None => 0,
+        };
+
+        Box::new(
+            self.inner
+                .size(id)
+                .map(move |size| size.map(|size| size.saturating_sub(sealed_overhead))),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::MemoryBackend;
+
+    #[test]
+    fn seal_open_roundtrip() {
+        let salt = generate_salt().unwrap();
+        let sealer = Sealer::derive(b"correct horse", &salt).unwrap();
+        let plaintext = b"some object payload".to_vec();
+
+        let sealed = sealer.seal(&plaintext).unwrap();
+        assert!(sealed != plaintext);
+        assert_eq!(sealer.open(&sealed).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let salt = generate_salt().unwrap();
+        let sealer = Sealer::derive(b"correct horse", &salt).unwrap();
+        let check = sealer.check_value().unwrap();
+        assert!(sealer.verify(&check).is_ok());
+
+        let wrong = Sealer::derive(b"battery staple", &salt).unwrap();
+        assert!(wrong.verify(&check).is_err());
+        assert!(wrong.open(&sealer.seal(b"payload").unwrap()).is_err());
+    }
+
+    fn write<B: Backend>(backend: &B, data: &[u8]) -> RawHandle {
+        let mut builder = backend.builder();
+        builder.write_all(data).unwrap();
+        backend.finish(builder).wait().unwrap()
+    }
+
+    #[test]
+    fn sealed_objects_keep_plaintext_digests() {
+        let salt = generate_salt().unwrap();
+        let sealer = Sealer::derive(b"correct horse", &salt).unwrap();
+        let plain = MemoryBackend::new();
+        let sealed = MemoryBackend::new().layer(SealLayer::new(sealer));
+
+        let plain_id = write(&plain, b"some object payload");
+        let sealed_id = write(&sealed, b"some object payload");
+        assert_eq!(
+            sealed.digest(Sha3Digest::SIGNATURE, sealed_id).wait().unwrap(),
+            plain.digest(Sha3Digest::SIGNATURE, plain_id).wait().unwrap()
+        );
+
+        let (stored, _) = read_content(sealed.get_ref().load(sealed_id).wait().unwrap()).unwrap();
+        assert!(!stored.windows(7).any(|window| window == b"payload"));
+        let (loaded, _) = read_content(sealed.load(sealed_id).wait().unwrap()).unwrap();
+        assert_eq!(loaded, b"some object payload".to_vec());
+
+        // Identical objects seal identically, so the wrapped backend still deduplicates them.
+        assert_eq!(write(&sealed, b"some object payload"), sealed_id);
+    }
+
+    fn write_with_refs<B: Backend>(backend: &B, data: &[u8], refs: Vec<RawHandle>) -> RawHandle {
+        let mut builder = backend.builder();
+        builder.write_all(data).unwrap();
+        builder.extend(refs);
+        backend.finish(builder).wait().unwrap()
+    }
+
+    #[test]
+    fn digest_index_is_kept_in_the_store() {
+        let salt = generate_salt().unwrap();
+        let sealed = MemoryBackend::new().layer(SealLayer::new(
+            Sealer::derive(b"correct horse", &salt).unwrap(),
+        ));
+        let child = write(&sealed, b"child");
+        let parent = write_with_refs(&sealed, b"parent", vec![child]);
+        let digest = sealed.digest(Sha3Digest::SIGNATURE, parent).wait().unwrap();
+        assert!(sealed.get_ref().load_branches().wait().unwrap().contains_key(INDEX_BRANCH));
+        assert!(sealed.load_branches().wait().unwrap().is_empty());
+
+        // A fresh layer over the same store finds the digest in the index.
+        let reopened = SealedBackend {
+            inner: sealed.inner.clone(),
+            sealer: Some(Arc::new(Sealer::derive(b"correct horse", &salt).unwrap())),
+            index: Arc::new(Mutex::new(DigestIndex::default())),
+        };
+        assert_eq!(
+            reopened.resolve_digest(Sha3Digest::SIGNATURE, digest.as_bytes()).wait().unwrap(),
+            Some(parent)
+        );
+        assert_eq!(reopened.index.lock().digests.len(), 2);
+    }
+
+    #[test]
+    fn digests_are_listed_by_prefix() {
+        let salt = generate_salt().unwrap();
+        let sealed = MemoryBackend::new().layer(SealLayer::new(
+            Sealer::derive(b"correct horse", &salt).unwrap(),
+        ));
+        let ids = [write(&sealed, b"one"), write(&sealed, b"two"), write(&sealed, b"three")];
+
+        for &id in &ids {
+            let digest = sealed.digest(Sha3Digest::SIGNATURE, id).wait().unwrap();
+            let prefix = &digest.as_bytes()[..2];
+            let listed = sealed
+                .list_digests_with_prefix(Sha3Digest::SIGNATURE, prefix)
+                .unwrap()
+                .collect()
+                .wait()
+                .unwrap();
+            assert!(listed.contains(&digest.as_bytes().to_vec()));
+            assert!(listed.iter().all(|listed| listed.starts_with(prefix)));
+        }
+    }
+
+    #[test]
+    fn tampered_references_are_rejected() {
+        let salt = generate_salt().unwrap();
+        let sealed = MemoryBackend::new().layer(SealLayer::new(
+            Sealer::derive(b"correct horse", &salt).unwrap(),
+        ));
+        let child = write(&sealed, b"child");
+        let other = write(&sealed, b"other");
+        let parent = write_with_refs(&sealed, b"parent", vec![child]);
+        assert!(sealed.load(parent).wait().is_ok());
+
+        // The same sealed payload, pointed at a different child.
+        let (payload, _) = read_content(sealed.get_ref().load(parent).wait().unwrap()).unwrap();
+        let tampered = write_with_refs(sealed.get_ref(), &payload, vec![other]);
+        assert!(sealed.load(tampered).wait().is_err());
+    }
+
+    #[test]
+    fn params_are_kept_out_of_sight() {
+        let salt = generate_salt().unwrap();
+        let sealer = Sealer::derive(b"correct horse", &salt).unwrap();
+        let params = SealParams {
+            salt,
+            check: sealer.check_value().unwrap(),
+        };
+        let sealed = MemoryBackend::new().layer(SealLayer::new(sealer));
+        sealed.set_params(&params).wait().unwrap();
+
+        assert_eq!(sealed.params().wait().unwrap(), Some(params));
+        assert!(sealed.load_branches().wait().unwrap().is_empty());
+        assert!(sealed.get_ref().load_branches().wait().unwrap().contains_key(PARAMS_BRANCH));
+
+        let id = write(&sealed, b"some object payload");
+        let mut branches = HashMap::new();
+        branches.insert("refs/heads/master".to_owned(), id);
+        sealed.swap_branches(HashMap::new(), branches.clone()).wait().unwrap();
+        assert_eq!(sealed.load_branches().wait().unwrap(), branches);
+        assert!(sealed.params().wait().unwrap().is_some());
+    }
+}
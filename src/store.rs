@@ -1,13 +1,15 @@
-use std::{fmt, iter, any::Any, borrow::Borrow, cmp::Ordering, collections::HashMap,
+use std::{fmt, iter, any::Any, borrow::Borrow, cmp::Ordering, collections::{HashMap, HashSet},
           hash::{Hash, Hasher}, io::{self, Read, Write}, sync::Arc};
 
 use failure::Error;
-use futures::{stream, prelude::*, sync::mpsc};
+use futures::{future, stream, prelude::*, sync::mpsc};
 use hex::ToHex;
 use uuid::Uuid;
 
 use canonical;
 use digest::prelude::*;
+use limit::RateLimiter;
+use pack::{self, Pack, TransferEncoding};
 
 pub type LocalId<B> = <B as Backend>::Id;
 pub type OwnedLocalId<B> = <LocalId<B> as ToOwned>::Owned;
@@ -21,20 +23,53 @@ pub type FutureResolveId<B> = BoxedFuture<Option<Handle<B>>, Error>;
 pub type FutureResolveDigest<B> = BoxedFuture<Option<Handle<B>>, Error>;
 pub type FutureLoadBranches<B> = BoxedFuture<HashMap<String, Handle<B>>, Error>;
 pub type FutureSwapBranches = BoxedFuture<(), Error>;
+pub type FutureStats = BoxedFuture<Option<StoreStats>, Error>;
+pub type FutureContainsDigest = BoxedFuture<bool, Error>;
+pub type FutureContainsDigests = BoxedFuture<Vec<bool>, Error>;
+pub type FutureCompact = BoxedFuture<Option<CompactStats>, Error>;
+pub type FutureSize = BoxedFuture<u64, Error>;
+pub type StreamDigests<D> = Box<Stream<Item = D, Error = Error>>;
 pub type FutureFinish<B> = BoxedFuture<Handle<B>, Error>;
 
 const FSCK_CHANNEL_SIZE: usize = 16;
 
 /// Convenience module reexporting all important traits.
 pub mod prelude {
-    pub use super::{Backend, Builder, Content, FutureContent, FutureDigest, FutureFinish,
-                    FutureId, FutureLoadBranches, FutureResolveDigest, FutureResolveId,
-                    FutureSwapBranches, Handle, LocalId, OwnedLocalId, Store};
+    pub use super::{Backend, Builder, CompactStats, Content, FutureCompact,
+                    FutureContainsDigest, FutureContainsDigests, FutureContent,
+                    FutureDigest, FutureFinish, FutureId, FutureLoadBranches,
+                    FutureResolveDigest, FutureResolveId, FutureSize, FutureStats,
+                    FutureSwapBranches, Handle, LocalId, OwnedLocalId, Store, StoreStats, StreamDigests};
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct RawHandle(pub u64);
 
+/// Storage statistics for a backend which is able to enumerate the objects it holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// The number of objects stored individually.
+    pub loose_objects: u64,
+
+    /// The number of bytes taken up by individually stored objects, as closely as the backend
+    /// can tell.
+    pub loose_bytes: u64,
+}
+
+/// On-disk size of a backend's storage before and after compaction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactStats {
+    pub before_bytes: u64,
+    pub after_bytes: u64,
+}
+
+impl CompactStats {
+    /// The number of bytes returned to the filesystem.
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.before_bytes.saturating_sub(self.after_bytes)
+    }
+}
+
 #[derive(Debug, Default)]
 struct Inner<B: Backend> {
     uuid: Uuid,
@@ -62,6 +97,11 @@ impl<B: Backend> Store<B> {
         }
     }
 
+    /// The UUID of the backend this store wraps.
+    pub fn uuid(&self) -> [u8; 16] {
+        *self.inner.uuid.as_bytes()
+    }
+
     pub fn builder(&self) -> Builder<B> {
         Builder {
             store: self.clone(),
@@ -110,6 +150,81 @@ impl<B: Backend> Store<B> {
         Box::new(blocking)
     }
 
+    pub fn stats(&self) -> FutureStats {
+        Box::new(self.inner.backend.stats())
+    }
+
+    /// Compact the backend's storage, returning its on-disk size before and after, or `None` if
+    /// the backend has nothing to compact.
+    pub fn compact(&self) -> FutureCompact {
+        Box::new(self.inner.backend.compact())
+    }
+
+    /// Check which of a batch of digests this store holds objects for. Uses the backend's batch
+    /// query if it has one, and otherwise resolves each digest in turn.
+    pub fn contains_digests<D: Digest>(&self, digests: Vec<D>) -> FutureContainsDigests {
+        let store = self.clone();
+        let raw_digests = digests.iter().map(|d| d.as_bytes().to_vec()).collect();
+        let blocking = async_block! {
+            let maybe_contained = await!(
+                store.inner.backend.contains_digests(D::SIGNATURE, raw_digests)
+            )?;
+
+            match maybe_contained {
+                Some(contained) => {
+                    ensure!(
+                        contained.len() == digests.len(),
+                        "Backend answered a membership query with the wrong number of results"
+                    );
+                    Ok(contained)
+                }
+                None => {
+                    let mut contained = Vec::with_capacity(digests.len());
+                    for digest in digests {
+                        contained.push(await!(store.resolve_digest(digest))?.is_some());
+                    }
+                    Ok(contained)
+                }
+            }
+        };
+        Box::new(blocking)
+    }
+
+    /// Check whether this store holds an object with the given digest, without loading it. This is a
+    /// batch of one for `contains_digests`, so backends answer it with a cheap existence probe
+    /// where they can.
+    pub fn contains_digest<D: Digest>(&self, digest: D) -> FutureContainsDigest {
+        Box::new(self.contains_digests(vec![digest]).map(|contained| contained[0]))
+    }
+
+    /// Enumerate the digests of every object in this store, whether or not it is reachable. Returns
+    /// `None` if the backend cannot enumerate its contents.
+    pub fn list_digests<D: Digest>(&self) -> Option<StreamDigests<D>> {
+        self.inner
+            .backend
+            .list_digests(D::SIGNATURE)
+            .map(Self::parse_digests)
+    }
+
+    /// Enumerate the digests of every object in this store which begin with `prefix`. Returns
+    /// `None` if the backend cannot enumerate its contents.
+    pub fn list_digests_with_prefix<D: Digest>(&self, prefix: &[u8]) -> Option<StreamDigests<D>> {
+        self.inner
+            .backend
+            .list_digests_with_prefix(D::SIGNATURE, prefix)
+            .map(Self::parse_digests)
+    }
+
+    fn parse_digests<D: Digest>(raw_digests: B::StreamDigests) -> StreamDigests<D> {
+        Box::new(raw_digests.and_then(|bytes| {
+            ensure!(
+                bytes.len() == D::SIGNATURE.size,
+                "Backend listed a digest of the wrong length"
+            );
+            Ok(D::from_bytes(&bytes))
+        }))
+    }
+
     pub fn swap_branches(
         &self,
         old: HashMap<String, Handle<B>>,
@@ -229,6 +344,22 @@ impl<B: Backend> Handle<B> {
         };
         Box::new(blocking)
     }
+
+    /// The length of this object's data, not counting its references. Uses the backend's
+    /// metadata if it has any, and otherwise loads the object and measures it.
+    pub fn size(&self) -> FutureSize {
+        let store = self.store.clone();
+        let id = self.id;
+        let blocking = async_block! {
+            if let Some(size) = await!(store.inner.backend.size(id))? {
+                return Ok(size);
+            }
+
+            let mut content = await!(store.inner.backend.load(id))?;
+            Ok(io::copy(&mut content, &mut io::sink())?)
+        };
+        Box::new(blocking)
+    }
 }
 
 pub struct Builder<B: Backend> {
@@ -333,6 +464,62 @@ pub trait Backend: Send + Sync + 'static {
         previous: HashMap<String, RawHandle>,
         new: HashMap<String, RawHandle>,
     ) -> Self::FutureSwapBranches;
+
+    /// Enumerate the backend's objects and report their count and size. Backends which cannot
+    /// enumerate their contents should return `None`.
+    type FutureStats: Future<Item = Option<StoreStats>, Error = Error>;
+    fn stats(&self) -> Self::FutureStats;
+
+    /// Transfer encodings in which this backend can produce packs, most preferred first. Backends
+    /// which cannot produce packs should return an empty list, in which case objects are copied
+    /// out of them one at a time.
+    fn pack_encodings(&self) -> &'static [TransferEncoding];
+
+    /// Pack up every object reachable from `root`, using `signature` for the digests of entries.
+    /// Objects whose digests are in `have` are held by the receiving store already; they are left
+    /// out as bases of the pack (see `pack`), and nothing is packed on their account.
+    type FuturePack: Future<Item = Pack, Error = Error>;
+    fn pack(
+        &self,
+        signature: DigestSignature,
+        encoding: TransferEncoding,
+        root: RawHandle,
+        have: Vec<Vec<u8>>,
+    ) -> Self::FuturePack;
+
+    /// Check which of a batch of digests this backend holds objects for, if it can do so more
+    /// cheaply than resolving each of them. Backends which can't should return `None`, in which
+    /// case the digests are resolved one at a time.
+    type FutureContainsDigests: Future<Item = Option<Vec<bool>>, Error = Error>;
+    fn contains_digests(
+        &self,
+        signature: DigestSignature,
+        digests: Vec<Vec<u8>>,
+    ) -> Self::FutureContainsDigests;
+
+    /// Enumerate the digests of every object this backend holds, using `signature`. Backends which
+    /// cannot enumerate their contents should return `None`.
+    type StreamDigests: Stream<Item = Vec<u8>, Error = Error>;
+    fn list_digests(&self, signature: DigestSignature) -> Option<Self::StreamDigests>;
+
+    /// Enumerate the digests using `signature` which begin with `prefix`, as `list_digests` does.
+    /// Backends which keep their digests in order should look up the range directly rather than
+    /// going through every digest.
+    fn list_digests_with_prefix(
+        &self,
+        signature: DigestSignature,
+        prefix: &[u8],
+    ) -> Option<Self::StreamDigests>;
+
+    /// Compact storage so that space freed by removed or overwritten entries is returned to the
+    /// filesystem. Backends with nothing to compact should return `None`.
+    type FutureCompact: Future<Item = Option<CompactStats>, Error = Error>;
+    fn compact(&self) -> Self::FutureCompact;
+
+    /// The length of an object's data, if the backend can find it without loading the object.
+    /// Backends which can't should return `None`, in which case the object is loaded and measured.
+    type FutureSize: Future<Item = Option<u64>, Error = Error>;
+    fn size(&self, id: RawHandle) -> Self::FutureSize;
 }
 
 trait AnyBuilder: 'static {
@@ -499,6 +686,62 @@ impl<B: Backend> Backend for BoxWrapped<B> {
     ) -> Self::FutureSwapBranches {
         Box::new(self.backend.swap_branches(old, new))
     }
+
+    type FutureStats = Box<Future<Item = Option<StoreStats>, Error = Error>>;
+    fn stats(&self) -> Self::FutureStats {
+        Box::new(self.backend.stats())
+    }
+
+    fn pack_encodings(&self) -> &'static [TransferEncoding] {
+        self.backend.pack_encodings()
+    }
+
+    type FuturePack = Box<Future<Item = Pack, Error = Error>>;
+    fn pack(
+        &self,
+        signature: DigestSignature,
+        encoding: TransferEncoding,
+        root: RawHandle,
+        have: Vec<Vec<u8>>,
+    ) -> Self::FuturePack {
+        Box::new(self.backend.pack(signature, encoding, root, have))
+    }
+
+    type FutureContainsDigests = Box<Future<Item = Option<Vec<bool>>, Error = Error>>;
+    fn contains_digests(
+        &self,
+        signature: DigestSignature,
+        digests: Vec<Vec<u8>>,
+    ) -> Self::FutureContainsDigests {
+        Box::new(self.backend.contains_digests(signature, digests))
+    }
+
+    type StreamDigests = Box<Stream<Item = Vec<u8>, Error = Error>>;
+    fn list_digests(&self, signature: DigestSignature) -> Option<Self::StreamDigests> {
+        self.backend
+            .list_digests(signature)
+            .map(|digests| -> Self::StreamDigests { Box::new(digests) })
+    }
+
+    fn list_digests_with_prefix(
+        &self,
+        signature: DigestSignature,
+        prefix: &[u8],
+    ) -> Option<Self::StreamDigests> {
+        self.backend
+            .list_digests_with_prefix(signature, prefix)
+            .map(|digests| -> Self::StreamDigests { Box::new(digests) })
+    }
+
+    type FutureCompact = Box<Future<Item = Option<CompactStats>, Error = Error>>;
+    fn compact(&self) -> Self::FutureCompact {
+        Box::new(self.backend.compact())
+    }
+
+    type FutureSize = Box<Future<Item = Option<u64>, Error = Error>>;
+    fn size(&self, id: RawHandle) -> Self::FutureSize {
+        Box::new(self.backend.size(id))
+    }
 }
 
 impl<B: Backend> BoxWrapped<B> {
@@ -522,6 +765,12 @@ pub struct ErasedBackend {
             FutureSwapBranches = Box<Future<Item = (), Error = Error>>,
             FutureResolveId = Box<Future<Item = Option<RawHandle>, Error = Error>>,
             FutureResolveDigest = Box<Future<Item = Option<RawHandle>, Error = Error>>,
+            FutureStats = Box<Future<Item = Option<StoreStats>, Error = Error>>,
+            FuturePack = Box<Future<Item = Pack, Error = Error>>,
+            FutureContainsDigests = Box<Future<Item = Option<Vec<bool>>, Error = Error>>,
+            StreamDigests = Box<Stream<Item = Vec<u8>, Error = Error>>,
+            FutureCompact = Box<Future<Item = Option<CompactStats>, Error = Error>>,
+            FutureSize = Box<Future<Item = Option<u64>, Error = Error>>,
         >,
     >,
 }
@@ -585,6 +834,58 @@ impl Backend for ErasedBackend {
     ) -> Self::FutureSwapBranches {
         self.boxed.swap_branches(old, new)
     }
+
+    type FutureStats = Box<Future<Item = Option<StoreStats>, Error = Error>>;
+    fn stats(&self) -> Self::FutureStats {
+        self.boxed.stats()
+    }
+
+    fn pack_encodings(&self) -> &'static [TransferEncoding] {
+        self.boxed.pack_encodings()
+    }
+
+    type FuturePack = Box<Future<Item = Pack, Error = Error>>;
+    fn pack(
+        &self,
+        signature: DigestSignature,
+        encoding: TransferEncoding,
+        root: RawHandle,
+        have: Vec<Vec<u8>>,
+    ) -> Self::FuturePack {
+        self.boxed.pack(signature, encoding, root, have)
+    }
+
+    type FutureContainsDigests = Box<Future<Item = Option<Vec<bool>>, Error = Error>>;
+    fn contains_digests(
+        &self,
+        signature: DigestSignature,
+        digests: Vec<Vec<u8>>,
+    ) -> Self::FutureContainsDigests {
+        self.boxed.contains_digests(signature, digests)
+    }
+
+    type StreamDigests = Box<Stream<Item = Vec<u8>, Error = Error>>;
+    fn list_digests(&self, signature: DigestSignature) -> Option<Self::StreamDigests> {
+        self.boxed.list_digests(signature)
+    }
+
+    fn list_digests_with_prefix(
+        &self,
+        signature: DigestSignature,
+        prefix: &[u8],
+    ) -> Option<Self::StreamDigests> {
+        self.boxed.list_digests_with_prefix(signature, prefix)
+    }
+
+    type FutureCompact = Box<Future<Item = Option<CompactStats>, Error = Error>>;
+    fn compact(&self) -> Self::FutureCompact {
+        self.boxed.compact()
+    }
+
+    type FutureSize = Box<Future<Item = Option<u64>, Error = Error>>;
+    fn size(&self, id: RawHandle) -> Self::FutureSize {
+        self.boxed.size(id)
+    }
 }
 
 impl ErasedBackend {
@@ -595,8 +896,21 @@ impl ErasedBackend {
     }
 }
 
+pub fn copy<B, C>(root: Handle<B>, target: Store<C>) -> Box<Future<Item = Handle<C>, Error = Error>>
+where
+    B: Backend,
+    C: Backend,
+{
+    copy_with_limit(root, target, RateLimiter::unlimited())
+}
+
+/// As `copy`, but throttled by `limiter`, which is charged for the data of each object copied.
 #[async(boxed)]
-pub fn copy<B, C>(root: Handle<B>, target: Store<C>) -> Result<Handle<C>, Error>
+pub fn copy_with_limit<B, C>(
+    root: Handle<B>,
+    target: Store<C>,
+    limiter: RateLimiter,
+) -> Result<Handle<C>, Error>
 where
     B: Backend,
     C: Backend,
@@ -604,12 +918,13 @@ where
     let mut content = await!(root.load())?;
     let mut builder = target.builder();
 
-    io::copy(&mut content, &mut builder)?;
+    let copied = io::copy(&mut content, &mut builder)?;
+    limiter.consume(copied);
 
     // TODO: buffer?
     let refs = {
         let future_refs = stream::iter_ok(content)
-            .and_then(move |r| copy(r, target.clone()))
+            .and_then(move |r| copy_with_limit(r, target.clone(), limiter.clone()))
             .collect();
         await!(future_refs)?
     };
@@ -618,6 +933,169 @@ where
     Ok(await!(builder.finish())?)
 }
 
+/// As `copy_with_limit`, but only copying objects which `target` doesn't already have, as found by
+/// their digests. An object which `target` has is assumed to come with everything reachable from
+/// it, so its children are not visited. Returns the copied root along with the number of objects
+/// which were actually copied.
+#[async(boxed)]
+pub fn copy_missing_with_limit<D, B, C>(
+    root: Handle<B>,
+    target: Store<C>,
+    limiter: RateLimiter,
+) -> Result<(Handle<C>, usize), Error>
+where
+    D: Digest,
+    B: Backend,
+    C: Backend,
+{
+    let digest = await!(root.digest::<D>())?;
+    if let Some(handle) = await!(target.resolve_digest(digest))? {
+        return Ok((handle, 0));
+    }
+
+    let mut content = await!(root.load())?;
+    let mut builder = target.builder();
+
+    let copied = io::copy(&mut content, &mut builder)?;
+    limiter.consume(copied);
+
+    let mut refs = Vec::new();
+    let mut count = 1;
+    for child in content {
+        let (handle, child_count) = await!(copy_missing_with_limit::<D, B, C>(
+            child,
+            target.clone(),
+            limiter.clone(),
+        ))?;
+        refs.push(handle);
+        count += child_count;
+    }
+    builder.extend(refs);
+
+    Ok((await!(builder.finish())?, count))
+}
+
+/// Find the objects reachable from `root` which `target` doesn't have. An object which the target
+/// has is assumed to come with everything reachable from it, so the walk doesn't descend into it.
+/// Returns the digests of the missing objects, and of the objects which the target has and which
+/// missing objects refer to.
+#[async(boxed)]
+pub fn find_missing<D, B, C>(root: Handle<B>, target: Store<C>) -> Result<(Vec<D>, Vec<D>), Error>
+where
+    D: Digest,
+    B: Backend,
+    C: Backend,
+{
+    let mut missing = Vec::new();
+    let mut have = Vec::new();
+    let mut visited = HashSet::new();
+    visited.insert(root.clone());
+    let mut frontier = vec![root];
+
+    while !frontier.is_empty() {
+        let digests = await!(future::join_all(
+            frontier
+                .iter()
+                .map(|handle| handle.digest::<D>())
+                .collect::<Vec<_>>()
+        ))?;
+        let contained = await!(target.contains_digests(digests.clone()))?;
+
+        let mut next = Vec::new();
+        for ((handle, digest), is_contained) in frontier.into_iter().zip(digests).zip(contained) {
+            if is_contained {
+                have.push(digest);
+                continue;
+            }
+
+            missing.push(digest);
+            let content = await!(handle.load())?;
+            next.extend(content.filter(|child| visited.insert(child.clone())));
+        }
+        frontier = next;
+    }
+
+    Ok((missing, have))
+}
+
+/// Copy everything reachable from `root` into `target` which `target` doesn't already have. If
+/// the source backend can produce a pack in an encoding we understand, the missing objects are
+/// transferred all at once and their digests are verified as they are unpacked; otherwise this
+/// falls back to copying one object at a time, as `copy_missing_with_limit` does.
+pub fn transfer<D, B, C>(
+    root: Handle<B>,
+    target: Store<C>,
+) -> Box<Future<Item = Handle<C>, Error = Error>>
+where
+    D: Digest,
+    B: Backend,
+    C: Backend,
+{
+    transfer_with_limit::<D, B, C>(root, target, RateLimiter::unlimited())
+}
+
+/// As `transfer`, but throttled by `limiter`, which is charged for the encoded data of a pack as
+/// it is unpacked, or for the data of each object copied.
+#[async(boxed)]
+pub fn transfer_with_limit<D, B, C>(
+    root: Handle<B>,
+    target: Store<C>,
+    limiter: RateLimiter,
+) -> Result<Handle<C>, Error>
+where
+    D: Digest,
+    B: Backend,
+    C: Backend,
+{
+    let have = match TransferEncoding::negotiate(root.store.inner.backend.pack_encodings()) {
+        Some(_) => await!(find_missing::<D, B, C>(root.clone(), target.clone()))?.1,
+        None => Vec::new(),
+    };
+
+    Ok(await!(transfer_missing_with_limit::<D, B, C>(
+        root,
+        target,
+        have,
+        limiter
+    ))?)
+}
+
+/// As `transfer_with_limit`, where `have` lists the objects which the target already has and
+/// which missing objects refer to, as found by `find_missing`.
+#[async(boxed)]
+pub fn transfer_missing_with_limit<D, B, C>(
+    root: Handle<B>,
+    target: Store<C>,
+    have: Vec<D>,
+    limiter: RateLimiter,
+) -> Result<Handle<C>, Error>
+where
+    D: Digest,
+    B: Backend,
+    C: Backend,
+{
+    let root_digest = await!(root.digest::<D>())?;
+    if have.contains(&root_digest) {
+        let handle = await!(target.resolve_digest(root_digest))?
+            .ok_or_else(|| format_err!("Target store lost an object during transfer"))?;
+        return Ok(handle);
+    }
+
+    match TransferEncoding::negotiate(root.store.inner.backend.pack_encodings()) {
+        Some(encoding) => {
+            let have = have.iter().map(|digest| digest.as_bytes().to_vec()).collect();
+            let future_pack =
+                root.store.inner.backend.pack(D::SIGNATURE, encoding, root.id, have);
+            let pack = await!(future_pack)?;
+            Ok(await!(pack::unpack_with_limit::<D, C>(pack, target, limiter))?)
+        }
+        None => {
+            let (handle, _) = await!(copy_missing_with_limit::<D, B, C>(root, target, limiter))?;
+            Ok(handle)
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Fail)]
 pub struct FsckError<D: Digest> {
     pub received: D,
@@ -840,7 +1318,8 @@ pub mod dummy {
         type Id = [u8];
         type FutureId = Box<Future<Item = Vec<u8>, Error = Error>>;
         fn id(&self, id: RawHandle) -> Self::FutureId {
-            unimplemented!();
+            let bytes = (0..8).map(|i| (id.0 >> (56 - 8 * i)) as u8).collect();
+            Box::new(Ok(bytes).into_future())
         }
 
         type Digest = DummyDigest;
@@ -876,6 +1355,58 @@ pub mod dummy {
         ) -> Self::FutureSwapBranches {
             unimplemented!();
         }
+
+        type FutureStats = Box<Future<Item = Option<StoreStats>, Error = Error>>;
+        fn stats(&self) -> Self::FutureStats {
+            unimplemented!();
+        }
+
+        fn pack_encodings(&self) -> &'static [TransferEncoding] {
+            &[]
+        }
+
+        type FuturePack = Box<Future<Item = Pack, Error = Error>>;
+        fn pack(
+            &self,
+            signature: DigestSignature,
+            encoding: TransferEncoding,
+            root: RawHandle,
+            have: Vec<Vec<u8>>,
+        ) -> Self::FuturePack {
+            unimplemented!();
+        }
+
+        type FutureContainsDigests = Box<Future<Item = Option<Vec<bool>>, Error = Error>>;
+        fn contains_digests(
+            &self,
+            signature: DigestSignature,
+            digests: Vec<Vec<u8>>,
+        ) -> Self::FutureContainsDigests {
+            unimplemented!();
+        }
+
+        type StreamDigests = Box<Stream<Item = Vec<u8>, Error = Error>>;
+        fn list_digests(&self, signature: DigestSignature) -> Option<Self::StreamDigests> {
+            unimplemented!();
+        }
+
+        fn list_digests_with_prefix(
+            &self,
+            signature: DigestSignature,
+            prefix: &[u8],
+        ) -> Option<Self::StreamDigests> {
+            unimplemented!();
+        }
+
+        type FutureCompact = Box<Future<Item = Option<CompactStats>, Error = Error>>;
+        fn compact(&self) -> Self::FutureCompact {
+            unimplemented!();
+        }
+
+        type FutureSize = Box<Future<Item = Option<u64>, Error = Error>>;
+        fn size(&self, id: RawHandle) -> Self::FutureSize {
+            unimplemented!();
+        }
     }
 
     pub fn dummy_handle(store: Store<DummyBackend>) -> BoxedStrategy<Handle<DummyBackend>> {
@@ -0,0 +1,192 @@
+//! Throughput of staging, committing and walking history.
+//!
+//! Every repository here is backed by `attaca::memory::MemoryBackend`, so these measure hashing,
+//! chunking and tree building rather than the speed of any particular store. The workspace itself
+//! is still a directory on disk, since staging reads files from it.
+//!
+//! Run with `cargo bench -p subito`.
+
+#[macro_use]
+extern crate criterion;
+extern crate subito;
+extern crate tempdir;
+extern crate url;
+
+use std::{fs::{self, File}, io::Write, path::PathBuf};
+
+use criterion::{Benchmark, Criterion, Throughput};
+use subito::{CommitArgs, LogArgs, Repository, StageArgs, attaca::memory::MemoryBackend,
+             config::{StoreConfig, StoreKind}, reexports::futures::prelude::*};
+use tempdir::TempDir;
+use url::Url;
+
+/// The directory, relative to the root of the workspace, which files are generated in.
+const DATA_DIR: &str = "data";
+
+/// The shapes of workspace staged and committed: a number of files and the size of each.
+const SHAPES: &[(&str, usize, usize)] = &[
+    ("1000x1KiB", 1000, 1 << 10),
+    ("100x64KiB", 100, 64 << 10),
+    ("10x4MiB", 10, 4 << 20),
+];
+
+/// The numbers of commits walked by the log benchmark.
+const HISTORIES: &[usize] = &[10, 100, 1000];
+
+struct Fixture {
+    // Declared before `_workspace` so that the repository is dropped before its directory.
+    repository: Repository<MemoryBackend>,
+    _workspace: TempDir,
+}
+
+/// Fill `buf` with bytes from a xorshift generator. Contents are deterministic, so runs are
+/// comparable, but incompressible and free of repetition that would let chunks deduplicate.
+fn fill(seed: u64, buf: &mut [u8]) {
+    let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+    for chunk in buf.chunks_mut(8) {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+
+        let bytes = (0..8).map(|i| (state >> (i * 8)) as u8).collect::<Vec<_>>();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
+
+/// Create an empty repository over an in-memory store, with `files` files of `size` bytes each
+/// written into its workspace.
+fn fixture(files: usize, size: usize) -> Fixture {
+    let workspace = TempDir::new("subito-bench").unwrap();
+    let data = workspace.path().join(DATA_DIR);
+    fs::create_dir(&data).unwrap();
+
+    let mut buf = vec![0; size];
+    for i in 0..files {
+        fill(i as u64, &mut buf);
+        File::create(data.join(format!("f{:04}", i)))
+            .and_then(|mut file| file.write_all(&buf))
+            .unwrap();
+    }
+
+    let repository = Repository::init_with(workspace.path().to_owned(), |_| {
+        let config = StoreConfig {
+            url: Url::parse("memory:")?,
+            kind: StoreKind::Memory,
+            encryption: None,
+        };
+        Ok((config, MemoryBackend::new()))
+    }).unwrap();
+
+    Fixture {
+        repository,
+        _workspace: workspace,
+    }
+}
+
+fn stage(repository: &mut Repository<MemoryBackend>) {
+    let out = repository.stage(StageArgs {
+        paths: vec![PathBuf::from(DATA_DIR)],
+        previous: false,
+        all: false,
+        progress: false,
+        no_progress: true,
+        quiet: false,
+        intent_to_add: false,
+    });
+    out.blocking
+        .join(out.progress.for_each(|_| Ok(())))
+        .wait()
+        .unwrap();
+}
+
+fn commit(repository: &mut Repository<MemoryBackend>, message: String) {
+    let out = repository.commit(CommitArgs {
+        message: Some(message),
+        reuse_message: None,
+        reedit_message: None,
+        author: Some("bench".to_owned()),
+        date: None,
+        amend: false,
+        force: true,
+        no_verify: true,
+        only: Vec::new(),
+        cleanup: None,
+        empty_as_delete: false,
+        dry_run: false,
+    });
+    out.blocking.wait().unwrap();
+}
+
+fn bench_stage(c: &mut Criterion) {
+    for &(name, files, size) in SHAPES {
+        let benchmark = Benchmark::new(name, move |b| {
+            b.iter_with_setup(
+                || fixture(files, size),
+                |mut fixture| {
+                    stage(&mut fixture.repository);
+                    // Returned so that the workspace is removed outside the timed routine.
+                    fixture
+                },
+            )
+        });
+        c.bench(
+            "stage",
+            benchmark
+                .sample_size(10)
+                .throughput(Throughput::Bytes((files * size) as u32)),
+        );
+    }
+}
+
+fn bench_commit(c: &mut Criterion) {
+    for &(name, files, size) in SHAPES {
+        let benchmark = Benchmark::new(name, move |b| {
+            b.iter_with_setup(
+                || {
+                    let mut fixture = fixture(files, size);
+                    stage(&mut fixture.repository);
+                    fixture
+                },
+                |mut fixture| {
+                    commit(&mut fixture.repository, "Benchmark".to_owned());
+                    fixture
+                },
+            )
+        });
+        c.bench(
+            "commit",
+            benchmark
+                .sample_size(10)
+                .throughput(Throughput::Elements(files as u32)),
+        );
+    }
+}
+
+fn bench_log(c: &mut Criterion) {
+    for &commits in HISTORIES {
+        let mut fixture = fixture(10, 1 << 10);
+        stage(&mut fixture.repository);
+        for i in 0..commits {
+            commit(&mut fixture.repository, format!("Commit #{}", i));
+        }
+
+        let benchmark = Benchmark::new(format!("{}", commits), move |b| {
+            b.iter(|| {
+                fixture
+                    .repository
+                    .log(LogArgs::default())
+                    .entries
+                    .for_each(|_| Ok(()))
+                    .wait()
+                    .unwrap()
+            })
+        });
+        c.bench(
+            "log",
+            benchmark.throughput(Throughput::Elements(commits as u32)),
+        );
+    }
+}
+
+criterion_group!(benches, bench_stage, bench_commit, bench_log);
+criterion_main!(benches);
@@ -0,0 +1,121 @@
+//! Abbreviating digests for display without making them ambiguous.
+//!
+//! An abbreviation is the shortest prefix of a digest's hex form which is at least the configured
+//! length (`Config::abbrev_len`) and which no other object in the store shares. Abbreviations which
+//! are unambiguous now may become ambiguous as the store grows, so they're meant for showing to
+//! people, not for recording.
+
+use std::{cmp, fmt, rc::Rc};
+
+use attaca::{digest::{prelude::*, Sha3Digest}, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+use hex;
+
+use Repository;
+use plumbing;
+
+/// The shortest length of displayed digest abbreviations, unless the configuration says otherwise.
+pub const DEFAULT_ABBREV_LEN: usize = 8;
+
+/// Abbreviates digests by looking up the digests in the store which share a prefix with them.
+#[derive(Clone)]
+pub struct Abbreviator {
+    /// Every digest in the store beginning with the given bytes, or `None` if the store can't
+    /// list them.
+    lookup: Rc<Fn(&[u8]) -> Option<Result<Vec<Vec<u8>>, Error>>>,
+    min_len: usize,
+}
+
+impl fmt::Debug for Abbreviator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Abbreviator")
+            .field("lookup", &"OPAQUE")
+            .field("min_len", &self.min_len)
+            .finish()
+    }
+}
+
+/// The number of leading hex digits which `a` and `b` have in common.
+fn common_hex_digits(a: &[u8], b: &[u8]) -> usize {
+    for (i, (x, y)) in a.iter().zip(b).enumerate() {
+        if x != y {
+            return 2 * i + if x >> 4 == y >> 4 { 1 } else { 0 };
+        }
+    }
+
+    2 * cmp::min(a.len(), b.len())
+}
+
+impl Abbreviator {
+    /// Abbreviate a full hex digest. If the store can't list its digests, collisions can't be
+    /// ruled out, so the digest is returned whole. Strings which aren't hex digests are returned
+    /// as they are.
+    pub fn abbreviate(&self, hex_digest: &str) -> String {
+        let bytes = match hex::decode(hex_digest) {
+            Ok(bytes) => bytes,
+            Err(_) => return hex_digest.to_owned(),
+        };
+
+        // Only digests sharing at least the first `min_len` hex digits can lengthen the
+        // abbreviation, and those all share its first `min_len / 2` bytes.
+        let prefix = &bytes[..cmp::min(self.min_len / 2, bytes.len())];
+        let others = match (self.lookup)(prefix) {
+            Some(Ok(others)) => others,
+            Some(Err(_)) | None => return hex_digest.to_owned(),
+        };
+        let shared = others
+            .iter()
+            .filter(|other| **other != bytes)
+            .map(|other| common_hex_digits(&bytes, other))
+            .max()
+            .unwrap_or(0);
+
+        let len = cmp::min(cmp::max(self.min_len, shared + 1), hex_digest.len());
+        hex_digest[..len].to_owned()
+    }
+}
+
+#[must_use = "AbbreviatorOut contains futures which must be driven to completion!"]
+pub struct AbbreviatorOut<'r> {
+    pub blocking: Box<Future<Item = Abbreviator, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for AbbreviatorOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AbbreviatorOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    /// Prepare to abbreviate digests. Each abbreviation looks up only those digests in the store
+    /// which begin the same way, so this is cheap however large the store is.
+    pub fn abbreviator<'r>(&'r self) -> AbbreviatorOut<'r> {
+        let store = self.store.clone();
+        let blocking = async_block! {
+            // Abbreviations shorter than `MIN_ABBREV_LEN` couldn't be resolved again.
+            let min_len = cmp::max(self.get_config()?.abbrev_len, plumbing::MIN_ABBREV_LEN);
+            let lookup = move |prefix: &[u8]| {
+                store
+                    .list_digests_with_prefix::<Sha3Digest>(prefix)
+                    .map(|stream| {
+                        stream
+                            .map(|digest| digest.as_bytes().to_vec())
+                            .collect()
+                            .wait()
+                    })
+            };
+
+            Ok(Abbreviator {
+                lookup: Rc::new(lookup),
+                min_len,
+            })
+        };
+
+        AbbreviatorOut {
+            blocking: Box::new(blocking),
+        }
+    }
+}
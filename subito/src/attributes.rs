@@ -0,0 +1,345 @@
+//! Per-path attributes controlling line-ending normalization, read from a `.gitattributes` file at
+//! the root of the workspace.
+//!
+//! Each line of the file is a glob pattern followed by attributes, as in Git. The attributes
+//! understood here are `text` (always normalize), `-text` or `binary` (never normalize),
+//! `text=auto` (normalize unless the file looks binary) and `eol=lf` or `eol=crlf` (the line ending
+//! to check out with, which also implies `text=auto` if `text` is not given). Other attributes are
+//! ignored. When several lines match a path, later lines take precedence for each attribute.
+//!
+//! Files which are normalized are stored with LF line endings, so that their digests don't depend
+//! on the platform they were staged on, and are checked out with the line ending given by `eol`,
+//! or the platform's own if there is none. Files with no `text` or `eol` attribute are stored and
+//! checked out byte for byte.
+//!
+//! Staging trusts its cache of which object each unchanged file was stored as, so a file keeps the
+//! representation it was last staged with after the rules change, until the file itself changes.
+
+use std::{cmp, borrow::Cow, fs::File, io::{self, BufRead, BufReader, Read}, path::Path};
+
+use attaca::path::ObjectPath;
+use failure::*;
+use globset::{GlobBuilder, GlobMatcher};
+
+/// The name of the attributes file, relative to the root of the workspace.
+pub const ATTRIBUTES_FILE: &'static str = ".gitattributes";
+
+/// The number of leading bytes inspected when deciding whether a `text=auto` file is binary.
+pub const SNIFF_LEN: usize = 8000;
+
+/// Whether a file is text, and so has its line endings normalized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Text {
+    /// `text`: always normalize.
+    Set,
+
+    /// `-text` or `binary`: never normalize.
+    Unset,
+
+    /// `text=auto`: normalize unless the file has a NUL byte near the start.
+    Auto,
+}
+
+/// A line ending to check text files out with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eol {
+    Lf,
+    Crlf,
+}
+
+impl Eol {
+    /// The line ending native to the platform we're running on.
+    pub fn native() -> Self {
+        if cfg!(windows) {
+            Eol::Crlf
+        } else {
+            Eol::Lf
+        }
+    }
+}
+
+/// The attributes which apply to a single path. `None` means the attribute is unspecified.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PathAttributes {
+    pub text: Option<Text>,
+    pub eol: Option<Eol>,
+}
+
+impl PathAttributes {
+    fn text_or_implied(&self) -> Option<Text> {
+        match (self.text, self.eol) {
+            (Some(text), _) => Some(text),
+            (None, Some(_)) => Some(Text::Auto),
+            (None, None) => None,
+        }
+    }
+
+    /// Whether a file with these attributes might be normalized, depending on its contents.
+    pub fn may_be_text(&self) -> bool {
+        match self.text_or_implied() {
+            Some(Text::Set) | Some(Text::Auto) => true,
+            Some(Text::Unset) | None => false,
+        }
+    }
+
+    /// Whether a file with these attributes, beginning with `head`, is normalized. `head` need only
+    /// hold the first `SNIFF_LEN` bytes.
+    pub fn is_text(&self, head: &[u8]) -> bool {
+        match self.text_or_implied() {
+            Some(Text::Set) => true,
+            Some(Text::Auto) => !head[..cmp::min(head.len(), SNIFF_LEN)].contains(&0),
+            Some(Text::Unset) | None => false,
+        }
+    }
+
+    /// The line ending text files with these attributes are checked out with.
+    pub fn checkout_eol(&self) -> Eol {
+        self.eol.unwrap_or_else(Eol::native)
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    matcher: GlobMatcher,
+    attributes: PathAttributes,
+}
+
+/// The rules of an attributes file.
+#[derive(Debug, Default)]
+pub struct Attributes {
+    rules: Vec<Rule>,
+}
+
+impl Attributes {
+    /// Load the attributes file at the root of the workspace `root`. A missing file has no rules.
+    pub fn load(root: &Path) -> Result<Self, Error> {
+        let path = root.join(ATTRIBUTES_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let mut source = String::new();
+        File::open(&path)?.read_to_string(&mut source)?;
+        Ok(Self::parse(&source).with_context(|_| format!("Error reading {}", path.display()))?)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        let mut rules = Vec::new();
+
+        for (i, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let pattern = words.next().unwrap();
+            let mut attributes = PathAttributes::default();
+            for word in words {
+                match word {
+                    "text" => attributes.text = Some(Text::Set),
+                    "-text" | "binary" => attributes.text = Some(Text::Unset),
+                    "text=auto" => attributes.text = Some(Text::Auto),
+                    "eol=lf" => attributes.eol = Some(Eol::Lf),
+                    "eol=crlf" => attributes.eol = Some(Eol::Crlf),
+                    _ if word.starts_with("text=") || word.starts_with("eol=") => bail!(
+                        "Unrecognized attribute {} on line {}",
+                        word,
+                        i + 1
+                    ),
+                    _ => {}
+                }
+            }
+
+            // As in Git, a pattern without a slash matches at any depth, and one with a slash is
+            // relative to the root.
+            let glob = if pattern.starts_with('/') {
+                pattern[1..].to_owned()
+            } else if pattern.contains('/') {
+                pattern.to_owned()
+            } else {
+                format!("**/{}", pattern)
+            };
+            let matcher = GlobBuilder::new(&glob)
+                .literal_separator(true)
+                .build()
+                .with_context(|_| format!("Bad pattern {} on line {}", pattern, i + 1))?
+                .compile_matcher();
+
+            rules.push(Rule {
+                matcher,
+                attributes,
+            });
+        }
+
+        Ok(Self { rules })
+    }
+
+    /// The attributes which apply to `path`.
+    pub fn get(&self, path: &ObjectPath) -> PathAttributes {
+        let path = path.to_path();
+        self.rules
+            .iter()
+            .filter(|rule| rule.matcher.is_match(&path))
+            .fold(PathAttributes::default(), |acc, rule| PathAttributes {
+                text: rule.attributes.text.or(acc.text),
+                eol: rule.attributes.eol.or(acc.eol),
+            })
+    }
+}
+
+/// A reader which converts CRLF line endings to LF as it reads. As in git, only the one CR
+/// immediately before an LF is dropped; any CRs before that one, and CRs elsewhere, are left
+/// alone.
+pub struct Normalize<R: Read> {
+    reader: BufReader<R>,
+
+    /// How many CRs have been consumed from `reader` but not yet written out, since whether they
+    /// end a line depends on the byte after them.
+    pending_crs: usize,
+}
+
+impl<R: Read> Normalize<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            pending_crs: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Normalize<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let mut n = 0;
+
+        while n < buf.len() {
+            let consumed = {
+                let chunk = self.reader.fill_buf()?;
+                if chunk.is_empty() {
+                    // CRs at the very end don't end a line.
+                    while self.pending_crs > 0 && n < buf.len() {
+                        self.pending_crs -= 1;
+                        buf[n] = b'\r';
+                        n += 1;
+                    }
+                    return Ok(n);
+                }
+
+                let mut i = 0;
+                while i < chunk.len() && n < buf.len() {
+                    match chunk[i] {
+                        b'\r' => self.pending_crs += 1,
+                        // Only the last of a run of CRs is part of the line ending; the rest are
+                        // written out by the next arm first.
+                        b'\n' if self.pending_crs <= 1 => {
+                            self.pending_crs = 0;
+                            buf[n] = b'\n';
+                            n += 1;
+                        }
+                        _ if self.pending_crs > 0 => {
+                            // Write out a pending CR, and look at this byte again next time.
+                            self.pending_crs -= 1;
+                            buf[n] = b'\r';
+                            n += 1;
+                            continue;
+                        }
+                        byte => {
+                            buf[n] = byte;
+                            n += 1;
+                        }
+                    }
+                    i += 1;
+                }
+                i
+            };
+            self.reader.consume(consumed);
+        }
+
+        Ok(n)
+    }
+}
+
+/// Convert normalized data to the line ending `eol` for checkout. Only bare LFs are converted, so
+/// data which somehow already has CRLF line endings is not doubled up.
+pub fn denormalize(data: &[u8], eol: Eol) -> Cow<[u8]> {
+    match eol {
+        Eol::Lf => Cow::Borrowed(data),
+        Eol::Crlf => {
+            let mut converted = Vec::with_capacity(data.len() + data.len() / 32);
+            for (i, &byte) in data.iter().enumerate() {
+                if byte == b'\n' && (i == 0 || data[i - 1] != b'\r') {
+                    converted.push(b'\r');
+                }
+                converted.push(byte);
+            }
+            Cow::Owned(converted)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A reader which hands out a single byte at a time, so that every CRLF is split between
+    /// reads.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+            match (self.0.split_first(), buf.first_mut()) {
+                (Some((&byte, rest)), Some(slot)) => {
+                    *slot = byte;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    fn normalize<R: Read>(reader: R) -> Vec<u8> {
+        let mut normalized = Vec::new();
+        Normalize::new(reader).read_to_end(&mut normalized).unwrap();
+        normalized
+    }
+
+    const SAMPLES: &[&[u8]] = &[
+        b"",
+        b"\r",
+        b"\r\n",
+        b"one\r\ntwo\r\n",
+        b"lone\rcr\r\r\nmixed\n\r",
+        b"no newline at all",
+    ];
+
+    #[test]
+    fn normalize_converts_line_endings_only() {
+        assert_eq!(normalize(&b"a\r\nb\rc\n\r\r\nd\r"[..]), b"a\nb\rc\n\r\nd\r".to_vec());
+    }
+
+    #[test]
+    fn normalize_is_the_same_however_the_data_is_split() {
+        for &sample in SAMPLES {
+            assert_eq!(normalize(Trickle(sample)), normalize(sample));
+        }
+    }
+
+    #[test]
+    fn normalize_round_trips() {
+        for &sample in SAMPLES {
+            let normalized = normalize(sample);
+            // A CR left in front of an LF makes a line ending of its own when read again, so data
+            // like that doesn't round-trip; git behaves the same way.
+            if normalized.windows(2).any(|window| window == b"\r\n") {
+                continue;
+            }
+            assert_eq!(normalize(&normalized[..]), normalized);
+
+            for &eol in &[Eol::Lf, Eol::Crlf] {
+                let denormalized = denormalize(&normalized, eol);
+                assert_eq!(normalize(&denormalized[..]), normalized);
+            }
+        }
+    }
+}
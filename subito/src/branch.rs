@@ -7,8 +7,10 @@ use url::Url;
 
 use Repository;
 use config::{StoreConfig, StoreKind};
+use plumbing;
+use refs;
 use state::{Head, State};
-use syntax::Name;
+use syntax::{Name, Rev};
 
 #[derive(Debug, Clone, StructOpt, Builder)]
 #[structopt(name = "branch")]
@@ -24,6 +26,9 @@ pub enum BranchCommand {
 
     #[structopt(name = "list")]
     List(BranchListArgs),
+
+    #[structopt(name = "delete")]
+    Delete(BranchDeleteArgs),
 }
 
 impl Default for BranchCommand {
@@ -39,11 +44,27 @@ pub struct BranchCreateArgs {
     name: Name,
 }
 
+/// Delete a branch. The commits it points to are left in the store.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "delete")]
+pub struct BranchDeleteArgs {
+    #[structopt(name = "BRANCH")]
+    name: Name,
+}
+
 #[derive(Debug, Clone, Default, StructOpt, Builder)]
 #[structopt(name = "list")]
 pub struct BranchListArgs {
     #[structopt(long = "remote", short = "r")]
     remote: Option<Name>,
+
+    /// Print only the name of the current branch, or nothing if HEAD is detached.
+    #[structopt(long = "show-current", raw(conflicts_with_all = r#"&["remote", "COMMIT"]"#))]
+    show_current: bool,
+
+    /// Only list branches whose tips contain the given commit in their history.
+    #[structopt(long = "contains", name = "COMMIT")]
+    contains: Option<Rev>,
 }
 
 pub struct BranchOut<'r> {
@@ -55,22 +76,47 @@ impl<B: Backend> Repository<B> {
         match args.cmd.unwrap_or_default() {
             BranchCommand::Create(create_args) => self.branch_create(create_args),
             BranchCommand::List(list_args) => self.branch_list(list_args),
+            BranchCommand::Delete(delete_args) => self.branch_delete(delete_args),
         }
     }
 
     pub fn branch_create<'r>(&'r mut self, args: BranchCreateArgs) -> BranchOut<'r> {
         let blocking = async_block! {
             let state = self.get_state()?;
-            let branches = await!(self.store.load_branches())?;
+            let branches = await!(refs::load_heads(&self.store))?;
             ensure!(!branches.contains_key(args.name.as_str()), "branch already exists");
             let commit_ref = match state.head {
                 Head::Empty => bail!("no prior commits"),
                 Head::Detached(commit_ref) => commit_ref.into_inner(),
-                Head::Branch(branch) => branches[branch.as_str()].clone(),
+                Head::Branch(branch) => match branches.get(branch.as_str()) {
+                    Some(handle) => handle.clone(),
+                    None => bail!("no prior commits"),
+                },
             };
             let mut new_branches = branches.clone();
             new_branches.insert(args.name.into_string(), commit_ref);
-            await!(self.store.swap_branches(branches, new_branches))?;
+            await!(refs::swap_heads(&self.store, branches, new_branches))?;
+            Ok(())
+        };
+
+        BranchOut {
+            blocking: Box::new(blocking),
+        }
+    }
+
+    pub fn branch_delete<'r>(&'r mut self, args: BranchDeleteArgs) -> BranchOut<'r> {
+        let blocking = async_block! {
+            ensure!(
+                self.current_branch()?.as_ref() != Some(&args.name),
+                "cannot delete the current branch {}",
+                args.name
+            );
+
+            let branches = await!(refs::load_heads(&self.store))?;
+            ensure!(branches.contains_key(args.name.as_str()), "no such branch {}", args.name);
+            let mut new_branches = branches.clone();
+            new_branches.remove(args.name.as_str());
+            await!(refs::swap_heads(&self.store, branches, new_branches))?;
             Ok(())
         };
 
@@ -81,12 +127,30 @@ impl<B: Backend> Repository<B> {
 
     pub fn branch_list<'r>(&'r mut self, args: BranchListArgs) -> BranchOut<'r> {
         let blocking = async_block! {
+            if args.show_current {
+                if let Some(branch) = self.current_branch()? {
+                    println!("{}", branch);
+                }
+                return Ok(());
+            }
+
             let state = self.get_state()?;
+            let contains = match args.contains {
+                Some(rev) => Some(await!(plumbing::resolve_rev(self, rev))?),
+                None => None,
+            };
+
             match args.remote {
                 Some(remote) => {
                     ensure!(state.remote_refs.contains_key(&remote), "no such remote");
 
-                    for (branch_name, _) in &state.remote_refs[&remote] {
+                    for (branch_name, commit_ref) in &state.remote_refs[&remote] {
+                        if let Some(ref commit) = contains {
+                            if !await!(plumbing::is_ancestor(commit.clone(), commit_ref.clone()))? {
+                                continue;
+                            }
+                        }
+
                         println!("   {}", branch_name);
                     }
                 }
@@ -96,12 +160,18 @@ impl<B: Backend> Repository<B> {
                         Head::Detached(_) => None,
                         Head::Branch(branch) => Some(branch),
                     };
-                    let branches = await!(self.store.load_branches())?;
+                    let branches = await!(refs::load_heads(&self.store))?;
                     // TODO better and more flexible output instead of just printlning everything
-                    for (branch_name, _) in branches {
+                    for (branch_name, handle) in branches {
+                        if let Some(ref commit) = contains {
+                            if !await!(plumbing::is_ancestor(commit.clone(), CommitRef::new(handle)))? {
+                                continue;
+                            }
+                        }
+
                         match maybe_branch {
                             Some(ref name) if name.as_str() == &branch_name => {
-                                println!("=> {}", branch_name)
+                                println!(" * {}", branch_name)
                             }
                             _ => println!("   {}", branch_name),
                         }
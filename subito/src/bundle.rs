@@ -0,0 +1,201 @@
+//! Bundles: branches and every object reachable from them, in a single file.
+//!
+//! A bundle moves history between machines whose stores can't reach each other. It is written by
+//! `bundle create`, carried across however is convenient, and then cloned or fetched from like any
+//! other remote, by a `bundle:` URL or a `file:` URL ending in `.bundle`. Bundles are read-only;
+//! pushing to one is refused.
+//!
+//! A bundle starts with `MAGIC`, followed by a packed Cap'n Proto header naming the digest used and
+//! listing the bundled refs by their fully qualified names, each with the index of its commit in
+//! the pack. The rest of the file is a single pack (see `attaca::pack`) holding everything
+//! reachable from those commits. Reading a bundle unpacks it into an in-memory store, checking the
+//! digest of every object as it goes, so a bundle has to fit in memory to be fetched from.
+
+use std::{fmt, collections::HashMap, fs::File, io::{BufRead, BufReader, BufWriter, Read, Write},
+          path::{Path, PathBuf}};
+
+use attaca::{digest::{Sha3Digest, prelude::*}, memory::MemoryBackend,
+             pack::{self, Pack, TransferEncoding}, store::prelude::*};
+use capnp::{message, serialize_packed};
+use failure::*;
+use futures::prelude::*;
+use url::{Url, percent_encoding::percent_decode};
+
+use Repository;
+use plumbing;
+use refs;
+use syntax::Name;
+
+use bundle_capnp::*;
+
+/// The first bytes of every bundle.
+const MAGIC: &'static [u8] = b"attaca bundle v1\n";
+
+/// URLs with this scheme always refer to bundles.
+pub const SCHEME: &'static str = "bundle";
+
+/// `file:` URLs with this extension refer to bundles.
+pub const EXTENSION: &'static str = "bundle";
+
+/// Create bundles of branches, for moving them between repositories without a network.
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(name = "bundle")]
+pub enum BundleArgs {
+    #[structopt(name = "create")]
+    Create(BundleCreateArgs),
+}
+
+/// Write branches and every object they need to a bundle file, which can be cloned or fetched from
+/// by a `bundle:` URL or a `file:` URL ending in `.bundle`.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "create")]
+pub struct BundleCreateArgs {
+    /// Local branches to put in the bundle.
+    #[structopt(name = "BRANCH", raw(required = "true"))]
+    pub branches: Vec<Name>,
+
+    /// Path to write the bundle to. Any existing file there is replaced.
+    #[structopt(name = "FILE", parse(from_os_str))]
+    pub file: PathBuf,
+}
+
+#[must_use = "BundleOut contains futures which must be driven to completion!"]
+pub struct BundleOut<'r> {
+    /// The number of objects written to the bundle.
+    pub blocking: Box<Future<Item = usize, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for BundleOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BundleOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+/// The path of the bundle which a URL refers to, or `None` if it doesn't refer to a bundle.
+pub fn path_of(url: &Url) -> Option<PathBuf> {
+    if url.scheme() == SCHEME {
+        let path = percent_decode(url.path().as_bytes()).decode_utf8_lossy();
+        return Some(PathBuf::from(path.into_owned()));
+    }
+
+    match url.to_file_path() {
+        Ok(ref path) if path.extension().map_or(false, |ext| ext == EXTENSION) => {
+            Some(path.clone())
+        }
+        _ => None,
+    }
+}
+
+fn write_header<W, I>(writer: &mut W, refs: I) -> Result<(), Error>
+where
+    W: Write,
+    I: IntoIterator<Item = (String, usize)>,
+{
+    let refs = refs.into_iter().collect::<Vec<_>>();
+    let mut message = message::Builder::new_default();
+
+    {
+        let mut bundle_builder = message.init_root::<bundle::Builder>();
+        bundle_builder.set_digest(Sha3Digest::SIGNATURE.name);
+        let mut refs_builder = bundle_builder.init_refs(refs.len() as u32);
+        for (i, &(ref name, entry)) in refs.iter().enumerate() {
+            let mut ref_builder = refs_builder.borrow().get(i as u32);
+            ref_builder.set_name(name);
+            ref_builder.set_entry(entry as u64);
+        }
+    }
+
+    serialize_packed::write_message(writer, &message)?;
+
+    Ok(())
+}
+
+fn read_header<R: BufRead>(reader: &mut R) -> Result<Vec<(String, usize)>, Error> {
+    let message_reader = serialize_packed::read_message(reader, message::ReaderOptions::new())?;
+    let bundle_reader = message_reader.get_root::<bundle::Reader>()?;
+
+    let digest = bundle_reader.get_digest()?;
+    ensure!(
+        digest == Sha3Digest::SIGNATURE.name,
+        "Bundle uses digest {} but {} was expected",
+        digest,
+        Sha3Digest::SIGNATURE.name
+    );
+
+    bundle_reader
+        .get_refs()?
+        .iter()
+        .map(|ref_reader| Ok((ref_reader.get_name()?.to_owned(), ref_reader.get_entry() as usize)))
+        .collect()
+}
+
+/// Read a bundle into a fresh in-memory store, whose branches are the refs of the bundle.
+pub fn open(path: &Path) -> Result<Store<MemoryBackend>, Error> {
+    let file = File::open(path)
+        .with_context(|_| format_err!("Error opening bundle {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+
+    let mut magic = vec![0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    ensure!(magic == MAGIC, "{} is not a bundle", path.display());
+
+    let refs = read_header(&mut reader)?;
+    let pack = Pack::read_from(Sha3Digest::SIGNATURE, &mut reader)?;
+
+    let store = Store::new(MemoryBackend::new());
+    let handles = pack::unpack_all::<Sha3Digest, _>(pack, store.clone()).wait()?;
+    let branches = refs.into_iter()
+        .map(|(name, entry)| match handles.get(entry) {
+            Some(handle) => Ok((name, handle.clone())),
+            None => Err(format_err!("Malformed bundle: {} refers to a missing entry", name)),
+        })
+        .collect::<Result<HashMap<_, _>, Error>>()?;
+    store.swap_branches(HashMap::new(), branches).wait()?;
+
+    Ok(store)
+}
+
+/// As `open`, for the bundle which a URL refers to.
+pub fn open_url(url: &Url) -> Result<Store<MemoryBackend>, Error> {
+    let path = path_of(url).ok_or_else(|| format_err!("{} does not refer to a bundle", url))?;
+    open(&path)
+}
+
+impl<B: Backend> Repository<B> {
+    pub fn bundle<'r>(&'r self, args: BundleArgs) -> BundleOut<'r> {
+        match args {
+            BundleArgs::Create(create_args) => self.bundle_create(create_args),
+        }
+    }
+
+    fn bundle_create<'r>(&'r self, args: BundleCreateArgs) -> BundleOut<'r> {
+        let blocking = async_block! {
+            let mut names = Vec::new();
+            let mut roots = Vec::new();
+            for branch in args.branches {
+                let commit_ref = await!(plumbing::resolve_local(self, branch.clone()))?;
+                names.push(format!("{}{}", refs::HEADS, branch));
+                roots.push(commit_ref.into_inner());
+            }
+
+            let (pack, indices) =
+                await!(pack::collect::<Sha3Digest, _>(TransferEncoding::Deflate, roots))?;
+
+            let file = File::create(&args.file)
+                .with_context(|_| format_err!("Error creating {}", args.file.display()))?;
+            let mut writer = BufWriter::new(file);
+            writer.write_all(MAGIC)?;
+            write_header(&mut writer, names.into_iter().zip(indices))?;
+            pack.write_to(&mut writer)?;
+            writer.flush()?;
+
+            Ok(pack.entry_count()? as usize)
+        };
+
+        BundleOut {
+            blocking: Box::new(blocking),
+        }
+    }
+}
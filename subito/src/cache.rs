@@ -1,11 +1,13 @@
-use std::{fmt, io::{BufRead, Write}, marker::PhantomData, ops::{BitAnd, BitOr, Not},
-          path::{Path, PathBuf}, sync::{Arc, RwLock}, time::{SystemTime, UNIX_EPOCH}};
+use std::{fmt, collections::BTreeMap, io::{BufRead, Write}, marker::PhantomData,
+          ops::{BitAnd, BitOr, Not}, path::{Path, PathBuf}, sync::{Arc, Mutex, RwLock},
+          time::{SystemTime, UNIX_EPOCH}};
 
 use attaca::{digest::prelude::*, object::{LargeRef, ObjectRef, SmallRef}, path::ObjectPath,
              store::prelude::*};
 use capnp::{serialize_packed, Word, message::{self, ScratchSpace, ScratchSpaceHeapAllocator}};
 use failure::*;
-use leveldb::{database::Database, kv::KV, options::{ReadOptions, WriteOptions}};
+use leveldb::{batch::{Batch, Writebatch}, database::Database, kv::KV,
+              options::{ReadOptions, WriteOptions}};
 use nix::{self, errno::Errno, libc::c_int, sys::stat::{lstat, FileStat}};
 use smallvec::SmallVec;
 
@@ -24,6 +26,9 @@ const EXT4_IOC_GETVERSION_MAGIC: u8 = b'f';
 const EXT4_IOC_GETVERSION_TYPE_MODE: u8 = 3;
 ioctl!(read ext4_ioc_getversion with EXT4_IOC_GETVERSION_MAGIC, EXT4_IOC_GETVERSION_TYPE_MODE; c_int);
 
+// The number of cache entries to buffer before writing them out as a single batch.
+const CACHE_BATCH_SIZE: usize = 256;
+
 fn ns_from_components(seconds: i64, nanos: i64) -> Option<i64> {
     seconds
         .checked_mul(1_000_000_000)
@@ -275,6 +280,73 @@ impl<B: Backend> Entry<B> {
     }
 }
 
+struct DirEntry<B: Backend> {
+    tree_id: OwnedLocalId<B>,
+
+    inode: Inode,
+}
+
+impl<B: Backend> DirEntry<B> {
+    fn decode<R: BufRead>(reader: &mut R) -> Result<Self, Error> {
+        use self::dir_entry::{self, inode};
+
+        let message_reader = serialize_packed::read_message(reader, message::ReaderOptions::new())?;
+        let entry = message_reader.get_root::<dir_entry::Reader>()?;
+
+        let tree_id = <B as Backend>::Id::from_bytes(entry.get_tree_id()?);
+
+        let inode = {
+            let inode = entry.get_inode();
+
+            let version_or_times = match inode.which()? {
+                inode::Version(version) => InodeVersionOrTimes::Version(version),
+                inode::Times(times) => InodeVersionOrTimes::Times {
+                    ctime_ns: times.get_ctime_ns(),
+                    mtime_ns: times.get_mtime_ns(),
+                },
+            };
+
+            Inode {
+                timestamp_ns: inode.get_timestamp_ns(),
+                generation: inode.get_generation(),
+                number: inode.get_number(),
+                version_or_times,
+            }
+        };
+
+        Ok(Self { tree_id, inode })
+    }
+
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        let mut message = message::Builder::new_default();
+        {
+            let mut entry = message.init_root::<dir_entry::Builder>();
+            {
+                use std::borrow::Borrow;
+                entry.set_tree_id(self.tree_id.borrow().as_ref());
+            }
+            {
+                let mut inode = entry.borrow().init_inode();
+                inode.set_timestamp_ns(self.inode.timestamp_ns);
+                inode.set_generation(self.inode.generation);
+                inode.set_number(self.inode.number);
+                match self.inode.version_or_times {
+                    InodeVersionOrTimes::Version(version) => inode.set_version(version),
+                    InodeVersionOrTimes::Times { ctime_ns, mtime_ns } => {
+                        let mut times = inode.init_times();
+                        times.set_ctime_ns(ctime_ns);
+                        times.set_mtime_ns(mtime_ns);
+                    }
+                }
+            }
+        }
+
+        serialize_packed::write_message(writer, &message)?;
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Certainty {
     Negative,
@@ -390,6 +462,28 @@ impl<B: Backend> Snapshot<B> {
     }
 }
 
+/// A snapshot of a directory in the local workspace, taken before walking it.
+#[derive(Debug, Clone)]
+pub struct DirSnapshot {
+    path_buf: PathBuf,
+    object_path: ObjectPath,
+
+    inode: Inode,
+}
+
+/// A source of knowledge about which parts of the local workspace may have changed, such as a
+/// filesystem watcher.
+///
+/// Directory modification times only change when entries are added, removed or renamed, so they
+/// cannot by themselves prove that the files inside a directory are unchanged. A `ChangeJournal`
+/// fills that gap: when one is attached to the cache, a directory whose recorded inode is
+/// unchanged *and* which the journal vouches for can be skipped entirely during staging.
+pub trait ChangeJournal: Send + Sync {
+    /// Returns `true` only if it is certain that nothing at or beneath `path` has changed since
+    /// `since_ns` nanoseconds after the UNIX epoch. If there is any doubt, return `false`.
+    fn is_unchanged_since(&self, path: &Path, since_ns: i64) -> bool;
+}
+
 pub enum Status<B: Backend> {
     Extant(Certainty, Snapshot<B>),
     New(Snapshot<B>),
@@ -425,9 +519,75 @@ impl<B: Backend> Clone for Status<B> {
     }
 }
 
+/// Cache entries which have been resolved but not yet written to the database.
+///
+/// Entries are written out together in a single leveldb write batch, which is applied atomically:
+/// if the process dies before or during a flush, the cache loses the pending entries (and the
+/// affected files are simply hashed again next time) but is never left half-written. The buffer is
+/// shared between all clones of a `Cache`, and is flushed when the last of them is dropped, though
+/// errors are only reported by flushing explicitly with `Cache::flush`.
+struct PendingWrites {
+    db: Arc<RwLock<Database<Key>>>,
+    entries: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl PendingWrites {
+    fn get(&self, key: &Key) -> Result<Option<Vec<u8>>, Error> {
+        if let Some(bytes) = self.entries.lock().unwrap().get(key.as_ref()) {
+            return Ok(Some(bytes.clone()));
+        }
+
+        Ok(self.db.read().unwrap().get(ReadOptions::new(), key)?)
+    }
+
+    fn put(&self, key: Key, value: &[u8]) -> Result<(), Error> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(key.as_ref().to_owned(), value.to_owned());
+
+        if entries.len() >= CACHE_BATCH_SIZE {
+            Self::write(&self.db, &mut entries)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Self::write(&self.db, &mut self.entries.lock().unwrap())
+    }
+
+    // The entries lock is held for the duration of the write so that concurrent lookups never miss
+    // an entry which has left the buffer but not yet reached the database.
+    fn write(
+        db: &RwLock<Database<Key>>,
+        entries: &mut BTreeMap<Vec<u8>, Vec<u8>>,
+    ) -> Result<(), Error> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut batch = Writebatch::new();
+        for (key, value) in entries.iter() {
+            batch.put(Key::Owned(SmallVec::from(&key[..])), value);
+        }
+        db.read().unwrap().write(WriteOptions::new(), &batch)?;
+        entries.clear();
+
+        Ok(())
+    }
+}
+
+impl Drop for PendingWrites {
+    fn drop(&mut self) {
+        // There's no one to report an error to here; operations which write the cache flush it
+        // explicitly first and return any error, and failing here only costs rehashing files.
+        let _ = self.flush();
+    }
+}
+
 pub struct Cache<B: Backend> {
     _phantom: PhantomData<B>,
-    db: Arc<RwLock<Database<Key>>>,
+    pending: Arc<PendingWrites>,
+    journal: Option<Arc<ChangeJournal>>,
 }
 
 impl<B: Backend> fmt::Debug for Cache<B> {
@@ -439,7 +599,8 @@ impl<B: Backend> fmt::Debug for Cache<B> {
 impl<B: Backend> Clone for Cache<B> {
     fn clone(&self) -> Self {
         Self {
-            db: self.db.clone(),
+            pending: self.pending.clone(),
+            journal: self.journal.clone(),
             _phantom: PhantomData,
         }
     }
@@ -448,7 +609,11 @@ impl<B: Backend> Clone for Cache<B> {
 impl<B: Backend> From<Arc<RwLock<Database<Key>>>> for Cache<B> {
     fn from(db: Arc<RwLock<Database<Key>>>) -> Self {
         Self {
-            db,
+            pending: Arc::new(PendingWrites {
+                db,
+                entries: Mutex::new(BTreeMap::new()),
+            }),
+            journal: None,
             _phantom: PhantomData,
         }
     }
@@ -456,10 +621,9 @@ impl<B: Backend> From<Arc<RwLock<Database<Key>>>> for Cache<B> {
 
 impl<B: Backend> Cache<B> {
     pub fn status(&self, path: &ObjectPath) -> Result<Status<B>, Error> {
-        let db_lock = self.db.read().unwrap();
         let key = Key::cache(path);
         let path_buf = path.to_path();
-        let entry_opt = db_lock.get(ReadOptions::new(), &key)?;
+        let entry_opt = self.pending.get(&key)?;
         let inode_opt = Inode::open(&path_buf)?;
 
         match (entry_opt, inode_opt) {
@@ -504,13 +668,82 @@ impl<B: Backend> Cache<B> {
                 let mut buf = SmallVec::<[u8; 1024]>::new();
                 entry.encode(&mut buf)?;
 
-                let db_lock = self.db.read().unwrap();
-                let key = Key::cache(&snapshot.object_path);
-                db_lock.put(WriteOptions::new(), &key, &buf)?;
+                self.pending.put(Key::cache(&snapshot.object_path), &buf)?;
 
                 Ok(())
             }
             Certainty::Unknown | Certainty::Negative => bail!("File has been changed!"),
         }
     }
+
+    /// Write out any buffered cache entries. This happens automatically once enough entries have
+    /// accumulated and when the last handle to the cache is dropped, but flushing explicitly allows
+    /// errors to be reported.
+    pub fn flush(&self) -> Result<(), Error> {
+        self.pending.flush()
+    }
+
+    /// Attach a change journal, enabling subtrees to be skipped during staging.
+    pub fn set_journal(&mut self, journal: Arc<ChangeJournal>) {
+        self.journal = Some(journal);
+    }
+
+    /// Snapshot a directory before walking it, so that the tree built from it can be recorded.
+    /// Without a change journal a recorded tree could never be reused, so there is nothing to
+    /// snapshot and this returns `None`.
+    pub fn dir_snapshot(
+        &self,
+        path: &Path,
+        object_path: &ObjectPath,
+    ) -> Result<Option<DirSnapshot>, Error> {
+        if self.journal.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Inode::open(path)?.map(|inode| DirSnapshot {
+            path_buf: path.to_owned(),
+            object_path: object_path.clone(),
+            inode,
+        }))
+    }
+
+    /// Look up the tree recorded for a directory the last time it was staged. This only returns
+    /// `Some` if it is certain that the directory and everything beneath it are unchanged; any
+    /// doubt results in `None`, and the caller should walk the directory.
+    pub fn dir_status(&self, snapshot: &DirSnapshot) -> Result<Option<OwnedLocalId<B>>, Error> {
+        let journal = match self.journal {
+            Some(ref journal) => journal,
+            None => return Ok(None),
+        };
+
+        let entry_opt = self.pending.get(&Key::dir_cache(&snapshot.object_path))?;
+        let entry = match entry_opt {
+            Some(bytes) => DirEntry::<B>::decode(&mut &bytes[..])?,
+            None => return Ok(None),
+        };
+
+        match Inode::is_unchanged(&entry.inode, &snapshot.inode) {
+            Certainty::Positive
+                if journal.is_unchanged_since(&snapshot.path_buf, entry.inode.timestamp_ns) =>
+            {
+                Ok(Some(entry.tree_id))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Record the tree built from a directory. The directory's inode is taken from the snapshot
+    /// made *before* walking it, so that changes made during the walk are not missed.
+    pub fn resolve_dir(&self, snapshot: DirSnapshot, tree_id: OwnedLocalId<B>) -> Result<(), Error> {
+        let entry: DirEntry<B> = DirEntry {
+            tree_id,
+            inode: snapshot.inode,
+        };
+        let mut buf = SmallVec::<[u8; 1024]>::new();
+        entry.encode(&mut buf)?;
+
+        self.pending.put(Key::dir_cache(&snapshot.object_path), &buf)?;
+
+        Ok(())
+    }
 }
@@ -1,41 +1,125 @@
-use std::{fmt, borrow::Borrow, ffi::OsStr, fs::File, path::PathBuf};
+use std::{fmt, fs, borrow::Borrow, cell::RefCell, collections::{BTreeMap, HashSet}, ffi::OsStr,
+          fs::File, io::{Cursor, Read}, path::{Path, PathBuf}, rc::Rc, sync::Arc};
 
-use attaca::{batch::{Batch as ObjectBatch, Operation as ObjectOperation}, digest::prelude::*,
-             hierarchy::Hierarchy,
-             object::{self, CommitAuthor, CommitBuilder, CommitRef, ObjectRef, TreeBuilder},
+use attaca::{batch::{Batch as ObjectBatch, Operation as ObjectOperation, SentTrees},
+             digest::{Sha3Digest, prelude::*}, hierarchy::Hierarchy, memory::MemoryBackend,
+             object::{self, Commit, CommitRef, ObjectRef, SmallBuilder, TreeBuilder, TreeRef},
              path::ObjectPath, store::prelude::*};
 use failure::{self, *};
-use futures::{stream, future::Either, prelude::*};
+use chrono::{DateTime, FixedOffset};
+use futures::{stream, future::Either, prelude::*, sync::mpsc::{self, UnboundedSender}};
+use futures_cpupool::CpuPool;
+use globset::GlobBuilder;
+use hex;
 use ignore::WalkBuilder;
 
 use {Repository, State};
-use cache::{Cache, Certainty, Status};
+use attributes::{self, Attributes, Normalize, PathAttributes};
+use cache::{Cache, Certainty, Snapshot, Status};
+use case;
+use config::CheckLevel;
+use editor::{self, Cleanup};
+use ident;
+use log;
+use pathspec::Excludes;
+use plumbing::{self, commit::CommitTree};
+use progress::{self, ProgressReader};
+use refs;
 use state::Head;
+use status::Change;
+use syntax::Rev;
+use walk::Walk;
+use xattrs;
+
+/// The name of the placeholder file which records an empty directory, when the repository is
+/// configured to keep them (see `Config::keep_empty_dirs`). Staging a directory with nothing at all
+/// in it, not even ignored files, stages an empty file of this name inside it instead, and checking
+/// it out creates the directory along with the file. Otherwise the placeholder is an ordinary file,
+/// and may be deleted once the directory has other contents.
+pub const KEEP_FILE: &'static str = ".attacakeep";
+
+/// The name of attaca's own ignore file. It is read wherever the local workspace is walked, in
+/// addition to `.gitignore` and `.ignore` files, and with the same syntax.
+pub const IGNORE_FILE: &'static str = ".attacaignore";
 
 /// Save the virtual workspace as a child commit of the previous commit.
 #[derive(Debug, StructOpt, Builder)]
 #[structopt(name = "commit")]
 pub struct CommitArgs {
-    /// Add a commit message.
+    /// Add a commit message. Without one, the message is written in an editor (see
+    /// `$ATTACA_EDITOR`) if stdin is a terminal, and otherwise the commit has no message.
     #[structopt(short = "m", long = "m")]
     pub message: Option<String>,
 
-    /// Add a commit author.
+    /// Reuse the message and author of another commit. An explicit `-m` or `--author` takes
+    /// precedence over what is reused.
+    #[structopt(short = "C", long = "reuse-message", name = "REUSE_COMMIT")]
+    pub reuse_message: Option<Rev>,
+
+    /// Like `--reuse-message`, but open the reused message in an editor to change it first.
+    #[structopt(short = "c", long = "reedit-message", name = "REEDIT_COMMIT",
+                raw(conflicts_with = r#""REUSE_COMMIT""#))]
+    pub reedit_message: Option<Rev>,
+
+    /// Add a commit author. This takes precedence over `$ATTACA_AUTHOR_NAME` and
+    /// `$ATTACA_AUTHOR_EMAIL`.
     #[structopt(long = "author")]
     pub author: Option<String>,
 
+    /// Set the date of the commit, in RFC 3339 format. This takes precedence over
+    /// `$ATTACA_AUTHOR_DATE`; if neither is given, the current time is used.
+    #[structopt(long = "date", parse(try_from_str = "ident::parse_date"))]
+    pub date: Option<DateTime<FixedOffset>>,
+
     /// Instead of making a new commit, load the previous commit and update it.
     #[structopt(long = "amend")]
     pub amend: bool,
 
-    /// Force a commit regardless of warnings.
+    /// Force a commit regardless of warnings, including failed pre-commit checks and an empty
+    /// message written in the editor.
     #[structopt(long = "force")]
     pub force: bool,
+
+    /// Skip the pre-commit checks set in the repository configuration.
+    #[structopt(long = "no-verify")]
+    pub no_verify: bool,
+
+    /// Commit only these paths from the virtual workspace, on top of the previous commit. Anything
+    /// else in the virtual workspace stays staged for a later commit. A named path which is absent
+    /// from the virtual workspace but present in the previous commit is committed as a removal;
+    /// one which is in neither is an error.
+    #[structopt(long = "only", parse(from_os_str))]
+    pub only: Vec<PathBuf>,
+
+    /// How to clean up the commit message: `strip` removes lines starting with `#` and surplus
+    /// whitespace, `whitespace` removes only the whitespace, `scissors` also discards everything
+    /// from a scissors line onwards, and `verbatim` changes nothing. Defaults to `strip` for a
+    /// message written in the editor and `verbatim` otherwise.
+    #[structopt(long = "cleanup", raw(possible_values = "Cleanup::VARIANTS"))]
+    pub cleanup: Option<Cleanup>,
+
+    /// Treat empty directories as deleted: prune every empty tree from what is committed, and
+    /// from the virtual workspace along with it. This is only needed in a repository configured
+    /// to keep empty trees (see `init --keep-empty-trees`), since otherwise none are staged. Every
+    /// tree in the commit is visited to find them.
+    #[structopt(long = "empty-as-delete")]
+    pub empty_as_delete: bool,
+
+    /// Show what would be committed, without committing it. The same checks are run as for a
+    /// real commit, but nothing is written: not the commit, not the HEAD or branch, and not the
+    /// virtual workspace. No editor is opened, so `--reedit-message` shows the reused message as
+    /// it is. This can't be combined with `--only` or `--empty-as-delete`, since both write new
+    /// trees to the store.
+    #[structopt(long = "dry-run", raw(conflicts_with_all = r#"&["only", "empty_as_delete"]"#))]
+    pub dry_run: bool,
 }
 
 #[must_use = "CommitOut contains futures which must be driven to completion!"]
 pub struct CommitOut<'r> {
-    pub blocking: Box<Future<Item = (), Error = Error> + 'r>,
+    /// The new commit, with its digest. With `--dry-run`, the commit which would have been made,
+    /// which has no digest since it was never sent to the store.
+    pub blocking:
+        Box<Future<Item = (Option<CommitRef<String>>, Commit<String>), Error = Error> + 'r>,
 }
 
 impl<'r> fmt::Debug for CommitOut<'r> {
@@ -50,22 +134,92 @@ impl<'r> fmt::Debug for CommitOut<'r> {
 #[derive(Debug, StructOpt, Builder)]
 #[structopt(name = "stage")]
 pub struct StageArgs {
-    /// Paths of files to load.
-    #[structopt(name = "PATH", parse(from_os_str), raw(required = r#"true"#))]
+    /// Paths of files to load. A path of the form `:!PATTERN` instead leaves out every path
+    /// matching the glob `PATTERN` (see `pathspec`); whatever the virtual workspace holds for
+    /// those paths is left as it is. Any other path containing glob characters, such as
+    /// `'src/**/*.rs'`, stands for the local files it matches (see `GLOB_CHARS`).
+    #[structopt(name = "PATH", parse(from_os_str), raw(required_unless = r#""all""#))]
     pub paths: Vec<PathBuf>,
 
-    /// Load files from the previous commit into the virtual workspace instead.
+    /// Load files from the previous commit into the virtual workspace instead, like `unstage`,
+    /// for which this is kept as an alias. Unlike `unstage`, paths are removed from the virtual
+    /// workspace if HEAD has no commits yet.
     #[structopt(short = "p", long = "previous")]
     pub previous: bool,
 
-    /// Do not track progress.
-    #[structopt(short = "q", long = "quiet")]
+    /// With `--previous`, discard the whole virtual workspace at once, resetting it to the
+    /// previous commit (or to empty if there is none). Local files are left untouched.
+    #[structopt(short = "a", long = "all", raw(requires = r#""previous""#),
+                raw(conflicts_with = r#""PATH""#))]
+    pub all: bool,
+
+    /// Report progress even if stderr is not a terminal.
+    #[structopt(long = "progress", raw(conflicts_with = r#""no_progress""#))]
+    pub progress: bool,
+
+    /// Do not report progress, even if stderr is a terminal.
+    #[structopt(long = "no-progress")]
+    pub no_progress: bool,
+
+    /// Deprecated alias of `--no-progress`, kept for callers which still set it. On the command
+    /// line, the global `-q`/`--quiet` flag sets it.
+    #[structopt(long = "quiet-progress", raw(hidden = "true"),
+                raw(conflicts_with = r#""progress""#))]
     pub quiet: bool,
+
+    /// Record the files as going to be added, without staging their contents. Each gets an empty
+    /// placeholder in the virtual workspace, which staging the file later replaces. A commit
+    /// fails while any placeholders remain.
+    #[structopt(short = "N", long = "intent-to-add", raw(conflicts_with = r#""previous""#))]
+    pub intent_to_add: bool,
+}
+
+/// How far hashing a single file has got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileProgress {
+    pub path: ObjectPath,
+    pub processed_bytes: u64,
+    pub total_bytes: u64,
+}
+
+/// Progress in staging, as it's reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StageProgress {
+    /// More of a file has been hashed. This is reported for every `progress::REPORT_INTERVAL`
+    /// bytes, so not at all for small files.
+    File(FileProgress),
+
+    /// One of the paths given to `stage` has been processed.
+    Path(ObjectPath),
+}
+
+/// Where to report the progress of hashing a single file.
+#[derive(Debug, Clone)]
+pub(crate) struct FileProgressSink {
+    sender: UnboundedSender<StageProgress>,
+    path: ObjectPath,
+}
+
+impl FileProgressSink {
+    fn new(sender: UnboundedSender<StageProgress>, path: ObjectPath) -> Self {
+        Self { sender, path }
+    }
+
+    fn report(&self, processed_bytes: u64, total_bytes: u64) {
+        // The receiving end may have been dropped; that's fine.
+        let _ = self.sender.unbounded_send(StageProgress::File(FileProgress {
+            path: self.path.clone(),
+            processed_bytes,
+            total_bytes,
+        }));
+    }
 }
 
 #[must_use = "StageOut contains futures which must be driven to completion!"]
 pub struct StageOut<'r> {
-    pub progress: Box<Stream<Item = (), Error = Error> + 'r>,
+    /// Progress in staging, which is always empty with `quiet` or `--no-progress`, and otherwise
+    /// empty unless stderr is a terminal or `--progress` is given.
+    pub progress: Box<Stream<Item = StageProgress, Error = Error> + 'r>,
     pub blocking: Box<Future<Item = (), Error = Error> + 'r>,
 }
 
@@ -78,6 +232,47 @@ impl<'r> fmt::Debug for StageOut<'r> {
     }
 }
 
+/// Reset paths in the virtual workspace to the previous commit, undoing `stage`. Local files are
+/// left untouched.
+#[derive(Debug, StructOpt, Builder)]
+#[structopt(name = "unstage")]
+pub struct UnstageArgs {
+    /// Paths to reset. A path missing from the previous commit is removed from the virtual
+    /// workspace. Exclude pathspecs work as they do for `stage`; glob patterns, which match local
+    /// files, can't be used.
+    #[structopt(name = "PATH", parse(from_os_str), raw(required_unless = r#""all""#))]
+    pub paths: Vec<PathBuf>,
+
+    /// Reset the whole virtual workspace to the previous commit (or to empty if there is none).
+    #[structopt(short = "a", long = "all", raw(conflicts_with = r#""PATH""#))]
+    pub all: bool,
+}
+
+#[must_use = "UnstageOut contains futures which must be driven to completion!"]
+pub struct UnstageOut<'r> {
+    pub blocking: Box<Future<Item = (), Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for UnstageOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UnstageOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+/// How many files of a directory being staged are hashed at once, unless `core.hashJobs` says
+/// otherwise. Hashing itself is done on the worker threads (see `core.workerThreads`); this bounds
+/// how many files are open and waiting for them.
+pub const DEFAULT_HASH_JOBS: usize = 16;
+
+/// Characters which make a path given to `stage` a glob pattern, unless something in the local
+/// workspace has that very path. A pattern is matched against the paths of the local files which
+/// staging a directory would pick up, relative to the root of the workspace, so ignored files
+/// never match. `*` and `?` don't match `/`, while `**` matches any number of directories. Files
+/// which have been deleted locally can't match, so a pattern never stages a deletion.
+pub const GLOB_CHARS: &'static [char] = &['*', '?', '[', '{'];
+
 /// Type for the two kinds of possible operations on the candidate tree.
 #[derive(Debug, Clone, Copy)]
 pub enum OpKind {
@@ -112,11 +307,99 @@ impl BatchOp {
     }
 }
 
+/// Prune every empty tree beneath `tree_ref`, returning `None` if `tree_ref` is left empty itself.
+/// Trees which had nothing to prune are not sent again.
+fn prune_empty_trees<B: Backend>(
+    store: Store<B>,
+    tree_ref: TreeRef<Handle<B>>,
+) -> Box<Future<Item = Option<TreeRef<Handle<B>>>, Error = Error>> {
+    let blocking = async_block! {
+        let mut builder = TreeBuilder::new();
+        let mut pruned_any = false;
+
+        for (name, object_ref) in await!(tree_ref.fetch())? {
+            match object_ref {
+                ObjectRef::Tree(subtree_ref) => {
+                    match await!(prune_empty_trees(store.clone(), subtree_ref.clone()))? {
+                        Some(pruned_ref) => {
+                            pruned_any |= pruned_ref != subtree_ref;
+                            builder.insert(name, ObjectRef::Tree(pruned_ref));
+                        }
+                        None => pruned_any = true,
+                    }
+                }
+                other => {
+                    builder.insert(name, other);
+                }
+            }
+        }
+
+        if builder.is_empty() {
+            Ok(None)
+        } else if pruned_any {
+            Ok(Some(await!(builder.as_tree().send(&store))?))
+        } else {
+            Ok(Some(tree_ref))
+        }
+    };
+
+    Box::new(blocking)
+}
+
+/// Hash a local file and send it to the store, normalizing its line endings first if its
+/// attributes say that it is text. The progress of reading the file is reported to `progress`, if
+/// given.
+pub(crate) fn share_file<B: Backend>(
+    store: Store<B>,
+    path_attributes: PathAttributes,
+    absolute_path: &Path,
+    progress: Option<FileProgressSink>,
+) -> Result<Box<Future<Item = ObjectRef<Handle<B>>, Error = Error>>, Error> {
+    let file = File::open(absolute_path).context("Error opening local file")?;
+    let total_bytes = file.metadata()
+        .context("Error reading local file metadata")?
+        .len();
+    let mut file = ProgressReader::new(file, move |processed_bytes| {
+        if let Some(ref sink) = progress {
+            sink.report(processed_bytes, total_bytes);
+        }
+    });
+
+    if !path_attributes.may_be_text() {
+        return Ok(Box::new(object::share(file, store)));
+    }
+
+    let mut head = Vec::with_capacity(attributes::SNIFF_LEN);
+    (&mut file)
+        .take(attributes::SNIFF_LEN as u64)
+        .read_to_end(&mut head)
+        .context("Error reading local file")?;
+    let is_text = path_attributes.is_text(&head);
+    let content = Cursor::new(head).chain(file);
+
+    if is_text {
+        Ok(Box::new(object::share(Normalize::new(content), store)))
+    } else {
+        Ok(Box::new(object::share(content, store)))
+    }
+}
+
+/// Find out what the digest of a local file would be if `share_file` sent it to a store, without
+/// storing it anywhere.
+pub(crate) fn hash_file(
+    path_attributes: PathAttributes,
+    absolute_path: &Path,
+) -> Result<ObjectRef<Sha3Digest>, Error> {
+    let store = Store::new(MemoryBackend::digests_only());
+    let objref = share_file(store, path_attributes, absolute_path, None)?.wait()?;
+    objref.digest::<Sha3Digest>().wait()
+}
+
 impl<B: Backend> Repository<B> {
     pub fn commit<'r>(&'r mut self, args: CommitArgs) -> CommitOut<'r> {
         let blocking = async_block! {
             let state = self.get_state()?;
-            let branches = await!(self.store.load_branches())?;
+            let branches = await!(refs::load_heads(&self.store))?;
 
             let candidate = state.candidate.clone().ok_or_else(|| {
                 format_err!(
@@ -132,55 +415,220 @@ impl<B: Backend> Repository<B> {
             };
             let maybe_head = await!(maybe_head_ref.as_ref().map(CommitRef::fetch))?;
 
+            ensure!(
+                state.rebase.is_none(),
+                "Cannot commit while a rebase is in progress! Stage the resolved paths, then use \
+                 `rebase --continue`."
+            );
+
+            if let Some(ref merge) = state.merge {
+                ensure!(
+                    merge.is_resolved(),
+                    "Cannot commit with unresolved merge conflicts! Stage the resolved paths first."
+                );
+                ensure!(!args.amend, "Cannot amend while a merge is in progress!");
+                ensure!(args.only.is_empty(), "Cannot commit only some paths of a merge!");
+            }
+
+            if !state.intent_to_add.is_empty() {
+                let listing = state
+                    .intent_to_add
+                    .iter()
+                    .map(|path| format!("\n    {}", path))
+                    .collect::<String>();
+                bail!(
+                    "Some paths were marked with --intent-to-add but their contents were never \
+                     staged! Stage or unstage them first:{}",
+                    listing
+                );
+            }
+
+            ensure!(
+                !args.dry_run || (args.only.is_empty() && !args.empty_as_delete),
+                "A dry run cannot be combined with --only or --empty-as-delete!"
+            );
+
+            let whole_candidate = args.only.is_empty();
+            let subtree = if whole_candidate {
+                candidate
+            } else {
+                let maybe_head_subtree = maybe_head.as_ref().map(|head| head.as_subtree().clone());
+                await!(self.partial_subtree(maybe_head_subtree, candidate, args.only))?
+            };
+
+            let subtree = if args.empty_as_delete {
+                match await!(prune_empty_trees(self.store.clone(), subtree))? {
+                    Some(pruned) => pruned,
+                    None => await!(TreeBuilder::new().as_tree().send(&self.store))?,
+                }
+            } else {
+                subtree
+            };
+
             if let Some(ref head_commit) = maybe_head {
                 ensure!(
-                    head_commit.as_subtree() != &candidate || args.force,
+                    head_commit.as_subtree() != &subtree || state.merge.is_some() || args.force,
                     "Previous commit is identical to virtual workspace! \
                      No changes will be committed - use --force to override."
                 );
             }
 
-            let mut commit_builder = if args.amend {
+            let checks = self.get_config()?.commit_checks;
+            if checks.is_enabled() && !args.no_verify {
+                let maybe_head_subtree = maybe_head.as_ref().map(|head| head.as_subtree().clone());
+                let violations =
+                    await!(self.check_changes(checks, maybe_head_subtree, subtree.clone()))?;
+                let (rejected, warned): (Vec<_>, Vec<_>) = violations
+                    .into_iter()
+                    .partition(|violation| violation.level == CheckLevel::Reject && !args.force);
+
+                for violation in warned {
+                    eprintln!("warning: {}", violation);
+                }
+
+                if !rejected.is_empty() {
+                    let listing = rejected
+                        .iter()
+                        .map(|violation| format!("\n    {}", violation))
+                        .collect::<String>();
+                    bail!(
+                        "Pre-commit checks failed! Fix these problems, or use --no-verify or \
+                         --force to commit anyway:{}",
+                        listing
+                    );
+                }
+            }
+
+            let maybe_head_subtree = maybe_head.as_ref().map(|head| head.as_subtree().clone());
+
+            // An amended commit takes the place of the previous one, keeping its parents, and
+            // anything not given anew is taken from it.
+            let (mut inputs, amended) = if args.amend {
                 match maybe_head {
-                    Some(head_commit) => head_commit.diverge(),
+                    Some(head_commit) => {
+                        let mut inputs =
+                            CommitTree::new(subtree, head_commit.as_parents().to_owned());
+                        inputs.headers = head_commit.as_headers().clone();
+                        (inputs, Some(head_commit))
+                    }
                     None => bail!("No previous commit to amend!"),
                 }
             } else {
-                let mut builder = CommitBuilder::new();
-                builder.parents(maybe_head_ref.into_iter());
-                builder
+                let parents = maybe_head_ref
+                    .into_iter()
+                    .chain(state.merge.as_ref().map(|merge| merge.theirs.clone()));
+                (CommitTree::new(subtree, parents), None)
+            };
+
+            let (reused, reedit) = match (args.reuse_message, args.reedit_message) {
+                (Some(rev), _) => (Some(rev), false),
+                (None, Some(rev)) => (Some(rev), true),
+                (None, None) => (None, false),
+            };
+            let maybe_reused = match reused {
+                Some(rev) => {
+                    let display = rev.to_string();
+                    let commit_ref = await!(plumbing::resolve_rev(self, rev))?;
+                    let commit = await!(commit_ref.fetch())
+                        .with_context(|_| format!("Error fetching commit {}", display))?;
+                    Some(commit)
+                }
+                None => None,
+            };
+
+            let cleanup = args.cleanup;
+            let message = match (args.message, maybe_reused.as_ref()) {
+                (Some(message), _) => Some(cleanup.unwrap_or(Cleanup::Verbatim).apply(&message)),
+                (None, Some(reused)) if reedit && !args.dry_run => {
+                    let edited = editor::edit(
+                        &self.path.join(".attaca/COMMIT_EDITMSG"),
+                        reused.as_message().unwrap_or(""),
+                    )?;
+                    let cleaned = cleanup.unwrap_or(Cleanup::Strip).apply(&edited);
+                    ensure!(
+                        !cleaned.trim().is_empty(),
+                        "Aborting commit due to an empty message."
+                    );
+                    Some(cleaned)
+                }
+                (None, Some(reused)) => reused
+                    .as_message()
+                    .map(|message| cleanup.unwrap_or(Cleanup::Verbatim).apply(message)),
+                // Without any message given, one is asked for, if there's anyone to ask. An
+                // amended commit keeps its message instead, and a dry run never asks.
+                (None, None) if amended.is_none() && !args.dry_run && editor::is_interactive() => {
+                    let changes = await!(self.commit_template(
+                        maybe_head_subtree,
+                        inputs.subtree.clone()
+                    ))?;
+                    let edited = editor::edit(&self.path.join(".attaca/COMMIT_EDITMSG"), &changes)?;
+                    let cleaned = cleanup.unwrap_or(Cleanup::Strip).apply(&edited);
+                    if cleaned.trim().is_empty() {
+                        ensure!(
+                            args.force,
+                            "Aborting commit due to an empty message. Use --force to commit \
+                             without one."
+                        );
+                        None
+                    } else {
+                        Some(cleaned)
+                    }
+                }
+                (None, None) => None,
+            };
+
+            let author = match (args.author, maybe_reused) {
+                (None, Some(reused)) => Some(reused.as_author().clone()),
+                (flag, _) => ident::author(flag)?,
             };
 
-            commit_builder.subtree(candidate);
+            inputs.message = message;
+            inputs.author = author;
+            inputs.timestamp = ident::date(args.date)?;
+            inputs.committer_timestamp = Some(ident::committer_date()?);
 
-            if let Some(message) = args.message {
-                commit_builder.message(message.to_string());
+            if let Some(amended) = amended {
+                inputs.message = inputs.message.or_else(|| amended.as_message().map(str::to_owned));
+                inputs.author = inputs.author.or_else(|| Some(amended.as_author().clone()));
+                inputs.timestamp = inputs.timestamp.or_else(|| Some(*amended.as_timestamp()));
             }
 
-            if let Some(author) = args.author {
-                commit_builder.author(CommitAuthor {
-                    name: Some(author.to_string()),
-                    mbox: None,
-                });
+            if args.dry_run {
+                let commit = plumbing::commit::build_commit(inputs)?;
+                return Ok((None, await!(log::describe(commit))?));
             }
 
-            let commit_ref = await!(commit_builder.into_commit()?.send(&self.store))?;
+            let (commit_ref, commit) = await!(plumbing::commit::commit_tree(self, inputs))?;
+            let commit_id = await!(commit_ref.id())?;
+            let digest = hex::encode(commit_id.as_inner().borrow().as_bytes());
 
-            match state.head {
-                Head::Empty | Head::Detached(_) => {
-                    self.set_state(&State {
-                        head: Head::Detached(commit_ref),
-                        ..state
-                    })?;
-                }
+            let head = match state.head {
+                Head::Empty | Head::Detached(_) => Head::Detached(commit_ref),
                 Head::Branch(branch) => {
                     let mut new_branches = branches.clone();
-                    new_branches.insert(branch.into_string(), commit_ref.into_inner());
-                    await!(self.store.swap_branches(branches, new_branches))?;
+                    new_branches.insert(branch.clone().into_string(), commit_ref.into_inner());
+                    await!(refs::swap_heads(&self.store, branches, new_branches))?;
+                    Head::Branch(branch)
                 }
-            }
+            };
 
-            Ok(())
+            // Pruning changes the committed tree, so the virtual workspace follows it. With
+            // `--only`, the rest of the virtual workspace is left as it was.
+            let candidate = if args.empty_as_delete && whole_candidate {
+                Some(commit.as_subtree().clone())
+            } else {
+                state.candidate.clone()
+            };
+
+            self.set_state(&State {
+                head,
+                candidate,
+                merge: None,
+                ..state
+            })?;
+
+            let (commit_ref, commit) = await!(log::entry(digest, commit))?;
+            Ok((Some(commit_ref), commit))
         };
 
         CommitOut {
@@ -188,31 +636,288 @@ impl<B: Backend> Repository<B> {
         }
     }
 
+    /// The initial contents of the file in which a commit message is written: instructions, and
+    /// a listing of the changes to be committed, all commented out.
+    fn commit_template<'r>(
+        &'r self,
+        maybe_head_subtree: Option<TreeRef<Handle<B>>>,
+        subtree: TreeRef<Handle<B>>,
+    ) -> impl Future<Item = String, Error = Error> + 'r {
+        async_block! {
+            let head_subtree = match maybe_head_subtree {
+                Some(head_subtree) => head_subtree,
+                None => await!(TreeBuilder::new().as_tree().send(&self.store))?,
+            };
+            let mut changes = await!(Self::compare_subtrees(head_subtree, subtree).collect())?;
+            changes.sort_by(|a, b| a.as_path().cmp(b.as_path()));
+
+            let mut template = String::from(
+                "\n\
+                 # Please enter the commit message for your changes. Lines starting with '#'\n\
+                 # will be ignored, and an empty message aborts the commit.\n\
+                 #\n\
+                 # Changes to be committed:\n",
+            );
+            for change in changes {
+                let label = match change {
+                    Change::Added(_) => "new file",
+                    Change::Modified(_) => "modified",
+                    Change::Removed(_) => "deleted",
+                };
+                template.push_str(&format!("#\t{}:   {}\n", label, change.as_path()));
+            }
+
+            Ok(template)
+        }
+    }
+
+    /// Build the tree of the previous commit with only the given paths taken from the virtual
+    /// workspace.
+    fn partial_subtree<'r>(
+        &'r self,
+        maybe_head_subtree: Option<TreeRef<Handle<B>>>,
+        candidate: TreeRef<Handle<B>>,
+        paths: Vec<PathBuf>,
+    ) -> impl Future<Item = TreeRef<Handle<B>>, Error = Error> + 'r {
+        async_block! {
+            let candidate_hierarchy = self.hierarchy(candidate)?;
+            let (head_hierarchy, tree_builder) = match maybe_head_subtree {
+                Some(head_subtree) => (
+                    self.hierarchy(head_subtree.clone())?,
+                    await!(head_subtree.fetch())?.diverge(),
+                ),
+                None => (Hierarchy::new(), TreeBuilder::new()),
+            };
+
+            let mut batch = ObjectBatch::<B>::new();
+            for raw_path in paths {
+                let (_, object_path) = self.resolve_path(raw_path)?;
+                let operation = match await!(candidate_hierarchy.get(object_path.clone()))? {
+                    Some(objref) => ObjectOperation::Add(object_path, objref),
+                    None => {
+                        ensure!(
+                            await!(head_hierarchy.get(object_path.clone()))?.is_some(),
+                            "{} is neither in the virtual workspace nor in the previous commit!",
+                            object_path
+                        );
+                        ObjectOperation::Delete(object_path)
+                    }
+                };
+                batch = batch.add(operation)?;
+            }
+
+            let built = await!(batch.run(self.store.clone(), tree_builder))?;
+            Ok(await!(built.as_tree().send(&self.store))?)
+        }
+    }
+
     pub fn stage<'r>(&'r mut self, args: StageArgs) -> StageOut<'r> {
         let op = if args.previous {
             OpKind::Unstage
         } else {
             OpKind::Stage
         };
-        let batch = args.paths.into_iter().map(move |path| BatchOp { path, op });
-        let progress = stream::empty();
+        let intent_to_add = args.intent_to_add;
+        let all = args.all;
+        let split = Excludes::split(args.paths);
+        let (progress, sender): (Box<Stream<Item = StageProgress, Error = Error>>, _) =
+            if args.quiet || !progress::enabled(args.progress, args.no_progress) {
+                (Box::new(stream::empty()), None)
+            } else {
+                let (sender, receiver) = mpsc::unbounded();
+                (
+                    Box::new(receiver.map_err(|()| unreachable!())),
+                    Some(sender),
+                )
+            };
         let blocking = async_block! {
-            await!(self.stage_batch(batch))?;
+            let (paths, excludes) = split?;
+            let paths = self.expand_globs(paths, op)?;
+            // A path which is itself excluded is skipped, rather than staged or removed.
+            let paths = paths
+                .into_iter()
+                .filter(|path| match self.resolve_path(path.clone()) {
+                    Ok((_, object_path)) => !excludes.is_excluded(&object_path),
+                    Err(_) => true,
+                })
+                .collect::<Vec<_>>();
+
+            if all {
+                await!(self.reset_candidate())?;
+            } else if intent_to_add {
+                await!(self.intend_to_add(paths))?;
+            } else {
+                let batch = paths.into_iter().map(move |path| BatchOp { path, op });
+                await!(self.stage_batch_with_progress(batch, excludes, sender))?;
+            }
             Ok(())
         };
 
         StageOut {
-            progress: Box::new(progress),
+            progress,
+            blocking: Box::new(blocking),
+        }
+    }
+
+    /// Reset the given paths in the virtual workspace to the previous commit. This is what
+    /// `stage --previous` does, except that it's an error for HEAD to have no commits, rather than
+    /// something which removes every given path from the virtual workspace. `--all` is the
+    /// exception: without a previous commit, it empties the virtual workspace, as
+    /// `stage --previous --all` does.
+    pub fn unstage<'r>(&'r mut self, args: UnstageArgs) -> UnstageOut<'r> {
+        let UnstageArgs { paths, all } = args;
+        let blocking = async_block! {
+            if all {
+                await!(self.reset_candidate())?;
+                return Ok(());
+            }
+
+            if await!(self.head())?.is_none() {
+                bail!("Nothing to unstage: HEAD has no commits to reset the paths to!");
+            }
+
+            let (paths, excludes) = Excludes::split(paths)?;
+            let paths = self.expand_globs(paths, OpKind::Unstage)?;
+            let batch = paths
+                .into_iter()
+                .filter(|path| match self.resolve_path(path.clone()) {
+                    Ok((_, object_path)) => !excludes.is_excluded(&object_path),
+                    Err(_) => true,
+                })
+                .map(BatchOp::unstage)
+                .collect::<Vec<_>>();
+            await!(self.stage_batch_with_progress(batch, excludes, None))?;
+            Ok(())
+        };
+
+        UnstageOut {
             blocking: Box::new(blocking),
         }
     }
 
+    /// Replace each glob pattern among the paths given to `stage` with the local files it
+    /// matches, in path order. See `GLOB_CHARS`. A pattern which matches nothing is an error,
+    /// rather than something to stage as a deletion.
+    fn expand_globs(&self, paths: Vec<PathBuf>, op: OpKind) -> Result<Vec<PathBuf>, Error> {
+        let mut expanded = Vec::new();
+        let mut local_files = None;
+
+        for path in paths {
+            let pattern = match path.to_str() {
+                Some(s) if s.contains(GLOB_CHARS) && !self.path.join(&path).exists() => {
+                    let relative = path.strip_prefix(&*self.path).unwrap_or(&path);
+                    relative.to_string_lossy().into_owned()
+                }
+                _ => {
+                    expanded.push(path);
+                    continue;
+                }
+            };
+
+            if let OpKind::Unstage = op {
+                bail!(
+                    "Glob patterns match local files, and can't be used to unstage: {}",
+                    pattern
+                );
+            }
+
+            let matcher = GlobBuilder::new(&pattern)
+                .literal_separator(true)
+                .build()
+                .with_context(|_| format!("Bad glob pattern {}", pattern))?
+                .compile_matcher();
+            if local_files.is_none() {
+                let files = self.local_files()
+                    .context("Error walking the local workspace")?;
+                local_files = Some(files);
+            }
+
+            let len_before = expanded.len();
+            expanded.extend(
+                local_files
+                    .as_ref()
+                    .unwrap()
+                    .iter()
+                    .map(ObjectPath::to_path)
+                    .filter(|local_path| matcher.is_match(local_path)),
+            );
+            ensure!(
+                expanded.len() > len_before,
+                "Glob pattern {} did not match any local files",
+                pattern
+            );
+        }
+
+        Ok(expanded)
+    }
+
+    /// Reset the virtual workspace to the tree of HEAD, or to nothing if HEAD has no commits, in a
+    /// single update of the state. No paths are visited and no local files are read or written.
+    /// Intent-to-add marks are cleared, since their placeholders go along with everything else.
+    fn reset_candidate<'r>(&'r mut self) -> impl Future<Item = (), Error = Error> + 'r {
+        async_block! {
+            let candidate = await!(self.head_tree())?;
+
+            let mut state = self.get_state().context("Error while fetching state")?;
+            state.candidate = candidate;
+            state.intent_to_add.clear();
+            self.set_state(&state).context("Error while updating state")?;
+
+            Ok(())
+        }
+    }
+
+    /// Give each file an empty placeholder in the virtual workspace and mark it as intended to be
+    /// added. Files which are already in the virtual workspace are left alone.
+    fn intend_to_add<'r>(
+        &'r mut self,
+        paths: Vec<PathBuf>,
+    ) -> impl Future<Item = (), Error = Error> + 'r {
+        async_block! {
+            let state = self.get_state()?;
+            let candidate_hierarchy = match state.candidate.clone() {
+                Some(candidate) => self.hierarchy(candidate)?,
+                None => Hierarchy::new(),
+            };
+            let placeholder =
+                ObjectRef::Small(await!(SmallBuilder::new().as_small().send(&self.store))?);
+
+            let mut batch = ObjectBatch::<B>::new();
+            let mut intended = Vec::new();
+            for raw_path in paths {
+                let (absolute_path, object_path) = self.resolve_path(raw_path)?;
+                ensure!(
+                    absolute_path.is_file(),
+                    "{} is not a file in the local workspace!",
+                    object_path
+                );
+
+                if await!(candidate_hierarchy.get(object_path.clone()))?.is_none() {
+                    let operation = ObjectOperation::Add(object_path.clone(), placeholder.clone());
+                    batch = batch.add(operation)?;
+                    intended.push(object_path);
+                }
+            }
+
+            await!(self.stage_objects(batch)).context("Error while staging placeholders")?;
+
+            let mut state = self.get_state().context("Error while fetching state")?;
+            state.intent_to_add.extend(intended);
+            self.set_state(&state).context("Error while updating state")?;
+
+            Ok(())
+        }
+    }
+
     #[async]
-    fn do_process_file(
+    pub(crate) fn do_process_file(
         store: Store<B>,
         cache: Cache<B>,
+        pool: CpuPool,
+        path_attributes: PathAttributes,
         absolute_path: PathBuf,
         object_path: ObjectPath,
+        progress: Option<FileProgressSink>,
     ) -> Result<ObjectRef<Handle<B>>, Error> {
         let status = cache
             .status(&object_path)
@@ -223,6 +928,14 @@ impl<B: Backend> Repository<B> {
                 let resolution = snapshot.as_object_ref().map(|odr| odr.resolve_id(&store));
                 resolution
             }
+            // A file whose timestamps match the cache but are too recent to prove that it hasn't
+            // changed since (see `Inode::is_unchanged`) could have been rewritten within the same
+            // tick, even to the same size, so it has to be hashed again. It only has to be sent
+            // to the store if that turns up different contents, though.
+            Status::Extant(Certainty::Unknown, ref snapshot) => {
+                let resolution = snapshot.as_object_ref().map(|odr| odr.resolve_id(&store));
+                resolution
+            }
             _ => None,
         };
 
@@ -230,74 +943,292 @@ impl<B: Backend> Repository<B> {
             .context("Error resolving cached digest")?
             .and_then(|x| x)
         {
+            if let Status::Extant(Certainty::Unknown, snapshot) = status {
+                let cached_digest = await!(resolved.digest::<Sha3Digest>())?;
+                let hashed = {
+                    let absolute_path = absolute_path.clone();
+                    pool.spawn_fn(move || hash_file(path_attributes, &absolute_path))
+                };
+                let local_digest = await!(hashed).context("Error hashing local file")?;
+
+                if local_digest == cached_digest {
+                    let id = await!(resolved.id()).context("Error fetching object digest")?;
+                    cache
+                        .resolve(snapshot, id)
+                        .context("Error during cache resolution for file")?;
+                    return Ok(resolved);
+                }
+
+                return await!(Self::share_and_resolve(
+                    store,
+                    cache,
+                    pool,
+                    path_attributes,
+                    absolute_path,
+                    snapshot,
+                    progress,
+                ));
+            }
+
             return Ok(resolved);
         }
 
         match status {
-            // TODO: Respect cache and reuse hash.
-            Status::Extant(_, snapshot) | Status::New(snapshot) => {
-                let mut file = File::open(&absolute_path).context("Error opening local file")?;
-                let objref =
-                    await!(object::share(file, store)).context("Error hashing/sending local file")?;
-                let id = await!(objref.id()).context("Error fetching object digest")?;
-                cache
-                    .resolve(snapshot, id)
-                    .context("Error during cache resolution for file")?;
+            Status::Extant(_, snapshot) | Status::New(snapshot) => await!(Self::share_and_resolve(
+                store,
+                cache,
+                pool,
+                path_attributes,
+                absolute_path,
+                snapshot,
+                progress,
+            )),
+            Status::Removed | Status::Extinct => bail!("File removed during processing!"),
+        }
+    }
 
-                Ok(objref)
+    /// The digest a local file would have if `do_process_file` staged it, found without sending
+    /// anything to the store. A file the cache proves unchanged has the digest recorded there, and
+    /// any other file is hashed.
+    #[async]
+    pub(crate) fn hash_local_file(
+        store: Store<B>,
+        cache: Cache<B>,
+        pool: CpuPool,
+        path_attributes: PathAttributes,
+        absolute_path: PathBuf,
+        object_path: ObjectPath,
+    ) -> Result<ObjectRef<Sha3Digest>, Error> {
+        let status = cache
+            .status(&object_path)
+            .context("Error during cache lookup for file")?;
+        let pre_resolution = match status {
+            Status::Extant(Certainty::Positive, ref snapshot) => {
+                snapshot.as_object_ref().map(|odr| odr.resolve_id(&store))
             }
-            Status::Removed | Status::Extinct => bail!("File removed during processing!"),
+            _ => None,
+        };
+
+        if let Some(resolved) = await!(pre_resolution)
+            .context("Error resolving cached digest")?
+            .and_then(|x| x)
+        {
+            return Ok(await!(resolved.digest::<Sha3Digest>())?);
         }
+
+        let hashed = pool.spawn_fn(move || hash_file(path_attributes, &absolute_path));
+        Ok(await!(hashed).context("Error hashing local file")?)
+    }
+
+    /// Hash a local file, send it to the store, and record the result in the cache.
+    #[async]
+    fn share_and_resolve(
+        store: Store<B>,
+        cache: Cache<B>,
+        pool: CpuPool,
+        path_attributes: PathAttributes,
+        absolute_path: PathBuf,
+        snapshot: Snapshot<B>,
+        progress: Option<FileProgressSink>,
+    ) -> Result<ObjectRef<Handle<B>>, Error> {
+        // Hashing (and compressing, for stores which do) is CPU-bound, so it's done on the worker
+        // pool rather than wherever this future happens to be driven.
+        let shared = pool.spawn_fn(move || {
+            share_file(store, path_attributes, &absolute_path, progress)
+                .and_then(|share| share.wait())
+        });
+        let objref = await!(shared).context("Error hashing/sending local file")?;
+        let id = await!(objref.id()).context("Error fetching object digest")?;
+        cache
+            .resolve(snapshot, id)
+            .context("Error during cache resolution for file")?;
+
+        Ok(objref)
     }
 
     #[async]
     fn do_process(
         store: Store<B>,
         cache: Cache<B>,
+        pool: CpuPool,
+        attributes: Arc<Attributes>,
+        keep_empty_dirs: bool,
+        keep_empty_trees: bool,
+        record_xattrs: bool,
+        hash_jobs: usize,
+        excludes: Arc<Excludes>,
+        sent: SentTrees<B>,
+        progress: Option<UnboundedSender<StageProgress>>,
         absolute_path: PathBuf,
         object_path: ObjectPath,
     ) -> Result<Option<ObjectRef<Handle<B>>>, Error> {
         if !absolute_path.exists() {
             return Ok(None);
         }
+        xattrs::ensure_not_reserved(&object_path)?;
 
         let file_type = absolute_path.symlink_metadata()?.file_type();
         if file_type.is_symlink() || file_type.is_file() {
+            let path_attributes = attributes.get(&object_path);
+            let sink = progress.map(|sender| FileProgressSink::new(sender, object_path.clone()));
             let objref = await!(Self::do_process_file(
                 store,
                 cache,
+                pool,
+                path_attributes,
                 absolute_path,
-                object_path
+                object_path,
+                sink
             ))?;
             Ok(Some(objref))
         } else {
+            // If nothing beneath this directory has changed since it was last staged, reuse the
+            // tree we built then instead of walking it again. A tree built with some paths
+            // excluded is incomplete, so it's neither reused nor recorded. Nothing is recorded
+            // either unless a change journal is attached, since only a journal can vouch for it.
+            let dir_snapshot = if excludes.is_empty() {
+                cache
+                    .dir_snapshot(&absolute_path, &object_path)
+                    .context("Error during cache lookup for directory")?
+            } else {
+                None
+            };
+            let pre_resolution = match dir_snapshot {
+                Some(ref snapshot) => cache
+                    .dir_status(snapshot)
+                    .context("Error during cache lookup for directory")?
+                    .map(|tree_id| TreeRef::new(tree_id).resolve_id(&store)),
+                None => None,
+            };
+
+            if let Some(resolved) = await!(pre_resolution)
+                .context("Error resolving cached directory digest")?
+                .and_then(|x| x)
+            {
+                return Ok(Some(ObjectRef::Tree(resolved)));
+            }
+
             let mut object_batch = ObjectBatch::<B>::new();
+            // The files to hash, which is done for several at once once the walk is over.
+            let mut files = Vec::new();
+            // The extended attributes of everything beneath this directory, by the directory
+            // which records them. See `xattrs`.
+            let mut manifests = BTreeMap::new();
             // TODO #33
-            let walk = WalkBuilder::new(&absolute_path).build();
+            let walk = WalkBuilder::new(&absolute_path)
+                .add_custom_ignore_filename(IGNORE_FILE)
+                .build();
 
             for direntry_res in walk {
                 let direntry = direntry_res?;
                 let file_type = direntry.file_type().unwrap();
 
+                if direntry.depth() > 0 {
+                    let relative_path = direntry.path().strip_prefix(&absolute_path)?;
+                    let full_path =
+                        ObjectPath::from_path(object_path.to_path().join(relative_path))?;
+                    if !excludes.is_excluded(&full_path) {
+                        xattrs::ensure_not_reserved(&full_path)?;
+
+                        let parent_and_name = (relative_path.parent(), relative_path.file_name());
+                        if let (Some(parent), Some(name)) = parent_and_name {
+                            if record_xattrs && !file_type.is_symlink() {
+                                let name = name.to_string_lossy().into_owned();
+                                manifests
+                                    .entry(ObjectPath::from_path(parent)?)
+                                    .or_insert_with(xattrs::Manifest::new)
+                                    .set(name, xattrs::read(direntry.path())?);
+                            }
+                        }
+                    }
+                }
+
                 if file_type.is_dir() {
+                    if keep_empty_dirs && fs::read_dir(direntry.path())?.next().is_none() {
+                        let relative_path = direntry.path().strip_prefix(&absolute_path)?;
+                        let keep_path = ObjectPath::from_path(relative_path.join(KEEP_FILE))?;
+                        if excludes.is_excluded(&(&object_path + &keep_path)) {
+                            continue;
+                        }
+
+                        let placeholder =
+                            ObjectRef::Small(await!(SmallBuilder::new().as_small().send(&store))?);
+                        let operation = ObjectOperation::Add(keep_path, placeholder);
+                        object_batch = await!(object_batch.add(operation))?;
+                    }
+
                     continue;
                 }
 
-                let object_path =
-                    ObjectPath::from_path(direntry.path().strip_prefix(&absolute_path)?)?;
-                // TODO: Concurrency here? Or more efficient not to?
-                let object_ref = await!(Self::do_process_file(
+                let relative_path = direntry.path().strip_prefix(&absolute_path)?;
+                let full_path = ObjectPath::from_path(object_path.to_path().join(relative_path))?;
+                if excludes.is_excluded(&full_path) {
+                    continue;
+                }
+
+                let path_attributes = attributes.get(&full_path);
+                let sink = progress
+                    .clone()
+                    .map(|sender| FileProgressSink::new(sender, full_path));
+                let object_path = ObjectPath::from_path(relative_path)?;
+                let hashed = Self::do_process_file(
                     store.clone(),
                     cache.clone(),
+                    pool.clone(),
+                    path_attributes,
                     direntry.path().to_owned(),
                     object_path.clone(),
-                ))?;
+                    sink,
+                ).map(move |object_ref| (object_path, object_ref));
+                files.push(hashed);
+            }
+
+            // Files finish hashing in any order, but a tree is the same whatever order its entries
+            // are added in, and sorting them keeps the batch the same from one run to the next.
+            let mut hashed = await!(
+                stream::iter_ok::<_, Error>(files)
+                    .buffer_unordered(hash_jobs.max(1))
+                    .collect()
+            )?;
+            hashed.sort_by(|a, b| a.0.cmp(&b.0));
+            for (object_path, object_ref) in hashed {
                 object_batch =
                     await!(object_batch.add(ObjectOperation::Add(object_path, object_ref)))?;
             }
 
-            let built = await!(object_batch.run(store.clone(), TreeBuilder::new()))?;
-            Ok(Some(ObjectRef::Tree(await!(built.as_tree().send(&store))?)))
+            for (dir, manifest) in manifests {
+                if manifest.is_empty() {
+                    continue;
+                }
+
+                let manifest_path = dir.push_back(xattrs::MANIFEST_FILE.to_owned());
+                let manifest_ref = await!(manifest.send(store.clone()))?;
+                object_batch =
+                    await!(object_batch.add(ObjectOperation::Add(manifest_path, manifest_ref)))?;
+            }
+
+            let built = await!(object_batch.run_deduplicated(
+                store.clone(),
+                TreeBuilder::new(),
+                sent.clone()
+            ))?;
+
+            // A directory with nothing to stage in it is staged as though it had been deleted,
+            // unless empty trees are kept.
+            if built.is_empty() && !keep_empty_trees {
+                return Ok(None);
+            }
+
+            let tree_ref = await!(sent.send(built.as_tree(), &store))?;
+
+            if let Some(snapshot) = dir_snapshot {
+                let tree_id = await!(tree_ref.id()).context("Error fetching tree digest")?;
+                cache
+                    .resolve_dir(snapshot, tree_id.into_inner())
+                    .context("Error during cache resolution for directory")?;
+            }
+
+            Ok(Some(ObjectRef::Tree(tree_ref)))
         }
     }
 
@@ -306,21 +1237,62 @@ impl<B: Backend> Repository<B> {
         absolute_path: PathBuf,
         object_path: ObjectPath,
     ) -> impl Future<Item = Option<ObjectRef<Handle<B>>>, Error = Error> {
-        Self::do_process(
-            self.store.clone(),
-            self.cache.clone(),
-            absolute_path,
-            object_path,
-        )
+        self.process_deduplicated(absolute_path, object_path, SentTrees::new())
     }
 
-    fn do_process_operation<'r>(
+    /// Like `process`, but skipping trees which have already been sent according to `sent`, so
+    /// that processing several paths with identical subtrees sends each tree only once.
+    pub fn process_deduplicated<'r>(
         &'r self,
-        hierarchy: Hierarchy<B>,
-        batch_op: BatchOp,
-    ) -> Result<impl Future<Item = ObjectOperation<B>, Error = Error>, Error> {
-        let BatchOp { path: raw_path, op } = batch_op;
+        absolute_path: PathBuf,
+        object_path: ObjectPath,
+        sent: SentTrees<B>,
+    ) -> impl Future<Item = Option<ObjectRef<Handle<B>>>, Error = Error> {
+        let excludes = Arc::new(Excludes::default());
+        self.process_excluding(absolute_path, object_path, sent, excludes, None)
+    }
+
+    /// Like `process_deduplicated`, but leaving out paths beneath `absolute_path` which `excludes`
+    /// matches, and reporting the progress of hashing files to `progress`, if given.
+    fn process_excluding<'r>(
+        &'r self,
+        absolute_path: PathBuf,
+        object_path: ObjectPath,
+        sent: SentTrees<B>,
+        excludes: Arc<Excludes>,
+        progress: Option<UnboundedSender<StageProgress>>,
+    ) -> impl Future<Item = Option<ObjectRef<Handle<B>>>, Error = Error> {
+        let store = self.store.clone();
+        let cache = self.cache.clone();
+        let pool = self.pool.clone();
+        let setup = Attributes::load(&self.path).and_then(|attributes| {
+            let config = self.get_config()?;
+            Ok((attributes, config))
+        });
+        setup
+            .into_future()
+            .and_then(move |(attributes, config)| {
+                Self::do_process(
+                    store,
+                    cache,
+                    pool,
+                    Arc::new(attributes),
+                    config.keep_empty_dirs,
+                    config.keep_empty_trees,
+                    config.xattrs,
+                    config.hash_jobs,
+                    excludes,
+                    sent,
+                    progress,
+                    absolute_path,
+                    object_path,
+                )
+            })
+    }
 
+    /// Find the absolute path and the object path of a path given on the command line, which is
+    /// either absolute or relative to the root of the repository.
+    pub(crate) fn resolve_path(&self, raw_path: PathBuf) -> Result<(PathBuf, ObjectPath), Error> {
         ensure!(
             !raw_path
                 .iter()
@@ -328,16 +1300,28 @@ impl<B: Backend> Repository<B> {
             "TODO #35: Better path parsing: currently normalizing `.` and `..` is not supported."
         );
 
-        let paths_res = if raw_path.is_absolute() {
+        if raw_path.is_absolute() {
             raw_path
                 .strip_prefix(&*self.path)
                 .map_err(failure::err_msg)
                 .and_then(|relative_path| ObjectPath::from_path(relative_path))
-                .map(|object_path| (raw_path, object_path))
+                .map(|object_path| (raw_path.clone(), object_path))
         } else {
             ObjectPath::from_path(&raw_path)
                 .map(|object_path| (self.path.join(&raw_path), object_path))
-        };
+        }
+    }
+
+    fn do_process_operation<'r>(
+        &'r self,
+        hierarchy: Hierarchy<B>,
+        sent: SentTrees<B>,
+        excludes: Arc<Excludes>,
+        progress: Option<UnboundedSender<StageProgress>>,
+        batch_op: BatchOp,
+    ) -> Result<impl Future<Item = ObjectOperation<B>, Error = Error>, Error> {
+        let BatchOp { path: raw_path, op } = batch_op;
+        let paths_res = self.resolve_path(raw_path);
 
         let future_res = paths_res.map(|(absolute_path, object_path)| {
             let future = match op {
@@ -347,8 +1331,13 @@ impl<B: Backend> Repository<B> {
                         .map_err(|e| e.context("Error processing file from previous commit")),
                 ),
                 OpKind::Stage => Either::B(
-                    self.process(absolute_path, object_path.clone())
-                        .map_err(|e| e.context("Error processing local file")),
+                    self.process_excluding(
+                        absolute_path,
+                        object_path.clone(),
+                        sent,
+                        excludes,
+                        progress,
+                    ).map_err(|e| e.context("Error processing local file")),
                 ),
             };
             future.map(|objref_opt| (object_path, objref_opt))
@@ -369,48 +1358,228 @@ impl<B: Backend> Repository<B> {
     fn process_operation<'r>(
         &'r self,
         hierarchy: Hierarchy<B>,
+        sent: SentTrees<B>,
+        excludes: Arc<Excludes>,
+        progress: Option<UnboundedSender<StageProgress>>,
         batch_op: BatchOp,
     ) -> impl Future<Item = ObjectOperation<B>, Error = Error> {
-        self.do_process_operation(hierarchy, batch_op)
+        self.do_process_operation(hierarchy, sent, excludes, progress, batch_op)
             .into_future()
             .flatten()
     }
 
     pub fn stage_batch<'r, I>(&'r mut self, batch: I) -> impl Future<Item = (), Error = Error> + 'r
+    where
+        I: IntoIterator<Item = BatchOp> + 'r,
+    {
+        self.stage_batch_with_progress(batch, Excludes::default(), None)
+    }
+
+    /// Find the entries beneath `tree_ref`, which is at `base` in the workspace, that `excludes`
+    /// matches. An excluded directory is found as a whole, not entry by entry.
+    fn find_excluded<'r>(
+        &'r self,
+        base: ObjectPath,
+        tree_ref: TreeRef<Handle<B>>,
+        excludes: Arc<Excludes>,
+    ) -> impl Future<Item = Vec<(ObjectPath, ObjectRef<Handle<B>>)>, Error = Error> + 'r {
+        let found = Rc::new(RefCell::new(Vec::new()));
+        let visitor_found = found.clone();
+        let visitor = move |path: &ObjectPath, object_ref: &ObjectRef<Handle<B>>| {
+            let full_path = &base + path;
+            let walk = if excludes.is_excluded(&full_path) {
+                visitor_found
+                    .borrow_mut()
+                    .push((full_path, object_ref.clone()));
+                Walk::Prune
+            } else {
+                Walk::Continue
+            };
+            let result: Result<Walk, Error> = Ok(walk);
+            result
+        };
+
+        self.walk_tree(tree_ref, visitor)
+            .blocking
+            .map(move |()| found.replace(Vec::new()))
+    }
+
+    /// Stage a batch of operations, reporting to `progress`, if given, each path as it is
+    /// processed and how far hashing large files has got. Paths which `excludes` matches are left
+    /// as they are in the virtual workspace.
+    fn stage_batch_with_progress<'r, I>(
+        &'r mut self,
+        batch: I,
+        excludes: Excludes,
+        progress: Option<UnboundedSender<StageProgress>>,
+    ) -> impl Future<Item = (), Error = Error> + 'r
     where
         I: IntoIterator<Item = BatchOp> + 'r,
     {
         async_block! {
             let state = self.get_state()?;
-            let branches = await!(self.store.load_branches())?;
+            let branches = await!(refs::load_heads(&self.store))?;
             let maybe_head_ref = match state.head {
                 Head::Empty => None,
                 Head::Detached(ref commit_ref) => Some(commit_ref.clone()),
                 Head::Branch(ref branch) => branches.get(branch.as_str()).cloned().map(CommitRef::new),
             };
             let hierarchy = match maybe_head_ref {
-                Some(head_ref) => Hierarchy::from(
+                Some(head_ref) => self.hierarchy(
                     await!(head_ref.fetch())
                         .context("Error while fetching head")?
                         .as_subtree()
                         .clone(),
-                ),
+                )?,
                 None => Hierarchy::new(),
             };
+            // Paths staged together may well share identical subtrees (vendored copies, say), so
+            // remember which trees have been sent to avoid sending them again.
+            let sent = SentTrees::new();
+            let excludes = Arc::new(excludes);
+            let batch = batch.into_iter().collect::<Vec<_>>();
+            let kinds = batch.iter().map(|batch_op| batch_op.op).collect::<Vec<_>>();
             let queue = stream::futures_ordered(
                 batch
                     .into_iter()
-                    .map(|batch_op| self.process_operation(hierarchy.clone(), batch_op)),
+                    .map(|batch_op| {
+                        let progress = progress.clone();
+                        self.process_operation(
+                            hierarchy.clone(),
+                            sent.clone(),
+                            excludes.clone(),
+                            progress.clone(),
+                            batch_op,
+                        )
+                            .map(move |op| {
+                                if let Some(ref sender) = progress {
+                                    // The receiving end may have been dropped; that's fine.
+                                    let path = op.as_object_path().clone();
+                                    let _ = sender.unbounded_send(StageProgress::Path(path));
+                                }
+                                op
+                            })
+                    }),
             );
+            let mut ops =
+                await!(queue.collect()).context("Error while processing stage operations")?;
+            self.cache.flush().context("Error while writing cache entries")?;
+            let staged_paths = ops.iter()
+                .map(|op| op.as_object_path().clone())
+                .collect::<Vec<_>>();
+            let xattr_targets = ops.iter()
+                .zip(kinds)
+                .map(|(op, kind)| match *op {
+                    ObjectOperation::Add(ref path, _) => (path.clone(), kind, true),
+                    ObjectOperation::Delete(ref path) => (path.clone(), kind, false),
+                })
+                .collect::<Vec<_>>();
+
+            // Staged trees have nothing beneath them which is excluded, and trees from the
+            // previous commit may have anything; either way, put back what the virtual workspace
+            // had at each excluded path. These operations come after those they amend, so they
+            // are batched on top of them.
+            if !excludes.is_empty() {
+                let candidate_hierarchy = match state.candidate.clone() {
+                    Some(candidate) => self.hierarchy(candidate)?,
+                    None => Hierarchy::new(),
+                };
+                let targets = ops.iter()
+                    .map(|op| match *op {
+                        ObjectOperation::Add(ref path, ObjectRef::Tree(ref tree_ref)) => {
+                            (path.clone(), Some(tree_ref.clone()))
+                        }
+                        _ => (op.as_object_path().clone(), None),
+                    })
+                    .collect::<Vec<_>>();
+
+                for (path, maybe_new_tree) in targets {
+                    let kept = match await!(candidate_hierarchy.get(path.clone()))? {
+                        Some(ObjectRef::Tree(tree_ref)) => {
+                            await!(self.find_excluded(path.clone(), tree_ref, excludes.clone()))?
+                        }
+                        _ => Vec::new(),
+                    };
+                    let brought_in = match maybe_new_tree {
+                        Some(tree_ref) => {
+                            await!(self.find_excluded(path, tree_ref, excludes.clone()))?
+                        }
+                        None => Vec::new(),
+                    };
+
+                    for (excluded_path, _) in brought_in {
+                        if !kept.iter().any(|&(ref kept_path, _)| *kept_path == excluded_path) {
+                            ops.push(ObjectOperation::Delete(excluded_path));
+                        }
+                    }
+                    ops.extend(kept.into_iter().map(|(kept_path, object_ref)| {
+                        ObjectOperation::Add(kept_path, object_ref)
+                    }));
+                }
+            }
+            // Staged trees carry the extended attributes of what's beneath them, but those of the
+            // staged paths themselves belong in the manifests of their parents.
+            if self.get_config()?.xattrs {
+                let candidate_hierarchy = match state.candidate.clone() {
+                    Some(candidate) => self.hierarchy(candidate)?,
+                    None => Hierarchy::new(),
+                };
+                let manifest_ops = await!(self.manifest_operations(
+                    candidate_hierarchy,
+                    hierarchy.clone(),
+                    xattr_targets
+                ))?;
+                ops.extend(manifest_ops);
+            }
+            // On a case-insensitive filesystem, a path which differs only in case from one
+            // already in the virtual workspace is most likely the same file, under another name.
+            if self.refuses_case_collisions()? {
+                let candidate_hierarchy = match state.candidate.clone() {
+                    Some(candidate) => self.hierarchy(candidate)?,
+                    None => Hierarchy::new(),
+                };
+                let mut added = Vec::new();
+                let mut deleted = HashSet::new();
+                for op in &ops {
+                    match *op {
+                        ObjectOperation::Add(ref path, _) => added.push(path.clone()),
+                        ObjectOperation::Delete(ref path) => {
+                            deleted.insert(path.clone());
+                        }
+                    }
+                }
+                let collisions =
+                    await!(case::staged_collisions(candidate_hierarchy, added, deleted))?;
+                if !collisions.is_empty() {
+                    return Err(case::collision_error(&collisions));
+                }
+            }
+
             let batch: ObjectBatch<B> = await!(
-                queue.fold(ObjectBatch::new(), |batch, op| batch.add(op))
+                stream::iter_ok(ops).fold(ObjectBatch::new(), |batch, op| batch.add(op))
             ).context("Error while batching stage operations")?;
             await!(self.stage_objects(batch)).context("Error while staging objects")?;
 
+            // Staging a conflicted path, or resetting it to the previous commit, marks it as
+            // resolved. The same goes for a path marked with intent-to-add.
+            let mut state = self.get_state().context("Error while fetching state")?;
+            for path in &staged_paths {
+                state.resolve_intent(path);
+            }
+            if let Some(mut merge) = state.merge.take() {
+                for path in &staged_paths {
+                    merge.resolve(path);
+                }
+                state.merge = Some(merge);
+            }
+            self.set_state(&state).context("Error while updating state")?;
+
             Ok(())
         }
     }
 
+    /// Apply a batch of operations to the virtual workspace. Whatever the batch says, subtrees it
+    /// leaves empty are pruned or kept according to `Config::keep_empty_trees`.
     pub fn stage_objects<'r>(
         &'r mut self,
         batch: ObjectBatch<B>,
@@ -424,6 +1593,8 @@ impl<B: Backend> Repository<B> {
                 None => TreeBuilder::new(),
             };
 
+            let keep_empty_trees = self.get_config()?.keep_empty_trees;
+            let batch = batch.keep_empty_trees(keep_empty_trees);
             let new_candidate_built = await!(batch.run(self.store.clone(), tree_builder))
                 .context("Error running batch on candidate")?;
             let candidate = if new_candidate_built.is_empty() && state.head.is_empty() {
@@ -440,3 +1611,87 @@ impl<B: Backend> Repository<B> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use tempdir::TempDir;
+    use url::Url;
+
+    use config::{StoreConfig, StoreKind};
+
+    const FILES: usize = 3;
+
+    /// Create a fresh repository in a temporary directory holding a few small files.
+    fn fresh_repository() -> (TempDir, Repository<MemoryBackend>) {
+        let workspace = TempDir::new("subito-stage").unwrap();
+        for i in 0..FILES {
+            File::create(workspace.path().join(format!("f{}", i)))
+                .and_then(|mut file| file.write_all(b"contents"))
+                .unwrap();
+        }
+
+        let repository = Repository::init_with(workspace.path().to_owned(), |_| {
+            let config = StoreConfig {
+                url: Url::parse("memory:")?,
+                kind: StoreKind::Memory,
+                encryption: None,
+            };
+            Ok((config, MemoryBackend::new()))
+        }).unwrap();
+
+        (workspace, repository)
+    }
+
+    /// Stage the files of a repository made by `fresh_repository`, counting the progress events
+    /// reported.
+    fn stage_files(repository: &mut Repository<MemoryBackend>, quiet: bool) -> usize {
+
+        let out = repository.stage(StageArgs {
+            paths: (0..FILES).map(|i| PathBuf::from(format!("f{}", i))).collect(),
+            previous: false,
+            all: false,
+            progress: true,
+            no_progress: false,
+            quiet,
+            intent_to_add: false,
+        });
+        let counted = out.progress.fold(0, |count, _| -> Result<_, Error> { Ok(count + 1) });
+        let (count, ()) = counted.join(out.blocking).wait().unwrap();
+        count
+    }
+
+    fn count_progress(quiet: bool) -> usize {
+        let (_workspace, mut repository) = fresh_repository();
+        stage_files(&mut repository, quiet)
+    }
+
+    #[test]
+    fn quiet_stage_reports_no_progress() {
+        assert_eq!(count_progress(true), 0);
+    }
+
+    #[test]
+    fn stage_reports_progress() {
+        assert!(count_progress(false) >= 1);
+    }
+
+    #[test]
+    fn unstage_all_without_commits_empties_the_candidate() {
+        let (_workspace, mut repository) = fresh_repository();
+        stage_files(&mut repository, true);
+        assert!(repository.get_state().unwrap().candidate.is_some());
+
+        repository
+            .unstage(UnstageArgs {
+                paths: Vec::new(),
+                all: true,
+            })
+            .blocking
+            .wait()
+            .unwrap();
+        assert!(repository.get_state().unwrap().candidate.is_none());
+    }
+}
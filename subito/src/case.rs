@@ -0,0 +1,205 @@
+//! Paths which differ only in case.
+//!
+//! A tree can hold two names which differ only in case, such as `README` and `readme`, but on a
+//! case-insensitive filesystem (as is usual on macOS and Windows) both name the same file. Checking
+//! out such a tree would write one over the other, and staging `readme` next to a `README` in the
+//! virtual workspace would quietly give the repository two copies of one file. Where the workspace
+//! is on a case-insensitive filesystem, checkout and staging refuse instead, naming the paths which
+//! collide.
+//!
+//! Whether the filesystem is case-insensitive is found out by probing the `.attaca` directory the
+//! first time it matters, and the answer is recorded in the configuration as
+//! `Config::case_collisions`. Setting that to `Refuse` or `Allow` forces one behaviour or the
+//! other.
+
+use std::{collections::{BTreeMap, HashSet}, ffi::OsStr, fs::{self, OpenOptions}, path::Path};
+
+use attaca::{hierarchy::Hierarchy, object::{ObjectRef, Tree, TreeRef}, path::ObjectPath,
+             store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+
+use Repository;
+use config::CaseCollisions;
+
+/// The file created in `.attaca` to probe whether the filesystem is case-insensitive.
+const PROBE_FILE: &'static str = "CaseProbe";
+
+/// The form in which names which differ only in case are equal.
+pub fn fold(name: &str) -> String {
+    name.to_lowercase()
+}
+
+/// Whether the filesystem holding `dir` is case-insensitive, found by creating a file there and
+/// looking for it by another case.
+pub fn probe(dir: &Path) -> Result<bool, Error> {
+    let probe_path = dir.join(PROBE_FILE);
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(&probe_path)
+        .with_context(|_| format_err!("Error creating {}", probe_path.display()))?;
+    let insensitive = dir.join(PROBE_FILE.to_lowercase()).exists();
+    fs::remove_file(&probe_path)?;
+
+    Ok(insensitive)
+}
+
+/// Groups of names which differ from each other only in case.
+pub fn colliding_names<'a, I>(names: I) -> Vec<Vec<String>>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut by_fold = BTreeMap::new();
+    for name in names {
+        by_fold
+            .entry(fold(name))
+            .or_insert_with(Vec::new)
+            .push(name.to_owned());
+    }
+
+    by_fold
+        .into_iter()
+        .map(|(_, group)| group)
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+/// Groups of paths in the tree at `path` which differ from each other only in case. Subtrees are
+/// not searched.
+pub fn tree_collisions<H>(tree: &Tree<H>, path: &ObjectPath) -> Vec<Vec<ObjectPath>> {
+    colliding_names(tree.keys().map(String::as_str))
+        .into_iter()
+        .map(|group| group.into_iter().map(|name| path.push_back(name)).collect())
+        .collect()
+}
+
+/// Groups of paths anywhere beneath the tree at `path`, which is searched with all of its
+/// subtrees, which differ from each other only in case.
+#[async]
+pub fn deep_tree_collisions<B: Backend>(
+    tree_ref: TreeRef<Handle<B>>,
+    path: ObjectPath,
+) -> Result<Vec<Vec<ObjectPath>>, Error> {
+    let mut collisions = Vec::new();
+    let mut stack = vec![(tree_ref, path)];
+    while let Some((tree_ref, path)) = stack.pop() {
+        let tree = await!(tree_ref.fetch())?;
+        collisions.extend(tree_collisions(&tree, &path));
+        for (name, objref) in tree {
+            if let ObjectRef::Tree(subtree_ref) = objref {
+                stack.push((subtree_ref, path.push_back(name)));
+            }
+        }
+    }
+
+    Ok(collisions)
+}
+
+/// An error naming groups of paths which differ only in case.
+pub fn collision_error(collisions: &[Vec<ObjectPath>]) -> Error {
+    let listing = collisions
+        .iter()
+        .map(|group| {
+            let paths = group.iter().map(ToString::to_string).collect::<Vec<_>>();
+            format!("\n    {}", paths.join(", "))
+        })
+        .collect::<String>();
+
+    format_err!(
+        "Some paths differ only in case, and would clobber each other on this case-insensitive \
+         filesystem:{}",
+        listing
+    )
+}
+
+/// Pairs of a path already in the virtual workspace `candidate` and a path in `added` which
+/// differs from it only in case. Paths listed in `deleted` are on their way out, and so collide
+/// with nothing.
+#[async]
+pub fn staged_collisions<B: Backend>(
+    candidate: Hierarchy<B>,
+    added: Vec<ObjectPath>,
+    deleted: HashSet<ObjectPath>,
+) -> Result<Vec<Vec<ObjectPath>>, Error> {
+    let mut collisions = Vec::new();
+
+    for path in added {
+        let as_path = path.to_path();
+        let (parent, name) = match (as_path.parent(), as_path.file_name().and_then(OsStr::to_str))
+        {
+            (Some(parent), Some(name)) => (ObjectPath::from_path(parent)?, name.to_owned()),
+            _ => continue,
+        };
+
+        let tree = match await!(candidate.get(parent.clone()))? {
+            Some(ObjectRef::Tree(tree_ref)) => await!(tree_ref.fetch())?,
+            _ => continue,
+        };
+
+        let folded = fold(&name);
+        for sibling in tree.keys() {
+            let sibling_path = parent.push_back(sibling.clone());
+            if *sibling != name && fold(sibling) == folded && !deleted.contains(&sibling_path) {
+                collisions.push(vec![sibling_path, path.clone()]);
+            }
+        }
+    }
+
+    Ok(collisions)
+}
+
+impl<B: Backend> Repository<B> {
+    /// Whether checkout and staging refuse paths which differ only in case. If the configuration
+    /// doesn't say, the filesystem is probed, and the answer recorded. See the module
+    /// documentation.
+    pub fn refuses_case_collisions(&self) -> Result<bool, Error> {
+        let mut config = self.get_config()?;
+
+        match config.case_collisions {
+            CaseCollisions::Refuse => Ok(true),
+            CaseCollisions::Allow => Ok(false),
+            CaseCollisions::Detect => {
+                let insensitive = probe(&self.path.join(".attaca"))?;
+                config.case_collisions = if insensitive {
+                    CaseCollisions::Refuse
+                } else {
+                    CaseCollisions::Allow
+                };
+                self.set_config(&config)?;
+
+                Ok(insensitive)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fold_ignores_only_case() {
+        assert_eq!(fold("README.md"), fold("readme.MD"));
+        assert_eq!(fold("Straße"), fold("STRAßE"));
+        assert_ne!(fold("readme"), fold("read_me"));
+    }
+
+    #[test]
+    fn colliding_names_groups_names_differing_only_in_case() {
+        let names = ["README", "src", "readme", "Makefile", "ReadMe", "SRC"];
+        assert_eq!(
+            colliding_names(names.iter().cloned()),
+            vec![
+                vec!["README".to_owned(), "readme".to_owned(), "ReadMe".to_owned()],
+                vec!["src".to_owned(), "SRC".to_owned()],
+            ]
+        );
+    }
+
+    #[test]
+    fn colliding_names_ignores_distinct_names() {
+        let names = ["a", "b", "c"];
+        assert!(colliding_names(names.iter().cloned()).is_empty());
+    }
+}
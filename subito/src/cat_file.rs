@@ -0,0 +1,342 @@
+//! Retrieve many objects in one go.
+//!
+//! `cat_file` takes a stream of object specs and gives back a stream of records, resolving and
+//! loading several objects at once. A spec is either a full hex digest, which names whatever
+//! object has it, a revision, which names a commit, or `REV:PATH`, which names the object at
+//! `PATH` in the tree of that commit; `REV:` names the tree itself.
+//!
+//! The store does not record what kind of object it holds, so the kind of an object named by its
+//! digest is found by decoding it: it is a commit if it decodes as one, otherwise a tree if it
+//! decodes as one, and otherwise a file. An empty object decodes as a tree, and is shown as one.
+//!
+//! On the command line, specs are read from stdin, one per line, and each record is written as
+//! `<digest> <kind> <size>`, followed with `--batch` by a line holding the object's contents. A
+//! spec which names no object is written as `<spec> missing`, with the reason on stderr; the rest
+//! of the batch carries on. The contents of a file (a small or large object) are its data; the
+//! contents of a tree or commit are the object as it is stored.
+//!
+//! With `--pretty`, trees and commits are shown in a readable form instead. A tree is shown as one
+//! line per entry, `<kind> <digest>\t<name>`. A commit is shown as a `tree` line, a `parent` line
+//! for each parent, `author`, `date` and `commit-date` lines (the last only if the commit has a
+//! committer timestamp of its own) and a line for each header, then a blank line and the message.
+
+use std::{fmt, str, borrow::Borrow, io::Read};
+
+use attaca::{digest::{prelude::*, Sha3Digest},
+             object::{CommitAuthor, CommitRef, LargeRef, ObjectKind, ObjectRef, SmallRef, TreeRef},
+             path::ObjectPath, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+use hex;
+
+use Repository;
+use diff;
+use log;
+use plumbing;
+use syntax::Rev;
+
+/// How many objects are retrieved at once unless `--jobs` says otherwise.
+pub const DEFAULT_JOBS: usize = 16;
+
+/// Show the digest, kind and size, and optionally the contents, of each object named on stdin.
+#[derive(Default, Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "cat-file")]
+pub struct CatFileArgs {
+    /// Also show the contents of each object.
+    #[structopt(long = "batch")]
+    pub batch: bool,
+
+    /// Show the contents of each object, with trees and commits in a readable form rather than as
+    /// they are stored. Implies `--batch`.
+    #[structopt(short = "p", long = "pretty")]
+    pub pretty: bool,
+
+    /// Retrieve up to this many objects at once. Defaults to 16.
+    #[structopt(long = "jobs")]
+    pub jobs: Option<usize>,
+
+    /// Show each object as soon as it has been retrieved, rather than in the order the specs were
+    /// given.
+    #[structopt(long = "unordered")]
+    pub unordered: bool,
+}
+
+/// An object named by a spec.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatFileObject {
+    /// The full hex digest of the object.
+    pub digest: String,
+    pub kind: ObjectKind,
+
+    /// The size of the object, as `Repository::object_size` reports it.
+    pub size: u64,
+
+    /// The contents of the object, if asked for.
+    pub contents: Option<Vec<u8>>,
+}
+
+/// The result of looking up a single spec.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatFileRecord {
+    pub spec: String,
+
+    /// `None` if the spec names no object.
+    pub object: Option<CatFileObject>,
+
+    /// Why the spec names no object, if it doesn't.
+    pub reason: Option<String>,
+}
+
+#[must_use = "CatFileOut contains futures which must be driven to completion!"]
+pub struct CatFileOut<'r> {
+    pub records: Box<Stream<Item = CatFileRecord, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for CatFileOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CatFileOut")
+            .field("records", &"OPAQUE")
+            .finish()
+    }
+}
+
+/// The name of an object kind, as written in records.
+pub fn kind_name(kind: ObjectKind) -> &'static str {
+    match kind {
+        ObjectKind::Small => "small",
+        ObjectKind::Large => "large",
+        ObjectKind::Tree => "tree",
+        ObjectKind::Commit => "commit",
+    }
+}
+
+/// How contents are shown, if they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Contents {
+    None,
+    Raw,
+    Pretty,
+}
+
+/// The readable form of an object. See the module documentation.
+#[async]
+fn pretty<B: Backend>(object_ref: ObjectRef<Handle<B>>) -> Result<Vec<u8>, Error> {
+    let mut lines = Vec::new();
+    match object_ref {
+        ObjectRef::Tree(tree_ref) => {
+            let tree = await!(tree_ref.fetch())?;
+            for (name, entry_ref) in tree {
+                let id = await!(entry_ref.as_inner().id())?;
+                lines.push(format!(
+                    "{} {}\t{}",
+                    kind_name(entry_ref.kind()),
+                    hex::encode(id.borrow().as_bytes()),
+                    name
+                ));
+            }
+        }
+        ObjectRef::Commit(commit_ref) => {
+            let commit = await!(log::describe(await!(commit_ref.fetch())?))?;
+            lines.push(format!("tree {}", commit.as_subtree().as_inner()));
+            for parent in commit.as_parents() {
+                lines.push(format!("parent {}", parent.as_inner()));
+            }
+            match *commit.as_author() {
+                CommitAuthor {
+                    name: Some(ref name),
+                    mbox: Some(ref mbox),
+                } => lines.push(format!("author {} <{}>", name, mbox)),
+                CommitAuthor {
+                    name: Some(ref name),
+                    mbox: None,
+                } => lines.push(format!("author {}", name)),
+                CommitAuthor {
+                    name: None,
+                    mbox: Some(ref mbox),
+                } => lines.push(format!("author <{}>", mbox)),
+                CommitAuthor {
+                    name: None,
+                    mbox: None,
+                } => {}
+            }
+            lines.push(format!("date {}", commit.as_timestamp()));
+            if commit.has_committer_timestamp() {
+                lines.push(format!("commit-date {}", commit.as_committer_timestamp()));
+            }
+            for (key, value) in commit.as_headers() {
+                lines.push(format!("header {} {:?}", key, value));
+            }
+            lines.push(String::new());
+            lines.push(commit.as_message().unwrap_or("").to_owned());
+        }
+        blob_ref => {
+            return Ok(await!(diff::read_blob(blob_ref))?
+                .ok_or_else(|| format_err!("Not a file"))?)
+        }
+    }
+
+    Ok(lines.join("\n").into_bytes())
+}
+
+/// Whether a spec is a full hex digest.
+fn is_digest(spec: &str) -> bool {
+    spec.len() == 2 * Sha3Digest::SIGNATURE.size && spec.chars().all(|c| c.is_digit(16))
+}
+
+/// The object a handle refers to, found by decoding it. See the module documentation. A file with
+/// children is a large object, whose size is where its last child ends, and whose depth is the
+/// number of levels of children beneath it.
+#[async]
+fn object_of_handle<B: Backend>(handle: Handle<B>) -> Result<ObjectRef<Handle<B>>, Error> {
+    let commit_ref = CommitRef::new(handle.clone());
+    if await!(plumbing::fetch_if_commit(commit_ref.clone()))?.is_some() {
+        return Ok(ObjectRef::Commit(commit_ref));
+    }
+
+    let tree_ref = TreeRef::new(handle.clone());
+    if await!(tree_ref.fetch()).is_ok() {
+        return Ok(ObjectRef::Tree(tree_ref));
+    }
+
+    let (data, first_child) = {
+        let mut content = await!(handle.load())?;
+        let mut data = Vec::new();
+        content.read_to_end(&mut data)?;
+        (data, content.next())
+    };
+
+    let mut maybe_child = match first_child {
+        Some(child) => Some(child),
+        None => return Ok(ObjectRef::Small(SmallRef::new(data.len() as u64, handle))),
+    };
+
+    let size = str::from_utf8(&data)?
+        .lines()
+        .last()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|end| end.parse::<u64>().ok())
+        .ok_or_else(|| format_err!("Malformed large object"))?;
+    let mut depth = 0u8;
+    while let Some(child) = maybe_child {
+        depth += 1;
+        maybe_child = await!(child.load())?.next();
+    }
+
+    Ok(ObjectRef::Large(LargeRef::new(size, depth, handle)))
+}
+
+impl<B: Backend> Repository<B> {
+    /// Find the object a spec names. See the module documentation.
+    fn resolve_spec<'r>(
+        &'r self,
+        spec: String,
+    ) -> Box<Future<Item = ObjectRef<Handle<B>>, Error = Error> + 'r> {
+        let blocking = async_block! {
+            if is_digest(&spec) {
+                let digest = Sha3Digest::from_bytes(&hex::decode(&spec)?);
+                if let Some(handle) = await!(self.store.resolve_digest(digest))? {
+                    return await!(object_of_handle(handle));
+                }
+            }
+
+            let (rev, path) = match spec.find(':') {
+                Some(i) => (spec[..i].to_owned(), Some(spec[i + 1..].to_owned())),
+                None => (spec.clone(), None),
+            };
+            let commit_ref = await!(plumbing::resolve_rev(self, rev.parse::<Rev>()?))?;
+
+            let path = match path {
+                Some(path) => ObjectPath::from_path(path)?,
+                None => return Ok(ObjectRef::Commit(commit_ref)),
+            };
+            let tree_ref = await!(self.tree_of(&commit_ref))?;
+            if path.depth() == 0 {
+                return Ok(ObjectRef::Tree(tree_ref));
+            }
+
+            let display = path.to_string();
+            await!(self.hierarchy(tree_ref)?.get(path))?
+                .ok_or_else(|| format_err!("nothing at {} in {}", display, rev))
+        };
+
+        Box::new(blocking)
+    }
+
+    fn cat_one<'r>(
+        &'r self,
+        spec: String,
+        contents: Contents,
+    ) -> Box<Future<Item = CatFileRecord, Error = Error> + 'r> {
+        let blocking = async_block! {
+            // A spec which names no object is reported rather than ending the whole batch.
+            let object_ref = match await!(self.resolve_spec(spec.clone())) {
+                Ok(object_ref) => object_ref,
+                Err(error) => {
+                    return Ok(CatFileRecord {
+                        spec,
+                        object: None,
+                        reason: Some(error.to_string()),
+                    })
+                }
+            };
+
+            let id = await!(object_ref.as_inner().id())?;
+            let digest = hex::encode(id.borrow().as_bytes());
+            let size = await!(self.object_size(&object_ref))?;
+            let contents = match contents {
+                Contents::None => None,
+                Contents::Raw => match await!(diff::read_blob(object_ref.clone()))? {
+                    Some(data) => Some(data),
+                    None => {
+                        let mut content = await!(object_ref.as_inner().load())?;
+                        let mut data = Vec::new();
+                        content.read_to_end(&mut data)?;
+                        Some(data)
+                    }
+                },
+                Contents::Pretty => Some(await!(pretty(object_ref.clone()))?),
+            };
+
+            Ok(CatFileRecord {
+                spec,
+                object: Some(CatFileObject {
+                    digest,
+                    kind: object_ref.kind(),
+                    size,
+                    contents,
+                }),
+                reason: None,
+            })
+        };
+
+        Box::new(blocking)
+    }
+
+    /// Look up every spec in a stream. See the module documentation.
+    pub fn cat_file<'r, S>(&'r self, args: CatFileArgs, specs: S) -> CatFileOut<'r>
+    where
+        S: Stream<Item = String, Error = Error> + 'r,
+    {
+        let CatFileArgs {
+            batch,
+            pretty,
+            jobs,
+            unordered,
+        } = args;
+        let contents = match (batch, pretty) {
+            (_, true) => Contents::Pretty,
+            (true, false) => Contents::Raw,
+            (false, false) => Contents::None,
+        };
+        let jobs = jobs.unwrap_or(DEFAULT_JOBS).max(1);
+        let lookups = specs.map(move |spec| self.cat_one(spec, contents));
+
+        let records: Box<Stream<Item = CatFileRecord, Error = Error> + 'r> = if unordered {
+            Box::new(lookups.buffer_unordered(jobs))
+        } else {
+            Box::new(lookups.buffered(jobs))
+        };
+
+        CatFileOut { records }
+    }
+}
@@ -2,7 +2,8 @@ use std::{fmt, usize, collections::{BTreeSet, HashMap}, fs::{self, OpenOptions},
           sync::Arc};
 
 use attaca::{digest::prelude::*, hierarchy::Hierarchy,
-             object::{CommitRef, Object, ObjectRef, TreeRef}, path::ObjectPath, store::prelude::*};
+             object::{CommitRef, Object, ObjectRef, TreeRef}, path::ObjectPath,
+             store::prelude::*};
 use failure::*;
 use futures::{stream, prelude::*};
 use ignore::WalkBuilder;
@@ -11,20 +12,41 @@ use memmap::MmapMut;
 use Repository;
 use cache::{Cache, Certainty, Status};
 use state::{Head, State};
-use syntax::Ref;
+use syntax::{Name, Ref};
 use plumbing;
 
 /// Copy files from the repository into the local workspace.
 #[derive(Debug, StructOpt, Builder)]
 #[structopt(name = "checkout")]
 pub struct CheckoutArgs {
-    /// The ref to checkout from.
+    /// The ref to checkout from. With `-b` or `-B`, this is where the new branch starts.
     #[structopt(name = "REF", default_value = "HEAD")]
     pub refr: Ref,
 
-    /// Paths files to checkout. If left empty, the whole tree is checked out.
+    /// Paths files to checkout. If left empty, the whole tree is checked out. Checking out paths
+    /// only rewrites them in the local workspace, discarding any local changes to them; neither
+    /// HEAD nor any branch is moved. A directory has the files it holds in REF written into it,
+    /// and keeps any others.
     #[structopt(name = "PATHS", last = true, parse(from_os_str))]
     pub paths: Vec<PathBuf>,
+
+    /// Checkout PATHS from the virtual workspace rather than from REF, discarding local changes
+    /// which haven't been staged.
+    #[structopt(long = "staged", raw(requires = r#""PATHS""#))]
+    pub staged: bool,
+
+    /// Leave paths which aren't in REF (or the virtual workspace) alone, rather than failing.
+    #[structopt(long = "ignore-missing", raw(requires = r#""PATHS""#))]
+    pub ignore_missing: bool,
+
+    /// Create a new branch at REF and switch to it. Fails if the branch already exists.
+    #[structopt(short = "b", value_name = "BRANCH",
+                raw(conflicts_with_all = r#"&["PATHS", "reset_branch"]"#))]
+    pub new_branch: Option<Name>,
+
+    /// As `-b`, but if the branch already exists, move it to REF instead of failing.
+    #[structopt(short = "B", value_name = "BRANCH", raw(conflicts_with = r#""PATHS""#))]
+    pub reset_branch: Option<Name>,
 }
 
 #[must_use = "CheckoutOut contains futures which must be driven to completion!"]
@@ -43,6 +65,12 @@ impl<'r> fmt::Debug for CheckoutOut<'r> {
 impl<B: Backend> Repository<B> {
     pub fn checkout<'r>(&'r mut self, args: CheckoutArgs) -> CheckoutOut<'r> {
         let blocking = async_block! {
+            if let Some(name) = args.new_branch {
+                return await!(plumbing::checkout::new_branch(self, name, args.refr, false));
+            } else if let Some(name) = args.reset_branch {
+                return await!(plumbing::checkout::new_branch(self, name, args.refr, true));
+            }
+
             let paths = if args.paths.is_empty() {
                 // If there are no paths specified, we checkout an entire branch and update the
                 // HEAD (unless the HEAD is being checked out. Because that's silly.)
@@ -51,18 +79,40 @@ impl<B: Backend> Repository<B> {
                 // If there are paths specified, we do not update HEAD.
                 let paths = args.paths
                     .into_iter()
-                    .map(ObjectPath::from_path)
+                    .map(|path| self.resolve_path(path).map(|(_, object_path)| object_path))
                     .collect::<Result<Vec<_>, _>>()?;
 
-                let commit_ref = await!(plumbing::resolve(self, args.refr))?;
-                let tree_ref = await!(commit_ref.fetch())?.as_subtree().clone();
+                let (hierarchy, source) = if args.staged {
+                    match args.refr {
+                        Ref::Head => {}
+                        ref other => bail!(
+                            "`--staged` checks out from the virtual workspace, not from {}",
+                            other
+                        ),
+                    }
+
+                    let hierarchy = match self.get_state()?.candidate {
+                        Some(candidate) => self.hierarchy(candidate)?,
+                        None => Hierarchy::new(),
+                    };
+                    (hierarchy, String::from("the virtual workspace"))
+                } else {
+                    let source = args.refr.to_string();
+                    let commit_ref = await!(self.commit_of(args.refr))?;
+                    let tree_ref = await!(self.tree_of(&commit_ref))?;
+                    (self.hierarchy(tree_ref)?, source)
+                };
 
-                await!(plumbing::checkout::subpaths_from_tree(
-                    self,
-                    tree_ref,
-                    ObjectPath::new(),
-                    paths,
-                ))?;
+                let mut found = Vec::new();
+                for path in paths {
+                    match await!(hierarchy.get(path.clone()))? {
+                        Some(object_ref) => found.push((path, object_ref)),
+                        None if args.ignore_missing => {}
+                        None => bail!("{} is not in {}", path, source),
+                    }
+                }
+
+                await!(self.checkout_found(found))?;
             };
 
             Ok(())
@@ -72,4 +122,49 @@ impl<B: Backend> Repository<B> {
             blocking: Box::new(blocking),
         }
     }
+
+    /// Write the given paths from a commit into the local workspace, discarding any local changes
+    /// to them. A path naming a directory in the commit has the entries of its tree written, and
+    /// any other local files in the directory are kept. Fails, having written nothing, if any path
+    /// isn't in the commit.
+    pub fn checkout_paths<'r>(
+        &'r mut self,
+        commit_ref: CommitRef<Handle<B>>,
+        paths: Vec<ObjectPath>,
+    ) -> impl Future<Item = (), Error = Error> + 'r {
+        async_block! {
+            let tree_ref = await!(self.tree_of(&commit_ref))?;
+            let hierarchy = self.hierarchy(tree_ref)?;
+
+            let mut found = Vec::new();
+            for path in paths {
+                match await!(hierarchy.get(path.clone()))? {
+                    Some(object_ref) => found.push((path, object_ref)),
+                    None => bail!("{} is not in the commit", path),
+                }
+            }
+
+            await!(self.checkout_found(found))
+        }
+    }
+
+    /// Write objects out to their paths in the local workspace, creating any missing parent
+    /// directories first. Trees are written over what is already there, keeping local files they
+    /// don't have.
+    fn checkout_found<'r>(
+        &'r mut self,
+        found: Vec<(ObjectPath, ObjectRef<Handle<B>>)>,
+    ) -> impl Future<Item = (), Error = Error> + 'r {
+        async_block! {
+            for &(ref path, _) in &found {
+                if let Some(parent) = path.with_base(&*self.path).parent() {
+                    fs::create_dir_all(parent).with_context(|_| {
+                        format!("Error creating the parent directories of {}", path)
+                    })?;
+                }
+            }
+
+            await!(plumbing::checkout::checkout_paths_from_objects(self, found))
+        }
+    }
 }
@@ -0,0 +1,156 @@
+//! Built-in pre-commit checks.
+//!
+//! Before a commit is made, the files it adds or modifies relative to its first parent can be
+//! checked for trailing whitespace, a missing final newline, and excessive size. Each check is set
+//! in the repository configuration to be off, to warn, or to reject the commit; see
+//! `config::CommitChecks`. Files which look binary, or which are larger than the size threshold,
+//! are exempt from the whitespace and newline checks.
+
+use std::fmt;
+
+use attaca::{object::{ObjectRef, TreeBuilder, TreeRef}, path::ObjectPath, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+
+use Repository;
+use config::{CheckLevel, CommitChecks};
+use diff;
+use status::Change;
+use textdiff;
+
+/// A problem found by a pre-commit check.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Problem {
+    /// The first line, counting from one, which ends in whitespace.
+    TrailingWhitespace(usize),
+    MissingFinalNewline,
+    /// The size of the file and the threshold it exceeds.
+    TooLarge(u64, u64),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::TrailingWhitespace(line) => write!(f, "trailing whitespace on line {}", line),
+            Problem::MissingFinalNewline => write!(f, "no newline at end of file"),
+            Problem::TooLarge(size, threshold) => write!(
+                f,
+                "file is {} bytes, larger than the limit of {} bytes",
+                size, threshold
+            ),
+        }
+    }
+}
+
+/// A problem found in a particular file, along with the level of the check which found it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Violation {
+    pub path: ObjectPath,
+    pub problem: Problem,
+    pub level: CheckLevel,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.problem)
+    }
+}
+
+/// Find the first line, counting from one, which ends in a space or tab. Line endings may be
+/// either `\n` or `\r\n`.
+fn trailing_whitespace(data: &[u8]) -> Option<usize> {
+    data.split(|&b| b == b'\n')
+        .map(|line| match line.last() {
+            Some(&b'\r') => &line[..line.len() - 1],
+            _ => line,
+        })
+        .position(|line| line.last().map_or(false, |&b| b == b' ' || b == b'\t'))
+        .map(|index| index + 1)
+}
+
+/// Run the text checks which are turned on over the contents of a file.
+pub fn check_text(checks: &CommitChecks, data: &[u8]) -> Vec<(Problem, CheckLevel)> {
+    let mut problems = Vec::new();
+
+    if textdiff::is_binary(data) {
+        return problems;
+    }
+
+    if checks.trailing_whitespace != CheckLevel::Off {
+        if let Some(line) = trailing_whitespace(data) {
+            problems.push((Problem::TrailingWhitespace(line), checks.trailing_whitespace));
+        }
+    }
+
+    if checks.final_newline != CheckLevel::Off && data.last().map_or(false, |&b| b != b'\n') {
+        problems.push((Problem::MissingFinalNewline, checks.final_newline));
+    }
+
+    problems
+}
+
+impl<B: Backend> Repository<B> {
+    /// Run the pre-commit checks over every file added or modified between `from` and `to`, or
+    /// over every file in `to` if there is no `from`.
+    pub(crate) fn check_changes<'r>(
+        &'r self,
+        checks: CommitChecks,
+        from: Option<TreeRef<Handle<B>>>,
+        to: TreeRef<Handle<B>>,
+    ) -> Box<Future<Item = Vec<Violation>, Error = Error> + 'r> {
+        let blocking = async_block! {
+            let from = match from {
+                Some(tree_ref) => tree_ref,
+                None => await!(TreeBuilder::new().as_tree().send(&self.store))?,
+            };
+            let to_hierarchy = self.hierarchy(to.clone())?;
+            let text_checks = checks.trailing_whitespace != CheckLevel::Off
+                || checks.final_newline != CheckLevel::Off;
+            let mut violations = Vec::new();
+
+            #[async]
+            for change in Self::compare_subtrees(from, to) {
+                let path = match change {
+                    Change::Added(path) | Change::Modified(path) => path,
+                    Change::Removed(_) => continue,
+                };
+
+                let object_ref = match await!(to_hierarchy.get(path.clone()))? {
+                    Some(object_ref) => object_ref,
+                    None => continue,
+                };
+                let size = match object_ref {
+                    ObjectRef::Small(ref small_ref) => small_ref.size(),
+                    ObjectRef::Large(ref large_ref) => large_ref.size(),
+                    ObjectRef::Tree(_) | ObjectRef::Commit(_) => continue,
+                };
+
+                if checks.large_file != CheckLevel::Off && size > checks.large_file_threshold {
+                    violations.push(Violation {
+                        path: path.clone(),
+                        problem: Problem::TooLarge(size, checks.large_file_threshold),
+                        level: checks.large_file,
+                    });
+                }
+
+                // Files over the threshold aren't read at all, whether or not the size check is
+                // on, since reading them whole just to look for whitespace could take a while.
+                if text_checks && size <= checks.large_file_threshold {
+                    let data = await!(diff::read_blob(object_ref))?.unwrap_or_default();
+                    violations.extend(check_text(&checks, &data).into_iter().map(
+                        |(problem, level)| Violation {
+                            path: path.clone(),
+                            problem,
+                            level,
+                        },
+                    ));
+                }
+            }
+
+            violations.sort();
+            Ok(violations)
+        };
+
+        Box::new(blocking)
+    }
+}
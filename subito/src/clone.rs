@@ -1,6 +1,7 @@
 use std::{env, fs, collections::BTreeMap, path::{Path, PathBuf}};
 
-use attaca::{Init, Open, Store, digest::prelude::*, store::prelude::*};
+use attaca::{Init, Open, Store, digest::prelude::*, limit::RateLimiter, path::ObjectPath,
+             store::prelude::*};
 use failure::*;
 use futures::prelude::*;
 use leveldb::{database::Database, kv::KV, options::{Options, ReadOptions}};
@@ -8,9 +9,12 @@ use url::{self, Url};
 
 use Repository;
 use config::{Config, StoreConfig, StoreKind};
+use candidate;
 use db::Key;
-use init::{InitArgs, InitStore};
+use fetch;
+use init::{self, InitArgs, InitStore};
 use plumbing;
+use state::{Head, State};
 use syntax::{Name, Ref};
 
 /// Create a local repository by cloning data from a remote repository. The default store type is `leveldb`.
@@ -19,7 +23,7 @@ use syntax::{Name, Ref};
 pub struct CloneArgs {
     /// URL of the repository to clone.
     #[structopt(name = "URL", parse(try_from_str = "Url::parse"))]
-    url: Url,
+    pub url: Url,
 
     /// Path to a directory to initialize as a repository. This defaults to the current directory.
     #[structopt(name = "PATH", parse(from_os_str))]
@@ -27,10 +31,48 @@ pub struct CloneArgs {
 
     #[structopt(subcommand)]
     store: Option<InitStore>,
+
+    /// Seed the new repository from a template directory. See `init --help` for what is copied.
+    #[structopt(long = "template", parse(from_os_str))]
+    template: Option<PathBuf>,
+
+    /// Allow cloning into a directory which already contains files, as long as it is not already
+    /// a repository. Files in the way of the checkout are reported and nothing is cloned.
+    #[structopt(long = "force")]
+    force: bool,
+
+    /// When cloning with `--force`, overwrite existing files which are in the way of the
+    /// checkout instead of refusing to clone.
+    #[structopt(long = "overwrite", raw(requires = r#""force""#))]
+    overwrite: bool,
+
+    /// The remote branch to check out. By default, this is the remote's branch named by
+    /// `init.defaultBranch`, or failing that its only branch, or `master` or `main`.
+    #[structopt(short = "b", long = "branch", name = "BRANCH")]
+    branch: Option<Name>,
+
+    /// Limit the transfer rate to this many bytes per second. Zero means no limit.
+    #[structopt(long = "limit-rate", default_value = "0")]
+    limit_rate: u64,
+
+    /// Report progress even if stderr is not a terminal.
+    #[structopt(long = "progress", raw(conflicts_with = r#""no_progress""#))]
+    pub progress: bool,
+
+    /// Do not report progress, even if stderr is a terminal.
+    #[structopt(long = "no-progress")]
+    pub no_progress: bool,
+
+    /// Report the outcome in a stable, easy-to-parse format for scripts instead, on stdout. The
+    /// format is that of `fetch --porcelain`. Implies `--no-progress`.
+    #[structopt(long = "porcelain")]
+    pub porcelain: bool,
 }
 
 pub struct CloneOut {
-    pub blocking: Box<Future<Item = (), Error = Error>>,
+    /// Resolves to the report of the initial fetch in the format of `fetch --porcelain`, if
+    /// `--porcelain` was given.
+    pub blocking: Box<Future<Item = Option<Vec<u8>>, Error = Error>>,
 }
 
 #[macro_export]
@@ -41,6 +83,10 @@ macro_rules! clone {
             let init_args = InitArgs {
                 path: args.path,
                 store: args.store,
+                template: args.template,
+                initial_branch: None,
+                keep_empty_dirs: false,
+                keep_empty_trees: false,
             };
 
             match () {
@@ -52,15 +98,28 @@ macro_rules! clone {
 
                         let repository = {
                             use $crate::reexports::futures::prelude::*;
-                            let store =
-                                Store::new(<$type as $crate::reexports::attaca::Open>
-                                    ::open(args.url.as_str())?);
-
-                            let branches = store.load_branches().wait()?;
-                            let local_master =
-                                store::copy(branches["master"].clone(), repository.store.clone()).wait()?;
-                            let head = CommitRef::new(local_master);
-                            let candidate = head.fetch().wait()?.as_subtree().clone();
+                            let remote =
+                                $crate::plumbing::remote::store_config(args.url.clone())?;
+                            let store = Store::new($crate::open::$lcname(&remote)?);
+
+                            let branches = $crate::refs::load_heads(&store).wait()?;
+                            let maybe_default = $crate::clone::remote_default_branch(
+                                branches.keys().map(|name| name.as_str()),
+                                args.branch.clone(),
+                            )?;
+
+                            // As with `clone`, an empty remote leaves an empty repository.
+                            if let Some(default_branch) = maybe_default {
+                                let local_default = store::copy_with_limit(
+                                    branches[default_branch.as_str()].clone(),
+                                    repository.store.clone(),
+                                    $crate::reexports::attaca::limit::RateLimiter::new(
+                                        args.limit_rate,
+                                    ),
+                                ).wait()?;
+                                let head = CommitRef::new(local_default);
+                                let candidate = head.fetch().wait()?.as_subtree().clone();
+                            }
                         };
 
                         {
@@ -83,30 +142,223 @@ macro_rules! clone {
 }
 
 pub fn clone(args: CloneArgs) -> CloneOut {
-    let init_args = InitArgs {
-        path: args.path,
-        store: args.store,
-    };
-    let url = args.url;
-    let blocking = init!(init_args, repository, clone_from(repository, url));
+    let CloneArgs {
+        url,
+        path,
+        store,
+        template,
+        force,
+        overwrite,
+        branch,
+        limit_rate,
+        porcelain,
+        ..
+    } = args;
+    let blocking = check_destination(path, force).and_then(|path| {
+        let created = created_paths(&path);
+        let init_args = InitArgs {
+            path: Some(path),
+            store,
+            template,
+            initial_branch: None,
+            keep_empty_dirs: false,
+            keep_empty_trees: false,
+        };
+        init!(
+            init_args,
+            repository,
+            clone_from(
+                repository,
+                url,
+                branch,
+                force,
+                overwrite,
+                RateLimiter::new(limit_rate),
+                porcelain,
+                created
+            )
+        )
+    });
 
     CloneOut {
         blocking: Box::new(blocking.into_future().flatten()),
     }
 }
 
+/// Resolve the directory to clone into, and make sure that we can safely create a repository
+/// there.
+fn check_destination(path: Option<PathBuf>, force: bool) -> Result<PathBuf, Error> {
+    let path = match path {
+        Some(path) => path,
+        None => env::current_dir()?,
+    };
+
+    if path.join(".attaca").exists() {
+        bail!(
+            "{} is already an attaca repository; refusing to clone into it.",
+            path.display()
+        );
+    }
+
+    if !force && path.exists() && fs::read_dir(&path)?.next().is_some() {
+        bail!(
+            "{} is not empty; use --force to clone into it anyway.",
+            path.display()
+        );
+    }
+
+    Ok(path)
+}
+
+/// The paths which initializing a repository at `path` would create: the directory itself,
+/// `.attaca`, and the ignore file a template may add, whichever don't exist yet. They're listed
+/// parents first.
+fn created_paths(path: &Path) -> Vec<PathBuf> {
+    [
+        path.to_owned(),
+        path.join(".attaca"),
+        path.join(candidate::IGNORE_FILE),
+    ].iter()
+        .filter(|created| !created.exists())
+        .cloned()
+        .collect()
+}
+
+/// Remove what `created_paths` listed, leaving the destination as it was found. A created
+/// directory goes with everything in it, all of which clone put there.
+fn remove_created(created: &[PathBuf]) -> Result<(), Error> {
+    for path in created.iter().rev() {
+        if path.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Choose the remote branch to check out after cloning, from the names of the remote's branches:
+/// the one asked for, or else the one `init.defaultBranch` names, the only one, `master` or `main`,
+/// in that order. A remote with no branches has nothing to check out.
+pub fn remote_default_branch<'a, I>(
+    remote_branches: I,
+    requested: Option<Name>,
+) -> Result<Option<Name>, Error>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut names = remote_branches.into_iter().collect::<Vec<_>>();
+    names.sort();
+
+    if let Some(requested) = requested {
+        ensure!(
+            names.contains(&requested.as_str()),
+            "The remote has no branch {}",
+            requested
+        );
+        return Ok(Some(requested));
+    }
+
+    if names.is_empty() {
+        return Ok(None);
+    }
+
+    let configured = init::initial_branch(None)?;
+    let chosen = if names.contains(&configured.as_str()) {
+        configured.as_str().to_owned()
+    } else if names.len() == 1 {
+        names[0].to_owned()
+    } else if let Some(name) = ["master", "main"].iter().find(|name| names.contains(*name)) {
+        name.to_string()
+    } else {
+        bail!(
+            "The remote has several branches and none is the default; choose one with --branch: {}",
+            names.join(", ")
+        );
+    };
+
+    Ok(Some(Name::from_string(chosen)?))
+}
+
 fn clone_from<B: Backend>(
     mut this: Repository<B>,
     url: Url,
-) -> Box<Future<Item = (), Error = Error>> {
+    branch: Option<Name>,
+    force: bool,
+    overwrite: bool,
+    limiter: RateLimiter,
+    porcelain: bool,
+    created: Vec<PathBuf>,
+) -> Box<Future<Item = Option<Vec<u8>>, Error = Error>> {
     let blocking = async_block! {
         let origin = "origin".parse::<Name>()?;
-        let master = "master".parse::<Name>()?;
         // NB wait here because of issues w/ borrowing in generators.
         plumbing::remote::add(&mut this, origin.clone(), url).wait()?;
-        plumbing::fetch::remote(&mut this, origin.clone()).wait()?;
-        plumbing::checkout::by_ref(&mut this, Ref::Remote(origin, master)).wait()?;
-        Ok(())
+        let report = plumbing::fetch::remote(&mut this, origin.clone(), limiter).wait()?;
+        let porcelain_report = if porcelain {
+            let mut written = Vec::new();
+            fetch::write_porcelain(origin.clone(), report, &mut written).wait()?;
+            Some(written)
+        } else {
+            None
+        };
+
+        let remote_branches = this.get_state()?
+            .remote_refs
+            .remove(&origin)
+            .unwrap_or_default();
+        let default_branch = match remote_default_branch(
+            remote_branches.keys().map(|name| name.as_str()),
+            branch,
+        )? {
+            Some(default_branch) => default_branch,
+            // An empty remote leaves an empty repository, with nothing to check out.
+            None => return Ok(porcelain_report),
+        };
+
+        if !force {
+            plumbing::checkout::by_ref(&mut this, Ref::Remote(origin, default_branch)).wait()?;
+            return Ok(porcelain_report);
+        }
+
+        // The destination may contain unrelated files, so a regular checkout (which removes
+        // anything not in the checked out tree) won't do.
+        let commit_ref = plumbing::resolve_remote(&this, origin, default_branch).wait()?;
+        let tree_ref = commit_ref.fetch().wait()?.as_subtree().clone();
+
+        if !overwrite {
+            let conflicts = plumbing::checkout::overlay_conflicts(
+                &this,
+                tree_ref.clone(),
+                ObjectPath::new(),
+            ).wait()?;
+
+            if !conflicts.is_empty() {
+                // Nothing in the workspace has been touched yet, so clean up after ourselves to
+                // leave the destination as we found it.
+                remove_created(&created)?;
+
+                let listing = conflicts
+                    .iter()
+                    .map(|path| format!("\n    {}", path))
+                    .collect::<String>();
+                bail!(
+                    "Existing files would be overwritten by the checkout; use --overwrite to \
+                     replace them. Conflicting paths:{}",
+                    listing
+                );
+            }
+        }
+
+        plumbing::checkout::overlay_from_tree(&mut this, tree_ref, ObjectPath::new()).wait()?;
+        let state = this.get_state()?;
+        this.set_state(&State {
+            head: Head::Detached(commit_ref),
+            ..state
+        })?;
+
+        Ok(porcelain_report)
     };
 
     Box::new(blocking)
@@ -0,0 +1,197 @@
+//! Shell completion scripts.
+//!
+//! Commands and flags are completed statically, from the definition of the command line. Names of
+//! refs and remotes depend on the repository, so the scripts complete them by running `subito
+//! completions --list-refs` or `--list-remotes` in the repository being worked in.
+
+use std::{fmt, io::Write};
+
+use attaca::store::prelude::*;
+use failure::*;
+use futures::prelude::*;
+use structopt::clap::{App, Shell};
+
+use Repository;
+use refs;
+
+const BIN_NAME: &'static str = "subito";
+
+/// Commands whose positional arguments are refs.
+const REF_COMMANDS: &'static [&'static str] =
+    &["branch", "checkout", "diff", "merge", "rev-parse", "show"];
+
+/// Commands whose first positional argument is a remote. For `push`, the rest are refs.
+const REMOTE_COMMANDS: &'static [&'static str] = &["fetch", "push"];
+
+/// Print a completion script for a shell. Besides commands and flags, the script completes the
+/// names of branches, tags and remotes of the repository being worked in.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "completions")]
+pub struct CompletionsArgs {
+    /// The shell to print a completion script for.
+    #[structopt(name = "SHELL", raw(possible_values = "&Shell::variants()"),
+                raw(required_unless_one = r#"&["list-refs", "list-remotes"]"#))]
+    pub shell: Option<Shell>,
+
+    /// Print the short name of every ref, one per line, for completion scripts to use.
+    #[structopt(name = "list-refs", long = "list-refs", raw(hidden = "true"))]
+    pub list_refs: bool,
+
+    /// Print the name of every remote, one per line, for completion scripts to use.
+    #[structopt(name = "list-remotes", long = "list-remotes", raw(hidden = "true"),
+                raw(conflicts_with = r#""list-refs""#))]
+    pub list_remotes: bool,
+}
+
+fn bash_dynamic() -> String {
+    format!(
+        r#"
+_{bin}_dynamic() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local names=""
+    if [[ ${{COMP_CWORD}} -gt 1 && "${{cur}}" != -* ]]; then
+        case "${{COMP_WORDS[1]}}" in
+            {ref_commands})
+                names="$({bin} completions --list-refs 2>/dev/null)"
+                ;;
+            {remote_commands})
+                if [[ ${{COMP_CWORD}} -eq 2 ]]; then
+                    names="$({bin} completions --list-remotes 2>/dev/null)"
+                else
+                    names="$({bin} completions --list-refs 2>/dev/null)"
+                fi
+                ;;
+        esac
+    fi
+    if [[ -n "${{names}}" ]]; then
+        COMPREPLY=( $(compgen -W "${{names}}" -- "${{cur}}") )
+        if [[ ${{#COMPREPLY[@]}} -gt 0 ]]; then
+            return 0
+        fi
+    fi
+    _{bin} "$@"
+}}
+
+complete -F _{bin}_dynamic -o bashdefault -o default {bin}
+"#,
+        bin = BIN_NAME,
+        ref_commands = REF_COMMANDS.join("|"),
+        remote_commands = REMOTE_COMMANDS.join("|"),
+    )
+}
+
+fn zsh_dynamic() -> String {
+    format!(
+        r#"
+_{bin}_dynamic() {{
+    local -a names
+    if (( CURRENT > 2 )) && [[ "${{words[CURRENT]}}" != -* ]]; then
+        case "${{words[2]}}" in
+            {ref_commands})
+                names=(${{(f)"$({bin} completions --list-refs 2>/dev/null)"}})
+                ;;
+            {remote_commands})
+                if (( CURRENT == 3 )); then
+                    names=(${{(f)"$({bin} completions --list-remotes 2>/dev/null)"}})
+                else
+                    names=(${{(f)"$({bin} completions --list-refs 2>/dev/null)"}})
+                fi
+                ;;
+        esac
+    fi
+    if (( ${{#names}} > 0 )); then
+        compadd -a names && return 0
+    fi
+    _{bin} "$@"
+}}
+
+compdef _{bin}_dynamic {bin}
+_{bin}_dynamic "$@"
+"#,
+        bin = BIN_NAME,
+        ref_commands = REF_COMMANDS.join("|"),
+        remote_commands = REMOTE_COMMANDS.join("|"),
+    )
+}
+
+fn fish_dynamic() -> String {
+    format!(
+        r#"
+complete -c {bin} -n "__fish_seen_subcommand_from {ref_commands}" -f -a "({bin} completions --list-refs 2>/dev/null)"
+complete -c {bin} -n "__fish_seen_subcommand_from {remote_commands}" -f -a "({bin} completions --list-remotes 2>/dev/null)"
+"#,
+        bin = BIN_NAME,
+        ref_commands = REF_COMMANDS.join(" "),
+        remote_commands = REMOTE_COMMANDS.join(" "),
+    )
+}
+
+/// Write a completion script for `shell` to `out`, covering the commands and flags of `app` as well
+/// as ref and remote names. PowerShell scripts only complete commands and flags.
+pub fn generate<W: Write>(app: &mut App, shell: Shell, out: &mut W) -> Result<(), Error> {
+    let mut buf = Vec::new();
+    app.gen_completions_to(BIN_NAME, shell, &mut buf);
+    let mut script = String::from_utf8(buf)?;
+
+    match shell {
+        Shell::Bash => script.push_str(&bash_dynamic()),
+        Shell::Zsh => {
+            // The generated script ends by calling its completion function, which would bypass the
+            // dynamic one; call the dynamic one instead.
+            let call = format!("_{} \"$@\"", BIN_NAME);
+            let trimmed_len = script.trim_right().len();
+            if script[..trimmed_len].ends_with(&call) {
+                script.truncate(trimmed_len - call.len());
+            }
+            script.push_str(&zsh_dynamic());
+        }
+        Shell::Fish => script.push_str(&fish_dynamic()),
+        Shell::PowerShell => {}
+    }
+
+    out.write_all(script.as_bytes())?;
+
+    Ok(())
+}
+
+#[must_use = "CompletionNamesOut contains futures which must be driven to completion!"]
+pub struct CompletionNamesOut<'r> {
+    /// Names to complete, sorted.
+    pub names: Box<Future<Item = Vec<String>, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for CompletionNamesOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CompletionNamesOut")
+            .field("names", &"OPAQUE")
+            .finish()
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    /// The short names of every ref in the repository, for completing them in the shell.
+    pub fn completion_refs<'r>(&'r self) -> CompletionNamesOut<'r> {
+        let names = self.for_each_ref("").map(|list| {
+            list.into_iter()
+                .map(|(name, _)| refs::short_name(&name).to_owned())
+                .collect()
+        });
+
+        CompletionNamesOut {
+            names: Box::new(names),
+        }
+    }
+
+    /// The names of every remote of the repository, for completing them in the shell.
+    pub fn completion_remotes<'r>(&'r self) -> CompletionNamesOut<'r> {
+        let names = self.get_config().map(|config| {
+            let mut names = config.remotes.keys().cloned().collect::<Vec<_>>();
+            names.sort();
+            names
+        });
+
+        CompletionNamesOut {
+            names: Box::new(names.into_future()),
+        }
+    }
+}
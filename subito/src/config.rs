@@ -1,31 +1,452 @@
-use std::{collections::HashMap, io::{BufRead, Write}};
+use std::{env, fmt, fs, collections::{BTreeMap, HashMap}, io::{BufRead, Write},
+          path::{Path, PathBuf}, str::FromStr};
 
-use attaca::store::prelude::*;
+use attaca::{hierarchy::{self, Hierarchy}, object::TreeRef, seal::SealParams, store::prelude::*};
 use capnp::{message, serialize_packed};
 use failure::*;
-use leveldb::{kv::KV, options::{ReadOptions, WriteOptions}};
+use futures::prelude::*;
+use leveldb::{database::Database, kv::KV, options::{ReadOptions, WriteOptions}};
 use url::Url;
 
 use Repository;
+use abbrev;
+use candidate;
 use db::Key;
+use plumbing;
 
-use config_capnp::*;
+use config_capnp::{self, *};
+
+/// Name of the per-user configuration file, which lives in the home directory. It holds settings
+/// which apply before any repository exists, as `key = value` lines; blank lines and lines
+/// starting with `#` are ignored.
+pub const USER_CONFIG_FILE: &'static str = ".attacaconfig";
+
+fn user_config_path() -> Option<PathBuf> {
+    env::home_dir().map(|home| home.join(USER_CONFIG_FILE))
+}
+
+/// Split a line of the per-user configuration file into its key and value, or return `None` if it
+/// is blank or a comment.
+fn parse_user_config_line<'a>(
+    path: &Path,
+    line: &'a str,
+) -> Result<Option<(&'a str, &'a str)>, Error> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let mut split = line.splitn(2, '=');
+    let key = split.next().unwrap().trim();
+    let value = split
+        .next()
+        .ok_or_else(|| format_err!("Malformed line in {}: {}", path.display(), line))?
+        .trim();
+
+    Ok(Some((key, value)))
+}
+
+/// Every key set in the per-user configuration file, in the order they appear. Empty if there is
+/// no such file.
+pub fn user_config_entries() -> Result<Vec<(String, String)>, Error> {
+    let path = match user_config_path() {
+        Some(path) => path,
+        None => return Ok(Vec::new()),
+    };
+
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+
+    let contents = fs::read_to_string(&path)
+        .with_context(|_| format_err!("Error reading {}", path.display()))?;
+    let mut entries = Vec::new();
+    for line in contents.lines() {
+        if let Some((key, value)) = parse_user_config_line(&path, line)? {
+            entries.push((key.to_owned(), value.to_owned()));
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Look up a key in the per-user configuration file. Returns `None` if there is no such file or it
+/// does not set the key.
+pub fn user_config_value(key: &str) -> Result<Option<String>, Error> {
+    Ok(user_config_entries()?
+        .into_iter()
+        .find(|&(ref line_key, _)| line_key == key)
+        .map(|(_, value)| value))
+}
+
+/// Set a key in the per-user configuration file, or remove it if `value` is `None`, creating the
+/// file if need be. Every other line, comments included, is left as it was. Returns whether the
+/// key was set before.
+pub fn set_user_config_value(key: &str, value: Option<&str>) -> Result<bool, Error> {
+    ensure!(
+        !key.is_empty() && !key.starts_with('#')
+            && !key.contains(|c: char| c.is_whitespace() || c == '='),
+        "Invalid configuration key {}",
+        key
+    );
+    ensure!(
+        value.map_or(true, |value| !value.contains('\n')),
+        "Configuration values can't span several lines"
+    );
+
+    let path = user_config_path()
+        .ok_or_else(|| format_err!("Can't find the home directory for the user configuration"))?;
+    let contents = if path.is_file() {
+        fs::read_to_string(&path)
+            .with_context(|_| format_err!("Error reading {}", path.display()))?
+    } else {
+        String::new()
+    };
+
+    let mut found = false;
+    let mut lines = Vec::new();
+    for line in contents.lines() {
+        match parse_user_config_line(&path, line)? {
+            Some((line_key, _)) if line_key == key => {
+                // Only the first line for a key is ever read, so any others are dropped.
+                if let (false, Some(value)) = (found, value) {
+                    lines.push(format!("{} = {}", key, value));
+                }
+                found = true;
+            }
+            _ => lines.push(line.to_owned()),
+        }
+    }
+    match (found, value) {
+        (false, Some(value)) => lines.push(format!("{} = {}", key, value)),
+        (false, None) => return Ok(false),
+        (true, _) => {}
+    }
+
+    let mut new_contents = lines.join("\n");
+    new_contents.push('\n');
+    fs::write(&path, new_contents)
+        .with_context(|_| format_err!("Error writing {}", path.display()))?;
+
+    Ok(found)
+}
 
 #[derive(Debug, Clone, Copy)]
 pub enum StoreKind {
     LevelDb,
+    /// The store of a repository on another host, served by `subito serve`.
+    Ssh,
+    /// A bundle file written by `bundle create`. Bundles can only be fetched from, so they are
+    /// only ever the stores of remotes.
+    Bundle,
+    /// A `MemoryBackend`, for tests and benchmarks. It lives only as long as the process which
+    /// created it, so a repository over one can never be reopened.
+    Memory,
+}
+
+/// Parameters for encrypting a store's objects at rest. See `attaca::seal`.
+#[derive(Debug, Clone)]
+pub struct EncryptionConfig {
+    /// Salt for deriving the key from a passphrase.
+    pub salt: Vec<u8>,
+
+    /// A value sealed with the key, used to detect a wrong passphrase.
+    pub check: Vec<u8>,
+}
+
+impl From<SealParams> for EncryptionConfig {
+    fn from(params: SealParams) -> Self {
+        let SealParams { salt, check } = params;
+        EncryptionConfig { salt, check }
+    }
+}
+
+impl From<EncryptionConfig> for SealParams {
+    fn from(encryption: EncryptionConfig) -> Self {
+        let EncryptionConfig { salt, check } = encryption;
+        SealParams { salt, check }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct StoreConfig {
     pub url: Url,
     pub kind: StoreKind,
+    pub encryption: Option<EncryptionConfig>,
+}
+
+impl StoreConfig {
+    fn decode(store_reader: store::Reader) -> Result<Self, Error> {
+        let url = Url::parse(store_reader.get_url()?)?;
+        let kind = match store_reader.which()? {
+            store::LevelDb(()) => StoreKind::LevelDb,
+            store::Ceph(()) => unimplemented!(),
+            store::Ssh(()) => StoreKind::Ssh,
+            store::Bundle(()) => StoreKind::Bundle,
+            store::Memory(()) => StoreKind::Memory,
+        };
+        let encryption = match store_reader.get_encryption().which()? {
+            store::encryption::None(()) => None,
+            store::encryption::Some(encryption_res) => {
+                let encryption_reader = encryption_res?;
+                Some(EncryptionConfig {
+                    salt: encryption_reader.get_salt()?.to_vec(),
+                    check: encryption_reader.get_check()?.to_vec(),
+                })
+            }
+        };
+
+        Ok(StoreConfig {
+            url,
+            kind,
+            encryption,
+        })
+    }
+
+    fn encode(&self, mut store_builder: store::Builder) {
+        match self.kind {
+            StoreKind::LevelDb => store_builder.set_level_db(()),
+            StoreKind::Ssh => store_builder.set_ssh(()),
+            StoreKind::Bundle => store_builder.set_bundle(()),
+            StoreKind::Memory => store_builder.set_memory(()),
+        }
+        store_builder.set_url(self.url.as_str());
+
+        let mut encryption_builder = store_builder.get_encryption();
+        match self.encryption {
+            Some(ref encryption) => {
+                let mut some_builder = encryption_builder.init_some();
+                some_builder.set_salt(&encryption.salt);
+                some_builder.set_check(&encryption.check);
+            }
+            None => encryption_builder.set_none(()),
+        }
+    }
+}
+
+/// Whether checkout and staging refuse paths which differ only in case. See the `case` module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CaseCollisions {
+    /// Probe the workspace's filesystem the first time it matters, and record the answer as
+    /// `Refuse` if it is case-insensitive or `Allow` if not.
+    Detect,
+
+    /// Refuse to check out or stage a path next to another which differs from it only in case.
+    Refuse,
+
+    /// Don't look for collisions at all.
+    Allow,
+}
+
+impl Default for CaseCollisions {
+    fn default() -> Self {
+        CaseCollisions::Detect
+    }
+}
+
+impl fmt::Display for CaseCollisions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            CaseCollisions::Detect => "detect",
+            CaseCollisions::Refuse => "refuse",
+            CaseCollisions::Allow => "allow",
+        })
+    }
+}
+
+impl FromStr for CaseCollisions {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "detect" => Ok(CaseCollisions::Detect),
+            "refuse" => Ok(CaseCollisions::Refuse),
+            "allow" => Ok(CaseCollisions::Allow),
+            _ => bail!("Expected one of detect, refuse or allow, not {}", s),
+        }
+    }
+}
+
+impl CaseCollisions {
+    fn decode(case_collisions: config_capnp::CaseCollisions) -> Self {
+        match case_collisions {
+            config_capnp::CaseCollisions::Detect => CaseCollisions::Detect,
+            config_capnp::CaseCollisions::Refuse => CaseCollisions::Refuse,
+            config_capnp::CaseCollisions::Allow => CaseCollisions::Allow,
+        }
+    }
+
+    fn encode(&self) -> config_capnp::CaseCollisions {
+        match *self {
+            CaseCollisions::Detect => config_capnp::CaseCollisions::Detect,
+            CaseCollisions::Refuse => config_capnp::CaseCollisions::Refuse,
+            CaseCollisions::Allow => config_capnp::CaseCollisions::Allow,
+        }
+    }
+}
+
+/// What to do when a pre-commit check finds a problem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CheckLevel {
+    /// Don't run the check.
+    Off,
+
+    /// Print a warning, but commit anyway.
+    Warn,
+
+    /// Refuse to commit, unless overridden with `--no-verify` or `--force`.
+    Reject,
+}
+
+impl Default for CheckLevel {
+    fn default() -> Self {
+        CheckLevel::Off
+    }
+}
+
+impl fmt::Display for CheckLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            CheckLevel::Off => "off",
+            CheckLevel::Warn => "warn",
+            CheckLevel::Reject => "reject",
+        })
+    }
+}
+
+impl FromStr for CheckLevel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(CheckLevel::Off),
+            "warn" => Ok(CheckLevel::Warn),
+            "reject" => Ok(CheckLevel::Reject),
+            _ => bail!("Expected one of off, warn or reject, not {}", s),
+        }
+    }
+}
+
+impl CheckLevel {
+    fn decode(level: config_capnp::CheckLevel) -> Self {
+        match level {
+            config_capnp::CheckLevel::Off => CheckLevel::Off,
+            config_capnp::CheckLevel::Warn => CheckLevel::Warn,
+            config_capnp::CheckLevel::Reject => CheckLevel::Reject,
+        }
+    }
+
+    fn encode(&self) -> config_capnp::CheckLevel {
+        match *self {
+            CheckLevel::Off => config_capnp::CheckLevel::Off,
+            CheckLevel::Warn => config_capnp::CheckLevel::Warn,
+            CheckLevel::Reject => config_capnp::CheckLevel::Reject,
+        }
+    }
+}
+
+/// The default size above which a file is considered large by the pre-commit checks: 50 MiB.
+pub const DEFAULT_LARGE_FILE_THRESHOLD: u64 = 50 * 1024 * 1024;
+
+/// Built-in checks run over the changed files of each commit. All of them are off unless enabled.
+/// See `checks`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitChecks {
+    /// Lines ending in spaces or tabs.
+    pub trailing_whitespace: CheckLevel,
+
+    /// Non-empty text files which don't end with a newline.
+    pub final_newline: CheckLevel,
+
+    /// Files larger than `large_file_threshold` bytes.
+    pub large_file: CheckLevel,
+    pub large_file_threshold: u64,
+}
+
+impl Default for CommitChecks {
+    fn default() -> Self {
+        Self {
+            trailing_whitespace: CheckLevel::Off,
+            final_newline: CheckLevel::Off,
+            large_file: CheckLevel::Off,
+            large_file_threshold: DEFAULT_LARGE_FILE_THRESHOLD,
+        }
+    }
+}
+
+impl CommitChecks {
+    /// Whether any check is turned on.
+    pub fn is_enabled(&self) -> bool {
+        self.trailing_whitespace != CheckLevel::Off || self.final_newline != CheckLevel::Off
+            || self.large_file != CheckLevel::Off
+    }
+
+    fn decode(checks_reader: commit_checks::Reader) -> Result<Self, Error> {
+        let large_file_threshold = match checks_reader.get_large_file_threshold() {
+            0 => DEFAULT_LARGE_FILE_THRESHOLD,
+            threshold => threshold,
+        };
+
+        Ok(CommitChecks {
+            trailing_whitespace: CheckLevel::decode(checks_reader.get_trailing_whitespace()?),
+            final_newline: CheckLevel::decode(checks_reader.get_final_newline()?),
+            large_file: CheckLevel::decode(checks_reader.get_large_file()?),
+            large_file_threshold,
+        })
+    }
+
+    fn encode(&self, mut checks_builder: commit_checks::Builder) {
+        checks_builder.set_trailing_whitespace(self.trailing_whitespace.encode());
+        checks_builder.set_final_newline(self.final_newline.encode());
+        checks_builder.set_large_file(self.large_file.encode());
+        checks_builder.set_large_file_threshold(self.large_file_threshold);
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub store: StoreConfig,
     pub remotes: HashMap<String, StoreConfig>,
+
+    /// How many fetched trees to keep in memory while walking a tree, for instance during a
+    /// checkout. See `attaca::hierarchy::Hierarchy`.
+    pub hierarchy_cache_capacity: usize,
+
+    /// Checks to run over the changed files of each commit.
+    pub commit_checks: CommitChecks,
+
+    /// Whether staging records each empty directory by placing an empty `.attacakeep` file in it,
+    /// so that the directory is recreated on checkout. See `candidate::KEEP_FILE`.
+    pub keep_empty_dirs: bool,
+
+    /// The shortest length of displayed digest abbreviations. They are lengthened where needed to
+    /// stay unambiguous; see `abbrev::Abbreviator`.
+    pub abbrev_len: usize,
+
+    /// Whether a directory left with no entries in the virtual workspace, for instance because
+    /// everything in it was unstaged, is kept as an empty tree. By default it is pruned from its
+    /// parent, just as if it had been deleted; see `attaca::batch::Batch`.
+    pub keep_empty_trees: bool,
+
+    /// How many threads hash and compress file contents while staging, or zero for one per CPU.
+    /// Takes effect the next time the repository is opened. See `Repository::cpu_pool`.
+    pub worker_threads: usize,
+
+    /// Whether checkout and staging refuse paths which differ only in case, as they would clobber
+    /// each other on a case-insensitive filesystem.
+    pub case_collisions: CaseCollisions,
+
+    /// Whether staging records the extended attributes of files and directories, and checkout
+    /// restores them. See `xattrs`.
+    pub xattrs: bool,
+
+    /// How many files of a directory being staged are hashed at once, on the worker threads. See
+    /// `candidate::DEFAULT_HASH_JOBS`.
+    pub hash_jobs: usize,
+
+    /// Keys which this version doesn't know, as they were set with `config set`. They are kept so
+    /// that they aren't lost between versions, and are otherwise ignored.
+    pub extra: BTreeMap<String, String>,
 }
 
 impl Config {
@@ -33,15 +454,7 @@ impl Config {
         let message_reader = serialize_packed::read_message(reader, message::ReaderOptions::new())?;
         let config_reader = message_reader.get_root::<config::Reader>()?;
 
-        let store = {
-            let store_reader = config_reader.get_store()?;
-            let url = Url::parse(store_reader.get_url()?)?;
-            let kind = match store_reader.which()? {
-                store::LevelDb(()) => StoreKind::LevelDb,
-                store::Ceph(()) => unimplemented!(),
-            };
-            StoreConfig { url, kind }
-        };
+        let store = StoreConfig::decode(config_reader.get_store()?)?;
 
         let remotes = {
             let remotes_reader = config_reader.get_remotes()?;
@@ -49,34 +462,106 @@ impl Config {
                 .iter()
                 .map(|remote_reader| {
                     let name = String::from(remote_reader.get_name()?);
-                    let store = {
-                        let store_reader = remote_reader.get_store()?;
-                        let url = Url::parse(store_reader.get_url()?)?;
-                        let kind = match store_reader.which()? {
-                            store::LevelDb(()) => StoreKind::LevelDb,
-                            store::Ceph(()) => unimplemented!(),
-                        };
-                        StoreConfig { url, kind }
-                    };
+                    let store = StoreConfig::decode(remote_reader.get_store()?)?;
                     Ok((name, store))
                 })
                 .collect::<Result<HashMap<_, _>, Error>>()?
         };
 
-        Ok(Config { store, remotes })
+        let hierarchy_cache_capacity = match config_reader.get_hierarchy_cache_capacity() {
+            0 => hierarchy::DEFAULT_CACHE_CAPACITY,
+            capacity => capacity as usize,
+        };
+
+        let commit_checks = CommitChecks::decode(config_reader.get_commit_checks()?)?;
+        let keep_empty_dirs = config_reader.get_keep_empty_dirs();
+        let abbrev_len = match config_reader.get_abbrev_length() {
+            0 => abbrev::DEFAULT_ABBREV_LEN,
+            len => len as usize,
+        };
+        let keep_empty_trees = config_reader.get_keep_empty_trees();
+        let worker_threads = config_reader.get_worker_threads() as usize;
+        let case_collisions = CaseCollisions::decode(config_reader.get_case_collisions()?);
+        let xattrs = config_reader.get_xattrs();
+        let hash_jobs = match config_reader.get_hash_jobs() {
+            0 => candidate::DEFAULT_HASH_JOBS,
+            jobs => jobs as usize,
+        };
+        let extra = config_reader
+            .get_extra()?
+            .iter()
+            .map(|entry_reader| {
+                let key = String::from(entry_reader.get_key()?);
+                let value = String::from(entry_reader.get_value()?);
+                Ok((key, value))
+            })
+            .collect::<Result<BTreeMap<_, _>, Error>>()?;
+
+        Ok(Config {
+            store,
+            remotes,
+            hierarchy_cache_capacity,
+            commit_checks,
+            keep_empty_dirs,
+            abbrev_len,
+            keep_empty_trees,
+            worker_threads,
+            case_collisions,
+            xattrs,
+            hash_jobs,
+            extra,
+        })
+    }
+
+    /// Read the configuration stored in a repository's database.
+    pub fn load(db: &Database<Key>) -> Result<Self, Error> {
+        let raw_config = db.get(ReadOptions::new(), &Key::config())?
+            .ok_or_else(|| format_err!("Missing configuration entry"))?;
+
+        Ok(Config::decode(&mut &raw_config[..])?)
     }
 
     pub fn encode<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        self.encode_over(None, writer)
+    }
+
+    /// Like `encode`, but starting from a previously encoded configuration, so that any fields
+    /// which a newer version wrote there and this one doesn't know about are kept.
+    pub fn encode_over<W: Write>(
+        &self,
+        previous: Option<&[u8]>,
+        writer: &mut W,
+    ) -> Result<(), Error> {
         let mut message = message::Builder::new_default();
 
+        if let Some(mut previous) = previous {
+            let previous_message =
+                serialize_packed::read_message(&mut previous, message::ReaderOptions::new())?;
+            message.set_root(previous_message.get_root::<config::Reader>()?)?;
+        }
+
         {
-            let mut config_builder = message.init_root::<config::Builder>();
+            let mut config_builder = message.get_root::<config::Builder>()?;
+            self.store.encode(config_builder.borrow().init_store());
+            config_builder.set_hierarchy_cache_capacity(self.hierarchy_cache_capacity as u64);
+            self.commit_checks
+                .encode(config_builder.borrow().init_commit_checks());
+            config_builder.set_keep_empty_dirs(self.keep_empty_dirs);
+            config_builder.set_abbrev_length(self.abbrev_len as u8);
+            config_builder.set_keep_empty_trees(self.keep_empty_trees);
+            config_builder.set_worker_threads(self.worker_threads as u32);
+            config_builder.set_case_collisions(self.case_collisions.encode());
+            config_builder.set_xattrs(self.xattrs);
+            config_builder.set_hash_jobs(self.hash_jobs as u32);
             {
-                let mut store_builder = config_builder.borrow().init_store();
-                match self.store.kind {
-                    StoreKind::LevelDb => store_builder.set_level_db(()),
+                let mut extra_builder = config_builder
+                    .borrow()
+                    .init_extra(self.extra.len() as u32);
+                for (i, (key, value)) in self.extra.iter().enumerate() {
+                    let mut entry_builder = extra_builder.borrow().get(i as u32);
+                    entry_builder.set_key(key);
+                    entry_builder.set_value(value);
                 }
-                store_builder.set_url(self.store.url.as_str());
             }
             {
                 let mut remotes_builder = config_builder
@@ -85,13 +570,7 @@ impl Config {
                 for (i, (name, remote)) in self.remotes.iter().enumerate() {
                     let mut remote_builder = remotes_builder.borrow().get(i as u32);
                     remote_builder.set_name(name);
-                    {
-                        let mut store_builder = remote_builder.get_store()?;
-                        match remote.kind {
-                            StoreKind::LevelDb => store_builder.set_level_db(()),
-                        }
-                        store_builder.set_url(remote.url.as_str());
-                    }
+                    remote.encode(remote_builder.get_store()?);
                 }
             }
         }
@@ -102,26 +581,346 @@ impl Config {
     }
 }
 
+/// The keys of the repository configuration which stand for fields of `Config`. Any other key may
+/// be set as well, and is kept in `Config::extra`.
+pub const KEYS: &'static [&'static str] = &[
+    "checks.finalNewline",
+    "checks.largeFile",
+    "checks.largeFileThreshold",
+    "checks.trailingWhitespace",
+    "core.abbrev",
+    "core.caseCollisions",
+    "core.hashJobs",
+    "core.hierarchyCacheCapacity",
+    "core.keepEmptyDirs",
+    "core.keepEmptyTrees",
+    "core.workerThreads",
+    "core.xattrs",
+];
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| format_err!("Invalid value for {}: {}", key, value))
+}
+
+impl Config {
+    /// The value of a configuration key, or `None` if it's neither one of `KEYS` nor set in
+    /// `extra`.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let value = match key {
+            "checks.finalNewline" => self.commit_checks.final_newline.to_string(),
+            "checks.largeFile" => self.commit_checks.large_file.to_string(),
+            "checks.largeFileThreshold" => self.commit_checks.large_file_threshold.to_string(),
+            "checks.trailingWhitespace" => self.commit_checks.trailing_whitespace.to_string(),
+            "core.abbrev" => self.abbrev_len.to_string(),
+            "core.caseCollisions" => self.case_collisions.to_string(),
+            "core.hashJobs" => self.hash_jobs.to_string(),
+            "core.hierarchyCacheCapacity" => self.hierarchy_cache_capacity.to_string(),
+            "core.keepEmptyDirs" => self.keep_empty_dirs.to_string(),
+            "core.keepEmptyTrees" => self.keep_empty_trees.to_string(),
+            "core.workerThreads" => self.worker_threads.to_string(),
+            "core.xattrs" => self.xattrs.to_string(),
+            _ => return self.extra.get(key).cloned(),
+        };
+
+        Some(value)
+    }
+
+    /// Set a configuration key. The value of one of `KEYS` must make sense for its field; any
+    /// other key is kept in `extra` as it is.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        match key {
+            "checks.finalNewline" => self.commit_checks.final_newline = value.parse()?,
+            "checks.largeFile" => self.commit_checks.large_file = value.parse()?,
+            "checks.largeFileThreshold" => {
+                self.commit_checks.large_file_threshold = parse_value(key, value)?
+            }
+            "checks.trailingWhitespace" => {
+                self.commit_checks.trailing_whitespace = value.parse()?
+            }
+            "core.abbrev" => {
+                let abbrev_len = parse_value::<u8>(key, value)? as usize;
+                ensure!(
+                    abbrev_len >= plumbing::MIN_ABBREV_LEN && abbrev_len <= 64,
+                    "{} must be between {} and 64",
+                    key,
+                    plumbing::MIN_ABBREV_LEN
+                );
+                self.abbrev_len = abbrev_len;
+            }
+            "core.caseCollisions" => self.case_collisions = value.parse()?,
+            "core.hashJobs" => {
+                let jobs = parse_value(key, value)?;
+                ensure!(jobs > 0, "{} must be at least 1", key);
+                self.hash_jobs = jobs;
+            }
+            "core.hierarchyCacheCapacity" => {
+                let capacity = parse_value(key, value)?;
+                ensure!(capacity > 0, "{} must be at least 1", key);
+                self.hierarchy_cache_capacity = capacity;
+            }
+            "core.keepEmptyDirs" => self.keep_empty_dirs = parse_value(key, value)?,
+            "core.keepEmptyTrees" => self.keep_empty_trees = parse_value(key, value)?,
+            "core.workerThreads" => self.worker_threads = parse_value(key, value)?,
+            "core.xattrs" => self.xattrs = parse_value(key, value)?,
+            _ => {
+                ensure!(
+                    key.contains('.') && !key.starts_with('.') && !key.ends_with('.')
+                        && !key.contains(|c: char| c.is_whitespace() || c == '='),
+                    "Invalid configuration key {}: keys look like `section.name`",
+                    key
+                );
+                self.extra.insert(key.to_owned(), value.to_owned());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Put one of `KEYS` back to its default, or forget any other key. Returns `false` if the key
+    /// wasn't set to begin with.
+    pub fn unset(&mut self, key: &str) -> bool {
+        let checks = CommitChecks::default();
+        match key {
+            "checks.finalNewline" => self.commit_checks.final_newline = checks.final_newline,
+            "checks.largeFile" => self.commit_checks.large_file = checks.large_file,
+            "checks.largeFileThreshold" => {
+                self.commit_checks.large_file_threshold = checks.large_file_threshold
+            }
+            "checks.trailingWhitespace" => {
+                self.commit_checks.trailing_whitespace = checks.trailing_whitespace
+            }
+            "core.abbrev" => self.abbrev_len = abbrev::DEFAULT_ABBREV_LEN,
+            "core.caseCollisions" => self.case_collisions = CaseCollisions::default(),
+            "core.hashJobs" => self.hash_jobs = candidate::DEFAULT_HASH_JOBS,
+            "core.hierarchyCacheCapacity" => {
+                self.hierarchy_cache_capacity = hierarchy::DEFAULT_CACHE_CAPACITY
+            }
+            "core.keepEmptyDirs" => self.keep_empty_dirs = false,
+            "core.keepEmptyTrees" => self.keep_empty_trees = false,
+            "core.workerThreads" => self.worker_threads = 0,
+            "core.xattrs" => self.xattrs = false,
+            _ => return self.extra.remove(key).is_some(),
+        }
+
+        true
+    }
+
+    /// Every key with its value: all of `KEYS`, and everything in `extra`, sorted by key.
+    pub fn entries(&self) -> Vec<(String, String)> {
+        let mut entries = KEYS.iter()
+            .map(|&key| (key.to_owned(), self.get(key).unwrap()))
+            .chain(self.extra.clone())
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries
+    }
+}
+
 impl<B: Backend> Repository<B> {
     pub fn get_config(&self) -> Result<Config, Error> {
-        let raw_config = self.db
-            .read()
-            .unwrap()
-            .get(ReadOptions::new(), &Key::config())?
-            .ok_or_else(|| format_err!("Missing configuration entry"))?;
+        Config::load(&self.db.read().unwrap())
+    }
 
-        Ok(Config::decode(&mut &raw_config[..])?)
+    /// Create a `Hierarchy` for a tree, with the cache capacity set in the configuration.
+    pub fn hierarchy(&self, tree_ref: TreeRef<Handle<B>>) -> Result<Hierarchy<B>, Error> {
+        let capacity = self.get_config()?.hierarchy_cache_capacity;
+        Ok(Hierarchy::with_capacity(tree_ref, capacity))
     }
 
     pub fn set_config(&self, config: &Config) -> Result<(), Error> {
+        let db = self.db.read().unwrap();
+        let previous = db.get(ReadOptions::new(), &Key::config())?;
         let mut buf = Vec::new();
-        config.encode(&mut buf)?;
+        config.encode_over(previous.as_ref().map(|raw| &raw[..]), &mut buf)?;
 
-        self.db
-            .read()
-            .unwrap()
-            .put(WriteOptions::new(), &Key::config(), &buf)?;
+        db.put(WriteOptions::new(), &Key::config(), &buf)?;
 
         Ok(())
     }
+
+    /// The value of a configuration key: from the repository configuration if it has the key, and
+    /// otherwise from the per-user configuration file.
+    pub fn config_value(&self, key: &str) -> Result<Option<String>, Error> {
+        match self.get_config()?.get(key) {
+            Some(value) => Ok(Some(value)),
+            None => user_config_value(key),
+        }
+    }
+
+    /// Set a key in the repository configuration. See `Config::set`.
+    pub fn set_config_value(&self, key: &str, value: &str) -> Result<(), Error> {
+        let mut config = self.get_config()?;
+        config.set(key, value)?;
+        self.set_config(&config)
+    }
+
+    /// Unset a key in the repository configuration. See `Config::unset`.
+    pub fn unset_config_value(&self, key: &str) -> Result<bool, Error> {
+        let mut config = self.get_config()?;
+        let was_set = config.unset(key);
+        self.set_config(&config)?;
+        Ok(was_set)
+    }
+
+    /// Every configuration key with its value, sorted by key. Keys the repository configuration
+    /// doesn't have are taken from the per-user configuration file.
+    pub fn config_entries(&self) -> Result<Vec<(String, String)>, Error> {
+        // The first line for a key in the per-user file is the one which counts.
+        let mut entries = user_config_entries()?
+            .into_iter()
+            .rev()
+            .collect::<BTreeMap<_, _>>();
+        entries.extend(self.get_config()?.entries());
+        Ok(entries.into_iter().collect())
+    }
+
+    pub fn config<'r>(&'r self, args: ConfigArgs) -> ConfigOut<'r> {
+        let blocking = async_block! {
+            if args.is_global() {
+                return user_config(args);
+            }
+
+            match args {
+                ConfigArgs::Get(ConfigGetArgs { key, .. }) => match self.config_value(&key)? {
+                    Some(value) => Ok(vec![(key, value)]),
+                    None => bail!("{} is not set", key),
+                },
+                ConfigArgs::Set(ConfigSetArgs { key, value, .. }) => {
+                    self.set_config_value(&key, &value)?;
+                    Ok(Vec::new())
+                }
+                ConfigArgs::Unset(ConfigUnsetArgs { key, .. }) => {
+                    ensure!(self.unset_config_value(&key)?, "{} is not set", key);
+                    Ok(Vec::new())
+                }
+                ConfigArgs::List(_) => self.config_entries(),
+            }
+        };
+
+        ConfigOut {
+            entries: Box::new(blocking),
+        }
+    }
+}
+
+/// Get and set configuration keys. Without `--global`, keys are set in the repository
+/// configuration, and looked up there before the per-user configuration file, `~/.attacaconfig`.
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(name = "config")]
+pub enum ConfigArgs {
+    #[structopt(name = "get")]
+    Get(ConfigGetArgs),
+
+    #[structopt(name = "set")]
+    Set(ConfigSetArgs),
+
+    #[structopt(name = "unset")]
+    Unset(ConfigUnsetArgs),
+
+    #[structopt(name = "list")]
+    List(ConfigListArgs),
+}
+
+impl ConfigArgs {
+    /// Whether these arguments are about the per-user configuration file alone, and so need no
+    /// repository.
+    pub fn is_global(&self) -> bool {
+        match *self {
+            ConfigArgs::Get(ref args) => args.global,
+            ConfigArgs::Set(ref args) => args.global,
+            ConfigArgs::Unset(ref args) => args.global,
+            ConfigArgs::List(ref args) => args.global,
+        }
+    }
+}
+
+/// Print the value of a key.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "get")]
+pub struct ConfigGetArgs {
+    #[structopt(name = "KEY")]
+    pub key: String,
+
+    /// Only look in the per-user configuration file.
+    #[structopt(long = "global")]
+    pub global: bool,
+}
+
+/// Set a key. Keys which subito doesn't know are kept as they are.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "set")]
+pub struct ConfigSetArgs {
+    #[structopt(name = "KEY")]
+    pub key: String,
+
+    #[structopt(name = "VALUE")]
+    pub value: String,
+
+    /// Set the key in the per-user configuration file.
+    #[structopt(long = "global")]
+    pub global: bool,
+}
+
+/// Unset a key, putting it back to its default.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "unset")]
+pub struct ConfigUnsetArgs {
+    #[structopt(name = "KEY")]
+    pub key: String,
+
+    /// Unset the key in the per-user configuration file.
+    #[structopt(long = "global")]
+    pub global: bool,
+}
+
+/// List every key with its value.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "list")]
+pub struct ConfigListArgs {
+    /// Only list the per-user configuration file.
+    #[structopt(long = "global")]
+    pub global: bool,
+}
+
+#[must_use = "ConfigOut contains futures which must be driven to completion!"]
+pub struct ConfigOut<'r> {
+    /// The keys asked for, with their values: one for `get`, all of them for `list` and none for
+    /// `set` and `unset`.
+    pub entries: Box<Future<Item = Vec<(String, String)>, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for ConfigOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConfigOut")
+            .field("entries", &"OPAQUE")
+            .finish()
+    }
+}
+
+/// Carry out `config` on the per-user configuration file alone, as with `--global`. This needs no
+/// repository.
+pub fn user_config(args: ConfigArgs) -> Result<Vec<(String, String)>, Error> {
+    match args {
+        ConfigArgs::Get(ConfigGetArgs { key, .. }) => match user_config_value(&key)? {
+            Some(value) => Ok(vec![(key, value)]),
+            None => bail!("{} is not set", key),
+        },
+        ConfigArgs::Set(ConfigSetArgs { key, value, .. }) => {
+            set_user_config_value(&key, Some(&value))?;
+            Ok(Vec::new())
+        }
+        ConfigArgs::Unset(ConfigUnsetArgs { key, .. }) => {
+            ensure!(set_user_config_value(&key, None)?, "{} is not set", key);
+            Ok(Vec::new())
+        }
+        ConfigArgs::List(_) => {
+            let entries = user_config_entries()?
+                .into_iter()
+                .rev()
+                .collect::<BTreeMap<_, _>>();
+            Ok(entries.into_iter().collect())
+        }
+    }
 }
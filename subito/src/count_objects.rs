@@ -0,0 +1,118 @@
+use std::{fmt, collections::HashSet};
+
+use attaca::{object::ObjectRef, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+
+use Repository;
+use plumbing;
+
+/// Count objects in the store and report how much space they take up.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "count-objects")]
+pub struct CountObjectsArgs {}
+
+/// Statistics about the objects in a repository's store.
+///
+/// Object kinds are only known for objects reachable from one of the repository's roots (the HEAD,
+/// a ref, the virtual workspace or the stash), since the store itself does not record them.
+#[derive(Debug, Clone, Default)]
+pub struct ObjectCounts {
+    /// Storage statistics reported by the backend, if it can enumerate its objects.
+    pub store: Option<StoreStats>,
+
+    pub commits: u64,
+    pub trees: u64,
+    /// Large objects; these index other blobs and hold no data themselves.
+    pub large_blobs: u64,
+    pub small_blobs: u64,
+    /// Total size of the data held by reachable small blobs.
+    pub blob_bytes: u64,
+}
+
+impl ObjectCounts {
+    /// The number of objects reachable from some root.
+    pub fn reachable(&self) -> u64 {
+        self.commits + self.trees + self.large_blobs + self.small_blobs
+    }
+
+    /// The number of objects in the store not reachable from any root, if the backend can tell us
+    /// how many objects it holds.
+    pub fn unreachable(&self) -> Option<u64> {
+        self.store
+            .map(|stats| stats.loose_objects.saturating_sub(self.reachable()))
+    }
+}
+
+#[must_use = "CountObjectsOut contains futures which must be driven to completion!"]
+pub struct CountObjectsOut<'r> {
+    pub blocking: Box<Future<Item = ObjectCounts, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for CountObjectsOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CountObjectsOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    pub fn count_objects<'r>(&'r self, args: CountObjectsArgs) -> CountObjectsOut<'r> {
+        let CountObjectsArgs {} = args;
+
+        let blocking = async_block! {
+            let mut counts = ObjectCounts {
+                store: await!(self.store.stats())?,
+                ..ObjectCounts::default()
+            };
+
+            let mut stack = await!(plumbing::roots(self))?
+                .into_iter()
+                .map(|(_, root)| root)
+                .collect::<Vec<_>>();
+
+            let mut visited = HashSet::new();
+            while let Some(object_ref) = stack.pop() {
+                if !visited.insert(object_ref.as_inner().clone()) {
+                    continue;
+                }
+
+                match object_ref {
+                    ObjectRef::Small(small_ref) => {
+                        counts.small_blobs += 1;
+                        counts.blob_bytes += small_ref.size();
+                    }
+                    ObjectRef::Large(large_ref) => {
+                        counts.large_blobs += 1;
+                        let large = await!(large_ref.fetch())?;
+                        stack.extend(large.into_iter().map(|(_, child_ref)| child_ref));
+                    }
+                    ObjectRef::Tree(tree_ref) => {
+                        counts.trees += 1;
+                        let tree = await!(tree_ref.fetch())?;
+                        stack.extend(tree.into_iter().map(|(_, child_ref)| child_ref));
+                    }
+                    ObjectRef::Commit(commit_ref) => {
+                        counts.commits += 1;
+                        let commit = await!(commit_ref.fetch())?;
+                        stack.push(ObjectRef::Tree(commit.as_subtree().clone()));
+                        stack.extend(
+                            commit
+                                .as_parents()
+                                .iter()
+                                .cloned()
+                                .map(ObjectRef::Commit),
+                        );
+                    }
+                }
+            }
+
+            Ok(counts)
+        };
+
+        CountObjectsOut {
+            blocking: Box::new(blocking),
+        }
+    }
+}
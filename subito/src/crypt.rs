@@ -0,0 +1,52 @@
+//! Passphrases for stores encrypted at rest.
+//!
+//! The passphrase is read from `$ATTACA_PASSPHRASE` if it is set, and otherwise prompted for on
+//! the terminal. The key is derived from it with the salt kept in the repository configuration.
+
+use std::env;
+
+use attaca::seal::{self, Sealer};
+use failure::*;
+use rpassword;
+
+use config::EncryptionConfig;
+
+/// Environment variable holding the passphrase of an encrypted store.
+pub const PASSPHRASE_VAR: &'static str = "ATTACA_PASSPHRASE";
+
+fn passphrase(confirm: bool) -> Result<String, Error> {
+    if let Some(passphrase) = env::var_os(PASSPHRASE_VAR) {
+        return passphrase
+            .into_string()
+            .map_err(|_| format_err!("${} is not valid unicode", PASSPHRASE_VAR));
+    }
+
+    let passphrase = rpassword::prompt_password_stderr("Passphrase: ")?;
+    if confirm {
+        let confirmation = rpassword::prompt_password_stderr("Confirm passphrase: ")?;
+        ensure!(passphrase == confirmation, "Passphrases do not match!");
+    }
+    ensure!(!passphrase.is_empty(), "Empty passphrase!");
+
+    Ok(passphrase)
+}
+
+/// Set up encryption for a new store, asking for a passphrase.
+pub fn create() -> Result<(EncryptionConfig, Sealer), Error> {
+    let passphrase = passphrase(true)?;
+    let salt = seal::generate_salt()?;
+    let sealer = Sealer::derive(passphrase.as_bytes(), &salt)?;
+    let check = sealer.check_value()?;
+
+    Ok((EncryptionConfig { salt, check }, sealer))
+}
+
+/// Derive the key for an existing encrypted store, asking for its passphrase. Fails if the
+/// passphrase is wrong.
+pub fn unlock(encryption: &EncryptionConfig) -> Result<Sealer, Error> {
+    let passphrase = passphrase(false)?;
+    let sealer = Sealer::derive(passphrase.as_bytes(), &encryption.salt)?;
+    sealer.verify(&encryption.check)?;
+
+    Ok(sealer)
+}
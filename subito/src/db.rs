@@ -2,7 +2,10 @@ use attaca::path::ObjectPath;
 use smallvec::SmallVec;
 
 const CACHE_PREFIX: &'static [u8] = b"CH";
+const DIR_CACHE_PREFIX: &'static [u8] = b"DC";
 const CONFIG_KEY: &'static [u8] = b"CONFIG";
+const SCHEMA_VERSION_KEY: &'static [u8] = b"SCHEMA_VERSION";
+const STASH_KEY: &'static [u8] = b"STASH";
 const STATE_KEY: &'static [u8] = b"STATE";
 
 #[derive(Debug, Clone)]
@@ -35,16 +38,30 @@ impl Key {
         Key::Borrowed(CONFIG_KEY)
     }
 
+    pub fn schema_version() -> Self {
+        Key::Borrowed(SCHEMA_VERSION_KEY)
+    }
+
     pub fn state() -> Self {
         Key::Borrowed(STATE_KEY)
     }
 
+    pub fn stash() -> Self {
+        Key::Borrowed(STASH_KEY)
+    }
+
     pub fn cache(path: &ObjectPath) -> Self {
         let mut buf = SmallVec::from(CACHE_PREFIX);
         path.encode(&mut buf).unwrap();
         Key::Owned(buf)
     }
 
+    pub fn dir_cache(path: &ObjectPath) -> Self {
+        let mut buf = SmallVec::from(DIR_CACHE_PREFIX);
+        path.encode(&mut buf).unwrap();
+        Key::Owned(buf)
+    }
+
     pub fn is_from_cache(&self) -> bool {
         &self.as_ref()[..2] == CACHE_PREFIX
     }
@@ -0,0 +1,114 @@
+use std::{fmt, borrow::Borrow, collections::{HashMap, HashSet, VecDeque}};
+
+use attaca::{object::CommitRef, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+use hex;
+
+use Repository;
+use plumbing::{self, merge};
+use refs;
+use syntax::{Ref, Rev};
+
+/// Name a commit after the nearest tag it descends from.
+///
+/// A tagged commit is named by its tag. Any other commit is named `TAG-N-gDIGEST`, where TAG is
+/// the nearest tag it descends from, N the number of commits it has which TAG doesn't, and DIGEST
+/// its own abbreviated digest.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "describe")]
+pub struct DescribeArgs {
+    /// The commit to describe. Defaults to HEAD.
+    #[structopt(name = "REV")]
+    pub rev: Option<Rev>,
+
+    /// Name a commit which descends from no tag by its abbreviated digest, rather than failing.
+    #[structopt(long = "always")]
+    pub always: bool,
+}
+
+#[must_use = "DescribeOut contains futures which must be driven to completion!"]
+pub struct DescribeOut<'r> {
+    pub blocking: Box<Future<Item = String, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for DescribeOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DescribeOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    pub fn describe<'r>(&'r self, args: DescribeArgs) -> DescribeOut<'r> {
+        let DescribeArgs { rev, always } = args;
+        let blocking = async_block! {
+            let rev = rev.unwrap_or_else(|| Rev::from(Ref::Head));
+            let commit_ref = await!(plumbing::resolve_rev(self, rev))?;
+            await!(self.describe_commit(commit_ref, always))
+        };
+
+        DescribeOut {
+            blocking: Box::new(blocking),
+        }
+    }
+
+    /// Name a commit after the nearest tag it descends from; see `DescribeArgs`. The nearest tag
+    /// is the one the fewest steps back through parents, and where several tags name the same
+    /// commit, the first by name is used. A commit which descends from no tag is named by its
+    /// abbreviated digest if `always` is set, and is an error otherwise.
+    pub fn describe_commit<'r>(
+        &'r self,
+        commit_ref: CommitRef<Handle<B>>,
+        always: bool,
+    ) -> Box<Future<Item = String, Error = Error> + 'r> {
+        let blocking = async_block! {
+            let mut tags = HashMap::new();
+            for (name, handle) in await!(refs::load_tags(&self.store))? {
+                tags.entry(CommitRef::new(handle))
+                    .or_insert_with(Vec::new)
+                    .push(name);
+            }
+
+            let mut nearest = None;
+            let mut visited = HashSet::new();
+            let mut queue = VecDeque::new();
+            queue.push_back(commit_ref.clone());
+            while let Some(ancestor) = queue.pop_front() {
+                if !visited.insert(ancestor.clone()) {
+                    continue;
+                }
+
+                if let Some(names) = tags.get(&ancestor) {
+                    let name = names.iter().min().cloned().unwrap();
+                    nearest = Some((name, ancestor));
+                    break;
+                }
+
+                let commit = await!(ancestor.fetch())?;
+                queue.extend(commit.as_parents().iter().cloned());
+            }
+
+            let commit_id = await!(commit_ref.id())?;
+            let digest = hex::encode(commit_id.as_inner().borrow().as_bytes());
+            let abbreviated = await!(self.abbreviator().blocking)?.abbreviate(&digest);
+
+            match nearest {
+                Some((name, tagged)) => if tagged == commit_ref {
+                    Ok(name)
+                } else {
+                    let (ahead, _) = await!(merge::ahead_behind(commit_ref, tagged))?;
+                    Ok(format!("{}-{}-g{}", name, ahead, abbreviated))
+                },
+                None if always => Ok(abbreviated),
+                None => bail!(
+                    "No tags can describe {}; use --always to show its digest instead.",
+                    abbreviated
+                ),
+            }
+        };
+
+        Box::new(blocking)
+    }
+}
@@ -0,0 +1,342 @@
+use std::{cmp, fmt, str};
+
+use attaca::{object::{ObjectRef, TreeRef}, path::ObjectPath, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+
+use Repository;
+use plumbing;
+use status::Change;
+use syntax::Ref;
+use textdiff::{self, Granularity, RenderOptions};
+use xattrs;
+
+/// Show changes between two commits, or between a commit and the virtual workspace.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "diff")]
+pub struct DiffArgs {
+    /// The ref to compare from.
+    #[structopt(name = "FROM", default_value = "HEAD")]
+    pub from: Ref,
+
+    /// The ref to compare to. If omitted, the virtual workspace is used.
+    #[structopt(name = "TO")]
+    pub to: Option<Ref>,
+
+    /// Number of lines of context to show around each change.
+    #[structopt(short = "U", long = "unified", default_value = "3")]
+    pub context: usize,
+
+    /// Show a word-level diff instead of a line-level one.
+    #[structopt(long = "word")]
+    pub word: bool,
+
+    /// Color removed and added text.
+    #[structopt(long = "color")]
+    pub color: bool,
+
+    /// Instead of the diff itself, show how many lines changed in each file as a histogram,
+    /// followed by a summary.
+    #[structopt(long = "stat", raw(conflicts_with = r#""numstat""#))]
+    pub stat: bool,
+
+    /// Instead of the diff itself, show the number of added and removed lines in each file, tab
+    /// separated, for use by scripts. Binary files have no lines, and are shown as `-` for both.
+    #[structopt(long = "numstat")]
+    pub numstat: bool,
+}
+
+/// The width of the largest bar in the `--stat` histogram.
+const STAT_WIDTH: usize = 50;
+
+/// Lines added and removed in a file, or `None` for a binary file.
+type LineCounts = Option<(usize, usize)>;
+
+fn line_counts(old_data: &[u8], new_data: &[u8]) -> Result<LineCounts, Error> {
+    if textdiff::is_binary(old_data) || textdiff::is_binary(new_data) {
+        return Ok(None);
+    }
+
+    let old_lines = str::from_utf8(old_data)?.lines().collect::<Vec<_>>();
+    let new_lines = str::from_utf8(new_data)?.lines().collect::<Vec<_>>();
+    Ok(Some(textdiff::line_counts(&old_lines, &new_lines)))
+}
+
+fn render_numstat(path: &str, counts: LineCounts) -> String {
+    match counts {
+        Some((added, removed)) => format!("{}\t{}\t{}\n", added, removed, path),
+        None => format!("-\t-\t{}\n", path),
+    }
+}
+
+fn render_stat(entries: &[(String, LineCounts)]) -> String {
+    let name_width = entries.iter().map(|&(ref path, _)| path.len()).max().unwrap_or(0);
+    let max_changed = entries
+        .iter()
+        .filter_map(|&(_, counts)| counts.map(|(added, removed)| added + removed))
+        .max()
+        .unwrap_or(0);
+    let count_width = max_changed.to_string().len();
+
+    // Scale the bars down only if the largest wouldn't fit, keeping at least one character for
+    // any nonzero count.
+    let scale = |n: usize| {
+        if max_changed <= STAT_WIDTH || n == 0 {
+            n
+        } else {
+            cmp::max(1, n * STAT_WIDTH / max_changed)
+        }
+    };
+
+    let mut out = String::new();
+    let (mut insertions, mut deletions) = (0, 0);
+    for &(ref path, counts) in entries {
+        match counts {
+            Some((added, removed)) => {
+                insertions += added;
+                deletions += removed;
+                out.push_str(&format!(
+                    " {:name_width$} | {:>count_width$} {}{}\n",
+                    path,
+                    added + removed,
+                    "+".repeat(scale(added)),
+                    "-".repeat(scale(removed)),
+                    name_width = name_width,
+                    count_width = count_width,
+                ));
+            }
+            None => out.push_str(&format!(
+                " {:name_width$} | Bin\n",
+                path,
+                name_width = name_width
+            )),
+        }
+    }
+    out.push_str(&format!(
+        " {} file{} changed, {} insertion{}(+), {} deletion{}(-)\n",
+        entries.len(),
+        if entries.len() == 1 { "" } else { "s" },
+        insertions,
+        if insertions == 1 { "" } else { "s" },
+        deletions,
+        if deletions == 1 { "" } else { "s" },
+    ));
+
+    out
+}
+
+/// How a path differs between the two sides of a diff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// A path which differs between the two sides of a diff. Files are compared by digest, without
+/// fetching their contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffEntry {
+    pub path: ObjectPath,
+    pub kind: DiffKind,
+}
+
+impl From<Change> for DiffEntry {
+    fn from(change: Change) -> Self {
+        match change {
+            Change::Added(path) => DiffEntry {
+                path,
+                kind: DiffKind::Added,
+            },
+            Change::Removed(path) => DiffEntry {
+                path,
+                kind: DiffKind::Removed,
+            },
+            Change::Modified(path) => DiffEntry {
+                path,
+                kind: DiffKind::Modified,
+            },
+        }
+    }
+}
+
+#[must_use = "DiffOut contains futures which must be driven to completion!"]
+pub struct DiffOut<'r> {
+    /// Rendered output: a header for each changed file, followed by one item per hunk. With
+    /// `--numstat` there is one item per file instead, and with `--stat` a single item.
+    pub output: Box<Stream<Item = String, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for DiffOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DiffOut")
+            .field("output", &"OPAQUE")
+            .finish()
+    }
+}
+
+/// Read the full contents of a blob. Returns `None` if the ref is to a tree or commit.
+#[async(boxed)]
+pub(crate) fn read_blob<B: Backend>(
+    object_ref: ObjectRef<Handle<B>>,
+) -> Result<Option<Vec<u8>>, Error> {
+    match object_ref {
+        ObjectRef::Small(small_ref) => Ok(Some(await!(small_ref.fetch())?.to_vec())),
+        ObjectRef::Large(large_ref) => {
+            let large = await!(large_ref.fetch())?;
+            let mut buf = Vec::with_capacity(large.size() as usize);
+            for (_, child_ref) in large {
+                let child = await!(read_blob(child_ref))?
+                    .ok_or_else(|| format_err!("Malformed large object: non-blob child"))?;
+                buf.extend(child);
+            }
+            Ok(Some(buf))
+        }
+        ObjectRef::Tree(_) | ObjectRef::Commit(_) => Ok(None),
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    pub fn diff<'r>(&'r self, args: DiffArgs) -> DiffOut<'r> {
+        let options = RenderOptions {
+            context: args.context,
+            granularity: if args.word {
+                Granularity::Word
+            } else {
+                Granularity::Line
+            },
+            color: args.color,
+        };
+
+        let output = async_stream_block! {
+            let (from_tree, to_tree) = await!(self.diff_trees(args.from, args.to))?;
+
+            let mut stats = Vec::new();
+            let from_hierarchy = self.hierarchy(from_tree.clone())?;
+            let to_hierarchy = self.hierarchy(to_tree.clone())?;
+
+            #[async]
+            for entry in Self::tree_entries(from_tree, to_tree) {
+                let path = entry.path.clone();
+                let (maybe_old, maybe_new) = await!(
+                    from_hierarchy.get(path.clone()).join(to_hierarchy.get(path.clone()))
+                )?;
+
+                let old_data = match maybe_old {
+                    Some(old_ref) => match await!(read_blob(old_ref))? {
+                        Some(data) => Some(data),
+                        None => continue,
+                    },
+                    None => None,
+                };
+                let new_data = match maybe_new {
+                    Some(new_ref) => match await!(read_blob(new_ref))? {
+                        Some(data) => Some(data),
+                        None => continue,
+                    },
+                    None => None,
+                };
+
+                if args.stat || args.numstat {
+                    let old_data = old_data.unwrap_or_default();
+                    let new_data = new_data.unwrap_or_default();
+                    let counts = line_counts(&old_data, &new_data)?;
+
+                    if args.numstat {
+                        stream_yield!(render_numstat(&path.to_string(), counts));
+                    } else {
+                        stats.push((path.to_string(), counts));
+                    }
+                    continue;
+                }
+
+                let (old_name, new_name) = match entry.kind {
+                    DiffKind::Added => ("/dev/null".to_owned(), format!("b/{}", path)),
+                    DiffKind::Removed => (format!("a/{}", path), "/dev/null".to_owned()),
+                    DiffKind::Modified => (format!("a/{}", path), format!("b/{}", path)),
+                };
+                stream_yield!(format!("diff {}\n--- {}\n+++ {}\n", path, old_name, new_name));
+
+                let old_data = old_data.unwrap_or_default();
+                let new_data = new_data.unwrap_or_default();
+
+                if textdiff::is_binary(&old_data) || textdiff::is_binary(&new_data) {
+                    stream_yield!("Binary files differ\n".to_owned());
+                    continue;
+                }
+
+                let old_lines = str::from_utf8(&old_data)?.lines().collect::<Vec<_>>();
+                let new_lines = str::from_utf8(&new_data)?.lines().collect::<Vec<_>>();
+
+                for hunk in textdiff::hunks(&old_lines, &new_lines, options.context) {
+                    stream_yield!(hunk.render(&options));
+                }
+            }
+
+            if args.stat && !stats.is_empty() {
+                stream_yield!(render_stat(&stats));
+            }
+
+            Ok(())
+        };
+
+        DiffOut {
+            output: Box::new(output),
+        }
+    }
+
+    /// The paths which differ between the trees of two refs, or between the tree of `from` and
+    /// the virtual workspace if `to` is omitted. Identical subtrees are not descended into.
+    pub fn diff_entries<'r>(
+        &'r self,
+        from: Ref,
+        to: Option<Ref>,
+    ) -> Box<Stream<Item = DiffEntry, Error = Error> + 'r> {
+        let entries = async_stream_block! {
+            let (from_tree, to_tree) = await!(self.diff_trees(from, to))?;
+
+            #[async]
+            for entry in Self::tree_entries(from_tree, to_tree) {
+                stream_yield!(entry);
+            }
+
+            Ok(())
+        };
+
+        Box::new(entries)
+    }
+
+    /// The trees compared by a diff: that of `from`, and that of `to` or else the virtual
+    /// workspace.
+    fn diff_trees<'r>(
+        &'r self,
+        from: Ref,
+        to: Option<Ref>,
+    ) -> impl Future<Item = (TreeRef<Handle<B>>, TreeRef<Handle<B>>), Error = Error> + 'r {
+        async_block! {
+            let from_ref = await!(plumbing::resolve(self, from))?;
+            let from_tree = await!(self.tree_of(&from_ref))?;
+            let to_tree = match to {
+                Some(to) => {
+                    let to_ref = await!(plumbing::resolve(self, to))?;
+                    await!(self.tree_of(&to_ref))?
+                }
+                None => self.get_state()?
+                    .candidate
+                    .ok_or_else(|| format_err!("No virtual workspace to compare against!"))?,
+            };
+
+            Ok((from_tree, to_tree))
+        }
+    }
+
+    /// The differences between two trees, leaving out extended attribute manifests.
+    fn tree_entries(
+        from_tree: TreeRef<Handle<B>>,
+        to_tree: TreeRef<Handle<B>>,
+    ) -> impl Stream<Item = DiffEntry, Error = Error> {
+        Self::compare_subtrees(from_tree, to_tree)
+            .filter(|change| !xattrs::is_manifest(change.as_path()))
+            .map(DiffEntry::from)
+    }
+}
@@ -0,0 +1,262 @@
+//! Compare the local workspace to the virtual workspace.
+//!
+//! This is the "unstaged" half of `status`: for every file staged in the virtual workspace or
+//! present in the local one, whether it has been added, modified or deleted locally since it was
+//! last staged. New local files are found by walking the workspace the same way `stage` does, so
+//! ignored and hidden files are not reported as added; a staged file is still compared even if it
+//! has since become ignored.
+//!
+//! Files which the cache proves unchanged since they were last hashed are not read again. Any
+//! others are hashed as staging them would, but nothing is sent to the store: comparing the
+//! workspace leaves the store as it was.
+
+use std::{fmt, fs, collections::{BTreeMap, BTreeSet}};
+
+use attaca::{digest::Sha3Digest, object::{ObjectRef, TreeRef}, path::ObjectPath,
+             store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+use ignore::WalkBuilder;
+use itertools::{EitherOrBoth, Itertools};
+
+use Repository;
+use attributes::Attributes;
+use candidate::IGNORE_FILE;
+use xattrs;
+
+/// Show changes in the local workspace which have not been staged.
+#[derive(Default, Debug, StructOpt, Builder)]
+#[structopt(name = "diff-workspace")]
+pub struct DiffWorkspaceArgs {
+    /// Also list files which are unchanged.
+    #[structopt(long = "unchanged")]
+    pub unchanged: bool,
+}
+
+/// The state of a file in the local workspace, relative to the virtual workspace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkspaceStatus {
+    /// Present locally, but not staged.
+    Added,
+
+    /// Staged, but with different contents locally.
+    Modified,
+
+    /// Staged, but missing locally.
+    Deleted,
+
+    /// Identical locally and staged.
+    Unchanged,
+}
+
+impl WorkspaceStatus {
+    /// The single-character code for this status, matching the `Y` column of `status
+    /// --porcelain`.
+    pub fn code(&self) -> char {
+        match *self {
+            WorkspaceStatus::Added => 'A',
+            WorkspaceStatus::Modified => 'M',
+            WorkspaceStatus::Deleted => 'D',
+            WorkspaceStatus::Unchanged => ' ',
+        }
+    }
+}
+
+#[must_use = "DiffWorkspaceOut contains futures which must be driven to completion!"]
+pub struct DiffWorkspaceOut<'r> {
+    /// Each file and its status, in path order. Unchanged files are only included if asked for.
+    pub entries: Box<Stream<Item = (ObjectPath, WorkspaceStatus), Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for DiffWorkspaceOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DiffWorkspaceOut")
+            .field("entries", &"OPAQUE")
+            .finish()
+    }
+}
+
+/// Whether `path` is at or beneath one of the paths in `within`, if it is given.
+fn is_within(path: &ObjectPath, within: &Option<Vec<ObjectPath>>) -> bool {
+    within
+        .as_ref()
+        .map_or(true, |prefixes| prefixes.iter().any(|prefix| path.starts_with(prefix)))
+}
+
+impl<B: Backend> Repository<B> {
+    /// Collect every blob beneath a tree, keyed by its path. Unlike `walk_tree`, identical
+    /// subtrees are descended into under every path they appear at. Manifests of extended
+    /// attributes aren't files of the workspace, and are left out.
+    #[async]
+    pub(crate) fn staged_files(
+        tree_ref: TreeRef<Handle<B>>,
+    ) -> Result<BTreeMap<ObjectPath, ObjectRef<Handle<B>>>, Error> {
+        let mut files = BTreeMap::new();
+        let mut stack = vec![(ObjectPath::new(), tree_ref)];
+
+        while let Some((tree_path, tree_ref)) = stack.pop() {
+            let tree = await!(tree_ref.fetch())?;
+            for (name, object_ref) in tree {
+                let path = tree_path.push_back(name);
+                match object_ref {
+                    ObjectRef::Tree(subtree_ref) => stack.push((path, subtree_ref)),
+                    ObjectRef::Commit(_) => bail!("Malformed tree: commit at {}", path),
+                    _ if xattrs::is_manifest(&path) => {}
+                    blob_ref => {
+                        files.insert(path, blob_ref);
+                    }
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// The paths of every file in the local workspace which `stage` would pick up.
+    pub(crate) fn local_files(&self) -> Result<BTreeSet<ObjectPath>, Error> {
+        let mut files = BTreeSet::new();
+        let walk = WalkBuilder::new(&*self.path)
+            .add_custom_ignore_filename(IGNORE_FILE)
+            .build();
+        for direntry_res in walk {
+            let direntry = direntry_res?;
+            if direntry.file_type().map_or(true, |file_type| file_type.is_dir()) {
+                continue;
+            }
+
+            let relative_path = direntry.path().strip_prefix(&*self.path)?;
+            files.insert(ObjectPath::from_path(relative_path)?);
+        }
+
+        Ok(files)
+    }
+
+    /// Compare the local workspace to the virtual workspace. See the module documentation.
+    pub fn diff_workspace<'r>(&'r self, args: DiffWorkspaceArgs) -> DiffWorkspaceOut<'r> {
+        let entries = async_stream_block! {
+            let candidate = self.get_state()?.candidate;
+            #[async]
+            for entry in self.compare_local(candidate, None, args.unchanged) {
+                stream_yield!(entry);
+            }
+
+            Ok(())
+        };
+
+        DiffWorkspaceOut {
+            entries: Box::new(entries),
+        }
+    }
+
+    /// Compare the local workspace to `tree`, or to an empty tree if there is none, yielding each
+    /// file and its status in path order. If `within` is given, only files at or beneath one of
+    /// its paths are compared.
+    fn compare_local<'r>(
+        &'r self,
+        tree: Option<TreeRef<Handle<B>>>,
+        within: Option<Vec<ObjectPath>>,
+        unchanged: bool,
+    ) -> Box<Stream<Item = (ObjectPath, WorkspaceStatus), Error = Error> + 'r> {
+        let entries = async_stream_block! {
+            // Manifests of extended attributes are never checked out, so they have no local
+            // counterpart to compare against. See `xattrs`.
+            let staged = match tree {
+                Some(tree_ref) => await!(Self::staged_files(tree_ref))?
+                    .into_iter()
+                    .filter(|&(ref path, _)| !xattrs::is_manifest(path) && is_within(path, &within))
+                    .collect(),
+                None => BTreeMap::new(),
+            };
+
+            let mut local = self.local_files().context("Error walking the local workspace")?;
+            local.retain(|path| is_within(path, &within));
+            for path in staged.keys() {
+                let is_file = fs::symlink_metadata(self.path.join(path.to_path()))
+                    .map(|metadata| !metadata.is_dir())
+                    .unwrap_or(false);
+                if is_file {
+                    local.insert(path.clone());
+                }
+            }
+
+            let attributes = Attributes::load(&self.path)?;
+            let merged = Itertools::merge_join_by(
+                staged.into_iter(),
+                local.into_iter(),
+                |&(ref staged_path, _), local_path| staged_path.cmp(local_path),
+            );
+
+            for either_or_both in merged {
+                match either_or_both {
+                    EitherOrBoth::Left((path, _)) => {
+                        stream_yield!((path, WorkspaceStatus::Deleted));
+                    }
+                    EitherOrBoth::Right(path) => {
+                        stream_yield!((path, WorkspaceStatus::Added));
+                    }
+                    EitherOrBoth::Both((path, staged_ref), _) => {
+                        let staged_digest = await!(staged_ref.digest::<Sha3Digest>())?;
+                        let local_digest = await!(Self::hash_local_file(
+                            self.store.clone(),
+                            self.cache.clone(),
+                            self.pool.clone(),
+                            attributes.get(&path),
+                            self.path.join(path.to_path()),
+                            path.clone(),
+                        ))?;
+
+                        if local_digest != staged_digest {
+                            stream_yield!((path, WorkspaceStatus::Modified));
+                        } else if unchanged {
+                            stream_yield!((path, WorkspaceStatus::Unchanged));
+                        }
+                    }
+                }
+            }
+
+            self.cache.flush().context("Error while writing cache entries")?;
+
+            Ok(())
+        };
+
+        Box::new(entries)
+    }
+
+    /// Refuse to go on if any local file at or beneath one of `paths` differs from the virtual
+    /// workspace (or from HEAD, if there is no virtual workspace), because `action` is about to
+    /// overwrite or remove it. Untracked files count as local changes, since they would be lost
+    /// just the same; ignored files do not. The empty path covers the whole workspace.
+    pub(crate) fn ensure_unchanged_locally<'r>(
+        &'r self,
+        paths: Vec<ObjectPath>,
+        action: &'r str,
+    ) -> Box<Future<Item = (), Error = Error> + 'r> {
+        let blocking = async_block! {
+            if paths.is_empty() {
+                return Ok(());
+            }
+
+            let tree = match self.get_state()?.candidate {
+                Some(candidate) => Some(candidate),
+                None => await!(self.head_tree())?,
+            };
+            let changed = await!(self.compare_local(tree, Some(paths), false).collect())?;
+            if !changed.is_empty() {
+                let listing = changed
+                    .iter()
+                    .map(|&(ref path, _)| format!("\n    {}", path))
+                    .collect::<String>();
+                bail!(
+                    "Local changes to these files would be lost by {}; stage, stash or remove \
+                     them first:{}",
+                    action,
+                    listing
+                );
+            }
+
+            Ok(())
+        };
+
+        Box::new(blocking)
+    }
+}
@@ -0,0 +1,138 @@
+//! Editing text, such as commit messages, in the user's editor.
+
+use std::{env, fmt, fs::File, io::{Read, Write}, path::Path, process::Command, str::FromStr};
+
+use failure::*;
+use nix::{libc::STDIN_FILENO, unistd::isatty};
+
+/// Editor to use, taking precedence over `$VISUAL` and `$EDITOR`.
+pub const EDITOR_VAR: &'static str = "ATTACA_EDITOR";
+
+const FALLBACK_EDITOR: &'static str = "vi";
+
+/// The line below which `Cleanup::Scissors` discards everything, itself included.
+pub const SCISSORS: &'static str = "# ------------------------ >8 ------------------------";
+
+/// How a commit message is cleaned up before it is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cleanup {
+    /// Like `Whitespace`, and also remove lines starting with `#`.
+    Strip,
+
+    /// Remove trailing whitespace from each line, collapse runs of blank lines into one, and
+    /// remove blank lines at the start and end.
+    Whitespace,
+
+    /// Keep the message exactly as it is.
+    Verbatim,
+
+    /// Like `Whitespace`, but first discard everything from the `SCISSORS` line onwards.
+    Scissors,
+}
+
+impl Cleanup {
+    pub const VARIANTS: &'static [&'static str] = &["strip", "whitespace", "verbatim", "scissors"];
+
+    /// Clean up `message` according to this mode.
+    pub fn apply(&self, message: &str) -> String {
+        let lines = match *self {
+            Cleanup::Verbatim => return message.to_owned(),
+            Cleanup::Strip => message
+                .lines()
+                .filter(|line| !line.starts_with('#'))
+                .collect::<Vec<_>>(),
+            Cleanup::Whitespace => message.lines().collect(),
+            Cleanup::Scissors => message
+                .lines()
+                .take_while(|line| line.trim_right() != SCISSORS)
+                .collect(),
+        };
+
+        let mut cleaned = String::new();
+        let mut blank_run = false;
+        for line in lines.into_iter().map(str::trim_right) {
+            if line.is_empty() {
+                blank_run = true;
+                continue;
+            }
+
+            if !cleaned.is_empty() {
+                cleaned.push('\n');
+                if blank_run {
+                    cleaned.push('\n');
+                }
+            }
+            cleaned.push_str(line);
+            blank_run = false;
+        }
+
+        cleaned
+    }
+}
+
+impl FromStr for Cleanup {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strip" => Ok(Cleanup::Strip),
+            "whitespace" => Ok(Cleanup::Whitespace),
+            "verbatim" => Ok(Cleanup::Verbatim),
+            "scissors" => Ok(Cleanup::Scissors),
+            _ => bail!("Unknown cleanup mode {:?}", s),
+        }
+    }
+}
+
+impl fmt::Display for Cleanup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Cleanup::Strip => "strip",
+            Cleanup::Whitespace => "whitespace",
+            Cleanup::Verbatim => "verbatim",
+            Cleanup::Scissors => "scissors",
+        })
+    }
+}
+
+/// The editor command to run: the first of `$ATTACA_EDITOR`, `$VISUAL` and `$EDITOR` which is set
+/// and non-empty, or `vi`.
+fn editor() -> String {
+    [EDITOR_VAR, "VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.trim().is_empty())
+        .unwrap_or_else(|| FALLBACK_EDITOR.to_owned())
+}
+
+/// Whether there is a user to run an editor for: stdin is a terminal. Otherwise, as when run by
+/// a script, the editor could wait forever for input which will never come.
+pub fn is_interactive() -> bool {
+    isatty(STDIN_FILENO).unwrap_or(false)
+}
+
+/// Let the user edit `initial` in their editor, using the file at `path`, and return the result
+/// as it was saved; see `Cleanup` for tidying it up. The editor command is run by the shell, so
+/// it may include arguments.
+pub fn edit(path: &Path, initial: &str) -> Result<String, Error> {
+    File::create(path)
+        .and_then(|mut file| file.write_all(initial.as_bytes()))
+        .context("Error writing file to edit")?;
+
+    let editor = editor();
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(&editor)
+        .arg(path)
+        .status()
+        .with_context(|_| format!("Error running editor {:?}", editor))?;
+    ensure!(status.success(), "Editor {:?} failed: {}", editor, status);
+
+    let mut edited = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut edited))
+        .context("Error reading edited file")?;
+
+    Ok(edited)
+}
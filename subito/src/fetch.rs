@@ -1,14 +1,16 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, io::Write};
 
-use attaca::{Open, object::CommitRef, store::{self, prelude::*}};
+use attaca::{Open, digest::{Sha3Digest, prelude::*}, limit::RateLimiter, object::CommitRef,
+             store::{self, prelude::*}};
 use attaca_leveldb::LevelDbBackend;
 use failure::Error;
 use futures::prelude::*;
+use hex;
 use url::Url;
 
 use Repository;
 use config::StoreKind;
-use plumbing;
+use plumbing::{self, fetch::FetchReport};
 use state::State;
 use syntax::Name;
 
@@ -18,17 +20,86 @@ use syntax::Name;
 pub struct FetchArgs {
     /// Name of the remote to fetch objects and branches from.
     #[structopt(name = "REMOTE")]
+    pub remote: Name,
+
+    /// Limit the transfer rate to this many bytes per second. Zero means no limit.
+    #[structopt(long = "limit-rate", default_value = "0")]
+    limit_rate: u64,
+
+    /// Report progress even if stderr is not a terminal.
+    #[structopt(long = "progress", raw(conflicts_with = r#""no_progress""#))]
+    pub progress: bool,
+
+    /// Do not report progress, even if stderr is a terminal.
+    #[structopt(long = "no-progress")]
+    pub no_progress: bool,
+
+    /// Report the outcome in a stable, easy-to-parse format for scripts instead, on stdout. See
+    /// `PORCELAIN_VERSION` for the format. Implies `--no-progress`.
+    #[structopt(long = "porcelain")]
+    pub porcelain: bool,
+}
+
+/// Version of the `--porcelain` output format of `fetch` and `clone`.
+///
+/// Version 2 emits one record per line, each a keyword followed by space-separated fields:
+///
+/// - `new-branch <remote>/<branch> <digest>`: a branch the remote did not have when last fetched.
+/// - `updated-branch <remote>/<branch> <digest>`: a branch which has moved since the last fetch.
+/// - `pruned-branch <remote>/<branch>`: a branch the remote has deleted since the last fetch,
+///   whose remote-tracking branch has been removed. (New in version 2.)
+/// - `objects-transferred <count>`: how many objects were copied. This is absent when the objects
+///   were transferred as a pack, in which case the count isn't known.
+/// - `done`: always the last record, written only if the whole operation succeeded.
+///
+/// Each kind of branch record is sorted by branch name, and digests are full hex SHA3-256 digests. Nothing is
+/// written for branches which haven't changed. Any change to this format will bump the version.
+pub const PORCELAIN_VERSION: u32 = 2;
+
+/// Write the branch and object records of a fetch from `remote` to `out`, in the format described
+/// by `PORCELAIN_VERSION`. The final `done` record is left to the caller, which may have more to
+/// do before the operation is complete.
+pub fn write_porcelain<'a, B: Backend, W: Write + 'a>(
     remote: Name,
+    report: FetchReport<B>,
+    mut out: W,
+) -> Box<Future<Item = (), Error = Error> + 'a> {
+    let blocking = async_block! {
+        for name in &report.updated {
+            let handle = report.branches[name].as_inner().clone();
+            let digest = await!(handle.digest::<Sha3Digest>())?;
+            let keyword = if report.created.contains(name) {
+                "new-branch"
+            } else {
+                "updated-branch"
+            };
+            writeln!(out, "{} {}/{} {}", keyword, remote, name, hex::encode(digest.as_bytes()))?;
+        }
+
+        for name in &report.pruned {
+            writeln!(out, "pruned-branch {}/{}", remote, name)?;
+        }
+
+        if let Some(count) = report.copied {
+            writeln!(out, "objects-transferred {}", count)?;
+        }
+
+        Ok(())
+    };
+
+    Box::new(blocking)
 }
 
-pub struct FetchOut<'r> {
-    pub blocking: Box<Future<Item = (), Error = Error> + 'r>,
+pub struct FetchOut<'r, B: Backend> {
+    pub blocking: Box<Future<Item = FetchReport<B>, Error = Error> + 'r>,
 }
 
 impl<B: Backend> Repository<B> {
-    pub fn fetch<'r>(&'r mut self, args: FetchArgs) -> FetchOut<'r> {
+    /// Fetch from a remote, reporting what changed. With `--porcelain`, the report is meant to be
+    /// written out with `write_porcelain`.
+    pub fn fetch<'r>(&'r mut self, args: FetchArgs) -> FetchOut<'r, B> {
         FetchOut {
-            blocking: Box::new(plumbing::fetch::remote(self, args.remote).map(|_| ())),
+            blocking: plumbing::fetch::remote(self, args.remote, RateLimiter::new(args.limit_rate)),
         }
     }
 }
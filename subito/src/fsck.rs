@@ -6,6 +6,8 @@ use futures::prelude::*;
 use hex;
 
 use Repository;
+use plumbing;
+use refs;
 use state::Head;
 
 /// Check repository integrity, verifying hashes of all objects.
@@ -17,6 +19,16 @@ pub struct FsckArgs {
                 raw(possible_values = r#"digest_names!()"#,
                     default_value = "::attaca::digest::Sha3Digest::SIGNATURE.name"))]
     digest_name: String,
+
+    /// Also list the dangling commits: those in the store which are reachable from no ref, HEAD,
+    /// virtual workspace or stash entry, and which aren't the parent of another such commit.
+    #[structopt(long = "lost-found")]
+    pub lost_found: bool,
+
+    /// Point a ref `refs/lost-found/<digest>` at each dangling commit, so that it can be inspected
+    /// and restored. Commits with such a ref are no longer dangling.
+    #[structopt(long = "write-refs", raw(requires = r#""lost_found""#))]
+    pub write_refs: bool,
 }
 
 pub struct FsckMismatch {
@@ -26,12 +38,17 @@ pub struct FsckMismatch {
 
 pub struct FsckOut<'r> {
     pub errors: Box<Stream<Item = FsckMismatch, Error = Error> + 'r>,
+
+    /// The hex digests of the dangling commits, if `--lost-found` was given, and otherwise
+    /// nothing.
+    pub lost_found: Box<Future<Item = Vec<String>, Error = Error> + 'r>,
 }
 
 impl<'r> fmt::Debug for FsckOut<'r> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("FsckOut")
             .field("errors", &"OPAQUE")
+            .field("lost_found", &"OPAQUE")
             .finish()
     }
 }
@@ -50,18 +67,24 @@ macro_rules! digest_fsck {
 
 impl<B: Backend> Repository<B> {
     pub fn fsck<'r>(&'r self, args: FsckArgs) -> FsckOut<'r> {
+        let FsckArgs {
+            digest_name,
+            lost_found,
+            write_refs,
+        } = args;
+
         let errors = async_stream_block! {
             let state = self.get_state()?;
 
             let maybe_head = match state.head {
                 Head::Empty => None,
                 Head::Detached(commit_ref) => Some(commit_ref.as_inner().clone()),
-                Head::Branch(branch) => await!(self.store.load_branches())?.get(branch.as_str()).cloned(),
+                Head::Branch(branch) => await!(refs::load_heads(&self.store))?.get(branch.as_str()).cloned(),
             };
 
             if let Some(head) = maybe_head {
                 #[async]
-                for error in digest_fsck!(args.digest_name, head) {
+                for error in digest_fsck!(digest_name, head) {
                     stream_yield!(FsckMismatch {
                         received: hex::encode(error.received.as_bytes()),
                         calculated: hex::encode(error.calculated.as_bytes()),
@@ -72,8 +95,32 @@ impl<B: Backend> Repository<B> {
             Ok(())
         };
 
+        let lost_found = async_block! {
+            if !lost_found {
+                return Ok(Vec::new());
+            }
+
+            let dangling = await!(plumbing::dangling_commits(self))?;
+
+            if write_refs && !dangling.is_empty() {
+                let previous = await!(refs::load(&self.store, refs::LOST_FOUND))?;
+                let mut new = previous.clone();
+                new.extend(
+                    dangling
+                        .iter()
+                        .map(|&(ref digest, ref commit_ref)| {
+                            (digest.clone(), commit_ref.as_inner().clone())
+                        }),
+                );
+                await!(refs::swap(&self.store, refs::LOST_FOUND, previous, new))?;
+            }
+
+            Ok(dangling.into_iter().map(|(digest, _)| digest).collect())
+        };
+
         FsckOut {
             errors: Box::new(errors),
+            lost_found: Box::new(lost_found),
         }
     }
 }
@@ -0,0 +1,37 @@
+use std::fmt;
+
+use attaca::store::prelude::*;
+use failure::*;
+use futures::prelude::*;
+
+use Repository;
+
+/// Compact the store, returning unused space to the filesystem.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "gc")]
+pub struct GcArgs {}
+
+#[must_use = "GcOut contains futures which must be driven to completion!"]
+pub struct GcOut<'r> {
+    /// The size of the store before and after compaction, or `None` if its backend has nothing to
+    /// compact.
+    pub blocking: Box<Future<Item = Option<CompactStats>, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for GcOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GcOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    pub fn gc<'r>(&'r self, args: GcArgs) -> GcOut<'r> {
+        let GcArgs {} = args;
+
+        GcOut {
+            blocking: Box::new(self.store.compact()),
+        }
+    }
+}
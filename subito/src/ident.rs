@@ -0,0 +1,93 @@
+//! Resolution of the identity and date recorded in new commits.
+//!
+//! Each is taken from the first of these which provides it:
+//!
+//! 1. command-line flags (`--author`, `--date`);
+//! 2. the environment variables below;
+//! 3. the defaults: no author, and the current time.
+//!
+//! Commits record an author and two timestamps: the author date, which is kept when a commit is
+//! amended or rebased, and the committer date, which is always when the commit was last made.
+//! There is no separate committer identity, and so no committer name or email variables.
+
+use std::env;
+
+use attaca::object::CommitAuthor;
+use chrono::{DateTime, FixedOffset, Local};
+use failure::*;
+
+/// Name to record as the author of new commits.
+pub const AUTHOR_NAME_VAR: &'static str = "ATTACA_AUTHOR_NAME";
+
+/// Email address to record for the author of new commits.
+pub const AUTHOR_EMAIL_VAR: &'static str = "ATTACA_AUTHOR_EMAIL";
+
+/// Timestamp to record for new commits, in RFC 3339 format (for example
+/// `2018-03-01T12:00:00+01:00`).
+pub const AUTHOR_DATE_VAR: &'static str = "ATTACA_AUTHOR_DATE";
+
+/// Timestamp to record as the committer date of new commits, in the same format as
+/// `ATTACA_AUTHOR_DATE`. If unset, the current time is used.
+pub const COMMITTER_DATE_VAR: &'static str = "ATTACA_COMMITTER_DATE";
+
+/// Parse a commit date, which must be in RFC 3339 format.
+pub fn parse_date(s: &str) -> Result<DateTime<FixedOffset>, Error> {
+    Ok(DateTime::parse_from_rfc3339(s)
+        .with_context(|_| format_err!("Invalid date {:?}; expected an RFC 3339 date", s))?)
+}
+
+/// Read an environment variable, treating an empty value as unset.
+fn var(name: &str) -> Result<Option<String>, Error> {
+    match env::var(name) {
+        Ok(ref value) if value.is_empty() => Ok(None),
+        Ok(value) => Ok(Some(value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(err) => Err(err.context(format_err!("Error reading ${}", name)).into()),
+    }
+}
+
+/// The author to record, given the value of `--author`. `None` means that no author was given
+/// anywhere, so whatever the commit already has (nothing, or the author of an amended commit)
+/// should be kept.
+pub fn author(flag: Option<String>) -> Result<Option<CommitAuthor>, Error> {
+    if let Some(name) = flag {
+        return Ok(Some(CommitAuthor {
+            name: Some(name),
+            mbox: None,
+        }));
+    }
+
+    let name = var(AUTHOR_NAME_VAR)?;
+    let mbox = var(AUTHOR_EMAIL_VAR)?;
+    if name.is_none() && mbox.is_none() {
+        return Ok(None);
+    }
+
+    Ok(Some(CommitAuthor { name, mbox }))
+}
+
+/// The date to record, given the value of `--date`. `None` means that the commit's existing
+/// timestamp should be kept. An invalid date in the environment is an error.
+pub fn date(flag: Option<DateTime<FixedOffset>>) -> Result<Option<DateTime<FixedOffset>>, Error> {
+    if flag.is_some() {
+        return Ok(flag);
+    }
+
+    match var(AUTHOR_DATE_VAR)? {
+        Some(value) => Ok(Some(parse_date(&value)
+            .with_context(|_| format_err!("Invalid ${}", AUTHOR_DATE_VAR))?)),
+        None => Ok(None),
+    }
+}
+
+/// The committer date to record: `$ATTACA_COMMITTER_DATE` if set, and the current time otherwise.
+pub fn committer_date() -> Result<DateTime<FixedOffset>, Error> {
+    match var(COMMITTER_DATE_VAR)? {
+        Some(value) => Ok(parse_date(&value)
+            .with_context(|_| format_err!("Invalid ${}", COMMITTER_DATE_VAR))?),
+        None => {
+            let local = Local::now();
+            Ok(local.with_timezone(local.offset()))
+        }
+    }
+}
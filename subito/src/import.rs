@@ -0,0 +1,203 @@
+//! Bulk import of a directory tree as a single commit.
+//!
+//! Importing is a faster alternative to `stage` followed by `commit` for one-shot migrations of
+//! large amounts of data. Everything being imported is assumed to be new, so the cache is neither
+//! consulted nor updated and no progress is reported. Files are hashed and sent on a pool of
+//! threads, one per core unless told otherwise, and the trees holding them are built in a single
+//! batch once every file has been sent.
+
+use std::{fmt, borrow::Borrow, path::PathBuf, sync::{Arc, Mutex}};
+
+use attaca::{batch::{Batch as ObjectBatch, Operation as ObjectOperation, SentTrees},
+             digest::prelude::*, object::{CommitRef, ObjectRef, TreeBuilder},
+             path::ObjectPath, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+use hex;
+use ignore::{WalkBuilder, WalkState};
+
+use {Repository, State};
+use attributes::Attributes;
+use candidate;
+use ident;
+use plumbing::{self, commit::CommitTree};
+use refs;
+use state::Head;
+
+/// Import a directory tree as a single commit, replacing the virtual workspace with it.
+#[derive(Debug, StructOpt, Builder)]
+#[structopt(name = "import")]
+pub struct ImportArgs {
+    /// The directory to import. Its contents become the whole tree of the new commit. Defaults to
+    /// the root of the repository.
+    #[structopt(name = "PATH", parse(from_os_str))]
+    pub path: Option<PathBuf>,
+
+    /// Add a commit message. Defaults to "Import PATH".
+    #[structopt(short = "m", long = "m")]
+    pub message: Option<String>,
+
+    /// The number of threads to hash files on. Defaults to the number of cores.
+    #[structopt(short = "j", long = "jobs")]
+    pub jobs: Option<usize>,
+}
+
+/// The result of an import.
+#[derive(Debug, Clone)]
+pub struct Imported {
+    /// The hex digest of the new commit.
+    pub digest: String,
+
+    /// The number of files imported.
+    pub files: usize,
+}
+
+#[must_use = "ImportOut contains futures which must be driven to completion!"]
+pub struct ImportOut<'r> {
+    pub blocking: Box<Future<Item = Imported, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for ImportOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ImportOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    /// Hash and send every file beneath `root` in parallel, returning their paths relative to
+    /// `root` and the objects they were sent as. Like `stage`, this honors ignore files and skips
+    /// hidden files, and normalizes line endings according to `.gitattributes`.
+    fn share_all(
+        &self,
+        root: PathBuf,
+        jobs: usize,
+    ) -> Result<Vec<(ObjectPath, ObjectRef<Handle<B>>)>, Error> {
+        let attributes = Arc::new(Attributes::load(&root)?);
+        let shared = Arc::new(Mutex::new(Vec::new()));
+        let failure = Arc::new(Mutex::new(None));
+
+        WalkBuilder::new(&root)
+            .threads(jobs)
+            .build_parallel()
+            .run(|| {
+                let store = self.store.clone();
+                let attributes = attributes.clone();
+                let shared = shared.clone();
+                let failure = failure.clone();
+                let root = root.clone();
+
+                Box::new(move |direntry_res| {
+                    let result = direntry_res.map_err(Error::from).and_then(|direntry| {
+                        if direntry.file_type().map_or(true, |file_type| file_type.is_dir()) {
+                            return Ok(None);
+                        }
+
+                        let relative_path = direntry.path().strip_prefix(&root)?;
+                        let object_path = ObjectPath::from_path(relative_path)?;
+                        let path_attributes = attributes.get(&object_path);
+                        let object_ref = candidate::share_file(
+                            store.clone(),
+                            path_attributes,
+                            direntry.path(),
+                            None,
+                        )?.wait()
+                            .with_context(|_| {
+                                format!("Error importing {}", direntry.path().display())
+                            })?;
+
+                        Ok(Some((object_path, object_ref)))
+                    });
+
+                    match result {
+                        Ok(Some(entry)) => {
+                            shared.lock().unwrap().push(entry);
+                            WalkState::Continue
+                        }
+                        Ok(None) => WalkState::Continue,
+                        Err(error) => {
+                            failure.lock().unwrap().get_or_insert(error);
+                            WalkState::Quit
+                        }
+                    }
+                })
+            });
+
+        if let Some(error) = failure.lock().unwrap().take() {
+            return Err(error);
+        }
+
+        let mut shared = shared.lock().unwrap();
+        Ok(shared.drain(..).collect())
+    }
+
+    /// Import a directory tree as a single commit on top of the HEAD. See the module
+    /// documentation for how this differs from staging and committing.
+    pub fn import<'r>(&'r mut self, args: ImportArgs) -> ImportOut<'r> {
+        let blocking = async_block! {
+            let root = match args.path {
+                Some(path) => self.path.join(path),
+                None => self.path.to_path_buf(),
+            };
+            ensure!(root.is_dir(), "{} is not a directory!", root.display());
+
+            let state = self.get_state()?;
+            ensure!(state.merge.is_none(), "Cannot import while a merge is in progress!");
+            let branches = await!(refs::load_heads(&self.store))?;
+            let maybe_head_ref = match state.head {
+                Head::Empty => None,
+                Head::Detached(ref commit_ref) => Some(commit_ref.clone()),
+                Head::Branch(ref branch) => branches.get(branch.as_str()).cloned().map(CommitRef::new),
+            };
+
+            let shared = self.share_all(root.clone(), args.jobs.unwrap_or(0))?;
+            let files = shared.len();
+
+            let mut object_batch = ObjectBatch::<B>::new();
+            for (object_path, object_ref) in shared {
+                let operation = ObjectOperation::Add(object_path, object_ref);
+                object_batch = await!(object_batch.add(operation))?;
+            }
+            let sent = SentTrees::new();
+            let built = await!(object_batch.run_deduplicated(
+                self.store.clone(),
+                TreeBuilder::new(),
+                sent.clone()
+            ))?;
+            let subtree = await!(sent.send(built.as_tree(), &self.store))?;
+
+            let mut inputs = CommitTree::new(subtree.clone(), maybe_head_ref);
+            inputs.message =
+                Some(args.message.unwrap_or_else(|| format!("Import {}", root.display())));
+            inputs.author = ident::author(None)?;
+            inputs.timestamp = ident::date(None)?;
+
+            let (commit_ref, _) = await!(plumbing::commit::commit_tree(self, inputs))?;
+            let commit_id = await!(commit_ref.id())?;
+            let digest = hex::encode(commit_id.as_inner().borrow().as_bytes());
+
+            let head = match state.head {
+                Head::Empty | Head::Detached(_) => Head::Detached(commit_ref),
+                Head::Branch(branch) => {
+                    let mut new_branches = branches.clone();
+                    new_branches.insert(branch.clone().into_string(), commit_ref.into_inner());
+                    await!(refs::swap_heads(&self.store, branches, new_branches))?;
+                    Head::Branch(branch)
+                }
+            };
+
+            self.set_state(&State {
+                head,
+                candidate: Some(subtree),
+                ..state
+            })?;
+
+            Ok(Imported { digest, files })
+        };
+
+        ImportOut {
+            blocking: Box::new(blocking),
+        }
+    }
+}
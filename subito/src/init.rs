@@ -1,14 +1,32 @@
 use std::{env, fs, path::{Path, PathBuf}};
 
-use attaca::{Init, Open, digest::prelude::*, store::prelude::*};
+use attaca::{Init, Open, digest::prelude::*, hierarchy, layer::BackendExt,
+             seal::{SealLayer, SealedBackend}, store::prelude::*};
 use attaca_leveldb::LevelDbBackend;
+use attaca_ssh::SshBackend;
 use failure::*;
+use futures::prelude::*;
 use leveldb::{database::Database, kv::KV, options::{Options, ReadOptions, WriteOptions}};
 use url::{self, Url};
 
 use Repository;
-use config::{Config, StoreConfig, StoreKind};
+use abbrev;
+use candidate;
+use config::{self, Config, StoreConfig, StoreKind};
+use crypt;
 use db::Key;
+use migrate;
+use state::{Head, State};
+use syntax::Name;
+
+/// The branch which the first commit in a new repository lands on, unless configured otherwise.
+pub const DEFAULT_BRANCH: &'static str = "master";
+
+/// Per-user configuration key which overrides `DEFAULT_BRANCH`.
+pub const DEFAULT_BRANCH_KEY: &'static str = "init.defaultBranch";
+
+/// Entries of `.attaca` which `init` creates itself, and which are never taken from a template.
+const RESERVED_TEMPLATE_ENTRIES: &'static [&'static str] = &["repository", "store", "workspace"];
 
 /// Create a local repository.
 #[derive(Debug, Clone, StructOpt, Builder)]
@@ -20,12 +38,37 @@ pub struct InitArgs {
 
     #[structopt(subcommand)]
     pub store: Option<InitStore>,
+
+    /// Seed the new repository from a template directory. A `.attacaignore` at the top of the
+    /// template is copied into the workspace; everything else (hooks, config fragments and so
+    /// on) is copied into `.attaca`. Files which `init` creates itself, and files which already
+    /// exist, are never overwritten.
+    #[structopt(long = "template", parse(from_os_str))]
+    pub template: Option<PathBuf>,
+
+    /// Name of the branch which the first commit will be made on. If absent, the
+    /// `init.defaultBranch` key of `~/.attacaconfig` is used, falling back to `master`.
+    #[structopt(short = "b", long = "initial-branch")]
+    pub initial_branch: Option<Name>,
+
+    /// Record empty directories when staging, by placing an empty `.attacakeep` file in each, so
+    /// that checkout recreates them. This changes the contents of staged trees.
+    #[structopt(long = "keep-empty-dirs")]
+    pub keep_empty_dirs: bool,
+
+    /// Keep directories which are left empty in the virtual workspace, for instance by unstaging
+    /// everything in them, as empty trees. By default they are pruned as though deleted.
+    #[structopt(long = "keep-empty-trees")]
+    pub keep_empty_trees: bool,
 }
 
 #[derive(Debug, Clone, StructOpt)]
 pub enum InitStore {
     #[structopt(name = "leveldb")]
     LevelDb(InitLevelDb),
+
+    #[structopt(name = "ssh")]
+    Ssh(InitSsh),
 }
 
 impl Default for InitStore {
@@ -43,6 +86,19 @@ pub struct InitLevelDb {
     /// Fail unless the LevelDB repository already exists.
     #[structopt(name = "no-init", long = "no-init", raw(requires = r#""URL""#))]
     no_init: bool,
+
+    /// Encrypt objects at rest with a key derived from a passphrase, which is read from
+    /// `$ATTACA_PASSPHRASE` or prompted for.
+    #[structopt(long = "encrypt", raw(conflicts_with = r#""no-init""#))]
+    encrypt: bool,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct InitSsh {
+    /// URL of a repository on another host, as `ssh://[USER@]HOST[:PORT]/PATH`, whose store
+    /// this repository will share. The remote repository must already exist.
+    #[structopt(name = "URL", parse(try_from_str = "Url::parse"))]
+    url: Url,
 }
 
 #[macro_export]
@@ -51,6 +107,10 @@ macro_rules! init {
         {
             match $args.store.unwrap_or_default() {
                 $($crate::init::InitStore::$ccname(spec_args) => {
+                    let template = $args.template;
+                    let initial_branch = $args.initial_branch;
+                    let keep_empty_dirs = $args.keep_empty_dirs;
+                    let keep_empty_trees = $args.keep_empty_trees;
                     $args.path
                         .map(Ok)
                         .unwrap_or_else(::std::env::current_dir)
@@ -62,6 +122,18 @@ macro_rules! init {
                                     path,
                                     |path| $crate::init::$lcname(path, spec_args)
                                 )?;
+                            $repo.set_initial_branch(
+                                $crate::init::initial_branch(initial_branch)?
+                            )?;
+                            if let Some(template) = template {
+                                $repo.apply_template(&template)?;
+                            }
+                            if keep_empty_dirs || keep_empty_trees {
+                                let mut config = $repo.get_config()?;
+                                config.keep_empty_dirs = keep_empty_dirs;
+                                config.keep_empty_trees = keep_empty_trees;
+                                $repo.set_config(&config)?;
+                            }
                             Ok({
                                 #[warn(unused_mut)]
                                 $generic
@@ -79,8 +151,12 @@ macro_rules! init {
 pub fn leveldb<P: AsRef<Path>>(
     path: P,
     args: InitLevelDb,
-) -> Result<(StoreConfig, LevelDbBackend), Error> {
-    let InitLevelDb { location, no_init } = args;
+) -> Result<(StoreConfig, SealedBackend<LevelDbBackend>), Error> {
+    let InitLevelDb {
+        location,
+        no_init,
+        encrypt,
+    } = args;
 
     let url = match location {
         Some(location) => match Url::parse(&location) {
@@ -105,15 +181,109 @@ pub fn leveldb<P: AsRef<Path>>(
         LevelDbBackend::init(url.as_str())?
     };
 
+    // The parameters are kept in the store as well as in the configuration, so that whoever opens
+    // the store as a remote can unlock it too.
+    let (encryption, backend) = if encrypt {
+        let (encryption, sealer) = crypt::create()?;
+        let backend = backend.layer(SealLayer::new(sealer));
+        backend.set_params(&encryption.clone().into()).wait()?;
+        (Some(encryption), backend)
+    } else {
+        (None, SealedBackend::unsealed(backend)?)
+    };
+
     let store_config = StoreConfig {
         url,
         kind: StoreKind::LevelDb,
+        encryption,
+    };
+
+    Ok((store_config, backend))
+}
+
+pub fn ssh<P: AsRef<Path>>(_path: P, args: InitSsh) -> Result<(StoreConfig, SshBackend), Error> {
+    let InitSsh { url } = args;
+    let backend = SshBackend::open(url.as_str())?;
+    let store_config = StoreConfig {
+        url,
+        kind: StoreKind::Ssh,
+        encryption: None,
     };
 
     Ok((store_config, backend))
 }
 
+/// Decide which branch a new repository starts out on: the one given explicitly, if any, then the
+/// one named by the user's configuration, then `DEFAULT_BRANCH`.
+pub fn initial_branch(explicit: Option<Name>) -> Result<Name, Error> {
+    match explicit {
+        Some(name) => Ok(name),
+        None => match config::user_config_value(DEFAULT_BRANCH_KEY)? {
+            Some(configured) => Ok(Name::from_string(configured)
+                .with_context(|_| format_err!("Invalid {} in user configuration", DEFAULT_BRANCH_KEY))?),
+            None => DEFAULT_BRANCH.parse(),
+        },
+    }
+}
+
+/// Recursively copy `from` to `to`, leaving anything which already exists at `to` untouched.
+fn copy_template_entry(from: &Path, to: &Path) -> Result<(), Error> {
+    if from.is_dir() {
+        if !to.exists() {
+            fs::create_dir(to)?;
+        } else if !to.is_dir() {
+            return Ok(());
+        }
+
+        for entry_res in fs::read_dir(from)? {
+            let entry = entry_res?;
+            copy_template_entry(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else if !to.exists() {
+        // `fs::copy` preserves permissions, which matters for hooks.
+        fs::copy(from, to)?;
+    }
+
+    Ok(())
+}
+
 impl<B: Backend> Repository<B> {
+    /// Copy the contents of a template directory into a freshly initialized repository. See
+    /// `InitArgs::template` for which paths are templated.
+    pub fn apply_template(&self, template: &Path) -> Result<(), Error> {
+        ensure!(
+            template.is_dir(),
+            "Template {} is not a directory",
+            template.display()
+        );
+
+        for entry_res in fs::read_dir(template)? {
+            let entry = entry_res?;
+            let name = entry.file_name();
+
+            let destination = match name.to_str() {
+                Some(candidate::IGNORE_FILE) => self.path.join(&name),
+                Some(reserved) if RESERVED_TEMPLATE_ENTRIES.contains(&reserved) => continue,
+                _ => self.path.join(".attaca").join(&name),
+            };
+
+            copy_template_entry(&entry.path(), &destination)
+                .with_context(|_| format_err!("Error copying template entry {:?}", name))?;
+        }
+
+        Ok(())
+    }
+
+    /// Point the HEAD of a freshly initialized repository at a branch which does not exist yet,
+    /// so that the first commit creates it.
+    pub fn set_initial_branch(&mut self, branch: Name) -> Result<(), Error> {
+        let state = self.get_state()?;
+        self.set_state(&State {
+            head: Head::Branch(branch),
+            ..state
+        })
+    }
+
     pub fn init_with<F: FnOnce(&Path) -> Result<(StoreConfig, B), Error>>(
         path: PathBuf,
         backend: F,
@@ -131,10 +301,25 @@ impl<B: Backend> Repository<B> {
         let config = Config {
             store: store_config,
             remotes: Default::default(),
+            hierarchy_cache_capacity: hierarchy::DEFAULT_CACHE_CAPACITY,
+            commit_checks: Default::default(),
+            keep_empty_dirs: false,
+            abbrev_len: abbrev::DEFAULT_ABBREV_LEN,
+            keep_empty_trees: false,
+            worker_threads: 0,
+            case_collisions: Default::default(),
+            xattrs: false,
+            hash_jobs: candidate::DEFAULT_HASH_JOBS,
+            extra: Default::default(),
         };
         let mut buf = Vec::new();
         config.encode(&mut buf)?;
         db.put(WriteOptions::new(), &Key::config(), &buf)?;
+        db.put(
+            WriteOptions::new(),
+            &Key::schema_version(),
+            &migrate::encode_version(migrate::SCHEMA_VERSION),
+        )?;
 
         Ok(Self::new(path, db, backend))
     }
@@ -3,13 +3,17 @@
 
 pub extern crate attaca;
 extern crate attaca_leveldb;
+extern crate attaca_ssh;
 extern crate capnp;
+extern crate chrono;
 extern crate db_key;
 #[macro_use]
 extern crate derive_builder;
 #[macro_use]
 pub extern crate failure;
 pub extern crate futures_await as futures;
+pub extern crate futures_cpupool;
+extern crate globset;
 extern crate hex;
 extern crate ignore;
 extern crate itertools;
@@ -19,21 +23,33 @@ extern crate leveldb;
 extern crate memmap;
 #[macro_use]
 extern crate nix;
+#[cfg(feature = "watch")]
+extern crate notify;
 extern crate regex;
+extern crate rpassword;
 extern crate smallvec;
 #[macro_use]
 extern crate structopt;
+#[cfg(test)]
+extern crate tempdir;
 extern crate url;
+extern crate xattr;
 
 pub mod reexports {
     pub use attaca;
     pub use failure;
     pub use futures;
+    pub use futures_cpupool;
     pub use leveldb::{database::Database, kv::KV, options::{Options, ReadOptions}};
 
     pub use super::db::Key;
 }
 
+#[allow(dead_code)]
+mod bundle_capnp {
+    include!(concat!(env!("OUT_DIR"), "/bundle_capnp.rs"));
+}
+
 #[allow(dead_code)]
 mod cache_capnp {
     include!(concat!(env!("OUT_DIR"), "/cache_capnp.rs"));
@@ -54,6 +70,11 @@ mod object_ref_capnp {
     include!(concat!(env!("OUT_DIR"), "/object_ref_capnp.rs"));
 }
 
+#[allow(dead_code)]
+mod stash_capnp {
+    include!(concat!(env!("OUT_DIR"), "/stash_capnp.rs"));
+}
+
 #[allow(dead_code)]
 mod state_capnp {
     include!(concat!(env!("OUT_DIR"), "/state_capnp.rs"));
@@ -114,25 +135,61 @@ digests! {
 }
 
 backends! {
-    leveldb, LevelDb : ::attaca_leveldb::LevelDbBackend,
+    leveldb, LevelDb : ::attaca::seal::SealedBackend<::attaca_leveldb::LevelDbBackend>,
+    ssh, Ssh : ::attaca_ssh::SshBackend,
 }
 
 mod cache;
 mod db;
+mod migrate;
 mod state;
 
+pub mod abbrev;
+pub mod attributes;
 pub mod branch;
+pub mod bundle;
 pub mod candidate;
+pub mod case;
+pub mod cat_file;
+pub mod checks;
 pub mod checkout;
+pub mod completions;
 pub mod config;
+pub mod count_objects;
+pub mod crypt;
+pub mod describe;
+pub mod diff;
+pub mod diff_workspace;
+pub mod editor;
 pub mod fetch;
 pub mod fsck;
+pub mod gc;
+pub mod ident;
+pub mod import;
+pub mod merge;
+pub mod pathspec;
 pub mod plumbing;
+pub mod progress;
+pub mod push;
+pub mod rebase;
+pub mod refs;
 pub mod remote;
+pub mod reset;
+pub mod rev_parse;
+pub mod serve;
 pub mod show;
+pub mod stash;
 pub mod status;
 pub mod syntax;
+pub mod tag;
+pub mod textdiff;
 pub mod log;
+pub mod verbosity;
+pub mod walk;
+#[cfg(feature = "watch")]
+pub mod watch;
+pub mod why_kept;
+pub mod xattrs;
 
 #[macro_use]
 pub mod init;
@@ -141,29 +198,72 @@ pub mod clone;
 #[macro_use]
 pub mod open;
 
-use std::{env, fmt, io::Cursor, path::PathBuf, sync::{Arc, RwLock}};
+use std::{env, fmt, thread, io::Cursor, path::PathBuf, sync::{Arc, RwLock}, time::Duration};
 
-use attaca::{Open, digest::{Sha3Digest, prelude::*}, store::prelude::*};
+use attaca::{Open, digest::{Sha3Digest, prelude::*}, object::{CommitRef, ObjectRef},
+             store::prelude::*};
 use failure::Error;
 use futures::prelude::*;
+use futures_cpupool::{Builder as CpuPoolBuilder, CpuPool};
 use leveldb::{database::Database, kv::KV, options::{Options, ReadOptions, WriteOptions}};
 
 use cache::Cache;
 use db::Key;
 use state::State;
+use syntax::{Name, Rev};
 
 pub use branch::BranchArgs;
-pub use candidate::{CommitArgs, StageArgs};
+pub use bundle::BundleArgs;
+pub use cache::ChangeJournal;
+pub use candidate::{CommitArgs, StageArgs, UnstageArgs};
+pub use cat_file::CatFileArgs;
 pub use checkout::CheckoutArgs;
+pub use diff::DiffArgs;
+pub use diff_workspace::DiffWorkspaceArgs;
 pub use clone::{clone, CloneArgs};
+pub use completions::CompletionsArgs;
+pub use config::ConfigArgs;
+pub use count_objects::CountObjectsArgs;
+pub use describe::DescribeArgs;
 pub use fetch::FetchArgs;
 pub use fsck::FsckArgs;
+pub use gc::GcArgs;
+pub use import::ImportArgs;
 pub use init::InitArgs;
 pub use log::LogArgs;
+pub use merge::MergeArgs;
+pub use migrate::SCHEMA_VERSION;
+pub use push::PushArgs;
+pub use rebase::RebaseArgs;
 pub use remote::RemoteArgs;
+pub use reset::ResetArgs;
+pub use rev_parse::RevParseArgs;
+pub use serve::ServeArgs;
 pub use show::ShowArgs;
-pub use state::Head;
+pub use stash::StashArgs;
+pub use state::{Head, MergeState, RebaseState};
 pub use status::StatusArgs;
+pub use tag::TagArgs;
+pub use verbosity::Verbosity;
+#[cfg(feature = "watch")]
+pub use watch::WatchArgs;
+pub use why_kept::WhyKeptArgs;
+
+/// How many times to try writing the state before giving up on a transient error.
+const SET_STATE_ATTEMPTS: u32 = 5;
+
+/// How long to wait before the first retry of a failed state write. The wait doubles each time.
+const SET_STATE_BACKOFF_MS: u64 = 50;
+
+/// Whether a database error is an I/O error which might succeed if retried. LevelDB reports errors
+/// only as messages naming the kind of error, which the `leveldb` crate displays after a prefix of
+/// its own, as in `LevelDB error: IO error: ...`. I/O errors caused by permissions or a read-only
+/// filesystem are not going to go away.
+fn is_transient(message: &str) -> bool {
+    const PERMANENT: &'static [&'static str] = &["Permission denied", "Read-only file system"];
+
+    message.contains("IO error") && !PERMANENT.iter().any(|cause| message.contains(cause))
+}
 
 pub struct Repository<B: Backend> {
     store: Store<B>,
@@ -171,6 +271,10 @@ pub struct Repository<B: Backend> {
 
     cache: Cache<B>,
     path: Arc<PathBuf>,
+
+    /// Threads for CPU-bound work such as hashing and compressing file contents, so that it
+    /// doesn't hold up whatever is driving the repository's futures.
+    pool: CpuPool,
 }
 
 impl<B: Backend + fmt::Debug> fmt::Debug for Repository<B> {
@@ -179,6 +283,7 @@ impl<B: Backend + fmt::Debug> fmt::Debug for Repository<B> {
             .field("store", &self.store)
             .field("cache", &self.cache)
             .field("path", &self.path)
+            .field("pool", &self.pool)
             .finish()
     }
 }
@@ -191,7 +296,10 @@ impl<B: Backend + Open> Repository<B> {
         // key to facilitate this.
         let backend = B::open_path(&path.join(".attaca/store"))?;
 
-        Ok(Self::new(path, db, backend))
+        let repository = Self::new(path, db, backend);
+        repository.migrate()?;
+
+        Ok(repository)
     }
 
     pub fn search() -> Result<Option<Self>, Error> {
@@ -208,7 +316,25 @@ impl<B: Backend + Open> Repository<B> {
 }
 
 impl<B: Backend> Repository<B> {
+    /// Open a repository with a worker pool sized by `Config::worker_threads`. If the
+    /// configuration can't be read, as while it is still being written by `init`, the pool has one
+    /// thread per CPU.
     pub fn new(path: PathBuf, db: Database<Key>, backend: B) -> Self {
+        let worker_threads = config::Config::load(&db)
+            .ok()
+            .map_or(0, |config| config.worker_threads);
+        let mut builder = CpuPoolBuilder::new();
+        builder.name_prefix("subito-worker-");
+        if worker_threads > 0 {
+            builder.pool_size(worker_threads);
+        }
+
+        Self::with_cpu_pool(path, db, backend, builder.create())
+    }
+
+    /// Like `new`, but doing CPU-bound work on the given pool, which may be shared with other
+    /// repositories or with the rest of the program.
+    pub fn with_cpu_pool(path: PathBuf, db: Database<Key>, backend: B, pool: CpuPool) -> Self {
         let store = Store::new(backend);
         let db = Arc::new(RwLock::new(db));
         let cache = Cache::from(db.clone());
@@ -219,16 +345,129 @@ impl<B: Backend> Repository<B> {
 
             cache,
             path: Arc::new(path),
+
+            pool,
+        }
+    }
+
+    /// The pool which CPU-bound work is done on.
+    pub fn cpu_pool(&self) -> &CpuPool {
+        &self.pool
+    }
+
+    /// The commit HEAD points to, if any. This is `None` in a new repository, or on a branch with
+    /// no commits yet.
+    pub fn head(&self) -> plumbing::FutureOptionCommitRef<B> {
+        plumbing::resolve_head_opt(self)
+    }
+
+    /// The tree of the commit HEAD points to, or `None` if there is no such commit.
+    pub fn head_tree(&self) -> plumbing::FutureOptionTreeRef<B> {
+        let tree_of = |commit_ref: &CommitRef<Handle<B>>| self.tree_of(commit_ref);
+        Box::new(self.head().and_then(move |maybe_head| maybe_head.as_ref().map(tree_of)))
+    }
+
+    /// The nearest common ancestor of two commits, or `None` if their histories are disjoint. See
+    /// `plumbing::merge::merge_base`.
+    pub fn merge_base(
+        &self,
+        ours: CommitRef<Handle<B>>,
+        theirs: CommitRef<Handle<B>>,
+    ) -> plumbing::FutureOptionCommitRef<B> {
+        plumbing::merge::merge_base(ours, theirs)
+    }
+
+    /// The tree recorded by a commit.
+    pub fn tree_of(&self, commit_ref: &CommitRef<Handle<B>>) -> plumbing::FutureTreeRef<B> {
+        Box::new(
+            commit_ref
+                .fetch()
+                .map(|commit| commit.as_subtree().clone())
+                .map_err(|err| Error::from(err.context("Error fetching commit"))),
+        )
+    }
+
+    /// The commit a revision names. A plain `Ref` converts into a revision with no ancestry steps.
+    pub fn commit_of<R: Into<Rev>>(&self, rev: R) -> plumbing::FutureCommitRef<B> {
+        plumbing::resolve_rev(self, rev.into())
+    }
+
+    /// The branch HEAD is on, or `None` if HEAD is detached (or has never been set).
+    pub fn current_branch(&self) -> Result<Option<Name>, Error> {
+        match self.get_state()?.head {
+            Head::Branch(branch) => Ok(Some(branch)),
+            Head::Empty | Head::Detached(_) => Ok(None),
         }
     }
 
+    /// The remote-tracking branch which a branch follows, as the name of the remote and of the
+    /// branch on it. Branches have no configured upstream; a branch follows the remote-tracking
+    /// branch of the same name on `origin` if there is one, and otherwise on the first remote (by
+    /// name) which has it.
+    pub fn upstream_of(&self, branch: &Name) -> Result<Option<(Name, Name)>, Error> {
+        let state = self.get_state()?;
+        let mut remotes = state
+            .remote_refs
+            .iter()
+            .filter(|&(_, branches)| branches.contains_key(branch))
+            .map(|(remote, _)| remote)
+            .collect::<Vec<_>>();
+        remotes.sort_by_key(|remote| (remote.as_str() != "origin", remote.as_str().to_owned()));
+
+        Ok(remotes
+            .first()
+            .map(|&remote| (remote.clone(), branch.clone())))
+    }
+
+    /// The size of an object. For file data this is the length of the file, which is recorded
+    /// wherever the data is referenced, so nothing is fetched. For trees and commits it is the
+    /// length of the stored object, which the backend is asked for; only backends which can't
+    /// answer from metadata load the object to measure it.
+    pub fn object_size(&self, objref: &ObjectRef<Handle<B>>) -> FutureSize {
+        match *objref {
+            ObjectRef::Small(ref small_ref) => Box::new(Ok(small_ref.size()).into_future()),
+            ObjectRef::Large(ref large_ref) => Box::new(Ok(large_ref.size()).into_future()),
+            ObjectRef::Tree(ref tree_ref) => tree_ref.as_inner().size(),
+            ObjectRef::Commit(ref commit_ref) => commit_ref.as_inner().size(),
+        }
+    }
+
+    /// Whether the store holds an object with the given digest. This asks the backend directly,
+    /// rather than resolving or fetching the object.
+    pub fn object_exists(&self, digest: Sha3Digest) -> FutureContainsDigest {
+        self.store.contains_digest(digest)
+    }
+
+    /// Attach a change journal (for example, a filesystem watcher) which allows staging to skip
+    /// directories known to be unchanged since they were last staged. See `ChangeJournal`.
+    pub fn set_change_journal(&mut self, journal: Arc<ChangeJournal>) {
+        self.cache.set_journal(journal);
+    }
+
+    /// Persist the state. Everything the state refers to has already been written to the store by
+    /// the time it's set, so writing it again is harmless; transient I/O errors are retried with
+    /// exponential backoff rather than throwing away an otherwise complete operation. Errors which
+    /// won't go away by themselves, such as corruption or a lack of permission, fail immediately.
     fn set_state(&self, state: &State<Handle<B>>) -> Result<(), Error> {
         let mut buf = Vec::new();
         state.encode(&mut buf).wait()?;
-        self.db
-            .read()
-            .unwrap()
-            .put(WriteOptions::new(), &Key::state(), &buf)?;
+
+        let mut backoff = Duration::from_millis(SET_STATE_BACKOFF_MS);
+        for attempt in 1.. {
+            let result = self.db
+                .read()
+                .unwrap()
+                .put(WriteOptions::new(), &Key::state(), &buf);
+
+            match result {
+                Ok(()) => break,
+                Err(ref err) if attempt < SET_STATE_ATTEMPTS && is_transient(&err.to_string()) => {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
 
         Ok(())
     }
@@ -248,3 +487,20 @@ impl<B: Backend> Repository<B> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_errors_are_transient_unless_permanent() {
+        assert!(is_transient("LevelDB error: IO error: /repo/.attaca/repository/000005.log: \
+                              Resource temporarily unavailable"));
+        assert!(is_transient("IO error: lock /repo/.attaca/repository/LOCK: already held"));
+        assert!(!is_transient("LevelDB error: IO error: /repo/.attaca/repository/LOCK: \
+                               Permission denied"));
+        assert!(!is_transient("LevelDB error: IO error: /repo/.attaca/repository/000005.log: \
+                               Read-only file system"));
+        assert!(!is_transient("LevelDB error: Corruption: bad record length"));
+    }
+}
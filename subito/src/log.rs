@@ -1,18 +1,95 @@
-use std::{fmt, borrow::Borrow, collections::HashSet};
+use std::{fmt, borrow::Borrow, cmp::Reverse, collections::{BinaryHeap, HashMap, HashSet},
+          path::PathBuf};
 
-use attaca::{digest::prelude::*, object::{Commit, CommitBuilder, CommitRef, TreeRef},
+use attaca::{digest::prelude::*, hierarchy::Hierarchy,
+             object::{Commit, CommitBuilder, CommitRef, ObjectRef, TreeRef}, path::ObjectPath,
              store::prelude::*};
+use chrono::{DateTime, FixedOffset};
 use failure::*;
 use futures::{stream, prelude::*};
 use hex;
 
 use Repository;
+use ident;
+use plumbing;
+use refs;
 use state::Head;
+use syntax::RevRange;
 
-/// Show commit history sorted chronologically.
+/// How many commits `log` prepares ahead of the one being shown, unless `--lookahead` says
+/// otherwise.
+pub const DEFAULT_LOOKAHEAD: usize = 8;
+
+/// Show commit history sorted chronologically by commit date, newest first, or with
+/// `--topo-order`, with every commit shown before its parents. Commits with the same timestamp are
+/// ordered by digest, so the order is always the same for the same history.
 #[derive(Default, Debug, StructOpt, Builder)]
 #[structopt(name = "log")]
-pub struct LogArgs {}
+pub struct LogArgs {
+    /// Show at most this many commits. This counts the commits shown, so commits left out by
+    /// `--since`, `--until` or paths don't count towards it.
+    #[structopt(short = "n", long = "max-count", raw(alias = r#""limit""#))]
+    pub limit: Option<usize>,
+
+    /// Show only commits made at or after this date, in RFC 3339 format (for example
+    /// `2018-03-01T12:00:00+01:00`). Commit dates are compared, not author dates. The history
+    /// beneath older commits is still walked, since their parents may be out of order.
+    #[structopt(long = "since", parse(try_from_str = "ident::parse_date"))]
+    pub since: Option<DateTime<FixedOffset>>,
+
+    /// Show only commits made at or before this date, in the same format as `--since`.
+    #[structopt(long = "until", parse(try_from_str = "ident::parse_date"))]
+    pub until: Option<DateTime<FixedOffset>>,
+
+    /// Show the oldest commits first. This has to hold the whole history in memory before
+    /// anything is shown, unless `--max-count` is also given, in which case only the limited
+    /// window (the newest commits) is held and reversed.
+    #[structopt(long = "reverse")]
+    pub reverse: bool,
+
+    /// Show no commit before all of its children which are shown, even when clocks were skewed
+    /// and a parent's date is later than its child's. Otherwise, commits are in the order of
+    /// their dates. The whole history to be shown is walked before anything is shown.
+    #[structopt(long = "topo-order")]
+    pub topo_order: bool,
+
+    /// Follow only the first parent of each commit, showing the mainline of the branch without
+    /// the history brought in by merges. Since each commit then has a single successor in the
+    /// walk, the mainline is shown in order, and `--max-count` counts mainline commits only.
+    #[structopt(long = "first-parent")]
+    pub first_parent: bool,
+
+    /// Prepare up to this many commits ahead of the one being shown, fetching their objects and
+    /// computing their digests concurrently. This hides the latency of slow stores without
+    /// changing the output. Defaults to 8; 1 prepares one commit at a time.
+    #[structopt(long = "lookahead")]
+    pub lookahead: Option<usize>,
+
+    /// Show the commits reachable from this revision instead of from HEAD, or only those in a
+    /// range: `A..B` for the commits reachable from `B` but not from `A`, or `A...B` for those
+    /// reachable from one of `A` and `B` but not both. See `RevRange` for the details.
+    #[structopt(name = "RANGE")]
+    pub range: Option<RevRange>,
+
+    /// With a range `A..B`, show only the commits on a path from `A` to `B`, i.e. those which are
+    /// descendants of `A` as well as ancestors of `B`. This leaves out history merged in from
+    /// elsewhere along the way.
+    #[structopt(long = "ancestry-path")]
+    pub ancestry_path: bool,
+
+    /// Keep following the history of a single file past the commit which renamed it. Renames are
+    /// detected heuristically: when the file is added by a commit which also deletes a file with
+    /// identical contents, the deleted file is taken to be its old name. Renames of directories,
+    /// and renames which also change the file, are not followed.
+    #[structopt(long = "follow", raw(requires = r#""PATH""#))]
+    pub follow: bool,
+
+    /// Show only the commits which change one of these paths, given after `--`, either absolute
+    /// or relative to the root of the repository. A merge is shown only if the paths differ from
+    /// every one of its parents.
+    #[structopt(name = "PATH", parse(from_os_str), raw(last = "true"))]
+    pub paths: Vec<PathBuf>,
+}
 
 #[must_use = "LogOut contains futures which must be driven to completion!"]
 pub struct LogOut<'r> {
@@ -27,69 +104,465 @@ impl<'r> fmt::Debug for LogOut<'r> {
     }
 }
 
+pub(crate) type LogEntry = (CommitRef<String>, Commit<String>);
+
+/// A commit found by `walk`, with the handle it was reached through.
+pub type WalkEntry<B> = (CommitRef<Handle<B>>, Commit<Handle<B>>);
+
+/// A commit waiting to be walked. Ordered by commit timestamp and then by ID.
+type Pending<B> = (
+    DateTime<FixedOffset>,
+    Vec<u8>,
+    CommitRef<Handle<B>>,
+    Commit<Handle<B>>,
+);
+
+#[async]
+fn pending<B: Backend>(commit_ref: CommitRef<Handle<B>>) -> Result<Pending<B>, Error> {
+    let (commit_id, commit) = await!(commit_ref.id().join(commit_ref.fetch()))?;
+    let id = commit_id.as_inner().borrow().as_bytes().to_owned();
+
+    Ok((commit.as_committer_timestamp().clone(), id, commit_ref, commit))
+}
+
+/// Walk the history beneath `tips`, newest first by commit timestamp, and by ID among commits
+/// with the same timestamp. Each commit is found once however many ways it is reachable, and
+/// hidden commits are never entered, so neither is anything beneath them that isn't reachable
+/// some other way. With `first_parent`, only the first parent of each commit is followed.
+///
+/// A commit's parents are fetched before it is yielded, so that the walk is always ready to pick
+/// the newest commit next.
+#[async_stream(item = WalkEntry<B>)]
+pub(crate) fn walk<B: Backend>(
+    tips: Vec<CommitRef<Handle<B>>>,
+    hidden: HashSet<CommitRef<Handle<B>>>,
+    first_parent: bool,
+) -> Result<(), Error> {
+    let mut visited = HashSet::new();
+    let mut queue = BinaryHeap::new();
+    for tip in tips {
+        if !hidden.contains(&tip) && visited.insert(tip.clone()) {
+            queue.push(await!(pending(tip))?);
+        }
+    }
+
+    while let Some((_, _, commit_ref, commit)) = queue.pop() {
+        let unvisited = walked_parents(&commit, first_parent)
+            .iter()
+            .filter(|&parent| !hidden.contains(parent) && visited.insert(parent.clone()))
+            .cloned()
+            .map(pending)
+            .collect::<Vec<_>>();
+        // The queue is ordered, so the parents may be fetched in any order.
+        queue.extend(await!(stream::futures_unordered(unvisited).collect())?);
+
+        stream_yield!((commit_ref, commit));
+    }
+
+    Ok(())
+}
+
+/// Like `walk`, but in topological order: no commit is yielded before all of its children which
+/// are walked. Among the commits whose children have all been yielded, the one which `walk` would
+/// yield first comes first. The whole history is walked before anything is yielded.
+#[async_stream(item = WalkEntry<B>)]
+pub(crate) fn topo_walk<B: Backend>(
+    tips: Vec<CommitRef<Handle<B>>>,
+    hidden: HashSet<CommitRef<Handle<B>>>,
+    first_parent: bool,
+) -> Result<(), Error> {
+    let mut walked = await!(walk(tips, hidden, first_parent).collect())?
+        .into_iter()
+        .map(Some)
+        .collect::<Vec<_>>();
+
+    // Each commit by its position in the chronological walk, and how many of its children have
+    // yet to be yielded.
+    let mut positions = HashMap::new();
+    let mut children = HashMap::new();
+    for (position, walk_entry) in walked.iter().enumerate() {
+        let &(ref commit_ref, ref commit) = walk_entry.as_ref().unwrap();
+        positions.insert(commit_ref.clone(), position);
+        for parent in walked_parents(commit, first_parent) {
+            *children.entry(parent.clone()).or_insert(0usize) += 1;
+        }
+    }
+
+    let mut ready = walked
+        .iter()
+        .enumerate()
+        .filter(|&(_, walk_entry)| {
+            let commit_ref = &walk_entry.as_ref().unwrap().0;
+            children.get(commit_ref).cloned().unwrap_or(0) == 0
+        })
+        .map(|(position, _)| Reverse(position))
+        .collect::<BinaryHeap<_>>();
+
+    while let Some(Reverse(position)) = ready.pop() {
+        let (commit_ref, commit) = walked[position].take().unwrap();
+        for parent in walked_parents(&commit, first_parent) {
+            if let Some(&parent_position) = positions.get(parent) {
+                let remaining = children.get_mut(parent).unwrap();
+                *remaining -= 1;
+                if *remaining == 0 {
+                    ready.push(Reverse(parent_position));
+                }
+            }
+        }
+
+        stream_yield!((commit_ref, commit));
+    }
+
+    Ok(())
+}
+
+/// The parents of `commit` which a walk follows.
+fn walked_parents<H>(commit: &Commit<H>, first_parent: bool) -> &[CommitRef<H>] {
+    let parents = commit.as_parents();
+    if first_parent {
+        &parents[..parents.len().min(1)]
+    } else {
+        parents
+    }
+}
+
+#[async]
+fn digest_entry<B: Backend>(
+    commit_ref: CommitRef<Handle<B>>,
+    commit: Commit<Handle<B>>,
+) -> Result<LogEntry, Error> {
+    let commit_id = await!(commit_ref.id())?;
+    let digest = hex::encode(commit_id.as_inner().borrow().as_bytes());
+    await!(entry(digest, commit))
+}
+
+/// Every commit reachable from `tip`, including `tip` itself, along with its parents.
+#[async]
+fn ancestors<B: Backend>(
+    tip: CommitRef<Handle<B>>,
+) -> Result<HashMap<CommitRef<Handle<B>>, Vec<CommitRef<Handle<B>>>>, Error> {
+    let mut graph = HashMap::new();
+    let mut stack = vec![tip];
+
+    while let Some(commit_ref) = stack.pop() {
+        if !graph.contains_key(&commit_ref) {
+            let parents = await!(commit_ref.fetch())?.as_parents().to_owned();
+            stack.extend(parents.iter().cloned());
+            graph.insert(commit_ref, parents);
+        }
+    }
+
+    Ok(graph)
+}
+
+/// The commits in `graph` which descend from `bottom`, not counting `bottom` itself.
+fn descendants<B: Backend>(
+    graph: &HashMap<CommitRef<Handle<B>>, Vec<CommitRef<Handle<B>>>>,
+    bottom: &CommitRef<Handle<B>>,
+) -> HashSet<CommitRef<Handle<B>>> {
+    let mut children = HashMap::new();
+    for (commit_ref, parents) in graph {
+        for parent in parents {
+            children
+                .entry(parent)
+                .or_insert_with(Vec::new)
+                .push(commit_ref);
+        }
+    }
+
+    let mut found = HashSet::new();
+    let mut stack = vec![bottom];
+    while let Some(commit_ref) = stack.pop() {
+        for &child in children.get(commit_ref).into_iter().flat_map(|children| children) {
+            if found.insert(child.clone()) {
+                stack.push(child);
+            }
+        }
+    }
+
+    found
+}
+
+/// Of the files deleted between `parent` and `child`, the first (in path order) which held
+/// `object`. This is the heuristic by which `--follow` detects renames.
+#[async]
+fn rename_source<B: Backend>(
+    parent: TreeRef<Handle<B>>,
+    child: TreeRef<Handle<B>>,
+    object: ObjectRef<Handle<B>>,
+) -> Result<Option<ObjectPath>, Error> {
+    let child_files = await!(Repository::<B>::staged_files(child))?;
+    let source = await!(Repository::<B>::staged_files(parent))?
+        .into_iter()
+        .find(|&(ref path, ref parent_object)| {
+            parent_object == &object && !child_files.contains_key(path)
+        })
+        .map(|(path, _)| path);
+
+    Ok(source)
+}
+
+/// Whether a commit changes any of `paths` relative to `parents`, along with the paths to look
+/// for in each parent. With `follow`, a parent which lacks the single path but from which it was
+/// renamed is looked at under its old name instead, and the rename counts as a change.
+#[async]
+fn changes<B: Backend>(
+    subtree: TreeRef<Handle<B>>,
+    parents: Vec<CommitRef<Handle<B>>>,
+    paths: Vec<ObjectPath>,
+    follow: bool,
+    capacity: usize,
+) -> Result<(bool, Vec<Vec<ObjectPath>>), Error> {
+    let hierarchy = Hierarchy::with_capacity(subtree.clone(), capacity);
+    let mut objects = Vec::with_capacity(paths.len());
+    for path in paths.clone() {
+        objects.push(await!(hierarchy.get(path))?);
+    }
+
+    // A root commit changes the paths if it has anything at them at all.
+    let mut changed = objects.iter().any(Option::is_some) || !parents.is_empty();
+    let mut parent_paths = Vec::with_capacity(parents.len());
+    for parent_ref in parents {
+        let parent_subtree = await!(parent_ref.fetch())?.as_subtree().clone();
+        let parent_hierarchy = Hierarchy::with_capacity(parent_subtree.clone(), capacity);
+        let mut parent_objects = Vec::with_capacity(paths.len());
+        for path in paths.clone() {
+            parent_objects.push(await!(parent_hierarchy.get(path))?);
+        }
+
+        let renamed_from = match (follow, objects[0].clone(), parent_objects[0].is_none()) {
+            (true, Some(object), true) => {
+                await!(rename_source(parent_subtree, subtree.clone(), object))?
+            }
+            _ => None,
+        };
+
+        match renamed_from {
+            Some(old_path) => parent_paths.push(vec![old_path]),
+            None => {
+                if parent_objects == objects {
+                    changed = false;
+                }
+                parent_paths.push(paths.clone());
+            }
+        }
+    }
+
+    Ok((changed, parent_paths))
+}
+
+#[async]
+pub(crate) fn entry<B: Backend>(
+    digest: String,
+    commit: Commit<Handle<B>>,
+) -> Result<LogEntry, Error> {
+    Ok((CommitRef::new(digest), await!(describe(commit))?))
+}
+
+/// The commit with its subtree and parents given by their digests.
+#[async]
+pub(crate) fn describe<B: Backend>(commit: Commit<Handle<B>>) -> Result<Commit<String>, Error> {
+    let mut builder = CommitBuilder::new();
+    let parent_stream = stream::futures_ordered(commit.as_parents().to_owned().into_iter().map(
+        |commit_ref| {
+            commit_ref.id().map(|commit_digest| {
+                CommitRef::new(hex::encode(commit_digest.as_inner().borrow().as_bytes()))
+            })
+        },
+    ));
+    let subtree_future = commit.as_subtree().id().map(|subtree_digest| {
+        TreeRef::new(hex::encode(subtree_digest.as_inner().borrow().as_bytes()))
+    });
+
+    let (subtree, parents) = await!(subtree_future.join(parent_stream.collect()))?;
+    builder.subtree(subtree);
+    builder.parents(parents);
+    builder.author(commit.as_author().clone());
+    builder.timestamp(commit.as_timestamp().clone());
+    if commit.has_committer_timestamp() {
+        builder.committer_timestamp(Some(commit.as_committer_timestamp().clone()));
+    }
+
+    if let Some(message) = commit.as_message() {
+        builder.message(message.to_owned());
+    }
+
+    for (key, value) in commit.as_headers() {
+        builder.header(key.clone(), value.clone());
+    }
+
+    Ok(builder.into_commit()?)
+}
+
 impl<B: Backend> Repository<B> {
-    pub fn log<'r>(&'r self, _args: LogArgs) -> LogOut<'r> {
-        let entries = async_stream_block! {
-            let state = self.get_state()?;
-
-            let head = match state.head {
-                Head::Empty => return Ok(()),
-                Head::Detached(head) => head,
-                Head::Branch(branch) => CommitRef::new(await!(self.store.load_branches())?[branch.as_str()].clone()),
-            };
+    /// Every commit reachable from `start`, including `start` itself, newest first, as `log`
+    /// walks them. See `walk` for the order.
+    pub fn walk_commits(
+        &self,
+        start: CommitRef<Handle<B>>,
+    ) -> impl Stream<Item = WalkEntry<B>, Error = Error> {
+        walk(vec![start], HashSet::new(), false)
+    }
+
+    pub fn log<'r>(&'r self, args: LogArgs) -> LogOut<'r> {
+        let LogArgs {
+            limit,
+            since,
+            until,
+            reverse,
+            topo_order,
+            first_parent,
+            lookahead,
+            range,
+            ancestry_path,
+            follow,
+            paths,
+        } = args;
+        let commits = async_stream_block! {
+            ensure!(!follow || paths.len() == 1, "--follow needs exactly one path!");
+            let paths = paths
+                .into_iter()
+                .map(|path| Ok(self.resolve_path(path)?.1))
+                .collect::<Result<Vec<_>, Error>>()?;
+            let capacity = self.get_config()?.hierarchy_cache_capacity;
 
-            let mut visited = HashSet::new();
-            let mut queue = vec![head];
+            // The walk starts from the tips and never enters a hidden commit, so everything
+            // beneath one is hidden too unless it is also reachable some other way.
+            let (tips, hidden) = match range {
+                None => {
+                    ensure!(!ancestry_path, "--ancestry-path needs a range A..B!");
+
+                    let head = match self.get_state()?.head {
+                        Head::Empty => return Ok(()),
+                        Head::Detached(head) => head,
+                        Head::Branch(branch) => {
+                            let branches = await!(refs::load_heads(&self.store))?;
+                            match branches.get(branch.as_str()) {
+                                Some(handle) => CommitRef::new(handle.clone()),
+                                // The branch has no commits yet.
+                                None => return Ok(()),
+                            }
+                        }
+                    };
+
+                    (vec![head], HashSet::new())
+                }
+                Some(RevRange::Rev(rev)) => {
+                    ensure!(!ancestry_path, "--ancestry-path needs a range A..B!");
+                    (vec![await!(plumbing::resolve_rev(self, rev))?], HashSet::new())
+                }
+                Some(RevRange::Between(from, to)) => {
+                    let from = await!(plumbing::resolve_rev(self, from))?;
+                    let to = await!(plumbing::resolve_rev(self, to))?;
 
-            while let Some(commit_ref) = queue.pop() {
-                let commit = await!(commit_ref.fetch())?;
-                queue.extend(commit.as_parents().iter().filter_map(|parent| {
-                    if visited.insert(parent.clone()) {
-                        Some(parent.clone())
+                    let hidden = if ancestry_path {
+                        // Descendants of `from` can't be its ancestors, so hiding everything else
+                        // reachable from `to` hides the ancestors of `from` as well.
+                        let graph = await!(ancestors(to.clone()))?;
+                        let on_path = descendants(&graph, &from);
+                        graph
+                            .into_iter()
+                            .map(|(commit_ref, _)| commit_ref)
+                            .filter(|commit_ref| !on_path.contains(commit_ref))
+                            .collect()
                     } else {
-                        None
-                    }
-                }));
-
-                let mut builder = CommitBuilder::new();
-                let parent_stream =
-                    stream::futures_ordered(commit.as_parents().to_owned().into_iter().map(
-                        |commit_ref| {
-                            commit_ref.id().map(|commit_digest| {
-                                CommitRef::new(hex::encode(commit_digest.as_inner().borrow().as_bytes()))
-                            })
-                        },
-                    ));
-                let subtree_future = commit
-                    .as_subtree()
-                    .id()
-                    .map(|subtree_digest| TreeRef::new(hex::encode(subtree_digest.as_inner().borrow().as_bytes())));
-                let digest_future = commit_ref
-                    .id()
-                    .map(|commit_digest| CommitRef::new(hex::encode(commit_digest.as_inner().borrow().as_bytes())));
-
-                let (digest, subtree, parents) = await!(
-                    digest_future
-
-                        .join3(subtree_future, parent_stream.collect())
-                )?;
-                builder.subtree(subtree);
-                builder.parents(parents);
-                builder.author(commit.as_author().clone());
-                builder.timestamp(commit.as_timestamp().clone());
-
-                if let Some(message) = commit.as_message() {
-                    builder.message(message.to_owned());
+                        await!(ancestors(from))?
+                            .into_iter()
+                            .map(|(commit_ref, _)| commit_ref)
+                            .collect()
+                    };
+
+                    (vec![to], hidden)
+                }
+                Some(RevRange::Symmetric(left, right)) => {
+                    ensure!(!ancestry_path, "--ancestry-path needs a range A..B, not A...B!");
+
+                    let left = await!(plumbing::resolve_rev(self, left))?;
+                    let right = await!(plumbing::resolve_rev(self, right))?;
+
+                    // The commits reachable from both sides are the merge bases and everything
+                    // beneath them.
+                    let left_graph = await!(ancestors(left.clone()))?;
+                    let right_graph = await!(ancestors(right.clone()))?;
+                    let hidden = left_graph
+                        .into_iter()
+                        .map(|(commit_ref, _)| commit_ref)
+                        .filter(|commit_ref| right_graph.contains_key(commit_ref))
+                        .collect();
+
+                    (vec![left, right], hidden)
                 }
+            };
+
+            let mut shown = 0;
+            // With `--follow`, the paths looked at in each commit still to be walked. A commit
+            // reached through several children takes the paths of the first.
+            let mut tracked = HashMap::new();
+
+            let walked: Box<Stream<Item = WalkEntry<B>, Error = Error>> = if topo_order {
+                Box::new(topo_walk(tips, hidden, first_parent))
+            } else {
+                Box::new(walk(tips, hidden, first_parent))
+            };
+
+            #[async]
+            for (commit_ref, commit) in walked {
+                if limit.map_or(false, |limit| shown >= limit) {
+                    break;
+                }
+
+                let timestamp = *commit.as_committer_timestamp();
+                let in_range = since.map_or(true, |since| timestamp >= since)
+                    && until.map_or(true, |until| timestamp <= until);
 
-                stream_yield!((digest, builder.into_commit().unwrap()));
+                // With `--follow`, the paths to track into the parents are needed even if this
+                // commit isn't shown.
+                let show = if paths.is_empty() {
+                    in_range
+                } else {
+                    let parents = walked_parents(&commit, first_parent).to_owned();
+                    let commit_paths = tracked.remove(&commit_ref).unwrap_or_else(|| paths.clone());
+                    let (changed, parent_paths) = await!(changes(
+                        commit.as_subtree().clone(),
+                        parents.clone(),
+                        commit_paths,
+                        follow,
+                        capacity,
+                    ))?;
+                    if follow {
+                        for (parent, parent_paths) in parents.iter().zip(parent_paths) {
+                            tracked.entry(parent.clone()).or_insert(parent_paths);
+                        }
+                    }
+                    changed && in_range
+                };
+
+                if show {
+                    shown += 1;
+                    stream_yield!((commit_ref, commit));
+                }
             }
 
             Ok(())
         };
 
-        LogOut {
-            entries: Box::new(entries),
-        }
+        // Digests of the subtree and parents are computed for several commits at once, while the
+        // walk runs ahead to find them; `buffered` hands them on in the order of the walk.
+        let entries = commits
+            .map(|(commit_ref, commit)| digest_entry(commit_ref, commit))
+            .buffered(lookahead.unwrap_or(DEFAULT_LOOKAHEAD).max(1));
+
+        let entries: Box<Stream<Item = LogEntry, Error = Error> + 'r> = if reverse {
+            Box::new(
+                entries
+                    .collect()
+                    .map(|buffered| stream::iter_ok(buffered.into_iter().rev()))
+                    .flatten_stream(),
+            )
+        } else {
+            Box::new(entries)
+        };
+
+        LogOut { entries }
     }
 }
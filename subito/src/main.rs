@@ -3,6 +3,7 @@
 
 extern crate attaca;
 extern crate attaca_leveldb;
+extern crate attaca_ssh;
 #[macro_use]
 extern crate clap;
 #[macro_use]
@@ -14,15 +15,20 @@ extern crate structopt;
 #[macro_use]
 extern crate subito;
 
-use std::fmt::Write;
+use std::{thread, io::{self, BufRead, Write as IoWrite}, fmt::Write};
 
-use attaca::object::CommitAuthor;
-use clap::App;
+use attaca::{digest::prelude::*, object::CommitAuthor, store::Backend};
+use clap::{App, ArgMatches};
 use failure::Error;
-use futures::prelude::*;
+use futures::{prelude::*, sync::mpsc};
 use structopt::StructOpt;
-use subito::{BranchArgs, CheckoutArgs, CloneArgs, CommitArgs, FetchArgs, FsckArgs, Head, InitArgs,
-             LogArgs, RemoteArgs, ShowArgs, StageArgs, StatusArgs};
+use subito::{BranchArgs, BundleArgs, CatFileArgs, CheckoutArgs, CloneArgs, CommitArgs,
+             CompletionsArgs, ConfigArgs, CountObjectsArgs, DescribeArgs, DiffArgs,
+             DiffWorkspaceArgs, FetchArgs, FsckArgs, GcArgs, Head, ImportArgs, InitArgs, LogArgs,
+             MergeArgs, PushArgs, RebaseArgs, RemoteArgs, Repository, ResetArgs, RevParseArgs,
+             ServeArgs, ShowArgs, StageArgs, StashArgs, StatusArgs, TagArgs, UnstageArgs,
+             Verbosity, WhyKeptArgs, candidate::StageProgress,
+             plumbing::{merge::MergeOutcome, rebase::RebaseOutcome}, progress};
 
 fn main() {
     match run() {
@@ -38,42 +44,204 @@ fn main() {
 
 fn run() -> Result<(), Error> {
     let yml = load_yaml!("main.yml");
-    let app = App::from_yaml(yml)
+    let mut app = App::from_yaml(yml)
         .subcommand(BranchArgs::clap())
+        .subcommand(BundleArgs::clap())
+        .subcommand(CatFileArgs::clap())
         .subcommand(CheckoutArgs::clap())
         .subcommand(CloneArgs::clap())
         .subcommand(CommitArgs::clap())
+        .subcommand(CompletionsArgs::clap())
+        .subcommand(ConfigArgs::clap())
+        .subcommand(CountObjectsArgs::clap())
+        .subcommand(DescribeArgs::clap())
+        .subcommand(DiffArgs::clap())
+        .subcommand(DiffWorkspaceArgs::clap())
         .subcommand(FetchArgs::clap())
         .subcommand(FsckArgs::clap())
+        .subcommand(GcArgs::clap())
+        .subcommand(ImportArgs::clap())
         .subcommand(LogArgs::clap())
         .subcommand(InitArgs::clap())
+        .subcommand(MergeArgs::clap())
+        .subcommand(PushArgs::clap())
+        .subcommand(RebaseArgs::clap())
         .subcommand(RemoteArgs::clap())
+        .subcommand(ResetArgs::clap())
+        .subcommand(RevParseArgs::clap())
+        .subcommand(ServeArgs::clap())
         .subcommand(ShowArgs::clap())
-        .subcommand(StatusArgs::clap());
-    let matches = app.get_matches();
+        .subcommand(StageArgs::clap())
+        .subcommand(StashArgs::clap())
+        .subcommand(StatusArgs::clap())
+        .subcommand(TagArgs::clap())
+        .subcommand(UnstageArgs::clap())
+        .subcommand(WhyKeptArgs::clap());
+    let matches = app.clone().get_matches();
 
     match matches.subcommand() {
         ("branch", Some(sub_m)) => {
             let args = BranchArgs::from_clap(sub_m);
             search!(repository, repository.branch(args).blocking.wait())?
         }
+        ("bundle", Some(sub_m)) => {
+            let args = BundleArgs::from_clap(sub_m);
+            search!(repository, {
+                let count = repository.bundle(args).blocking.wait()?;
+                eprintln!("Wrote {} objects to the bundle.", count);
+                Ok(())
+            })?
+        }
+        ("cat-file", Some(sub_m)) => {
+            let args = CatFileArgs::from_clap(sub_m);
+            search!(repository, {
+                // Specs are read on their own thread, so that a record can be written as soon as
+                // it is ready rather than once whoever is writing specs has written the next.
+                let (tx, rx) = mpsc::unbounded();
+                thread::spawn(move || {
+                    let stdin = io::stdin();
+                    for line in stdin.lock().lines() {
+                        if tx.unbounded_send(line.map_err(Error::from)).is_err() {
+                            break;
+                        }
+                    }
+                });
+                let specs = rx
+                    .map_err(|()| format_err!("Spec channel closed unexpectedly"))
+                    .and_then(|line| line);
+                let records = repository.cat_file(args, specs).records;
+
+                let stdout = io::stdout();
+                let mut lock = stdout.lock();
+                for record in records.wait() {
+                    let record = record?;
+                    match record.object {
+                        Some(object) => {
+                            let kind = subito::cat_file::kind_name(object.kind);
+                            writeln!(lock, "{} {} {}", object.digest, kind, object.size)?;
+                            if let Some(contents) = object.contents {
+                                lock.write_all(&contents)?;
+                                writeln!(lock)?;
+                            }
+                        }
+                        None => {
+                            writeln!(lock, "{} missing", record.spec)?;
+                            if let Some(reason) = record.reason {
+                                eprintln!("{}: {}", record.spec, reason);
+                            }
+                        }
+                    }
+                    lock.flush()?;
+                }
+
+                Ok(())
+            })?
+        }
         ("checkout", Some(sub_m)) => {
             let args = CheckoutArgs::from_clap(sub_m);
             search!(repository, repository.checkout(args).blocking.wait())?
         }
         ("clone", Some(sub_m)) => {
-            subito::clone(CloneArgs::from_clap(sub_m)).blocking.wait()?;
+            let mut args = CloneArgs::from_clap(sub_m);
+            match verbosity(sub_m) {
+                Verbosity::Quiet => args.no_progress = !args.progress,
+                Verbosity::Normal => {}
+                Verbosity::Verbose | Verbosity::VeryVerbose => {
+                    args.progress = !args.no_progress
+                }
+            }
+            if progress::enabled(args.progress, args.no_progress || args.porcelain) {
+                eprintln!("Cloning {}...", args.url);
+            }
+            if let Some(report) = subito::clone(args).blocking.wait()? {
+                io::stdout().write_all(&report)?;
+                println!("done");
+            }
             Ok(())
         }
+        ("completions", Some(sub_m)) => {
+            let args = CompletionsArgs::from_clap(sub_m);
+            match args.shell {
+                Some(shell) if !args.list_refs && !args.list_remotes => {
+                    let stdout = io::stdout();
+                    subito::completions::generate(&mut app, shell, &mut stdout.lock())
+                }
+                _ => search!(repository, {
+                    let names = if args.list_refs {
+                        repository.completion_refs().names.wait()?
+                    } else {
+                        repository.completion_remotes().names.wait()?
+                    };
+                    for name in names {
+                        println!("{}", name);
+                    }
+                    Ok(())
+                })?,
+            }
+        }
+        ("config", Some(sub_m)) => {
+            let args = ConfigArgs::from_clap(sub_m);
+            let list = match args {
+                ConfigArgs::List(_) => true,
+                _ => false,
+            };
+            let entries = if args.is_global() {
+                subito::config::user_config(args)?
+            } else {
+                search!(repository, repository.config(args).entries.wait())?
+            };
+
+            for (key, value) in entries {
+                if list {
+                    println!("{} = {}", key, value);
+                } else {
+                    println!("{}", value);
+                }
+            }
+        }
         ("fetch", Some(sub_m)) => {
             let args = FetchArgs::from_clap(sub_m);
-            search!(repository, repository.fetch(args).blocking.wait())?
+            if progress::enabled(args.progress, args.no_progress || args.porcelain) {
+                eprintln!("Fetching from {}...", args.remote);
+            }
+            let (remote, porcelain) = (args.remote.clone(), args.porcelain);
+            search!(repository, {
+                let report = repository.fetch(args).blocking.wait()?;
+
+                if porcelain {
+                    subito::fetch::write_porcelain(remote, report, io::stdout()).wait()?;
+                    println!("done");
+                } else if report.is_up_to_date() {
+                    println!("Already up to date.");
+                } else {
+                    if !report.updated.is_empty() {
+                        let names = report
+                            .updated
+                            .iter()
+                            .map(|name| name.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        match report.copied {
+                            Some(count) => {
+                                println!("Updated {} ({} objects copied)", names, count)
+                            }
+                            None => println!("Updated {}", names),
+                        }
+                    }
+
+                    for name in &report.pruned {
+                        println!("Pruned {}/{}", remote, name);
+                    }
+                }
+
+                Ok(())
+            })?
         }
         ("fsck", Some(sub_m)) => search!(repository, {
             let args = FsckArgs::from_clap(sub_m);
-            let errored = repository
-                .fsck(args)
-                .errors
+            let write_refs = args.write_refs;
+            let out = repository.fsck(args);
+            let errored = out.errors
                 .fold(false, |_, error| -> Result<bool, Error> {
                     println!(
                         "Fsck error: digest mismatch: calculated {}, received {}",
@@ -87,8 +255,30 @@ fn run() -> Result<(), Error> {
                 println!("No errors found.");
             }
 
+            for digest in out.lost_found.wait()? {
+                if write_refs {
+                    println!("dangling commit {} (refs/lost-found/{})", digest, digest);
+                } else {
+                    println!("dangling commit {}", digest);
+                }
+            }
+
             Ok(())
         })?,
+        ("gc", Some(sub_m)) => {
+            let args = GcArgs::from_clap(sub_m);
+            search!(repository, {
+                match repository.gc(args).blocking.wait()? {
+                    Some(stats) => {
+                        println!("size before: {} bytes", stats.before_bytes);
+                        println!("size after: {} bytes", stats.after_bytes);
+                        println!("reclaimed: {} bytes", stats.reclaimed_bytes());
+                    }
+                    None => println!("Nothing to compact."),
+                }
+                Ok(())
+            })?
+        }
         ("log", Some(sub_m)) => search!(repository, {
             let args = LogArgs::from_clap(sub_m);
             let commits = repository.log(args).entries.collect().wait()?;
@@ -106,6 +296,9 @@ fn run() -> Result<(), Error> {
                         (&None, &None) => {}
                     }
                     writeln!(&mut buf, "date {}", commit.as_timestamp())?;
+                    if commit.as_committer_timestamp() != commit.as_timestamp() {
+                        writeln!(&mut buf, "commit date {}", commit.as_committer_timestamp())?;
+                    }
                     if let Some(message) = commit.as_message() {
                         writeln!(&mut buf, "\t{}", message)?;
                     }
@@ -119,40 +312,292 @@ fn run() -> Result<(), Error> {
         })?,
         ("init", Some(sub_m)) => init!(InitArgs::from_clap(sub_m), _repository, Ok(()))?,
         ("stage", Some(sub_m)) => {
-            let mut args = StageArgs::from_clap(sub_m);
-            args.quiet = true;
-            search!(repository, repository.stage(args).blocking.wait())?
+            let args = StageArgs::from_clap(sub_m);
+            search!(repository, stage(&mut repository, args, verbosity(sub_m)))?
         }
         ("unstage", Some(sub_m)) => {
-            let mut args = StageArgs::from_clap(sub_m);
-            args.quiet = true;
-            args.previous = true;
-            search!(repository, repository.stage(args).blocking.wait())?
+            let args = UnstageArgs::from_clap(sub_m);
+            search!(repository, repository.unstage(args).blocking.wait())?
         }
         ("commit", Some(sub_m)) => {
             let args = CommitArgs::from_clap(sub_m);
-            search!(repository, repository.commit(args).blocking.wait())?
+            search!(repository, {
+                let (maybe_commit_ref, commit) = repository.commit(args).blocking.wait()?;
+                let summary = commit
+                    .as_message()
+                    .and_then(|message| message.lines().next())
+                    .unwrap_or("");
+                let print_details = || {
+                    let CommitAuthor { ref name, ref mbox } = *commit.as_author();
+                    match (name, mbox) {
+                        (&Some(ref n), &Some(ref m)) => println!("author {} <{}>", n, m),
+                        (&Some(ref n), &None) => println!("author {}", n),
+                        (&None, &Some(ref m)) => println!("author <{}>", m),
+                        (&None, &None) => {}
+                    }
+                    println!("date {}", commit.as_timestamp());
+                    if commit.as_committer_timestamp() != commit.as_timestamp() {
+                        println!("commit date {}", commit.as_committer_timestamp());
+                    }
+                    for parent in commit.as_parents() {
+                        println!("parent {}", parent.as_inner());
+                    }
+                };
+
+                match (verbosity(sub_m), maybe_commit_ref) {
+                    (Verbosity::Quiet, _) => {}
+                    (_, None) => {
+                        println!("[dry run] {}", summary);
+                        println!("tree {}", commit.as_subtree().as_inner());
+                        print_details();
+                    }
+                    (Verbosity::Normal, Some(commit_ref)) => {
+                        let abbreviator = repository.abbreviator().blocking.wait()?;
+                        let abbrev = abbreviator.abbreviate(commit_ref.as_inner());
+                        println!("[{}] {}", abbrev, summary);
+                    }
+                    (Verbosity::Verbose, Some(commit_ref)) => {
+                        println!("[{}] {}", commit_ref.as_inner(), summary)
+                    }
+                    (Verbosity::VeryVerbose, Some(commit_ref)) => {
+                        println!("[{}] {}", commit_ref.as_inner(), summary);
+                        print_details();
+                    }
+                }
+
+                Ok(())
+            })?
+        }
+        ("import", Some(sub_m)) => {
+            let args = ImportArgs::from_clap(sub_m);
+            search!(repository, {
+                let imported = repository.import(args).blocking.wait()?;
+                println!("[{}] Imported {} files", imported.digest, imported.files);
+                Ok(())
+            })?
+        }
+        ("count-objects", Some(sub_m)) => {
+            let args = CountObjectsArgs::from_clap(sub_m);
+            search!(repository, {
+                let counts = repository.count_objects(args).blocking.wait()?;
+
+                match counts.store {
+                    Some(stats) => {
+                        println!("loose objects: {}", stats.loose_objects);
+                        println!("loose size: {} bytes", stats.loose_bytes);
+                    }
+                    None => println!("loose objects: unknown (backend cannot enumerate objects)"),
+                }
+
+                println!("reachable objects: {}", counts.reachable());
+                println!("    commits: {}", counts.commits);
+                println!("    trees: {}", counts.trees);
+                println!("    large blobs: {}", counts.large_blobs);
+                println!("    small blobs: {} ({} bytes)", counts.small_blobs, counts.blob_bytes);
+
+                if let Some(unreachable) = counts.unreachable() {
+                    println!("unreachable objects: {}", unreachable);
+                }
+
+                Ok(())
+            })?
+        }
+        ("describe", Some(sub_m)) => {
+            let args = DescribeArgs::from_clap(sub_m);
+            search!(repository, {
+                println!("{}", repository.describe(args).blocking.wait()?);
+                Ok(())
+            })?
+        }
+        ("diff", Some(sub_m)) => {
+            let args = DiffArgs::from_clap(sub_m);
+            search!(repository, {
+                for chunk in repository.diff(args).output.wait() {
+                    print!("{}", chunk?);
+                }
+                Ok(())
+            })?
+        }
+        ("diff-workspace", Some(sub_m)) => {
+            let args = DiffWorkspaceArgs::from_clap(sub_m);
+            search!(repository, {
+                for entry in repository.diff_workspace(args).entries.wait() {
+                    let (path, status) = entry?;
+                    println!("{} {}", status.code(), path);
+                }
+                Ok(())
+            })?
+        }
+        ("merge", Some(sub_m)) => {
+            let args = MergeArgs::from_clap(sub_m);
+            search!(repository, {
+                match repository.merge(args).blocking.wait()? {
+                    Some(MergeOutcome::UpToDate) => println!("Already up to date."),
+                    Some(MergeOutcome::FastForward) => println!("Fast-forwarded."),
+                    Some(MergeOutcome::Merged { ref conflicts }) if conflicts.is_empty() => {
+                        println!("Merged without conflicts. Commit to conclude the merge.");
+                    }
+                    Some(MergeOutcome::Merged { conflicts }) => {
+                        println!("Merge conflicts in:");
+                        for path in conflicts {
+                            println!("    {}", path);
+                        }
+                        println!("Stage the resolved files, then commit to conclude the merge.");
+                    }
+                    None => println!("Merge aborted."),
+                }
+
+                Ok(())
+            })?
+        }
+        ("rebase", Some(sub_m)) => {
+            let args = RebaseArgs::from_clap(sub_m);
+            search!(repository, {
+                match repository.rebase(args).blocking.wait()? {
+                    Some(RebaseOutcome::UpToDate) => println!("Already up to date."),
+                    Some(RebaseOutcome::FastForward) => println!("Fast-forwarded."),
+                    Some(RebaseOutcome::Finished) => println!("Rebased."),
+                    Some(RebaseOutcome::Stopped { commit, conflicts }) => {
+                        println!("Conflicts while replaying commit {}, in:", commit);
+                        for path in conflicts {
+                            println!("    {}", path);
+                        }
+                        println!("Stage the resolved files, then use `rebase --continue`.");
+                    }
+                    None => println!("Rebase aborted."),
+                }
+
+                Ok(())
+            })?
+        }
+        ("push", Some(sub_m)) => {
+            let args = PushArgs::from_clap(sub_m);
+            if progress::enabled(args.progress, args.no_progress) && !args.dry_run {
+                eprintln!("Pushing to {}...", args.remote);
+            }
+            search!(repository, {
+                let report = repository.push(args).blocking.wait()?;
+
+                if report.dry_run {
+                    println!("Would push branch {} ({})", report.branch, report.update);
+                    println!("{} objects missing on the remote:", report.missing.len());
+                    for digest in &report.missing {
+                        println!("    {}", hex::encode(digest.as_bytes()));
+                    }
+                } else {
+                    println!(
+                        "Pushed branch {} ({}), sending {} objects",
+                        report.branch,
+                        report.update,
+                        report.missing.len()
+                    );
+                }
+
+                Ok(())
+            })?
         }
         ("remote", Some(sub_m)) => {
             let args = RemoteArgs::from_clap(sub_m);
-            search!(repository, repository.remote(args).blocking.wait())?
+            let pruning = match args {
+                RemoteArgs::Prune(ref prune_args) => Some(prune_args.name.clone()),
+                _ => None,
+            };
+            search!(repository, {
+                let pruned = repository.remote(args).blocking.wait()?;
+                if let Some(name) = pruning {
+                    if pruned.is_empty() {
+                        println!("Nothing to prune.");
+                    }
+
+                    for branch in pruned {
+                        println!("Pruned {}/{}", name, branch);
+                    }
+                }
+                Ok(())
+            })?
+        }
+        ("reset", Some(sub_m)) => {
+            let args = ResetArgs::from_clap(sub_m);
+            search!(repository, repository.reset(args).blocking.wait())?
+        }
+        ("rev-parse", Some(sub_m)) => {
+            let args = RevParseArgs::from_clap(sub_m);
+            search!(repository, {
+                println!("{}", repository.rev_parse(args).digest.wait()?);
+                Ok(())
+            })?
+        }
+        ("serve", Some(sub_m)) => {
+            let args = ServeArgs::from_clap(sub_m);
+            match args.path.clone() {
+                Some(path) => open!(path, repository, repository.serve(args).blocking.wait())?,
+                None => search!(repository, repository.serve(args).blocking.wait())?,
+            }
         }
         ("show", Some(sub_m)) => {
             let args = ShowArgs::from_clap(sub_m);
             search!(repository, repository.show(args).blocking.wait())?
         }
+        ("stash", Some(sub_m)) => {
+            let args = StashArgs::from_clap(sub_m);
+            search!(repository, repository.stash(args).blocking.wait())?
+        }
         ("status", Some(sub_m)) => {
             let args = StatusArgs::from_clap(sub_m);
+            let porcelain = args.porcelain;
+            let short = args.short;
+            let nul_terminated = args.nul_terminated;
             search!(repository, {
                 let status = repository.status(args);
-                let (head, cand) = status.head.join(status.candidate).wait()?;
+
+                if short {
+                    let (head, tracking) = status.head.join(status.tracking).wait()?;
+                    let abbreviator = repository.abbreviator().blocking.wait()?;
+                    let stdout = io::stdout();
+                    let mut lock = stdout.lock();
+                    subito::status::write_branch_header(
+                        &mut lock,
+                        &head,
+                        tracking.as_ref(),
+                        &abbreviator,
+                    )?;
+                    for record in status.staged.collect().wait()? {
+                        record.write_porcelain(&mut lock, false)?;
+                    }
+                    for path in status.intent_to_add.wait()? {
+                        subito::status::write_intent_porcelain(&mut lock, &path, false)?;
+                    }
+                    return Ok(());
+                }
+
+                if porcelain {
+                    let records = status.staged.collect().wait()?;
+                    let stdout = io::stdout();
+                    let mut lock = stdout.lock();
+                    subito::status::write_porcelain_header(&mut lock, nul_terminated)?;
+                    for record in records {
+                        record.write_porcelain(&mut lock, nul_terminated)?;
+                    }
+                    for path in status.intent_to_add.wait()? {
+                        subito::status::write_intent_porcelain(&mut lock, &path, nul_terminated)?;
+                    }
+                    return Ok(());
+                }
+
+                let (head, cand, merge, intents) = status
+                    .head
+                    .join4(status.candidate, status.merge, status.intent_to_add)
+                    .wait()?;
+                let abbreviator = repository.abbreviator().blocking.wait()?;
 
                 let head_display = match head {
                     Head::Empty => None,
-                    Head::Detached(commit) => Some(format!("commit {}", &commit.as_inner()[..8])),
+                    Head::Detached(commit) => {
+                        Some(format!("commit {}", abbreviator.abbreviate(commit.as_inner())))
+                    }
                     Head::Branch(branch) => Some(format!("branch {}", branch)),
                 };
-                let cand_display = cand.as_ref().map(|s| &s[..8]);
+                let cand_display = cand.as_ref().map(|s| abbreviator.abbreviate(s));
 
                 match (head_display, cand_display) {
                     (Some(h), Some(c)) => println!("On {} with virtual workspace {}", h, c),
@@ -161,16 +606,64 @@ fn run() -> Result<(), Error> {
                     (None, None) => println!("No prior commit or virtual workspace"),
                 }
 
+                if let Some(merge) = merge {
+                    println!("Merging commit {}", abbreviator.abbreviate(&merge.theirs));
+                    if merge.conflicts.is_empty() {
+                        println!("All conflicts resolved. Commit to conclude the merge.");
+                    } else {
+                        println!("Unresolved conflicts:");
+                        for path in merge.conflicts {
+                            println!("    {}", path);
+                        }
+                    }
+                }
+
                 println!();
 
-                let staged_changes = status.staged.collect().wait()?;
-                if staged_changes.is_empty() {
-                    println!("No changes to be committed. The virtual workspace and previous commit are the same.");
+                let entries = status.entries.collect().wait()?;
+                if entries.is_empty() {
+                    println!(
+                        "No changes. The previous commit, virtual workspace and local workspace \
+                         are the same."
+                    );
                 } else {
-                    for change in staged_changes {
-                        println!("{:?}", change);
+                    for (path, path_status) in entries {
+                        println!("    {:<22}{}", format!("{}:", path_status), path);
                     }
                 }
+
+                if !intents.is_empty() {
+                    println!();
+                    println!("Intended to be added, but contents not yet staged:");
+                    for path in intents {
+                        println!("    {}", path);
+                    }
+                }
+                Ok(())
+            })?
+        }
+        ("tag", Some(sub_m)) => {
+            let args = TagArgs::from_clap(sub_m);
+            search!(repository, {
+                for name in repository.tag(args).blocking.wait()? {
+                    println!("{}", name);
+                }
+                Ok(())
+            })?
+        }
+        ("why-kept", Some(sub_m)) => {
+            let args = WhyKeptArgs::from_clap(sub_m);
+            let digest = args.digest.clone();
+            search!(repository, {
+                let kept_by = repository.why_kept(args).kept_by.wait()?;
+
+                if kept_by.is_empty() {
+                    println!("Nothing keeps {} alive.", digest);
+                }
+                for route in kept_by {
+                    println!("{}", route);
+                }
+
                 Ok(())
             })?
         }
@@ -181,3 +674,57 @@ fn run() -> Result<(), Error> {
         }
     }
 }
+
+fn verbosity(matches: &ArgMatches) -> Verbosity {
+    Verbosity::from_flags(
+        matches.is_present("quiet"),
+        matches.occurrences_of("verbose"),
+    )
+}
+
+fn stage<B: Backend>(
+    repository: &mut Repository<B>,
+    mut args: StageArgs,
+    verbosity: Verbosity,
+) -> Result<(), Error> {
+    match verbosity {
+        Verbosity::Quiet => args.quiet = !args.progress,
+        Verbosity::Normal => {}
+        Verbosity::Verbose | Verbosity::VeryVerbose => args.progress = !args.no_progress,
+    }
+
+    let out = repository.stage(args);
+    // The number of paths processed so far, and the length of the line last written, so that a
+    // shorter one can blank out what's left of it.
+    let progress = out.progress
+        .fold((0, 0), move |(processed, width), event| -> Result<_, Error> {
+            let line = match event {
+                StageProgress::Path(ref path) if verbosity >= Verbosity::Verbose => {
+                    eprintln!("\r{:width$}\r{}", "", path, width = width);
+                    return Ok((processed + 1, 0));
+                }
+                StageProgress::Path(_) => format!("Processed {} paths", processed + 1),
+                StageProgress::File(ref file) => format!(
+                    "Processed {} paths, hashing {} ({}/{} MiB)",
+                    processed,
+                    file.path,
+                    file.processed_bytes >> 20,
+                    file.total_bytes >> 20,
+                ),
+            };
+            eprint!("\r{:width$}", line, width = width);
+
+            let processed = match event {
+                StageProgress::Path(_) => processed + 1,
+                StageProgress::File(_) => processed,
+            };
+            Ok((processed, line.len()))
+        })
+        .map(move |(_, width)| {
+            if width > 0 {
+                eprintln!();
+            }
+        });
+
+    out.blocking.join(progress).map(|((), ())| ()).wait()
+}
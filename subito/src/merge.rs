@@ -0,0 +1,71 @@
+use std::fmt;
+
+use attaca::store::prelude::*;
+use failure::*;
+use futures::prelude::*;
+
+use Repository;
+use plumbing::{self, merge::MergeOutcome};
+use syntax::Rev;
+
+/// Join another line of history into HEAD.
+///
+/// If both sides have changed, the merged tree becomes the virtual workspace and the merge stays
+/// in progress until it is committed with `commit` or abandoned with `merge --abort`. Conflicting
+/// paths keep HEAD's version; each is resolved by staging it.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "merge")]
+pub struct MergeArgs {
+    /// The revision to merge into HEAD.
+    #[structopt(name = "REV", raw(required_unless = r#""abort""#))]
+    pub rev: Option<Rev>,
+
+    /// Merge even if REV and HEAD have no common ancestor. Both sides are then merged as if they
+    /// had started from an empty tree, so any path they both add differently conflicts.
+    #[structopt(long = "allow-unrelated-histories", raw(conflicts_with = r#""abort""#))]
+    pub allow_unrelated_histories: bool,
+
+    /// Abandon the merge in progress, restoring the virtual and local workspaces.
+    #[structopt(long = "abort", raw(conflicts_with = r#""REV""#))]
+    pub abort: bool,
+}
+
+#[must_use = "MergeOut contains futures which must be driven to completion!"]
+pub struct MergeOut<'r> {
+    /// The outcome of the merge, or `None` if a merge in progress was aborted.
+    pub blocking: Box<Future<Item = Option<MergeOutcome>, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for MergeOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MergeOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    pub fn merge<'r>(&'r mut self, args: MergeArgs) -> MergeOut<'r> {
+        let blocking = async_block! {
+            match args.rev {
+                Some(rev) if !args.abort => {
+                    let theirs = await!(plumbing::resolve_rev(self, rev))?;
+                    let outcome = await!(plumbing::merge::begin(
+                        self,
+                        theirs,
+                        args.allow_unrelated_histories,
+                    ))?;
+                    Ok(Some(outcome))
+                }
+                _ => {
+                    await!(plumbing::merge::abort(self))?;
+                    Ok(None)
+                }
+            }
+        };
+
+        MergeOut {
+            blocking: Box::new(blocking),
+        }
+    }
+}
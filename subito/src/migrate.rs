@@ -0,0 +1,79 @@
+//! Upgrading repositories written by older versions of subito.
+//!
+//! The layout of a repository's workspace database and store is versioned as a whole. The version
+//! is recorded under its own key when the repository is created, and each time a repository is
+//! opened, any migrations between its version and `SCHEMA_VERSION` are run in order. Repositories
+//! from before versioning was introduced have no version recorded and are treated as version 0.
+//!
+//! Fields added to the capnp schemas are read back as their defaults from older data, so they
+//! don't need a migration unless the default is wrong for existing repositories.
+
+use std::str;
+
+use attaca::store::prelude::*;
+use failure::*;
+use futures::prelude::*;
+use leveldb::{kv::KV, options::{ReadOptions, WriteOptions}};
+
+use Repository;
+use db::Key;
+use refs;
+
+/// The version of the repository layout written by this version of subito.
+pub const SCHEMA_VERSION: u32 = 1;
+
+pub(crate) fn encode_version(version: u32) -> Vec<u8> {
+    version.to_string().into_bytes()
+}
+
+fn decode_version(bytes: &[u8]) -> Result<u32, Error> {
+    Ok(str::from_utf8(bytes)?.parse()?)
+}
+
+impl<B: Backend> Repository<B> {
+    /// Find the version of the layout this repository was written with.
+    pub fn schema_version(&self) -> Result<u32, Error> {
+        let maybe_bytes = self.db
+            .read()
+            .unwrap()
+            .get(ReadOptions::new(), &Key::schema_version())?;
+
+        match maybe_bytes {
+            Some(bytes) => decode_version(&bytes).context("Malformed schema version"),
+            None => Ok(0),
+        }
+    }
+
+    /// Upgrade this repository to the current layout, one version at a time. Fails without
+    /// touching anything if the repository was written by a newer version of subito.
+    pub fn migrate(&self) -> Result<(), Error> {
+        let version = self.schema_version()?;
+        ensure!(
+            version <= SCHEMA_VERSION,
+            "This repository has schema version {}, but this version of subito only supports \
+             versions up to {}. Please upgrade subito to open it.",
+            version,
+            SCHEMA_VERSION
+        );
+
+        for from in version..SCHEMA_VERSION {
+            self.migrate_from(from)
+                .with_context(|_| format!("Error migrating from schema version {}", from))?;
+            self.db.read().unwrap().put(
+                WriteOptions::new(),
+                &Key::schema_version(),
+                &encode_version(from + 1),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn migrate_from(&self, version: u32) -> Result<(), Error> {
+        match version {
+            // Version 0 stored branches under bare names, rather than under `refs/heads/`.
+            0 => refs::migrate(&self.store).wait(),
+            _ => unreachable!("no migration from schema version {}", version),
+        }
+    }
+}
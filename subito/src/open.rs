@@ -1,11 +1,15 @@
 use std::{env, path::PathBuf};
 
+use attaca::{layer::BackendExt, seal::{SealLayer, SealedBackend}};
 use attaca_leveldb::LevelDbBackend;
+use attaca_ssh::SshBackend;
 use failure::*;
+use futures::prelude::*;
 use leveldb::{database::Database, kv::KV, options::{Options, ReadOptions}};
 
 use {Open, Repository};
-use config::{Config, StoreConfig, StoreKind};
+use config::{EncryptionConfig, StoreConfig, StoreKind};
+use crypt;
 use db::Key;
 
 #[macro_export]
@@ -32,13 +36,23 @@ macro_rules! open {
 
                 match config.store.kind {
                     $($crate::config::StoreKind::$ccname =>
-                        $crate::open::$lcname(config)
-                            .map(|backend| $crate::Repository::new(path, db, backend))
+                        $crate::open::$lcname(&config.store)
+                            .and_then(|backend| {
+                                let repository = $crate::Repository::new(path, db, backend);
+                                repository.migrate()?;
+                                Ok(repository)
+                            })
                             .map(|mut $repo: $crate::Repository<$dty>| {
                                 #[warn(unused_mut)]
 
                                 $generic
                             }),)*
+                    $crate::config::StoreKind::Bundle => Err($crate::reexports::failure::err_msg(
+                        "Malformed repository: a bundle cannot be a repository's store",
+                    )),
+                    $crate::config::StoreKind::Memory => Err($crate::reexports::failure::err_msg(
+                        "An in-memory store cannot be reopened",
+                    )),
                 }
             })
         }
@@ -48,8 +62,25 @@ macro_rules! open {
     };
 }
 
-pub fn leveldb(config: Config) -> Result<LevelDbBackend, Error> {
-    Ok(LevelDbBackend::open(config.store.url.as_str())?)
+/// Open a leveldb store, asking for its passphrase if it is encrypted at rest.
+pub fn leveldb(store: &StoreConfig) -> Result<SealedBackend<LevelDbBackend>, Error> {
+    let backend = LevelDbBackend::open(store.url.as_str())?;
+
+    match store.encryption {
+        Some(ref encryption) => Ok(backend.layer(SealLayer::new(crypt::unlock(encryption)?))),
+        None => SealedBackend::unsealed(backend),
+    }
+}
+
+/// Find out whether the leveldb store at `url` is encrypted at rest, from the parameters it keeps.
+pub fn leveldb_encryption(url: &str) -> Result<Option<EncryptionConfig>, Error> {
+    let backend = LevelDbBackend::open(url)?.layer(SealLayer::none());
+    Ok(backend.params().wait()?.map(EncryptionConfig::from))
+}
+
+/// Any encryption of a remote store is handled by the server, so there is nothing to unlock here.
+pub fn ssh(store: &StoreConfig) -> Result<SshBackend, Error> {
+    SshBackend::open(store.url.as_str())
 }
 
 #[macro_export]
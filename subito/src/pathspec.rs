@@ -0,0 +1,96 @@
+//! Exclude pathspecs, which leave paths out of a command which would otherwise cover them.
+//!
+//! A path given as `:!PATTERN` (or `:^PATTERN`) excludes every path matching the glob `PATTERN`,
+//! and everything beneath it. As with `.gitattributes`, a pattern without a slash matches at any
+//! depth, and one with a slash is relative to the root of the workspace. Patterns are matched
+//! against object paths, which are always `/`-separated, so they mean the same on every platform.
+
+use std::path::PathBuf;
+
+use attaca::path::ObjectPath;
+use failure::*;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+const EXCLUDE_PREFIXES: &'static [&'static str] = &[":!", ":^"];
+
+/// A set of exclude patterns. The default set excludes nothing.
+#[derive(Debug, Clone)]
+pub struct Excludes {
+    set: GlobSet,
+    len: usize,
+}
+
+impl Default for Excludes {
+    fn default() -> Self {
+        Self {
+            set: GlobSetBuilder::new()
+                .build()
+                .expect("An empty glob set always builds"),
+            len: 0,
+        }
+    }
+}
+
+impl Excludes {
+    /// Separate exclude pathspecs from plain paths, returning the plain paths and the excludes.
+    pub fn split(paths: Vec<PathBuf>) -> Result<(Vec<PathBuf>, Self), Error> {
+        let mut plain = Vec::new();
+        let mut patterns = Vec::new();
+
+        for path in paths {
+            let pattern = path.to_str().and_then(|s| {
+                EXCLUDE_PREFIXES
+                    .iter()
+                    .find(|prefix| s.starts_with(*prefix))
+                    .map(|prefix| s[prefix.len()..].to_owned())
+            });
+
+            match pattern {
+                Some(pattern) => patterns.push(pattern),
+                None => plain.push(path),
+            }
+        }
+
+        Ok((plain, Self::new(&patterns)?))
+    }
+
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self, Error> {
+        let mut builder = GlobSetBuilder::new();
+
+        for pattern in patterns {
+            let pattern = pattern.as_ref().trim_right_matches('/');
+            ensure!(!pattern.is_empty(), "Empty exclude pathspec");
+
+            let glob = if pattern.starts_with('/') {
+                pattern[1..].to_owned()
+            } else if pattern.contains('/') {
+                pattern.to_owned()
+            } else {
+                format!("**/{}", pattern)
+            };
+
+            for glob in &[glob.clone(), format!("{}/**", glob)] {
+                builder.add(
+                    GlobBuilder::new(glob)
+                        .literal_separator(true)
+                        .build()
+                        .with_context(|_| format!("Bad exclude pathspec {}", pattern))?,
+                );
+            }
+        }
+
+        Ok(Self {
+            set: builder.build()?,
+            len: patterns.len(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether `path`, relative to the root of the workspace, is excluded.
+    pub fn is_excluded(&self, path: &ObjectPath) -> bool {
+        !self.is_empty() && self.set.is_match(path.to_path())
+    }
+}
@@ -15,7 +15,7 @@ use syntax::Name;
 /// Create a new branch using HEAD.
 pub fn create<B: Backend>(this: &mut Repository<B>, name: Name) -> FutureUnit {
     let blocking = async_block! {
-        let branches = await!(this.store.load_branches())?;
+        let branches = await!(refs::load_heads(&this.store))?;
         ensure!(!branches.contains_key(name.as_str()), "branch already exists");
         let state = this.get_state()?;
         let maybe_commit_ref = match state.head {
@@ -26,7 +26,7 @@ pub fn create<B: Backend>(this: &mut Repository<B>, name: Name) -> FutureUnit {
         let commit_ref = maybe_commit_ref.ok_or_else(|| format_err!("no prior commits"))?;
         let mut new_branches = branches.clone();
         new_branches.insert((*name).to_owned(), commit_ref);
-        await!(this.store.swap_branches(branches, new_branches))?;
+        await!(refs::swap_heads(&this.store, branches, new_branches))?;
         Ok(())
     };
 
@@ -36,11 +36,11 @@ pub fn create<B: Backend>(this: &mut Repository<B>, name: Name) -> FutureUnit {
 /// Delete a branch.
 pub fn delete<B: Backend>(this: &mut Repository<B>, name: Name) -> FutureUnit {
     let blocking = async_block! {
-        let branches = await!(this.store.load_branches())?;
+        let branches = await!(refs::load_heads(&this.store))?;
         ensure!(branches.contains_key(name.as_str()), "no such branch {}", name);
         let mut new_branches = branches.clone();
         new_branches.remove(name.as_str());
-        await!(this.store.swap_branches(branches, new_branches))?;
+        await!(refs::swap_heads(&this.store, branches, new_branches))?;
         Ok(())
     };
 
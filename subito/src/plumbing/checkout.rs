@@ -1,7 +1,7 @@
-use std::{usize, collections::BTreeSet, fs::{self, File, OpenOptions}, path::Path};
+use std::{usize, collections::BTreeSet, fs::{self, File, OpenOptions}, io::Write, path::Path,
+          sync::Arc};
 
-use attaca::{hierarchy::Hierarchy, object::{Large, Object, ObjectRef, TreeRef}, path::ObjectPath,
-             store::prelude::*};
+use attaca::{object::{Large, Object, ObjectRef, TreeRef}, path::ObjectPath, store::prelude::*};
 use failure::*;
 use futures::{stream, prelude::*};
 use memmap::MmapMut;
@@ -9,11 +9,59 @@ use ignore::WalkBuilder;
 
 use super::*;
 use Repository;
+use attributes::{self, Attributes, Eol};
 use cache::{Certainty, Status};
+use candidate::IGNORE_FILE;
+use case;
+use diff;
 use state::State;
+use syntax::Name;
+use xattrs;
 
 const LARGE_CHILD_LOOKAHEAD_BUFFER_SIZE: usize = 32;
 
+/// What checking out needs to know about the repository, read once per checkout rather than again
+/// for every path.
+#[derive(Debug, Clone)]
+struct CheckoutSettings {
+    attributes: Arc<Attributes>,
+
+    /// Whether paths which differ only in case are refused. See the `case` module.
+    refuses_case_collisions: bool,
+
+    /// Whether extended attributes are restored. See the `xattrs` module.
+    xattrs: bool,
+}
+
+impl CheckoutSettings {
+    fn load<B: Backend>(this: &Repository<B>) -> Result<Self, Error> {
+        Ok(Self {
+            attributes: Arc::new(Attributes::load(&this.path)?),
+            refuses_case_collisions: this.refuses_case_collisions()?,
+            xattrs: this.get_config()?.xattrs,
+        })
+    }
+}
+
+/// Refuse to check out the tree at `path` if the settings say so and paths anywhere in it differ
+/// only in case. This is done before anything is written, so that a refused checkout leaves the
+/// local workspace as it was.
+#[async]
+fn refuse_case_collisions<B: Backend>(
+    tree_ref: TreeRef<Handle<B>>,
+    path: ObjectPath,
+    settings: CheckoutSettings,
+) -> Result<(), Error> {
+    if settings.refuses_case_collisions {
+        let collisions = await!(case::deep_tree_collisions(tree_ref, path))?;
+        if !collisions.is_empty() {
+            return Err(case::collision_error(&collisions));
+        }
+    }
+
+    Ok(())
+}
+
 #[async]
 fn checkout_data_from_large_with_previous<B: Backend>(
     new_large: Large<Handle<B>>,
@@ -136,6 +184,20 @@ pub fn checkout_path_from_data<B: Backend>(
     this: &mut Repository<B>,
     data_ref: ObjectRef<Handle<B>>,
     path: ObjectPath,
+) -> FutureUnit {
+    let blocking = async_block! {
+        let settings = CheckoutSettings::load(this)?;
+        await!(checkout_path_from_data_with(this, data_ref, path, settings))
+    };
+
+    Box::new(blocking)
+}
+
+fn checkout_path_from_data_with<B: Backend>(
+    this: &mut Repository<B>,
+    data_ref: ObjectRef<Handle<B>>,
+    path: ObjectPath,
+    settings: CheckoutSettings,
 ) -> FutureUnit {
     let blocking = async_block! {
         let maybe_previous_ref = match this.cache.status(&path)? {
@@ -166,7 +228,7 @@ pub fn checkout_path_from_data<B: Backend>(
         };
 
         let absolute_path = path.with_base(&*this.path);
-        let file = if absolute_path.exists() {
+        let mut file = if absolute_path.exists() {
             let metadata = absolute_path.symlink_metadata()?;
             let file_type = metadata.file_type();
 
@@ -193,6 +255,19 @@ pub fn checkout_path_from_data<B: Backend>(
                 .open(&absolute_path)?
         };
 
+        // Text files are stored with LF line endings; if they should be checked out with CRLF, they
+        // can't be mapped in directly.
+        let path_attributes = settings.attributes.get(&path);
+        if path_attributes.may_be_text() && path_attributes.checkout_eol() == Eol::Crlf {
+            let data = await!(diff::read_blob(data_ref.clone()))?
+                .expect("data_ref is a Small or Large ref");
+            if path_attributes.is_text(&data) {
+                file.set_len(0)?;
+                file.write_all(&attributes::denormalize(&data, Eol::Crlf))?;
+                return Ok(());
+            }
+        }
+
         await!(checkout_file_from_data(this, data_ref, maybe_previous_ref, file))
     };
 
@@ -203,6 +278,21 @@ pub fn checkout_path_from_tree<B: Backend>(
     this: &mut Repository<B>,
     tree_ref: TreeRef<Handle<B>>,
     path: ObjectPath,
+) -> FutureUnit {
+    let blocking = async_block! {
+        let settings = CheckoutSettings::load(this)?;
+        await!(refuse_case_collisions(tree_ref.clone(), path.clone(), settings.clone()))?;
+        await!(checkout_path_from_tree_with(this, tree_ref, path, settings))
+    };
+
+    Box::new(blocking)
+}
+
+fn checkout_path_from_tree_with<B: Backend>(
+    this: &mut Repository<B>,
+    tree_ref: TreeRef<Handle<B>>,
+    path: ObjectPath,
+    settings: CheckoutSettings,
 ) -> FutureUnit {
     let blocking = async_block! {
         let tree = await!(tree_ref.fetch())?;
@@ -230,6 +320,7 @@ pub fn checkout_path_from_tree<B: Backend>(
         // to all WalkBuilders.
         // TODO #33
         let mut entries = WalkBuilder::new(&absolute_path)
+            .add_custom_ignore_filename(IGNORE_FILE)
             .max_depth(Some(1))
             .build()
             .map(|direntry| {
@@ -245,15 +336,27 @@ pub fn checkout_path_from_tree<B: Backend>(
                 ))
             })
             .collect::<Result<HashMap<_, _>, Error>>()?;
+
+        // On a case-insensitive filesystem, a local file whose name differs only in case from one
+        // in the tree is the same file, which is about to be overwritten rather than removed.
+        if settings.refuses_case_collisions {
+            let folded = tree.keys().map(|name| case::fold(name)).collect::<HashSet<_>>();
+            entries.retain(|name, _| !folded.contains(&case::fold(name)));
+        }
+        // The manifest of extended attributes is never written out; a local file by that name is
+        // left alone.
+        let manifest_ref = tree.get(xattrs::MANIFEST_FILE).cloned();
         entries.extend(tree.into_iter().map(|(name, objref)| (name, Some(objref))));
+        entries.remove(xattrs::MANIFEST_FILE);
 
         for (name, maybe_objref) in entries {
             match maybe_objref {
                 Some(objref) => {
-                    await!(checkout_path_from_object(
+                    await!(checkout_path_from_object_with(
                         this,
                         objref,
                         path.push_back(name),
+                        settings.clone(),
                     ))?;
                 }
                 None => {
@@ -274,6 +377,13 @@ pub fn checkout_path_from_tree<B: Backend>(
             }
         }
 
+        if let Some(manifest_ref) = manifest_ref {
+            if settings.xattrs {
+                let manifest = await!(xattrs::Manifest::fetch(manifest_ref))?;
+                xattrs::restore(&absolute_path, &manifest)?;
+            }
+        }
+
         Ok(())
     };
 
@@ -285,38 +395,180 @@ pub fn checkout_path_from_object<B: Backend>(
     this: &mut Repository<B>,
     object_ref: ObjectRef<Handle<B>>,
     path: ObjectPath,
+) -> FutureUnit {
+    let blocking = async_block! {
+        let settings = CheckoutSettings::load(this)?;
+        let maybe_tree_ref = match object_ref {
+            ObjectRef::Tree(ref tree_ref) => Some(tree_ref.clone()),
+            _ => None,
+        };
+        if let Some(tree_ref) = maybe_tree_ref {
+            await!(refuse_case_collisions(tree_ref, path.clone(), settings.clone()))?;
+        }
+        await!(checkout_path_from_object_with(this, object_ref, path, settings))
+    };
+
+    Box::new(blocking)
+}
+
+fn checkout_path_from_object_with<B: Backend>(
+    this: &mut Repository<B>,
+    object_ref: ObjectRef<Handle<B>>,
+    path: ObjectPath,
+    settings: CheckoutSettings,
 ) -> FutureUnit {
     match object_ref {
         ObjectRef::Small(_) | ObjectRef::Large(_) => {
-            checkout_path_from_data(this, object_ref, path)
+            checkout_path_from_data_with(this, object_ref, path, settings)
         }
-        ObjectRef::Tree(tree_ref) => checkout_path_from_tree(this, tree_ref, path),
+        ObjectRef::Tree(tree_ref) => checkout_path_from_tree_with(this, tree_ref, path, settings),
         ObjectRef::Commit(_) => unreachable!(),
     }
 }
 
-/// Selectively checkout paths from a tree.
-pub fn subpaths_from_tree<'r, I, B: Backend>(
-    this: &'r mut Repository<B>,
-    tree: TreeRef<Handle<B>>,
-    base_path: ObjectPath,
-    paths: I,
-) -> FutureUnit
-where
-    I: IntoIterator<Item = ObjectPath> + 'r,
-{
+/// Check out several objects, each to its own path. A tree is overlaid on whatever is at its path
+/// as with `overlay_from_tree`, so nothing the tree lacks is removed; anything else is checked out
+/// as with `checkout_path_from_object`.
+pub fn checkout_paths_from_objects<B: Backend>(
+    this: &mut Repository<B>,
+    objects: Vec<(ObjectPath, ObjectRef<Handle<B>>)>,
+) -> FutureUnit {
     let blocking = async_block! {
-        let subtree = Hierarchy::from(tree);
+        let settings = CheckoutSettings::load(this)?;
+        let trees = objects
+            .iter()
+            .filter_map(|&(ref path, ref object_ref)| match *object_ref {
+                ObjectRef::Tree(ref tree_ref) => Some((tree_ref.clone(), path.clone())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for (tree_ref, path) in trees {
+            await!(refuse_case_collisions(tree_ref, path, settings.clone()))?;
+        }
 
-        for object_path in paths {
-            let maybe_object_ref = await!(subtree.get(object_path.clone()))?;
-            match maybe_object_ref {
-                Some(object_ref) => await!(checkout_path_from_object(
+        for (path, object_ref) in objects {
+            match object_ref {
+                ObjectRef::Tree(tree_ref) => {
+                    await!(overlay_from_tree_with(this, tree_ref, path, settings.clone()))?
+                }
+                object_ref => await!(checkout_path_from_object_with(
                     this,
                     object_ref,
-                    &base_path + object_path,
+                    path,
+                    settings.clone(),
+                ))?,
+            }
+        }
+
+        Ok(())
+    };
+
+    Box::new(blocking)
+}
+
+/// Collect the paths in a tree which would clobber something already present in the local
+/// workspace if the tree were checked out with `overlay_from_tree`.
+pub fn overlay_conflicts<'r, B: Backend>(
+    this: &'r Repository<B>,
+    tree_ref: TreeRef<Handle<B>>,
+    path: ObjectPath,
+) -> Box<Future<Item = Vec<ObjectPath>, Error = Error> + 'r> {
+    let blocking = async_block! {
+        let tree = await!(tree_ref.fetch())?;
+        let mut conflicts = Vec::new();
+
+        for (name, objref) in tree {
+            if name == xattrs::MANIFEST_FILE {
+                continue;
+            }
+
+            let child_path = path.push_back(name);
+            let absolute_path = child_path.with_base(&*this.path);
+
+            let metadata = match absolute_path.symlink_metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+
+            match objref {
+                ObjectRef::Tree(subtree_ref) if metadata.is_dir() => {
+                    conflicts.extend(await!(overlay_conflicts(this, subtree_ref, child_path))?);
+                }
+                _ => conflicts.push(child_path),
+            }
+        }
+
+        Ok(conflicts)
+    };
+
+    Box::new(blocking)
+}
+
+/// Checkout a tree on top of the local workspace. Unlike `checkout_path_from_tree`, nothing
+/// already present in the workspace is removed unless the tree has something at the same path,
+/// in which case it is overwritten.
+pub fn overlay_from_tree<B: Backend>(
+    this: &mut Repository<B>,
+    tree_ref: TreeRef<Handle<B>>,
+    path: ObjectPath,
+) -> FutureUnit {
+    let blocking = async_block! {
+        let settings = CheckoutSettings::load(this)?;
+        await!(refuse_case_collisions(tree_ref.clone(), path.clone(), settings.clone()))?;
+        await!(overlay_from_tree_with(this, tree_ref, path, settings))
+    };
+
+    Box::new(blocking)
+}
+
+fn overlay_from_tree_with<B: Backend>(
+    this: &mut Repository<B>,
+    tree_ref: TreeRef<Handle<B>>,
+    path: ObjectPath,
+    settings: CheckoutSettings,
+) -> FutureUnit {
+    let blocking = async_block! {
+        let tree = await!(tree_ref.fetch())?;
+
+        let absolute_path = path.with_base(&*this.path);
+        if absolute_path.exists() {
+            let metadata = absolute_path.symlink_metadata()?;
+
+            if !metadata.is_dir() {
+                fs::remove_file(&absolute_path)?;
+                fs::create_dir(&absolute_path)?;
+            }
+        } else {
+            fs::create_dir(&absolute_path)?;
+        }
+
+        let manifest_ref = tree.get(xattrs::MANIFEST_FILE).cloned();
+        for (name, objref) in tree {
+            if name == xattrs::MANIFEST_FILE {
+                continue;
+            }
+
+            match objref {
+                ObjectRef::Tree(subtree_ref) => await!(overlay_from_tree_with(
+                    this,
+                    subtree_ref,
+                    path.push_back(name),
+                    settings.clone(),
+                ))?,
+                ObjectRef::Small(_) | ObjectRef::Large(_) => await!(checkout_path_from_data_with(
+                    this,
+                    objref,
+                    path.push_back(name),
+                    settings.clone(),
                 ))?,
-                None => bail!("No such object in the previous commit!"),
+                ObjectRef::Commit(_) => unreachable!(),
+            }
+        }
+
+        if let Some(manifest_ref) = manifest_ref {
+            if settings.xattrs {
+                let manifest = await!(xattrs::Manifest::fetch(manifest_ref))?;
+                xattrs::restore(&absolute_path, &manifest)?;
             }
         }
 
@@ -331,10 +583,17 @@ pub fn by_ref<B: Backend>(this: &mut Repository<B>, refr: Ref) -> FutureUnit {
         match refr {
             Ref::Head => Ok(()),
             Ref::Local(name) => {
-                if let Some(commit_ref) =
-                    await!(resolve_local_opt(this, name.clone()))?
-                {
-                    let tree_ref = await!(commit_ref.fetch())?.as_subtree().clone();
+                let maybe_branch = await!(resolve_local_opt(this, name.clone()))?;
+                // A name which isn't a branch but is a tag detaches HEAD at the tag, rather than
+                // switching to a new branch of that name.
+                if maybe_branch.is_none() {
+                    if let Some(commit_ref) = await!(resolve_tag_opt(this, name.clone()))? {
+                        return await!(detach_at(this, commit_ref));
+                    }
+                }
+
+                if let Some(commit_ref) = maybe_branch {
+                    let tree_ref = await!(this.tree_of(&commit_ref))?;
                     await!(checkout_path_from_tree(
                         this,
                         tree_ref,
@@ -350,23 +609,103 @@ pub fn by_ref<B: Backend>(this: &mut Repository<B>, refr: Ref) -> FutureUnit {
 
                 Ok(())
             }
+            Ref::Tag(tag) => {
+                let commit_ref = await!(resolve_tag(this, tag))?;
+                await!(detach_at(this, commit_ref))
+            }
             Ref::Remote(remote_name, branch_name) => {
                 let commit_ref = await!(resolve_remote(this, remote_name, branch_name))?;
-                let tree_ref = await!(commit_ref.fetch())?.as_subtree().clone();
-                await!(checkout_path_from_tree(
-                    this,
-                    tree_ref,
-                    ObjectPath::new()
-                ))?;
-                let state = this.get_state()?;
-                this.set_state(&State {
-                    head: Head::Detached(commit_ref),
-                    ..state
-                })?;
+                await!(detach_at(this, commit_ref))
+            }
+        }
+    };
 
-                Ok(())
+    Box::new(blocking)
+}
+
+/// Check out a commit, detaching HEAD at it.
+fn detach_at<B: Backend>(
+    this: &mut Repository<B>,
+    commit_ref: CommitRef<Handle<B>>,
+) -> FutureUnit {
+    let blocking = async_block! {
+        let tree_ref = await!(this.tree_of(&commit_ref))?;
+        await!(checkout_path_from_tree(this, tree_ref, ObjectPath::new()))?;
+        let state = this.get_state()?;
+        this.set_state(&State {
+            head: Head::Detached(commit_ref),
+            ..state
+        })?;
+
+        Ok(())
+    };
+
+    Box::new(blocking)
+}
+
+/// Create a branch at `start` and check it out, updating HEAD to point to it. If the branch
+/// already exists, this fails unless `force` is set, in which case the branch is moved to `start`.
+/// Should the checkout fail, the branch is restored to how it was before.
+///
+/// Branching at HEAD leaves both workspaces alone, so any changes carry over to the new branch.
+/// Branching anywhere else fails if there are changes, staged or not, which would be overwritten.
+pub fn new_branch<B: Backend>(
+    this: &mut Repository<B>,
+    name: Name,
+    start: Ref,
+    force: bool,
+) -> FutureUnit {
+    let blocking = async_block! {
+        let commit_ref = await!(resolve(this, start))?;
+        let head = await!(resolve_head_opt(this))?;
+        let checkout_tree = if head.as_ref() == Some(&commit_ref) {
+            None
+        } else {
+            let head_tree = match head {
+                Some(head_ref) => Some(await!(this.tree_of(&head_ref))?),
+                None => None,
+            };
+            let candidate = this.get_state()?.candidate;
+            ensure!(
+                candidate.is_none() || candidate == head_tree,
+                "The virtual workspace has uncommitted changes! Commit or stash them before \
+                 checking out a new branch."
+            );
+            await!(this.ensure_unchanged_locally(
+                vec![ObjectPath::new()],
+                "checking out the new branch",
+            ))?;
+
+            Some(await!(this.tree_of(&commit_ref))?)
+        };
+
+        let branches = await!(load_branches(this))?;
+        ensure!(
+            force || !branches.contains_key(&name),
+            "branch {} already exists (use -B to move it)",
+            name
+        );
+        let mut new_branches = branches.clone();
+        new_branches.insert(name.clone(), commit_ref);
+        await!(swap_branches(this, branches.clone(), new_branches.clone()))?;
+
+        if let Some(tree_ref) = checkout_tree {
+            let checkout = await!(checkout_path_from_tree(this, tree_ref, ObjectPath::new()));
+            if let Err(error) = checkout {
+                if let Err(restore_error) = await!(swap_branches(this, new_branches, branches)) {
+                    bail!(
+                        "{} (and branch {} could not be restored: {})",
+                        error,
+                        name,
+                        restore_error
+                    );
+                }
+
+                return Err(error);
             }
         }
+
+        await!(set_head(this, Head::Branch(name)))
     };
 
     Box::new(blocking)
@@ -0,0 +1,112 @@
+//! Making commits from explicit inputs.
+//!
+//! `commit_tree` builds a commit out of a tree, parents, message, author and timestamp given by the
+//! caller, sends it to the store and returns it. Nothing else is touched: not the HEAD, not any
+//! branch and not the virtual workspace. Commands such as `commit`, `import` and `stash` decide
+//! what goes into a commit and what to do with it afterwards.
+
+use std::collections::BTreeMap;
+
+use attaca::{object::{Commit, CommitAuthor, CommitBuilder, CommitRef, TreeRef}, store::prelude::*};
+use chrono::{DateTime, FixedOffset};
+use failure::*;
+use futures::prelude::*;
+
+use Repository;
+
+pub type FutureCommit<'r, B> =
+    Box<Future<Item = (CommitRef<Handle<B>>, Commit<Handle<B>>), Error = Error> + 'r>;
+
+/// Everything that goes into a commit.
+#[derive(Debug, Clone)]
+pub struct CommitTree<H> {
+    pub subtree: TreeRef<H>,
+
+    /// Duplicate parents are dropped, and the rest are put in canonical order when the commit is
+    /// sent.
+    pub parents: Vec<CommitRef<H>>,
+
+    pub message: Option<String>,
+
+    /// If `None`, the commit has no author.
+    pub author: Option<CommitAuthor>,
+
+    /// The author timestamp. If `None`, the current time is used.
+    pub timestamp: Option<DateTime<FixedOffset>>,
+
+    /// If `None`, the commit has no committer timestamp of its own, and was committed at its
+    /// author timestamp.
+    pub committer_timestamp: Option<DateTime<FixedOffset>>,
+
+    pub headers: BTreeMap<String, String>,
+}
+
+impl<H> CommitTree<H> {
+    /// Inputs for a commit of `subtree` with the given parents and nothing else.
+    pub fn new<I>(subtree: TreeRef<H>, parents: I) -> Self
+    where
+        I: IntoIterator<Item = CommitRef<H>>,
+    {
+        Self {
+            subtree,
+            parents: parents.into_iter().collect(),
+            message: None,
+            author: None,
+            timestamp: None,
+            committer_timestamp: None,
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+/// Build a commit from explicit inputs without sending it anywhere.
+pub fn build_commit<H>(inputs: CommitTree<H>) -> Result<Commit<H>, Error> {
+    let CommitTree {
+        subtree,
+        parents,
+        message,
+        author,
+        timestamp,
+        committer_timestamp,
+        headers,
+    } = inputs;
+
+    let mut builder = CommitBuilder::new();
+    builder.parents(parents).subtree(subtree);
+
+    if let Some(message) = message {
+        builder.message(message);
+    }
+
+    if let Some(author) = author {
+        builder.author(author);
+    }
+
+    if let Some(timestamp) = timestamp {
+        builder.timestamp(timestamp);
+    }
+
+    builder.committer_timestamp(committer_timestamp);
+
+    for (key, value) in headers {
+        builder.header(key, value);
+    }
+
+    builder.into_commit()
+}
+
+/// Build a commit from explicit inputs and send it to the store, returning the reference to it
+/// along with the commit itself. See the module documentation.
+pub fn commit_tree<'r, B: Backend>(
+    this: &'r Repository<B>,
+    inputs: CommitTree<Handle<B>>,
+) -> FutureCommit<'r, B> {
+    let store = this.store.clone();
+    let blocking = build_commit(inputs).into_future().and_then(move |commit| {
+        commit
+            .send(&store)
+            .map(move |commit_ref| (commit_ref, commit))
+    });
+
+    Box::new(blocking)
+}
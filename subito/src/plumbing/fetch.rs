@@ -1,49 +1,178 @@
+use std::fmt;
+
+use attaca::{digest::Sha3Digest, limit::RateLimiter};
+
 use super::*;
+use bundle;
+use open;
 
 macro_rules! dispatch_fetch {
-    (@inner $this:expr, $remote:expr, $($lcname:ident, $ccname:ident : $type:ty),*) => {
+    (@inner $this:expr, $remote:expr, $limiter:expr, $($lcname:ident, $ccname:ident : $type:ty),*) => {
         {
             match $remote.kind {
-                $(StoreKind::$ccname => await!(backend($this, <$type>::open($remote.url.as_str())?))?,)*
+                $(StoreKind::$ccname => await!(backend(
+                    $this,
+                    open::$lcname(&$remote)?,
+                    $limiter,
+                ))?,)*
+                StoreKind::Bundle => await!(store(
+                    $this,
+                    bundle::open_url(&$remote.url)?,
+                    $limiter,
+                ))?,
+                StoreKind::Memory => bail!("An in-memory store cannot be a remote."),
             }
         }
     };
-    ($this:expr, $remote:expr) => {
-        all_backends!(dispatch_fetch!(@inner $this, $remote))
+    ($this:expr, $remote:expr, $limiter:expr) => {
+        all_backends!(dispatch_fetch!(@inner $this, $remote, $limiter))
     };
 }
 
-pub fn remote<B: Backend>(this: &mut Repository<B>, remote_name: Name) -> FutureBranches<B> {
+/// What a fetch brought in from a remote.
+pub struct FetchReport<B: Backend> {
+    /// Every branch of the remote, now recorded as its remote refs.
+    pub branches: Branches<B>,
+
+    /// Branches which are new or have moved since the remote was last fetched.
+    pub updated: Vec<Name>,
+
+    /// Branches which the remote did not have when it was last fetched. These are also in
+    /// `updated`.
+    pub created: Vec<Name>,
+
+    /// Branches which the remote had when it was last fetched, but has no longer. Their
+    /// remote-tracking branches have been removed.
+    pub pruned: Vec<Name>,
+
+    /// How many objects were copied into the local store, or `None` if they were transferred as a
+    /// pack, in which case the count isn't known.
+    pub copied: Option<usize>,
+}
+
+impl<B: Backend> FetchReport<B> {
+    pub fn is_up_to_date(&self) -> bool {
+        self.updated.is_empty() && self.pruned.is_empty()
+    }
+}
+
+impl<B: Backend> fmt::Debug for FetchReport<B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FetchReport")
+            .field("branches", &self.branches.keys().collect::<Vec<_>>())
+            .field("updated", &self.updated)
+            .field("created", &self.created)
+            .field("pruned", &self.pruned)
+            .field("copied", &self.copied)
+            .finish()
+    }
+}
+
+/// Fetch every branch of a remote, throttled by `limiter`, and record them as its remote refs.
+/// These replace the remote refs recorded before, so remote-tracking branches which the remote has
+/// since deleted are pruned.
+pub fn remote<'r, B: Backend>(
+    this: &'r mut Repository<B>,
+    remote_name: Name,
+    limiter: RateLimiter,
+) -> Box<Future<Item = FetchReport<B>, Error = Error> + 'r> {
     let blocking = async_block! {
-        let new_branches = {
+        let (new_branches, copied) = {
             let config = this.get_config()?;
             let remote = config.remotes[remote_name.as_str()].clone();
-            dispatch_fetch!(this, remote)
+            dispatch_fetch!(this, remote, limiter)
         };
         let mut state = this.get_state()?;
-        state.remote_refs.insert(remote_name, new_branches.clone());
+        let previous = state.remote_refs.insert(remote_name, new_branches.clone());
         this.set_state(&state)?;
-        Ok(new_branches)
+
+        let mut updated = new_branches
+            .iter()
+            .filter(|&(name, commit_ref)| {
+                previous.as_ref().and_then(|branches| branches.get(name)) != Some(commit_ref)
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        updated.sort();
+        let created = updated
+            .iter()
+            .filter(|&name| {
+                !previous.as_ref().map_or(false, |branches| branches.contains_key(name))
+            })
+            .cloned()
+            .collect();
+        let mut pruned = previous
+            .map(|branches| {
+                branches
+                    .into_iter()
+                    .map(|(name, _)| name)
+                    .filter(|name| !new_branches.contains_key(name))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        pruned.sort();
+
+        Ok(FetchReport {
+            branches: new_branches,
+            updated,
+            created,
+            pruned,
+            copied,
+        })
     };
 
     Box::new(blocking)
 }
 
+/// Copy every branch of a remote store into the local store. Objects are negotiated one by one,
+/// so that only those which the local store is missing are copied, unless the local store has no
+/// branches yet; then it's unlikely to share much with the remote, and everything is transferred
+/// as a pack if the remote can produce one. Branches whose tips are already in the local store
+/// cost only a digest lookup.
 pub fn backend<B: Backend, C: Backend>(
     this: &mut Repository<B>,
     remote_backend: C,
-) -> FutureBranches<B> {
+    limiter: RateLimiter,
+) -> Box<Future<Item = (Branches<B>, Option<usize>), Error = Error>> {
+    store(this, Store::new(remote_backend), limiter)
+}
+
+/// As `backend`, for a remote store which is already open.
+pub fn store<B: Backend, C: Backend>(
+    this: &mut Repository<B>,
+    remote: Store<C>,
+    limiter: RateLimiter,
+) -> Box<Future<Item = (Branches<B>, Option<usize>), Error = Error>> {
+    let local = this.store.clone();
     let blocking = async_block! {
-        let remote = Store::new(remote_backend);
-        let branches = await!(remote.load_branches())?;
+        let branches = await!(refs::load_heads(&remote))?;
+        let incremental = !await!(refs::load_heads(&local))?.is_empty();
 
         let mut new_branches = HashMap::new();
+        let mut copied = Some(0);
         for (branch_name, commit_handle) in branches {
-            let commit_ref = CommitRef::new(await!(store::copy(commit_handle, this.store.clone()))?);
+            let commit_handle = if incremental {
+                let (commit_handle, count) =
+                    await!(store::copy_missing_with_limit::<Sha3Digest, _, _>(
+                        commit_handle,
+                        local.clone(),
+                        limiter.clone(),
+                    ))?;
+                copied = copied.map(|total| total + count);
+                commit_handle
+            } else {
+                copied = None;
+                await!(store::transfer_with_limit::<Sha3Digest, _, _>(
+                    commit_handle,
+                    local.clone(),
+                    limiter.clone(),
+                ))?
+            };
+            let commit_ref = CommitRef::new(commit_handle);
             new_branches.insert(Name::from_string(branch_name)?, commit_ref);
         }
 
-        Ok(new_branches)
+        Ok((new_branches, copied))
     };
 
     Box::new(blocking)
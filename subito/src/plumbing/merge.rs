@@ -0,0 +1,353 @@
+use std::collections::{BTreeSet, VecDeque};
+
+use attaca::{object::{TreeBuilder, TreeRef}, path::ObjectPath};
+
+use super::*;
+use log;
+use state::MergeState;
+use xattrs::{self, Manifest};
+
+/// The result of beginning a merge.
+#[derive(Debug, Clone)]
+pub enum MergeOutcome {
+    /// The commit being merged is already an ancestor of HEAD; nothing was changed.
+    UpToDate,
+
+    /// HEAD was an ancestor of the commit being merged and has been moved forward to it.
+    FastForward,
+
+    /// Both sides have diverged. The merged tree is now the virtual workspace and the merge is
+    /// recorded in the repository state until it is committed or aborted. The paths listed are
+    /// those which conflict; the virtual workspace keeps our side of each of them.
+    Merged { conflicts: Vec<ObjectPath> },
+}
+
+/// Find the nearest common ancestor of two commits: a commit reachable from both which isn't an
+/// ancestor of any other such commit. If one commit is an ancestor of the other, that commit is
+/// the base. Where there are several candidates (after criss-cross merges), the one the fewest
+/// steps from `theirs` is taken. Histories with nothing in common have no base.
+pub fn merge_base<'r, B: Backend>(
+    ours: CommitRef<Handle<B>>,
+    theirs: CommitRef<Handle<B>>,
+) -> FutureOptionCommitRef<'r, B> {
+    let blocking = async_block! {
+        let ancestors = await!(log::walk(vec![ours], HashSet::new(), false).collect())?
+            .into_iter()
+            .map(|(commit_ref, _)| commit_ref)
+            .collect::<HashSet<_>>();
+        let parents = await!(log::walk(vec![theirs.clone()], HashSet::new(), false).collect())?
+            .into_iter()
+            .map(|(commit_ref, commit)| (commit_ref, commit.as_parents().to_vec()))
+            .collect::<HashMap<_, _>>();
+
+        // Everything beneath a common ancestor is also common, so it is enough to rule out the
+        // parents of each common ancestor.
+        let mut redundant = HashSet::new();
+        for (commit_ref, commit_parents) in &parents {
+            if ancestors.contains(commit_ref) {
+                redundant.extend(commit_parents.iter().cloned());
+            }
+        }
+
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(theirs);
+        while let Some(commit_ref) = queue.pop_front() {
+            if ancestors.contains(&commit_ref) && !redundant.contains(&commit_ref) {
+                return Ok(Some(commit_ref));
+            }
+
+            if visited.insert(commit_ref.clone()) {
+                queue.extend(parents[&commit_ref].iter().cloned());
+            }
+        }
+
+        Ok(None)
+    };
+
+    Box::new(blocking)
+}
+
+/// Count the commits reachable from `ours` but not from `theirs`, and those reachable from `theirs`
+/// but not from `ours`.
+pub fn ahead_behind<'r, B: Backend>(
+    ours: CommitRef<Handle<B>>,
+    theirs: CommitRef<Handle<B>>,
+) -> Box<Future<Item = (usize, usize), Error = Error> + 'r> {
+    let blocking = async_block! {
+        let mut reachable = [HashSet::new(), HashSet::new()];
+        for (i, tip) in vec![ours, theirs].into_iter().enumerate() {
+            let mut stack = vec![tip];
+            while let Some(commit_ref) = stack.pop() {
+                if reachable[i].insert(commit_ref.clone()) {
+                    let commit = await!(commit_ref.fetch())?;
+                    stack.extend(commit.as_parents().iter().cloned());
+                }
+            }
+        }
+
+        let ahead = reachable[0].difference(&reachable[1]).count();
+        let behind = reachable[1].difference(&reachable[0]).count();
+        Ok((ahead, behind))
+    };
+
+    Box::new(blocking)
+}
+
+/// Fetch a manifest of extended attributes, or an empty one if there is none.
+#[async]
+fn manifest_or_default<B: Backend>(
+    maybe_ref: Option<ObjectRef<Handle<B>>>,
+) -> Result<Manifest, Error> {
+    match maybe_ref {
+        Some(object_ref) => await!(Manifest::fetch(object_ref)),
+        None => Ok(Manifest::new()),
+    }
+}
+
+/// Three-way merge manifests of extended attributes with `Manifest::merge`. Returns the merged
+/// manifest as it is to be kept in the merged tree, or `None` if it is empty.
+#[async]
+fn manifests<B: Backend>(
+    store: Store<B>,
+    base: Option<ObjectRef<Handle<B>>>,
+    ours: Option<ObjectRef<Handle<B>>>,
+    theirs: Option<ObjectRef<Handle<B>>>,
+) -> Result<Option<ObjectRef<Handle<B>>>, Error> {
+    let base = await!(manifest_or_default(base))?;
+    let ours = await!(manifest_or_default(ours))?;
+    let theirs = await!(manifest_or_default(theirs))?;
+    let merged = Manifest::merge(&base, &ours, &theirs);
+    if merged.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(await!(merged.send(store))?))
+    }
+}
+
+/// Three-way merge two trees against their common base. Returns the merged tree along with the
+/// paths which were changed differently on both sides; for those, the merged tree keeps `ours`.
+/// Manifests of extended attributes are never among them, since they are merged entry by entry.
+pub fn trees<'r, B: Backend>(
+    store: Store<B>,
+    base: Option<TreeRef<Handle<B>>>,
+    ours: TreeRef<Handle<B>>,
+    theirs: TreeRef<Handle<B>>,
+    path: ObjectPath,
+) -> Box<Future<Item = (TreeRef<Handle<B>>, Vec<ObjectPath>), Error = Error> + 'r> {
+    let blocking = async_block! {
+        if ours == theirs || base.as_ref() == Some(&theirs) {
+            return Ok((ours, Vec::new()));
+        } else if base.as_ref() == Some(&ours) {
+            return Ok((theirs, Vec::new()));
+        }
+
+        let base_tree = match base {
+            Some(base_ref) => Some(await!(base_ref.fetch())?),
+            None => None,
+        };
+        let ours_tree = await!(ours.fetch())?;
+        let theirs_tree = await!(theirs.fetch())?;
+
+        let names = base_tree
+            .iter()
+            .flat_map(|tree| tree.keys())
+            .chain(ours_tree.keys())
+            .chain(theirs_tree.keys())
+            .cloned()
+            .collect::<BTreeSet<_>>();
+
+        let mut builder = TreeBuilder::new();
+        let mut conflicts = Vec::new();
+
+        for name in names {
+            let base_entry = base_tree.as_ref().and_then(|tree| tree.get(&name).cloned());
+            let ours_entry = ours_tree.get(&name).cloned();
+            let theirs_entry = theirs_tree.get(&name).cloned();
+            let child_path = path.push_back(name.clone());
+
+            let merged = if ours_entry == theirs_entry || base_entry == theirs_entry {
+                ours_entry
+            } else if base_entry == ours_entry {
+                theirs_entry
+            } else if name == xattrs::MANIFEST_FILE {
+                await!(manifests(store.clone(), base_entry, ours_entry, theirs_entry))?
+            } else {
+                match (base_entry, ours_entry, theirs_entry) {
+                    (base_entry, Some(ObjectRef::Tree(ours_ref)), Some(ObjectRef::Tree(theirs_ref))) => {
+                        let base_ref = match base_entry {
+                            Some(ObjectRef::Tree(base_ref)) => Some(base_ref),
+                            _ => None,
+                        };
+                        let (merged_ref, sub_conflicts) = await!(trees(
+                            store.clone(),
+                            base_ref,
+                            ours_ref,
+                            theirs_ref,
+                            child_path,
+                        ))?;
+                        conflicts.extend(sub_conflicts);
+                        Some(ObjectRef::Tree(merged_ref))
+                    }
+                    (_, ours_entry, _) => {
+                        conflicts.push(child_path);
+                        ours_entry
+                    }
+                }
+            };
+
+            if let Some(objref) = merged {
+                builder.insert(name, objref);
+            }
+        }
+
+        let merged_ref = await!(builder.as_tree().send(&store))?;
+
+        Ok((merged_ref, conflicts))
+    };
+
+    Box::new(blocking)
+}
+
+/// Merge `theirs` into HEAD, updating the local workspace and the virtual workspace. A merge which
+/// is neither up to date nor a fast-forward is left in progress in the repository state. Fails
+/// without changing anything if the local workspace differs from HEAD.
+///
+/// If the two histories have no common ancestor, this fails unless `allow_unrelated` is set, in
+/// which case they are merged as if their common base were the empty tree.
+pub fn begin<'r, B: Backend>(
+    this: &'r mut Repository<B>,
+    theirs: CommitRef<Handle<B>>,
+    allow_unrelated: bool,
+) -> Box<Future<Item = MergeOutcome, Error = Error> + 'r> {
+    let blocking = async_block! {
+        let state = this.get_state()?;
+        ensure!(
+            state.merge.is_none(),
+            "A merge is already in progress! Commit it or use `merge --abort` first."
+        );
+
+        let ours = await!(resolve_head_opt(this))?
+            .ok_or_else(|| format_err!("No previous commit to merge into!"))?;
+        let ours_subtree = await!(this.tree_of(&ours))?;
+        ensure!(
+            state.candidate.as_ref().map_or(true, |cand| cand == &ours_subtree),
+            "The virtual workspace has uncommitted changes! Commit or stash them before merging."
+        );
+
+        let base = await!(merge_base(ours.clone(), theirs.clone()))?;
+        ensure!(
+            base.is_some() || allow_unrelated,
+            "Refusing to merge unrelated histories! Use --allow-unrelated-histories to merge them \
+             anyway."
+        );
+
+        if base.as_ref() == Some(&theirs) {
+            return Ok(MergeOutcome::UpToDate);
+        }
+
+        // Both a fast-forward and a merge rewrite the local workspace wholesale, so anything
+        // changed or added there since the last commit would be lost.
+        await!(this.ensure_unchanged_locally(vec![ObjectPath::new()], "merging"))?;
+
+        let theirs_subtree = await!(this.tree_of(&theirs))?;
+
+        if base.as_ref() == Some(&ours) {
+            await!(checkout::checkout_path_from_tree(
+                this,
+                theirs_subtree.clone(),
+                ObjectPath::new(),
+            ))?;
+
+            match state.head {
+                Head::Branch(ref branch) => {
+                    let branches = await!(load_branches(this))?;
+                    let mut new_branches = branches.clone();
+                    new_branches.insert(branch.clone(), theirs.clone());
+                    await!(swap_branches(this, branches, new_branches))?;
+                }
+                Head::Empty | Head::Detached(_) => {}
+            }
+
+            let head = match state.head {
+                Head::Branch(branch) => Head::Branch(branch),
+                Head::Empty | Head::Detached(_) => Head::Detached(theirs),
+            };
+            this.set_state(&State {
+                candidate: Some(theirs_subtree),
+                head,
+                ..state
+            })?;
+
+            return Ok(MergeOutcome::FastForward);
+        }
+
+        let base_subtree = await!(base.as_ref().map(|base_ref| this.tree_of(base_ref)))?;
+        let (merged_subtree, conflicts) = await!(trees(
+            this.store.clone(),
+            base_subtree,
+            ours_subtree,
+            theirs_subtree,
+            ObjectPath::new(),
+        ))?;
+
+        await!(checkout::checkout_path_from_tree(
+            this,
+            merged_subtree.clone(),
+            ObjectPath::new(),
+        ))?;
+
+        let merge = MergeState {
+            ours,
+            theirs,
+            base,
+            candidate: state.candidate.clone(),
+            conflicts: conflicts.iter().cloned().collect(),
+        };
+        this.set_state(&State {
+            candidate: Some(merged_subtree),
+            merge: Some(merge),
+            ..state
+        })?;
+
+        Ok(MergeOutcome::Merged { conflicts })
+    };
+
+    Box::new(blocking)
+}
+
+/// Abandon the merge in progress, restoring the virtual workspace and the local workspace to how
+/// they were before it began.
+pub fn abort<B: Backend>(this: &mut Repository<B>) -> FutureUnit {
+    let blocking = async_block! {
+        let state = this.get_state()?;
+        ensure!(
+            state.rebase.is_none(),
+            "The merge in progress is part of a rebase! Use `rebase --abort` instead."
+        );
+        let merge = state
+            .merge
+            .clone()
+            .ok_or_else(|| format_err!("No merge in progress!"))?;
+
+        let restored = match merge.candidate {
+            Some(ref candidate) => candidate.clone(),
+            None => await!(this.tree_of(&merge.ours))?,
+        };
+        await!(checkout::checkout_path_from_tree(
+            this,
+            restored,
+            ObjectPath::new(),
+        ))?;
+
+        this.set_state(&State {
+            candidate: merge.candidate,
+            merge: None,
+            ..state
+        })?;
+
+        Ok(())
+    };
+
+    Box::new(blocking)
+}
@@ -1,18 +1,28 @@
 pub mod branch;
 pub mod checkout;
+pub mod commit;
 pub mod fetch;
+pub mod merge;
+pub mod push;
+pub mod rebase;
 pub mod remote;
 
-use std::collections::HashMap;
+use std::{fmt, borrow::Borrow, collections::{BTreeMap, HashMap, HashSet}};
 
-use attaca::{Open, object::CommitRef, store::{self, prelude::*}};
+use attaca::{Open, digest::{Sha3Digest, prelude::*},
+             object::{Commit, CommitRef, ObjectRef, TreeRef}, store::{self, prelude::*}};
 use failure::*;
 use futures::prelude::*;
+use hex;
 
 use Repository;
 use config::StoreKind;
+use refs;
 use state::{Head, State};
-use syntax::{Name, Ref};
+use syntax::{Ancestry, Name, Ref, Rev};
+
+/// The shortest digest abbreviation which will be resolved.
+pub const MIN_ABBREV_LEN: usize = 4;
 
 pub type Branches<B> = HashMap<Name, CommitRef<Handle<B>>>;
 
@@ -20,8 +30,38 @@ pub type FutureBranches<'r, B> = Box<Future<Item = Branches<B>, Error = Error> +
 pub type FutureCommitRef<'r, B> = Box<Future<Item = CommitRef<Handle<B>>, Error = Error> + 'r>;
 pub type FutureOptionCommitRef<'r, B> =
     Box<Future<Item = Option<CommitRef<Handle<B>>>, Error = Error> + 'r>;
+pub type FutureTreeRef<'r, B> = Box<Future<Item = TreeRef<Handle<B>>, Error = Error> + 'r>;
+pub type FutureOptionTreeRef<'r, B> =
+    Box<Future<Item = Option<TreeRef<Handle<B>>>, Error = Error> + 'r>;
 pub type FutureUnit<'r> = Box<Future<Item = (), Error = Error> + 'r>;
 
+/// Objects which keep everything reachable from them alive, each with a name for where it's kept.
+pub type Roots<B> = Vec<(String, ObjectRef<Handle<B>>)>;
+
+pub type FutureRoots<'r, B> = Box<Future<Item = Roots<B>, Error = Error> + 'r>;
+
+/// The error for a revision which names no commit, as opposed to one which couldn't be looked up.
+#[derive(Debug, Clone, Fail)]
+pub struct UnknownRevision {
+    pub rev: String,
+    pub reason: String,
+}
+
+impl fmt::Display for UnknownRevision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown revision {}: {}", self.rev, self.reason)
+    }
+}
+
+impl UnknownRevision {
+    fn new<R: Into<String>>(rev: &str, reason: R) -> Self {
+        UnknownRevision {
+            rev: rev.to_owned(),
+            reason: reason.into(),
+        }
+    }
+}
+
 // NB eventually get_state will end up async since it talks to the local store, which is why
 // this is async.
 pub fn load_remote_branches<B: Backend>(
@@ -43,7 +83,7 @@ pub fn load_remote_branches<B: Backend>(
 
 pub fn load_branches<B: Backend>(this: &Repository<B>) -> FutureBranches<B> {
     let blocking = async_block! {
-        let handles = await!(this.store.load_branches())?;
+        let handles = await!(refs::load_heads(&this.store))?;
         let branches = handles
             .into_iter()
             .map(|(name, handle)| Ok((Name::from_string(name)?, CommitRef::new(handle))))
@@ -67,7 +107,7 @@ pub fn swap_branches<B: Backend>(
         let new_handles = new.into_iter()
             .map(|(name, commit_ref)| (name.into_string(), commit_ref.into_inner()))
             .collect();
-        await!(this.store.swap_branches(previous_handles, new_handles))?;
+        await!(refs::swap_heads(&this.store, previous_handles, new_handles))?;
         Ok(())
     };
 
@@ -77,14 +117,16 @@ pub fn swap_branches<B: Backend>(
 pub fn resolve_opt<B: Backend>(this: &Repository<B>, refr: Ref) -> FutureOptionCommitRef<B> {
     match refr {
         Ref::Head => resolve_head_opt(this),
-        Ref::Local(local_ref) => resolve_local_opt(this, local_ref),
+        Ref::Local(local_ref) => resolve_name_opt(this, local_ref),
+        Ref::Tag(tag) => resolve_tag_opt(this, tag),
         Ref::Remote(remote, local_ref) => resolve_remote_opt(this, remote, local_ref),
     }
 }
 
 pub fn resolve<B: Backend>(this: &Repository<B>, refr: Ref) -> FutureCommitRef<B> {
     match refr {
-        Ref::Local(local_ref) => resolve_local(this, local_ref),
+        Ref::Local(local_ref) => resolve_name(this, local_ref),
+        Ref::Tag(tag) => resolve_tag(this, tag),
         Ref::Remote(remote, local_ref) => resolve_remote(this, remote, local_ref),
         Ref::Head => resolve_head(this),
     }
@@ -109,6 +151,42 @@ pub fn resolve_local<B: Backend>(this: &Repository<B>, local_ref: Name) -> Futur
     )
 }
 
+pub fn resolve_tag_opt<B: Backend>(this: &Repository<B>, tag: Name) -> FutureOptionCommitRef<B> {
+    let blocking = async_block! {
+        let tags = await!(refs::load_tags(&this.store))?;
+        Ok(tags.get(tag.as_str()).cloned().map(CommitRef::new))
+    };
+
+    Box::new(blocking)
+}
+
+pub fn resolve_tag<B: Backend>(this: &Repository<B>, tag: Name) -> FutureCommitRef<B> {
+    Box::new(resolve_tag_opt(this, tag.clone()).and_then(|maybe_ref| {
+        maybe_ref.ok_or_else(move || format_err!("no such tag {}", tag))
+    }))
+}
+
+/// Resolve a short name, as a branch if there is one by that name and otherwise as a tag.
+pub fn resolve_name_opt<B: Backend>(
+    this: &Repository<B>,
+    name: Name,
+) -> FutureOptionCommitRef<B> {
+    let blocking = async_block! {
+        match await!(resolve_local_opt(this, name.clone()))? {
+            Some(commit_ref) => Ok(Some(commit_ref)),
+            None => await!(resolve_tag_opt(this, name)),
+        }
+    };
+
+    Box::new(blocking)
+}
+
+pub fn resolve_name<B: Backend>(this: &Repository<B>, name: Name) -> FutureCommitRef<B> {
+    Box::new(resolve_name_opt(this, name.clone()).and_then(|maybe_ref| {
+        maybe_ref.ok_or_else(move || format_err!("no such branch or tag {}", name))
+    }))
+}
+
 pub fn resolve_remote_opt<B: Backend>(
     this: &Repository<B>,
     remote_name: Name,
@@ -164,6 +242,278 @@ pub fn resolve_head<B: Backend>(this: &Repository<B>) -> FutureCommitRef<B> {
     Box::new(blocking)
 }
 
+/// Every root of the repository: the HEAD, every ref (branches, tags and remote-tracking
+/// branches), the virtual workspace and each stash entry. An object is in use if and only if it is
+/// reachable from one of these.
+pub fn roots<B: Backend>(this: &Repository<B>) -> FutureRoots<B> {
+    let blocking = async_block! {
+        let mut roots = Vec::new();
+
+        roots.extend(
+            await!(resolve_head_opt(this))?
+                .map(|commit_ref| ("HEAD".to_owned(), ObjectRef::Commit(commit_ref))),
+        );
+        roots.extend(
+            await!(this.for_each_ref(""))?
+                .into_iter()
+                .map(|(name, commit_ref)| (name, ObjectRef::Commit(commit_ref))),
+        );
+        roots.extend(
+            this.get_state()?
+                .candidate
+                .map(|tree_ref| ("the virtual workspace".to_owned(), ObjectRef::Tree(tree_ref))),
+        );
+        for (i, entry) in this.get_stash()?.into_iter().enumerate() {
+            for root in await!(entry.roots(&this.store))? {
+                roots.push((format!("stash@{{{}}}", i), root));
+            }
+        }
+
+        Ok(roots)
+    };
+
+    Box::new(blocking)
+}
+
+/// Every object reachable from one of the repository's roots. See `roots`.
+pub fn reachable<'r, B: Backend>(
+    this: &'r Repository<B>,
+) -> Box<Future<Item = HashSet<Handle<B>>, Error = Error> + 'r> {
+    let blocking = async_block! {
+        let mut stack = await!(roots(this))?
+            .into_iter()
+            .map(|(_, root)| root)
+            .collect::<Vec<_>>();
+
+        let mut visited = HashSet::new();
+        while let Some(object_ref) = stack.pop() {
+            if !visited.insert(object_ref.as_inner().clone()) {
+                continue;
+            }
+
+            match object_ref {
+                ObjectRef::Small(_) => {}
+                ObjectRef::Large(large_ref) => {
+                    let large = await!(large_ref.fetch())?;
+                    stack.extend(large.into_iter().map(|(_, child_ref)| child_ref));
+                }
+                ObjectRef::Tree(tree_ref) => {
+                    let tree = await!(tree_ref.fetch())?;
+                    stack.extend(tree.into_iter().map(|(_, child_ref)| child_ref));
+                }
+                ObjectRef::Commit(commit_ref) => {
+                    let commit = await!(commit_ref.fetch())?;
+                    stack.push(ObjectRef::Tree(commit.as_subtree().clone()));
+                    stack.extend(commit.as_parents().iter().cloned().map(ObjectRef::Commit));
+                }
+            }
+        }
+
+        Ok(visited)
+    };
+
+    Box::new(blocking)
+}
+
+/// The dangling commits in the store: those reachable from no root, and which are not the parent
+/// of another unreachable commit. Each is given with its hex digest, in order of digest.
+///
+/// The store doesn't record what kind of object it holds, so an unreachable object is taken to be
+/// a commit if it decodes as one and its tree decodes as a tree. This needs a backend which can
+/// list the digests of its objects.
+pub fn dangling_commits<'r, B: Backend>(
+    this: &'r Repository<B>,
+) -> Box<Future<Item = Vec<(String, CommitRef<Handle<B>>)>, Error = Error> + 'r> {
+    let blocking = async_block! {
+        let reachable = await!(reachable(this))?;
+        let digests = this.store.list_digests::<Sha3Digest>().ok_or_else(|| {
+            format_err!("This store can't list its objects, so dangling commits can't be found!")
+        })?;
+
+        let mut unreachable = BTreeMap::new();
+        let mut parents = HashSet::new();
+
+        #[async]
+        for digest in digests {
+            let handle = match await!(this.store.resolve_digest(digest.clone()))? {
+                Some(handle) => handle,
+                None => continue,
+            };
+            if reachable.contains(&handle) {
+                continue;
+            }
+
+            let commit_ref = CommitRef::new(handle);
+            let commit = match await!(fetch_if_commit(commit_ref.clone()))? {
+                Some(commit) => commit,
+                None => continue,
+            };
+
+            parents.extend(commit.as_parents().iter().cloned());
+            unreachable.insert(hex::encode(digest.as_bytes()), commit_ref);
+        }
+
+        Ok(unreachable
+            .into_iter()
+            .filter(|&(_, ref commit_ref)| !parents.contains(commit_ref))
+            .collect())
+    };
+
+    Box::new(blocking)
+}
+
+/// Fetch an object as a commit, if it is one. The store doesn't record what kind of object it
+/// holds, so this is as good a check as there is: the object must decode as a commit, and its tree
+/// as a tree.
+#[async]
+pub(crate) fn fetch_if_commit<B: Backend>(
+    commit_ref: CommitRef<Handle<B>>,
+) -> Result<Option<Commit<Handle<B>>>, Error> {
+    let commit = match await!(commit_ref.fetch()) {
+        Ok(commit) => commit,
+        Err(_) => return Ok(None),
+    };
+    let subtree = commit.as_subtree().clone();
+    if await!(subtree.fetch()).is_err() {
+        return Ok(None);
+    }
+
+    Ok(Some(commit))
+}
+
+fn is_abbrev(s: &str) -> bool {
+    s.len() >= MIN_ABBREV_LEN && s.chars().all(|c| c.is_digit(16))
+}
+
+/// Resolve a full or abbreviated hex digest to a commit. Abbreviations are matched against every
+/// commit reachable from the HEAD, branches, remote branches and the stash; a full digest may name
+/// any commit in the store.
+pub fn resolve_abbrev<B: Backend>(this: &Repository<B>, abbrev: String) -> FutureCommitRef<B> {
+    let blocking = async_block! {
+        let abbrev = abbrev.to_lowercase();
+        ensure!(
+            is_abbrev(&abbrev),
+            "{} is not a digest abbreviation (at least {} hex digits are needed)",
+            abbrev,
+            MIN_ABBREV_LEN
+        );
+
+        if abbrev.len() == 2 * Sha3Digest::SIGNATURE.size {
+            let digest = Sha3Digest::from_bytes(&hex::decode(&abbrev)?);
+            if let Some(handle) = await!(this.store.resolve_digest(digest))? {
+                let commit_ref = CommitRef::new(handle);
+                ensure!(
+                    await!(fetch_if_commit(commit_ref.clone()))?.is_some(),
+                    "{} is not a commit",
+                    abbrev
+                );
+                return Ok(commit_ref);
+            }
+        }
+
+        let state = this.get_state()?;
+        let mut stack = Vec::new();
+        stack.extend(await!(resolve_head_opt(this))?);
+        stack.extend(await!(load_branches(this))?.into_iter().map(|(_, commit_ref)| commit_ref));
+        stack.extend(
+            state
+                .remote_refs
+                .into_iter()
+                .flat_map(|(_, branches)| branches.into_iter())
+                .map(|(_, commit_ref)| commit_ref),
+        );
+        for entry in this.get_stash()? {
+            for root in await!(entry.roots(&this.store))? {
+                if let ObjectRef::Commit(commit_ref) = root {
+                    stack.push(commit_ref);
+                }
+            }
+        }
+
+        let mut visited = HashSet::new();
+        let mut matches = BTreeMap::new();
+        while let Some(commit_ref) = stack.pop() {
+            if !visited.insert(commit_ref.clone()) {
+                continue;
+            }
+
+            let commit_id = await!(commit_ref.id())?;
+            let commit_hex = hex::encode(commit_id.as_inner().borrow().as_bytes());
+            let commit = await!(commit_ref.fetch())?;
+            stack.extend(commit.as_parents().iter().cloned());
+
+            if commit_hex.starts_with(&abbrev) {
+                matches.insert(commit_hex, commit_ref);
+            }
+        }
+
+        match matches.len() {
+            0 => Err(UnknownRevision::new(&abbrev, "no commit has a matching digest").into()),
+            1 => Ok(matches.into_iter().next().unwrap().1),
+            _ => {
+                let listing = matches
+                    .keys()
+                    .map(|commit_hex| format!("\n    {}", commit_hex))
+                    .collect::<String>();
+                bail!(
+                    "ambiguous abbreviation {}; it matches the commits:{}",
+                    abbrev,
+                    listing
+                );
+            }
+        }
+    };
+
+    Box::new(blocking)
+}
+
+/// Resolve a revision, following any ancestry steps from its base. A bare name which is neither a
+/// branch nor a tag is tried as a digest abbreviation.
+pub fn resolve_rev<B: Backend>(this: &Repository<B>, rev: Rev) -> FutureCommitRef<B> {
+    let blocking = async_block! {
+        let display = rev.to_string();
+        let Rev { base, ancestry } = rev;
+
+        let mut commit_ref = match base {
+            Ref::Local(name) => match await!(resolve_name_opt(this, name.clone()))? {
+                Some(commit_ref) => commit_ref,
+                None if is_abbrev(&name) => await!(resolve_abbrev(this, name.into_string()))?,
+                None => {
+                    let reason = format!("no such branch or tag {}", name);
+                    return Err(UnknownRevision::new(&display, reason).into());
+                }
+            },
+            other => await!(resolve(this, other))?,
+        };
+
+        for step in ancestry {
+            commit_ref = match step {
+                Ancestry::Parent(0) | Ancestry::Ancestor(0) => commit_ref,
+                Ancestry::Parent(n) => {
+                    let commit = await!(commit_ref.fetch())?;
+                    commit.as_parents().get(n - 1).cloned().ok_or_else(|| {
+                        UnknownRevision::new(&display, format!("a commit has no parent {}", n))
+                    })?
+                }
+                Ancestry::Ancestor(n) => {
+                    let mut ancestor = commit_ref;
+                    for _ in 0..n {
+                        let commit = await!(ancestor.fetch())?;
+                        ancestor = commit.as_parents().first().cloned().ok_or_else(|| {
+                            UnknownRevision::new(&display, "history is not that deep")
+                        })?;
+                    }
+                    ancestor
+                }
+            };
+        }
+
+        Ok(commit_ref)
+    };
+
+    Box::new(blocking)
+}
+
 pub fn set_head<B: Backend>(this: &mut Repository<B>, head: Head<Handle<B>>) -> FutureUnit {
     let blocking = async_block! {
         let state = this.get_state()?;
@@ -173,3 +523,28 @@ pub fn set_head<B: Backend>(this: &mut Repository<B>, head: Head<Handle<B>>) ->
 
     Box::new(blocking)
 }
+
+/// Whether `ancestor` is reachable from `descendant` by following parents. A commit counts as its
+/// own ancestor. The walk stops as soon as `ancestor` is found, and otherwise visits each commit
+/// in the history of `descendant` once.
+#[async]
+pub fn is_ancestor<B: Backend>(
+    ancestor: CommitRef<Handle<B>>,
+    descendant: CommitRef<Handle<B>>,
+) -> Result<bool, Error> {
+    let mut visited = HashSet::new();
+    let mut stack = vec![descendant];
+
+    while let Some(commit_ref) = stack.pop() {
+        if commit_ref == ancestor {
+            return Ok(true);
+        }
+
+        if visited.insert(commit_ref.clone()) {
+            let commit = await!(commit_ref.fetch())?;
+            stack.extend(commit.as_parents().iter().cloned());
+        }
+    }
+
+    Ok(false)
+}
@@ -0,0 +1,166 @@
+use std::fmt;
+
+use attaca::{digest::Sha3Digest, limit::RateLimiter};
+
+use super::*;
+use open;
+
+macro_rules! dispatch_push {
+    (@inner $this:expr, $remote:expr, $branch:expr, $force:expr, $dry_run:expr, $($lcname:ident, $ccname:ident : $type:ty),*) => {
+        {
+            match $remote.kind {
+                $(StoreKind::$ccname => await!(backend(
+                    $this,
+                    open::$lcname(&$remote)?,
+                    $branch,
+                    $force,
+                    $dry_run,
+                ))?,)*
+                StoreKind::Bundle => bail!("Bundles are read-only; use `bundle create` instead."),
+                StoreKind::Memory => bail!("An in-memory store cannot be a remote."),
+            }
+        }
+    };
+    ($this:expr, $remote:expr, $branch:expr, $force:expr, $dry_run:expr) => {
+        all_backends!(dispatch_push!(@inner $this, $remote, $branch, $force, $dry_run))
+    };
+}
+
+/// How pushing a branch changes the corresponding branch on the remote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefUpdate {
+    /// The remote doesn't have the branch yet.
+    New,
+
+    /// The remote branch is already at the local commit.
+    UpToDate,
+
+    /// The remote branch is an ancestor of the local commit.
+    FastForward,
+
+    /// The remote branch has commits which the local branch does not, or which we haven't
+    /// fetched; pushing would discard them.
+    NonFastForward,
+}
+
+impl fmt::Display for RefUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RefUpdate::New => f.pad("new branch"),
+            RefUpdate::UpToDate => f.pad("up to date"),
+            RefUpdate::FastForward => f.pad("fast-forward"),
+            RefUpdate::NonFastForward => f.pad("non-fast-forward"),
+        }
+    }
+}
+
+/// What a push did, or with `dry_run`, what it would have done.
+#[derive(Debug, Clone)]
+pub struct PushReport {
+    pub branch: Name,
+    pub update: RefUpdate,
+    /// Digests of the objects which the remote is missing, and which are the only ones sent.
+    pub missing: Vec<Sha3Digest>,
+    pub dry_run: bool,
+}
+
+pub fn remote<'r, B: Backend>(
+    this: &'r mut Repository<B>,
+    remote_name: Name,
+    branch: Name,
+    force: bool,
+    dry_run: bool,
+) -> Box<Future<Item = PushReport, Error = Error> + 'r> {
+    let blocking = async_block! {
+        let report = {
+            let config = this.get_config()?;
+            let remote = config
+                .remotes
+                .get(remote_name.as_str())
+                .cloned()
+                .ok_or_else(|| format_err!("no such remote {}", remote_name))?;
+            dispatch_push!(this, remote, branch, force, dry_run)
+        };
+
+        if !report.dry_run && report.update != RefUpdate::UpToDate {
+            let commit_ref = await!(resolve_local(this, report.branch.clone()))?;
+            let mut state = this.get_state()?;
+            state
+                .remote_refs
+                .entry(remote_name)
+                .or_insert_with(HashMap::new)
+                .insert(report.branch.clone(), commit_ref);
+            this.set_state(&state)?;
+        }
+
+        Ok(report)
+    };
+
+    Box::new(blocking)
+}
+
+pub fn backend<'r, B: Backend, C: Backend>(
+    this: &'r Repository<B>,
+    remote_backend: C,
+    branch: Name,
+    force: bool,
+    dry_run: bool,
+) -> Box<Future<Item = PushReport, Error = Error> + 'r> {
+    let blocking = async_block! {
+        let remote = Store::new(remote_backend);
+        let local_ref = await!(resolve_local(this, branch.clone()))?;
+        let remote_branches = await!(refs::load_heads(&remote))?;
+
+        let update = match remote_branches.get(branch.as_str()) {
+            None => RefUpdate::New,
+            Some(remote_handle) => {
+                let remote_digest = await!(remote_handle.digest::<Sha3Digest>())?;
+                match await!(this.store.resolve_digest(remote_digest))? {
+                    Some(ref handle) if handle == local_ref.as_inner() => RefUpdate::UpToDate,
+                    Some(handle) => {
+                        if await!(is_ancestor(CommitRef::new(handle), local_ref.clone()))? {
+                            RefUpdate::FastForward
+                        } else {
+                            RefUpdate::NonFastForward
+                        }
+                    }
+                    // We've never seen the remote's commit, so it can't be in our history.
+                    None => RefUpdate::NonFastForward,
+                }
+            }
+        };
+
+        let (missing, have) = await!(store::find_missing::<Sha3Digest, _, _>(
+            local_ref.as_inner().clone(),
+            remote.clone()
+        ))?;
+
+        if !dry_run && update != RefUpdate::UpToDate {
+            ensure!(
+                update != RefUpdate::NonFastForward || force,
+                "Updating {} on the remote is not a fast-forward; fetch and merge first, or use \
+                 --force to discard the remote's changes.",
+                branch
+            );
+
+            let remote_handle = await!(store::transfer_missing_with_limit::<Sha3Digest, _, _>(
+                local_ref.as_inner().clone(),
+                remote.clone(),
+                have,
+                RateLimiter::unlimited(),
+            ))?;
+            let mut new_branches = remote_branches.clone();
+            new_branches.insert(branch.as_str().to_owned(), remote_handle);
+            await!(refs::swap_heads(&remote, remote_branches, new_branches))?;
+        }
+
+        Ok(PushReport {
+            branch,
+            update,
+            missing,
+            dry_run,
+        })
+    };
+
+    Box::new(blocking)
+}
@@ -0,0 +1,530 @@
+//! Replaying commits on top of another.
+//!
+//! A rebase replays the commits reachable from HEAD but not from the upstream, oldest first, on
+//! top of the upstream. Each commit is replayed by a three-way merge of its changes (against its
+//! first parent) into the last commit replayed, and then recommitted with its original message,
+//! author, timestamp and headers through `commit::commit_tree`. Merge commits are not replayed, and
+//! commits whose changes turn out to be upstream already are dropped.
+//!
+//! The rebase is recorded in the repository state as it goes. If a replay conflicts, the rebase
+//! stops with the conflict left as a merge in progress; once the conflicting paths are staged,
+//! `proceed` recommits the replayed commit and carries on, and `abort` puts everything back as it
+//! was before the rebase began.
+
+use attaca::{object::{Commit, TreeRef}, path::ObjectPath};
+
+use super::*;
+use super::commit::{self, CommitTree};
+use ident;
+use state::{MergeState, RebaseState};
+
+/// The result of beginning or continuing a rebase.
+#[derive(Debug, Clone)]
+pub enum RebaseOutcome {
+    /// Everything reachable from HEAD is already reachable from the upstream; nothing was changed.
+    UpToDate,
+
+    /// HEAD was an ancestor of the upstream and has been moved forward to it.
+    FastForward,
+
+    /// Every commit has been replayed, and HEAD (or the branch being rebased) moved to the last of
+    /// them.
+    Finished,
+
+    /// Replaying the commit with the given hex digest conflicted in the paths listed. The merged
+    /// tree is now the virtual workspace, keeping HEAD's version of each conflicting path.
+    Stopped {
+        commit: String,
+        conflicts: Vec<ObjectPath>,
+    },
+}
+
+/// The commits reachable from `tip` but not from `upstream`, leaving out merge commits, in an order
+/// where every commit comes after its parents.
+#[async]
+fn to_replay<B: Backend>(
+    upstream: CommitRef<Handle<B>>,
+    tip: CommitRef<Handle<B>>,
+) -> Result<Vec<CommitRef<Handle<B>>>, Error> {
+    let mut hidden = HashSet::new();
+    let mut stack = vec![upstream];
+    while let Some(commit_ref) = stack.pop() {
+        if hidden.insert(commit_ref.clone()) {
+            let commit = await!(commit_ref.fetch())?;
+            stack.extend(commit.as_parents().iter().cloned());
+        }
+    }
+
+    // A depth-first walk, with each commit pushed a second time (marked with whether or not it is
+    // a merge) to be emitted once all of its parents have been.
+    let mut ordered = Vec::new();
+    let mut visited = HashSet::new();
+    let mut stack = vec![(tip, None)];
+    while let Some((commit_ref, expanded)) = stack.pop() {
+        match expanded {
+            Some(true) => {}
+            Some(false) => ordered.push(commit_ref),
+            None => {
+                if hidden.contains(&commit_ref) || !visited.insert(commit_ref.clone()) {
+                    continue;
+                }
+
+                let commit = await!(commit_ref.fetch())?;
+                let is_merge = commit.as_parents().len() > 1;
+                stack.push((commit_ref, Some(is_merge)));
+                stack.extend(commit.as_parents().iter().map(|parent| (parent.clone(), None)));
+            }
+        }
+    }
+
+    Ok(ordered)
+}
+
+/// Recommit `original` on top of `parent` with a new tree, keeping everything else about it.
+fn recommit<'r, B: Backend>(
+    this: &'r Repository<B>,
+    original: &Commit<Handle<B>>,
+    subtree: TreeRef<Handle<B>>,
+    parent: CommitRef<Handle<B>>,
+) -> FutureCommitRef<'r, B> {
+    let mut inputs = CommitTree::new(subtree, Some(parent));
+    inputs.message = original.as_message().map(str::to_owned);
+    inputs.author = Some(original.as_author().clone());
+    inputs.timestamp = Some(*original.as_timestamp());
+    inputs.headers = original.as_headers().clone();
+
+    // The author timestamp is kept, but the commit itself is made now.
+    let blocking = ident::committer_date()
+        .into_future()
+        .and_then(move |committer_timestamp| {
+            inputs.committer_timestamp = Some(committer_timestamp);
+            commit::commit_tree(this, inputs)
+        })
+        .map(|(commit_ref, _)| commit_ref);
+
+    Box::new(blocking)
+}
+
+/// Replay the remaining commits of the rebase in progress, stopping at the first conflict. The
+/// local workspace is rewritten wholesale when the rebase finishes or stops, so callers must first
+/// check that it has nothing which would be lost.
+fn replay<'r, B: Backend>(
+    this: &'r mut Repository<B>,
+) -> Box<Future<Item = RebaseOutcome, Error = Error> + 'r> {
+    let blocking = async_block! {
+        loop {
+            let state = this.get_state()?;
+            let mut rebase = state
+                .rebase
+                .clone()
+                .ok_or_else(|| format_err!("No rebase in progress!"))?;
+            let tip = match state.head {
+                Head::Detached(ref commit_ref) => commit_ref.clone(),
+                _ => bail!("HEAD was moved during the rebase! Use `rebase --abort`."),
+            };
+            let tip_subtree = await!(this.tree_of(&tip))?;
+
+            if rebase.todo.is_empty() {
+                let head = match rebase.branch {
+                    Some(branch) => {
+                        let branches = await!(load_branches(this))?;
+                        let mut new_branches = branches.clone();
+                        new_branches.insert(branch.clone(), tip.clone());
+                        await!(swap_branches(this, branches, new_branches))?;
+                        Head::Branch(branch)
+                    }
+                    None => Head::Detached(tip),
+                };
+
+                await!(checkout::checkout_path_from_tree(
+                    this,
+                    tip_subtree.clone(),
+                    ObjectPath::new(),
+                ))?;
+                this.set_state(&State {
+                    head,
+                    candidate: Some(tip_subtree),
+                    rebase: None,
+                    ..state
+                })?;
+
+                return Ok(RebaseOutcome::Finished);
+            }
+
+            let pick_ref = rebase.todo.remove(0);
+            let pick = await!(pick_ref.fetch())?;
+            let pick_base = pick.as_parents().first().cloned();
+            let base_subtree = await!(pick_base.as_ref().map(|base_ref| this.tree_of(base_ref)))?;
+            let (merged_subtree, conflicts) = await!(merge::trees(
+                this.store.clone(),
+                base_subtree,
+                tip_subtree.clone(),
+                pick.as_subtree().clone(),
+                ObjectPath::new(),
+            ))?;
+
+            if !conflicts.is_empty() {
+                let pick_id = await!(pick_ref.id())?;
+                let commit = hex::encode(pick_id.as_inner().borrow().as_bytes());
+
+                await!(checkout::checkout_path_from_tree(
+                    this,
+                    merged_subtree.clone(),
+                    ObjectPath::new(),
+                ))?;
+
+                let merge = MergeState {
+                    ours: tip,
+                    theirs: pick_ref,
+                    base: pick_base,
+                    candidate: Some(tip_subtree),
+                    conflicts: conflicts.iter().cloned().collect(),
+                };
+                this.set_state(&State {
+                    candidate: Some(merged_subtree),
+                    merge: Some(merge),
+                    rebase: Some(rebase),
+                    ..state
+                })?;
+
+                return Ok(RebaseOutcome::Stopped { commit, conflicts });
+            }
+
+            let new_tip = if merged_subtree == tip_subtree {
+                tip
+            } else {
+                await!(recommit(this, &pick, merged_subtree.clone(), tip))?
+            };
+            this.set_state(&State {
+                head: Head::Detached(new_tip),
+                candidate: Some(merged_subtree),
+                rebase: Some(rebase),
+                ..state
+            })?;
+        }
+    };
+
+    Box::new(blocking)
+}
+
+/// Rebase HEAD onto `upstream`. See the module documentation.
+pub fn begin<'r, B: Backend>(
+    this: &'r mut Repository<B>,
+    upstream: CommitRef<Handle<B>>,
+) -> Box<Future<Item = RebaseOutcome, Error = Error> + 'r> {
+    let blocking = async_block! {
+        let state = this.get_state()?;
+        ensure!(
+            state.rebase.is_none(),
+            "A rebase is already in progress! Use `rebase --continue` or `rebase --abort`."
+        );
+        ensure!(state.merge.is_none(), "Cannot rebase while a merge is in progress!");
+
+        let ours = await!(resolve_head_opt(this))?
+            .ok_or_else(|| format_err!("No previous commit to rebase!"))?;
+        let ours_subtree = await!(this.tree_of(&ours))?;
+        ensure!(
+            state.candidate.as_ref().map_or(true, |cand| cand == &ours_subtree),
+            "The virtual workspace has uncommitted changes! Commit or stash them before rebasing."
+        );
+
+        let base = await!(merge::merge_base(ours.clone(), upstream.clone()))?;
+        if base.as_ref() == Some(&upstream) {
+            return Ok(RebaseOutcome::UpToDate);
+        }
+
+        await!(this.ensure_unchanged_locally(vec![ObjectPath::new()], "rebasing"))?;
+        let is_fast_forward = base.as_ref() == Some(&ours);
+
+        let todo = await!(to_replay(upstream.clone(), ours.clone()))?;
+        let upstream_subtree = await!(this.tree_of(&upstream))?;
+        let branch = match state.head {
+            Head::Branch(ref branch) => Some(branch.clone()),
+            Head::Empty | Head::Detached(_) => None,
+        };
+
+        this.set_state(&State {
+            head: Head::Detached(upstream.clone()),
+            candidate: Some(upstream_subtree),
+            rebase: Some(RebaseState {
+                onto: upstream,
+                orig_head: ours,
+                branch,
+                todo,
+            }),
+            ..state
+        })?;
+
+        match await!(replay(this))? {
+            RebaseOutcome::Finished if is_fast_forward => Ok(RebaseOutcome::FastForward),
+            outcome => Ok(outcome),
+        }
+    };
+
+    Box::new(blocking)
+}
+
+/// Carry on with the rebase in progress. If it stopped at a conflict, every conflicting path must
+/// have been staged; the virtual workspace is then committed in place of the commit which
+/// conflicted.
+pub fn proceed<'r, B: Backend>(
+    this: &'r mut Repository<B>,
+) -> Box<Future<Item = RebaseOutcome, Error = Error> + 'r> {
+    let blocking = async_block! {
+        let state = this.get_state()?;
+        ensure!(state.rebase.is_some(), "No rebase in progress!");
+        await!(this.ensure_unchanged_locally(
+            vec![ObjectPath::new()],
+            "continuing the rebase",
+        ))?;
+
+        if let Some(merge) = state.merge.clone() {
+            ensure!(
+                merge.is_resolved(),
+                "Cannot continue the rebase with unresolved conflicts! Stage the resolved paths \
+                 first."
+            );
+
+            let candidate = state
+                .candidate
+                .clone()
+                .ok_or_else(|| format_err!("No virtual workspace to commit!"))?;
+            let pick = await!(merge.theirs.fetch())?;
+            let tip_subtree = await!(this.tree_of(&merge.ours))?;
+            let new_tip = if candidate == tip_subtree {
+                merge.ours
+            } else {
+                await!(recommit(this, &pick, candidate, merge.ours))?
+            };
+
+            this.set_state(&State {
+                head: Head::Detached(new_tip),
+                merge: None,
+                ..state
+            })?;
+        }
+
+        await!(replay(this))
+    };
+
+    Box::new(blocking)
+}
+
+/// Abandon the rebase in progress, restoring HEAD, the virtual workspace and the local workspace
+/// to how they were before it began.
+pub fn abort<B: Backend>(this: &mut Repository<B>) -> FutureUnit {
+    let blocking = async_block! {
+        let state = this.get_state()?;
+        let rebase = state
+            .rebase
+            .clone()
+            .ok_or_else(|| format_err!("No rebase in progress!"))?;
+        await!(this.ensure_unchanged_locally(
+            vec![ObjectPath::new()],
+            "aborting the rebase",
+        ))?;
+
+        let restored = await!(this.tree_of(&rebase.orig_head))?;
+        await!(checkout::checkout_path_from_tree(
+            this,
+            restored.clone(),
+            ObjectPath::new(),
+        ))?;
+
+        let head = match rebase.branch {
+            Some(branch) => Head::Branch(branch),
+            None => Head::Detached(rebase.orig_head),
+        };
+        this.set_state(&State {
+            head,
+            candidate: Some(restored),
+            merge: None,
+            rebase: None,
+            ..state
+        })?;
+
+        Ok(())
+    };
+
+    Box::new(blocking)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::{Path, PathBuf}};
+
+    use attaca::memory::MemoryBackend;
+    use tempdir::TempDir;
+    use url::Url;
+
+    use {CommitArgs, StageArgs};
+    use config::StoreConfig;
+
+    fn f() -> ObjectPath {
+        ObjectPath::from_path(Path::new("f")).unwrap()
+    }
+
+    fn contents(workspace: &TempDir) -> String {
+        fs::read_to_string(workspace.path().join("f")).unwrap()
+    }
+
+    fn stage(repository: &mut Repository<MemoryBackend>, contents: &str) {
+        fs::write(repository.path.join("f"), contents).unwrap();
+        let out = repository.stage(StageArgs {
+            paths: vec![PathBuf::from("f")],
+            previous: false,
+            all: false,
+            progress: false,
+            no_progress: true,
+            quiet: true,
+            intent_to_add: false,
+        });
+        out.blocking
+            .join(out.progress.for_each(|_| Ok(())))
+            .wait()
+            .unwrap();
+    }
+
+    fn commit(
+        repository: &mut Repository<MemoryBackend>,
+        contents: &str,
+    ) -> CommitRef<Handle<MemoryBackend>> {
+        stage(repository, contents);
+        let out = repository.commit(CommitArgs {
+            message: Some(contents.to_owned()),
+            reuse_message: None,
+            reedit_message: None,
+            author: Some("test".to_owned()),
+            date: None,
+            amend: false,
+            force: true,
+            no_verify: true,
+            only: Vec::new(),
+            cleanup: None,
+            empty_as_delete: false,
+            dry_run: false,
+        });
+        out.blocking.wait().unwrap();
+        resolve_head_opt(repository).wait().unwrap().unwrap()
+    }
+
+    /// A repository where `f` was committed as "base\n", then changed to "upstream\n" in one
+    /// commit and to "ours\n" in another with the same parent, with HEAD detached at the latter.
+    /// Returns the repository, the upstream commit and HEAD.
+    fn diverged(
+        workspace: &TempDir,
+    ) -> (
+        Repository<MemoryBackend>,
+        CommitRef<Handle<MemoryBackend>>,
+        CommitRef<Handle<MemoryBackend>>,
+    ) {
+        let mut repository = Repository::init_with(workspace.path().to_owned(), |_| {
+            let config = StoreConfig {
+                url: Url::parse("memory:")?,
+                kind: StoreKind::Memory,
+                encryption: None,
+            };
+            Ok((config, MemoryBackend::new()))
+        }).unwrap();
+
+        let base = commit(&mut repository, "base\n");
+        let upstream = commit(&mut repository, "upstream\n");
+
+        let base_subtree = repository.tree_of(&base).wait().unwrap();
+        checkout::checkout_path_from_tree(&mut repository, base_subtree.clone(), ObjectPath::new())
+            .wait()
+            .unwrap();
+        let state = repository.get_state().unwrap();
+        repository
+            .set_state(&State {
+                head: Head::Detached(base),
+                candidate: Some(base_subtree),
+                ..state
+            })
+            .unwrap();
+
+        let ours = commit(&mut repository, "ours\n");
+        (repository, upstream, ours)
+    }
+
+    /// Begin rebasing the diverged repository, which stops at the conflict in `f`.
+    fn stop(
+        repository: &mut Repository<MemoryBackend>,
+        upstream: CommitRef<Handle<MemoryBackend>>,
+    ) {
+        match begin(repository, upstream).wait().unwrap() {
+            RebaseOutcome::Stopped { conflicts, .. } => assert_eq!(conflicts, vec![f()]),
+            outcome => panic!("expected the rebase to stop, but got {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn rebase_stops_at_a_conflict() {
+        let workspace = TempDir::new("subito-rebase").unwrap();
+        let (mut repository, upstream, _) = diverged(&workspace);
+        stop(&mut repository, upstream.clone());
+
+        let state = repository.get_state().unwrap();
+        assert!(state.rebase.is_some());
+        assert!(state.merge.is_some());
+        match state.head {
+            Head::Detached(ref head) => assert!(head == &upstream),
+            _ => panic!("expected HEAD to be detached at the upstream"),
+        }
+        assert_eq!(contents(&workspace), "upstream\n");
+    }
+
+    #[test]
+    fn rebase_continues_once_conflicts_are_staged() {
+        let workspace = TempDir::new("subito-rebase").unwrap();
+        let (mut repository, upstream, _) = diverged(&workspace);
+        stop(&mut repository, upstream.clone());
+
+        stage(&mut repository, "resolved\n");
+        match proceed(&mut repository).wait().unwrap() {
+            RebaseOutcome::Finished => {}
+            outcome => panic!("expected the rebase to finish, but got {:?}", outcome),
+        }
+
+        let state = repository.get_state().unwrap();
+        assert!(state.rebase.is_none());
+        assert!(state.merge.is_none());
+        let head = resolve_head_opt(&repository).wait().unwrap().unwrap();
+        let head_commit = head.fetch().wait().unwrap();
+        assert!(head_commit.as_parents().to_vec() == vec![upstream]);
+        assert_eq!(head_commit.as_message().map(str::trim), Some("ours"));
+        assert_eq!(contents(&workspace), "resolved\n");
+    }
+
+    #[test]
+    fn rebase_abort_restores_head() {
+        let workspace = TempDir::new("subito-rebase").unwrap();
+        let (mut repository, upstream, ours) = diverged(&workspace);
+        stop(&mut repository, upstream);
+
+        abort(&mut repository).wait().unwrap();
+
+        let state = repository.get_state().unwrap();
+        assert!(state.rebase.is_none());
+        assert!(state.merge.is_none());
+        match state.head {
+            Head::Detached(ref head) => assert!(head == &ours),
+            _ => panic!("expected HEAD to be detached where it was"),
+        }
+        assert_eq!(contents(&workspace), "ours\n");
+    }
+
+    #[test]
+    fn rebase_abort_keeps_local_changes() {
+        let workspace = TempDir::new("subito-rebase").unwrap();
+        let (mut repository, upstream, _) = diverged(&workspace);
+        stop(&mut repository, upstream);
+
+        fs::write(workspace.path().join("f"), "edited\n").unwrap();
+        assert!(abort(&mut repository).wait().is_err());
+
+        assert!(repository.get_state().unwrap().rebase.is_some());
+        assert_eq!(contents(&workspace), "edited\n");
+    }
+}
@@ -2,7 +2,9 @@ use attaca::Open;
 use url::Url;
 
 use super::*;
+use bundle;
 use config::StoreConfig;
+use open;
 
 macro_rules! backend_remote_add {
     (@inner $url:expr, $($lcname:ident, $ccname:ident : $type:ty),*) => {
@@ -16,15 +18,106 @@ macro_rules! backend_remote_add {
     ($url:expr) => { all_backends!(backend_remote_add!(@inner $url)) };
 }
 
+/// Find the kind of store to open at `url`, by its scheme. URLs which refer to a bundle are
+/// recognized first, since a bundle may be named by a `file:` URL.
+pub fn store_kind(url: &Url) -> Result<StoreKind, Error> {
+    if bundle::path_of(url).is_some() {
+        return Ok(StoreKind::Bundle);
+    }
+
+    Ok(backend_remote_add!(url))
+}
+
+/// Describe the store at `url` for use as a remote: its kind and, if it is encrypted at rest,
+/// the parameters needed to unlock it.
+pub fn store_config(url: Url) -> Result<StoreConfig, Error> {
+    let kind = store_kind(&url)?;
+    let encryption = match kind {
+        StoreKind::LevelDb => open::leveldb_encryption(url.as_str())?,
+        // A repository served over ssh is unlocked by the server, and bundles and in-memory
+        // stores are never sealed.
+        StoreKind::Ssh | StoreKind::Bundle | StoreKind::Memory => None,
+    };
+
+    Ok(StoreConfig {
+        url,
+        kind,
+        encryption,
+    })
+}
+
 pub fn add<B: Backend>(this: &mut Repository<B>, name: Name, url: Url) -> FutureUnit {
     let blocking = async_block! {
         let mut config = this.get_config()?;
         ensure!(!config.remotes.contains_key(name.as_str()), "remote already exists");
-        let kind = backend_remote_add!(url);
-        config.remotes.insert(name.into_string(), StoreConfig { url, kind });
+        config.remotes.insert(name.into_string(), store_config(url)?);
         this.set_config(&config)?;
         Ok(())
     };
 
     Box::new(blocking)
 }
+
+macro_rules! dispatch_branch_names {
+    (@inner $remote:expr, $($lcname:ident, $ccname:ident : $type:ty),*) => {
+        {
+            match $remote.kind {
+                $(StoreKind::$ccname => {
+                    let store = Store::new(open::$lcname(&$remote)?);
+                    await!(refs::load_heads(&store))?
+                        .into_iter()
+                        .map(|(name, _)| name)
+                        .collect::<HashSet<_>>()
+                })*
+                StoreKind::Bundle => {
+                    let store = bundle::open_url(&$remote.url)?;
+                    await!(refs::load_heads(&store))?
+                        .into_iter()
+                        .map(|(name, _)| name)
+                        .collect::<HashSet<_>>()
+                }
+                StoreKind::Memory => bail!("An in-memory store cannot be a remote."),
+            }
+        }
+    };
+    ($remote:expr) => { all_backends!(dispatch_branch_names!(@inner $remote)) };
+}
+
+/// Remove the remote-tracking branches of a remote which the remote no longer has, without
+/// fetching anything else. Local branches are never touched. Returns the names of the removed
+/// branches, sorted.
+pub fn prune<'r, B: Backend>(
+    this: &'r mut Repository<B>,
+    remote_name: Name,
+) -> Box<Future<Item = Vec<Name>, Error = Error> + 'r> {
+    let blocking = async_block! {
+        let remote = this.get_config()?
+            .remotes
+            .get(remote_name.as_str())
+            .cloned()
+            .ok_or_else(|| format_err!("No such remote {}", remote_name))?;
+        let advertised = dispatch_branch_names!(remote);
+
+        let mut state = this.get_state()?;
+        let mut pruned = match state.remote_refs.get_mut(&remote_name) {
+            Some(tracked) => {
+                let stale = tracked
+                    .keys()
+                    .filter(|name| !advertised.contains(name.as_str()))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for name in &stale {
+                    tracked.remove(name);
+                }
+                stale
+            }
+            None => Vec::new(),
+        };
+        this.set_state(&state)?;
+
+        pruned.sort();
+        Ok(pruned)
+    };
+
+    Box::new(blocking)
+}
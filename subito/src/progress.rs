@@ -0,0 +1,53 @@
+//! Deciding whether to report progress, and measuring it.
+
+use std::io::{self, Read};
+
+use nix::{libc::STDERR_FILENO, unistd::isatty};
+
+/// How many bytes `ProgressReader` reads between reports.
+pub const REPORT_INTERVAL: u64 = 1 << 20;
+
+/// Whether an operation should report progress. `--progress` and `--no-progress` force it on and
+/// off; with neither, progress is shown only if stderr is a terminal, so that output piped to a
+/// file or another program isn't cluttered with it.
+pub fn enabled(progress: bool, no_progress: bool) -> bool {
+    if no_progress {
+        false
+    } else if progress {
+        true
+    } else {
+        isatty(STDERR_FILENO).unwrap_or(false)
+    }
+}
+
+/// A reader which reports how many bytes have been read through it so far, once for every
+/// `REPORT_INTERVAL` bytes. Nothing is reported for a reader shorter than that.
+pub struct ProgressReader<R, F> {
+    inner: R,
+    report: F,
+    processed: u64,
+    next_report: u64,
+}
+
+impl<R: Read, F: FnMut(u64)> ProgressReader<R, F> {
+    pub fn new(inner: R, report: F) -> Self {
+        Self {
+            inner,
+            report,
+            processed: 0,
+            next_report: REPORT_INTERVAL,
+        }
+    }
+}
+
+impl<R: Read, F: FnMut(u64)> Read for ProgressReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.processed += n as u64;
+        if self.processed >= self.next_report {
+            (self.report)(self.processed);
+            self.next_report = (self.processed / REPORT_INTERVAL + 1) * REPORT_INTERVAL;
+        }
+        Ok(n)
+    }
+}
@@ -0,0 +1,83 @@
+use std::fmt;
+
+use attaca::store::prelude::*;
+use failure::*;
+use futures::prelude::*;
+
+use Repository;
+use plumbing::{self, push::PushReport};
+use state::Head;
+use syntax::Name;
+
+/// Send a local branch and the objects it needs to a remote repository.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "push")]
+pub struct PushArgs {
+    /// Name of the remote to push to.
+    #[structopt(name = "REMOTE")]
+    pub remote: Name,
+
+    /// The local branch to push. This defaults to the current branch.
+    #[structopt(name = "BRANCH")]
+    pub branch: Option<Name>,
+
+    /// Report which objects are missing on the remote and how its branch would be updated,
+    /// without sending anything.
+    #[structopt(short = "n", long = "dry-run")]
+    pub dry_run: bool,
+
+    /// Update the remote branch even if the update is not a fast-forward.
+    #[structopt(short = "f", long = "force")]
+    pub force: bool,
+
+    /// Report progress even if stderr is not a terminal.
+    #[structopt(long = "progress", raw(conflicts_with = r#""no_progress""#))]
+    pub progress: bool,
+
+    /// Do not report progress, even if stderr is a terminal.
+    #[structopt(long = "no-progress")]
+    pub no_progress: bool,
+}
+
+#[must_use = "PushOut contains futures which must be driven to completion!"]
+pub struct PushOut<'r> {
+    pub blocking: Box<Future<Item = PushReport, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for PushOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PushOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    pub fn push<'r>(&'r mut self, args: PushArgs) -> PushOut<'r> {
+        let PushArgs {
+            remote,
+            branch,
+            dry_run,
+            force,
+            ..
+        } = args;
+
+        let blocking = async_block! {
+            let branch = match branch {
+                Some(branch) => branch,
+                None => match self.get_state()?.head {
+                    Head::Branch(branch) => branch,
+                    Head::Empty | Head::Detached(_) => {
+                        bail!("Not on a branch; name the branch to push explicitly.")
+                    }
+                },
+            };
+
+            await!(plumbing::push::remote(self, remote, branch, force, dry_run))
+        };
+
+        PushOut {
+            blocking: Box::new(blocking),
+        }
+    }
+}
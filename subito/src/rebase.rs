@@ -0,0 +1,70 @@
+use std::fmt;
+
+use attaca::store::prelude::*;
+use failure::*;
+use futures::prelude::*;
+
+use Repository;
+use plumbing::{self, rebase::RebaseOutcome};
+use syntax::Rev;
+
+/// Replay the commits of HEAD which aren't in another line of history on top of it.
+///
+/// If replaying a commit conflicts, the rebase stops with the merged tree as the virtual workspace.
+/// Conflicting paths keep the version being rebased onto; each is resolved by staging it, after
+/// which `rebase --continue` commits the result and carries on. `rebase --abort` abandons the
+/// rebase, restoring HEAD and the virtual and local workspaces.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "rebase")]
+pub struct RebaseArgs {
+    /// The revision to replay HEAD's commits on top of.
+    #[structopt(name = "UPSTREAM", raw(required_unless_one = r#"&["proceed", "abort"]"#))]
+    pub upstream: Option<Rev>,
+
+    /// Carry on with the rebase in progress, once any conflicts have been staged.
+    #[structopt(long = "continue", raw(conflicts_with_all = r#"&["UPSTREAM", "abort"]"#))]
+    pub proceed: bool,
+
+    /// Abandon the rebase in progress.
+    #[structopt(long = "abort", raw(conflicts_with = r#""UPSTREAM""#))]
+    pub abort: bool,
+}
+
+#[must_use = "RebaseOut contains futures which must be driven to completion!"]
+pub struct RebaseOut<'r> {
+    /// The outcome of the rebase, or `None` if a rebase in progress was aborted.
+    pub blocking: Box<Future<Item = Option<RebaseOutcome>, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for RebaseOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RebaseOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    pub fn rebase<'r>(&'r mut self, args: RebaseArgs) -> RebaseOut<'r> {
+        let blocking = async_block! {
+            if args.abort {
+                await!(plumbing::rebase::abort(self))?;
+                return Ok(None);
+            }
+
+            let outcome = match args.upstream {
+                Some(rev) if !args.proceed => {
+                    let upstream = await!(plumbing::resolve_rev(self, rev))?;
+                    await!(plumbing::rebase::begin(self, upstream))?
+                }
+                _ => await!(plumbing::rebase::proceed(self))?,
+            };
+
+            Ok(Some(outcome))
+        };
+
+        RebaseOut {
+            blocking: Box::new(blocking),
+        }
+    }
+}
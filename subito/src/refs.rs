@@ -0,0 +1,176 @@
+//! Namespaced refs.
+//!
+//! A store keeps all of its refs in a single map, which is swapped atomically as a whole. To keep
+//! different kinds of refs from colliding, they are stored under fully qualified names:
+//! `refs/heads/<branch>` for branches, `refs/tags/<tag>` for tags and `refs/lost-found/<digest>`
+//! for dangling commits recovered by `fsck --lost-found`. Refs from before namespaces were
+//! introduced are stored under their bare names, and are read as branches; `migrate` rewrites them
+//! in place.
+//!
+//! Remote-tracking refs (`refs/remotes/<remote>/<branch>`) are kept in the workspace state rather
+//! than in the store, so they do not share this map. `Repository::for_each_ref` lists the refs of
+//! every namespace together, wherever they are kept.
+
+use std::collections::HashMap;
+
+use attaca::{object::CommitRef, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+
+use Repository;
+
+pub const HEADS: &'static str = "refs/heads/";
+pub const TAGS: &'static str = "refs/tags/";
+pub const REMOTES: &'static str = "refs/remotes/";
+
+/// Where `fsck --lost-found --write-refs` records the dangling commits it finds, by digest.
+pub const LOST_FOUND: &'static str = "refs/lost-found/";
+
+const REFS: &'static str = "refs/";
+
+/// Refs from a single namespace, keyed by their names without the namespace prefix.
+pub type Refs<B> = HashMap<String, Handle<B>>;
+
+pub type FutureRefs<B> = Box<Future<Item = Refs<B>, Error = Error>>;
+pub type FutureUnit = Box<Future<Item = (), Error = Error>>;
+
+/// A ref's fully qualified name, such as `refs/heads/master` or `refs/remotes/origin/master`.
+pub type FullRefName = String;
+
+pub type FutureRefList<'r, B> =
+    Box<Future<Item = Vec<(FullRefName, CommitRef<Handle<B>>)>, Error = Error> + 'r>;
+
+/// Qualify any refs stored under bare names as branches. If a bare name and its qualified
+/// counterpart both exist, the qualified one wins.
+fn qualify<B: Backend>(raw: Refs<B>) -> Refs<B> {
+    let (qualified, bare): (Vec<_>, Vec<_>) = raw.into_iter()
+        .partition(|&(ref name, _)| name.starts_with(REFS));
+
+    bare.into_iter()
+        .map(|(name, handle)| (format!("{}{}", HEADS, name), handle))
+        .chain(qualified)
+        .collect()
+}
+
+fn strip<B: Backend>(qualified: &Refs<B>, namespace: &str) -> Refs<B> {
+    qualified
+        .iter()
+        .filter(|&(name, _)| name.starts_with(namespace))
+        .map(|(name, handle)| (name[namespace.len()..].to_owned(), handle.clone()))
+        .collect()
+}
+
+/// Load every ref in a namespace.
+pub fn load<B: Backend>(store: &Store<B>, namespace: &'static str) -> FutureRefs<B> {
+    Box::new(
+        store
+            .load_branches()
+            .map(move |raw| strip(&qualify(raw), namespace)),
+    )
+}
+
+/// Atomically replace the refs in a namespace, failing if they are no longer `previous`. Refs in
+/// other namespaces are left as they are.
+pub fn swap<B: Backend>(
+    store: &Store<B>,
+    namespace: &'static str,
+    previous: Refs<B>,
+    new: Refs<B>,
+) -> FutureUnit {
+    let store = store.clone();
+    let blocking = async_block! {
+        let raw = await!(store.load_branches())?;
+        let qualified = qualify(raw.clone());
+        ensure!(strip(&qualified, namespace) == previous, "compare failed");
+
+        let mut updated = qualified
+            .into_iter()
+            .filter(|&(ref name, _)| !name.starts_with(namespace))
+            .collect::<Refs<B>>();
+        updated.extend(
+            new.into_iter()
+                .map(|(name, handle)| (format!("{}{}", namespace, name), handle)),
+        );
+        await!(store.swap_branches(raw, updated))?;
+
+        Ok(())
+    };
+
+    Box::new(blocking)
+}
+
+/// Shorten a fully qualified ref name to the name it is usually written as: `refs/heads/master`
+/// to `master`, `refs/tags/v1.0` to `v1.0` and `refs/remotes/origin/master` to `origin/master`.
+pub fn short_name(name: &str) -> &str {
+    [HEADS, TAGS, REMOTES]
+        .iter()
+        .find(|namespace| name.starts_with(*namespace))
+        .map(|namespace| &name[namespace.len()..])
+        .unwrap_or(name)
+}
+
+pub fn load_heads<B: Backend>(store: &Store<B>) -> FutureRefs<B> {
+    load(store, HEADS)
+}
+
+pub fn swap_heads<B: Backend>(store: &Store<B>, previous: Refs<B>, new: Refs<B>) -> FutureUnit {
+    swap(store, HEADS, previous, new)
+}
+
+pub fn load_tags<B: Backend>(store: &Store<B>) -> FutureRefs<B> {
+    load(store, TAGS)
+}
+
+pub fn swap_tags<B: Backend>(store: &Store<B>, previous: Refs<B>, new: Refs<B>) -> FutureUnit {
+    swap(store, TAGS, previous, new)
+}
+
+/// Rewrite refs stored under bare names to their qualified names, if there are any.
+pub fn migrate<B: Backend>(store: &Store<B>) -> FutureUnit {
+    let store = store.clone();
+    let blocking = async_block! {
+        let raw = await!(store.load_branches())?;
+        if raw.keys().all(|name| name.starts_with(REFS)) {
+            return Ok(());
+        }
+
+        let qualified = qualify(raw.clone());
+        await!(store.swap_branches(raw, qualified))?;
+
+        Ok(())
+    };
+
+    Box::new(blocking)
+}
+
+impl<B: Backend> Repository<B> {
+    /// Every ref whose fully qualified name starts with `prefix`, along with the commit it points
+    /// to, sorted by name. This covers the branches and tags kept in the store as well as the
+    /// remote-tracking branches kept in the workspace state. An empty prefix matches every ref.
+    pub fn for_each_ref<'r>(&'r self, prefix: &str) -> FutureRefList<'r, B> {
+        let prefix = prefix.to_owned();
+        let blocking = async_block! {
+            let raw = await!(self.store.load_branches())?;
+            let state = self.get_state()?;
+
+            let stored = qualify(raw)
+                .into_iter()
+                .map(|(name, handle)| (name, CommitRef::new(handle)));
+            let remote_tracking = state.remote_refs.iter().flat_map(|(remote, branches)| {
+                branches.iter().map(move |(branch, commit_ref)| {
+                    (format!("{}{}/{}", REMOTES, remote, branch), commit_ref.clone())
+                })
+            });
+
+            let mut refs = stored
+                .chain(remote_tracking)
+                .filter(|&(ref name, _)| name.starts_with(&prefix))
+                .collect::<Vec<_>>();
+            refs.sort_by(|a, b| a.0.cmp(&b.0));
+
+            Ok(refs)
+        };
+
+        Box::new(blocking)
+    }
+}
@@ -6,8 +6,9 @@ use futures::prelude::*;
 use url::Url;
 
 use Repository;
-use config::{StoreConfig, StoreKind};
+use plumbing;
 use state::State;
+use syntax::Name;
 
 /// Manipulate remotes of a repository.
 #[derive(Debug, Clone, StructOpt)]
@@ -18,6 +19,9 @@ pub enum RemoteArgs {
 
     #[structopt(name = "list")]
     List(RemoteListArgs),
+
+    #[structopt(name = "prune")]
+    Prune(RemotePruneArgs),
 }
 
 #[derive(Debug, Clone, StructOpt, Builder)]
@@ -34,18 +38,32 @@ pub struct RemoteAddArgs {
 #[structopt(name = "list")]
 pub struct RemoteListArgs {}
 
+/// Remove remote-tracking branches which the remote no longer has, without fetching. `fetch` does
+/// this too, along with fetching everything else.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "prune")]
+pub struct RemotePruneArgs {
+    #[structopt(name = "NAME")]
+    pub name: Name,
+}
+
 pub struct RemoteOut<'r> {
-    pub blocking: Box<Future<Item = (), Error = Error> + 'r>,
+    /// Resolves to the remote-tracking branches removed by `prune`; the other subcommands remove
+    /// none.
+    pub blocking: Box<Future<Item = Vec<Name>, Error = Error> + 'r>,
 }
 
 impl<B: Backend> Repository<B> {
     pub fn remote<'r>(&'r mut self, args: RemoteArgs) -> RemoteOut<'r> {
         match args {
             RemoteArgs::Add(add_args) => RemoteOut {
-                blocking: Box::new(self.remote_add(add_args).into_future()),
+                blocking: Box::new(self.remote_add(add_args).map(|()| Vec::new()).into_future()),
             },
             RemoteArgs::List(list_args) => RemoteOut {
-                blocking: Box::new(self.remote_list(list_args).into_future()),
+                blocking: Box::new(self.remote_list(list_args).map(|()| Vec::new()).into_future()),
+            },
+            RemoteArgs::Prune(prune_args) => RemoteOut {
+                blocking: Box::new(self.remote_prune(prune_args)),
             },
         }
     }
@@ -54,15 +72,8 @@ impl<B: Backend> Repository<B> {
         let RemoteAddArgs { name, url } = args;
 
         let mut config = self.get_config()?;
-        // TODO get store kind from URL
         ensure!(!config.remotes.contains_key(&name), "remote already exists");
-        config.remotes.insert(
-            name,
-            StoreConfig {
-                url,
-                kind: StoreKind::LevelDb,
-            },
-        );
+        config.remotes.insert(name, plumbing::remote::store_config(url)?);
         self.set_config(&config)?;
         Ok(())
     }
@@ -78,4 +89,13 @@ impl<B: Backend> Repository<B> {
         }
         Ok(())
     }
+
+    /// Remove the remote-tracking branches the remote no longer has, returning their names.
+    pub fn remote_prune<'r>(
+        &'r mut self,
+        args: RemotePruneArgs,
+    ) -> impl Future<Item = Vec<Name>, Error = Error> + 'r {
+        let RemotePruneArgs { name } = args;
+        plumbing::remote::prune(self, name)
+    }
 }
@@ -0,0 +1,138 @@
+use std::{fmt, fs, collections::BTreeSet, path::PathBuf, sync::Arc};
+
+use attaca::{object::ObjectRef, path::ObjectPath, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+
+use Repository;
+use plumbing;
+use state::State;
+use status::Change;
+
+/// Throw away the virtual workspace, resetting it to the previous commit.
+///
+/// Any merge or rebase in progress is abandoned along with it. Local files are left untouched
+/// unless `--hard` is given.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "reset")]
+pub struct ResetArgs {
+    /// Also reset the local workspace: every file in the previous commit is rewritten, discarding
+    /// local changes to it, and files staged since are removed. Files which have never been
+    /// staged, including those only marked with `stage --intent-to-add`, are left alone.
+    #[structopt(long = "hard")]
+    pub hard: bool,
+}
+
+#[must_use = "ResetOut contains futures which must be driven to completion!"]
+pub struct ResetOut<'r> {
+    pub blocking: Box<Future<Item = (), Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for ResetOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResetOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+/// Remove the local files at `path` which `object_ref` tracks, other than those in `untracked`. A
+/// directory is only removed once nothing is left in it, so that files which were never staged
+/// survive.
+fn remove_tracked<B: Backend>(
+    base: PathBuf,
+    object_ref: ObjectRef<Handle<B>>,
+    path: ObjectPath,
+    untracked: Arc<BTreeSet<ObjectPath>>,
+) -> Box<Future<Item = (), Error = Error>> {
+    let blocking = async_block! {
+        if untracked.contains(&path) {
+            return Ok(());
+        }
+
+        let absolute_path = path.with_base(&base);
+        let metadata = match absolute_path.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(()),
+        };
+
+        match object_ref {
+            ObjectRef::Tree(tree_ref) => {
+                if !metadata.is_dir() {
+                    return Ok(());
+                }
+
+                for (name, child_ref) in await!(tree_ref.fetch())? {
+                    let child_path = path.push_back(name);
+                    await!(remove_tracked(base.clone(), child_ref, child_path, untracked.clone()))?;
+                }
+
+                if fs::read_dir(&absolute_path)?.next().is_none() {
+                    fs::remove_dir(&absolute_path)?;
+                }
+            }
+            ObjectRef::Small(_) | ObjectRef::Large(_) => {
+                if !metadata.is_dir() {
+                    fs::remove_file(&absolute_path)?;
+                }
+            }
+            ObjectRef::Commit(_) => unreachable!(),
+        }
+
+        Ok(())
+    };
+
+    Box::new(blocking)
+}
+
+impl<B: Backend> Repository<B> {
+    pub fn reset<'r>(&'r mut self, args: ResetArgs) -> ResetOut<'r> {
+        let blocking = async_block! {
+            let head_ref = await!(self.head())?
+                .ok_or_else(|| format_err!("Nothing to reset to: HEAD has no commits!"))?;
+            let head_tree = await!(self.tree_of(&head_ref))?;
+            let state = self.get_state().context("Error while fetching state")?;
+
+            if args.hard {
+                // Files staged since the previous commit are tracked, so they go; everything else
+                // tracked is rewritten from the previous commit. Paths marked with
+                // `--intent-to-add` have only an empty placeholder staged, so their local files
+                // were never staged and are kept.
+                if let Some(candidate) = state.candidate.clone() {
+                    let untracked = Arc::new(state.intent_to_add.clone());
+                    let hierarchy = self.hierarchy(candidate.clone())?;
+                    let changes =
+                        await!(Self::compare_subtrees(head_tree.clone(), candidate).collect())?;
+                    for change in changes {
+                        if let Change::Added(path) = change {
+                            if let Some(object_ref) = await!(hierarchy.get(path.clone()))? {
+                                let base = (*self.path).clone();
+                                await!(remove_tracked(base, object_ref, path, untracked.clone()))?;
+                            }
+                        }
+                    }
+                }
+
+                await!(plumbing::checkout::overlay_from_tree(
+                    self,
+                    head_tree.clone(),
+                    ObjectPath::new(),
+                ))?;
+            }
+
+            self.set_state(&State {
+                candidate: Some(head_tree),
+                merge: None,
+                intent_to_add: Default::default(),
+                rebase: None,
+                ..state
+            }).context("Error while updating state")?;
+
+            Ok(())
+        };
+
+        ResetOut {
+            blocking: Box::new(blocking),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::{fmt, borrow::Borrow};
+
+use attaca::{digest::prelude::*, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+use hex;
+
+use Repository;
+use plumbing;
+use syntax::Rev;
+
+/// Print the full digest of the commit named by a revision.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "rev-parse")]
+pub struct RevParseArgs {
+    /// The revision to resolve: a ref or abbreviated digest, optionally followed by ancestry
+    /// steps such as `~2` (second first-parent ancestor) or `^2` (second parent).
+    #[structopt(name = "REV", default_value = "HEAD")]
+    pub rev: Rev,
+}
+
+#[must_use = "RevParseOut contains futures which must be driven to completion!"]
+pub struct RevParseOut<'r> {
+    /// The full hex digest of the resolved commit.
+    pub digest: Box<Future<Item = String, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for RevParseOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RevParseOut")
+            .field("digest", &"OPAQUE")
+            .finish()
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    pub fn rev_parse<'r>(&'r self, args: RevParseArgs) -> RevParseOut<'r> {
+        let digest = async_block! {
+            let commit_ref = await!(plumbing::resolve_rev(self, args.rev))?;
+            let commit_id = await!(commit_ref.id())?;
+            Ok(hex::encode(commit_id.as_inner().borrow().as_bytes()))
+        };
+
+        RevParseOut {
+            digest: Box::new(digest),
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::{fmt, io::{self, BufReader}, net::{SocketAddr, TcpListener}, path::PathBuf, thread};
+
+use attaca::store::prelude::*;
+use attaca_ssh::{self, Access};
+use failure::*;
+use futures::prelude::*;
+
+use Repository;
+
+/// Serve this repository's store to other repositories. By default, a single client is served over
+/// stdin and stdout; this is what clients connecting to an `ssh://` URL run on the remote host.
+/// With `--listen`, clients connect to `attaca+tcp://HOST:PORT` instead.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "serve")]
+pub struct ServeArgs {
+    /// Path to the repository to serve. This defaults to the repository containing the current
+    /// directory.
+    #[structopt(name = "PATH", parse(from_os_str))]
+    pub path: Option<PathBuf>,
+
+    /// Refuse to accept objects or update branches, so that clients can fetch and clone but not
+    /// push. This is always the case with `--listen`, unless `--allow-push` is given.
+    #[structopt(long = "read-only", raw(conflicts_with = r#""allow_push""#))]
+    pub read_only: bool,
+
+    /// Listen for clients on this address, such as `0.0.0.0:7265`, serving each one on its own
+    /// thread. Clients connecting this way are not authenticated, so they may only fetch and
+    /// clone unless `--allow-push` is given.
+    #[structopt(long = "listen", name = "ADDRESS")]
+    pub listen: Option<SocketAddr>,
+
+    /// With `--listen`, let clients push: anyone who can reach the address can then upload
+    /// objects and rewrite branches.
+    #[structopt(long = "allow-push", raw(requires = r#""ADDRESS""#))]
+    pub allow_push: bool,
+}
+
+#[must_use = "ServeOut contains futures which must be driven to completion!"]
+pub struct ServeOut<'r> {
+    /// Finishes when the client hangs up, or never if listening on a socket.
+    pub blocking: Box<Future<Item = (), Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for ServeOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServeOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+fn listen_on<B: Backend>(
+    store: &Store<B>,
+    access: Access,
+    address: SocketAddr,
+) -> Result<(), Error> {
+    let listener = TcpListener::bind(address)
+        .with_context(|_| format!("Error listening on {}", address))?;
+
+    for stream_res in listener.incoming() {
+        let stream = stream_res?;
+        let peer = stream.peer_addr()?;
+        let store = store.clone();
+
+        thread::spawn(move || {
+            let result = stream.try_clone().map_err(Error::from).and_then(|reader| {
+                attaca_ssh::serve(&store, access, BufReader::new(reader), stream)
+            });
+            if let Err(err) = result {
+                eprintln!("Error serving {}: {}", peer, err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+impl<B: Backend> Repository<B> {
+    pub fn serve<'r>(&'r self, args: ServeArgs) -> ServeOut<'r> {
+        // The path is only needed to find the repository in the first place.
+        let ServeArgs {
+            path: _,
+            read_only,
+            listen,
+            allow_push,
+        } = args;
+        let access = if read_only || (listen.is_some() && !allow_push) {
+            Access::ReadOnly
+        } else {
+            Access::ReadWrite
+        };
+
+        let blocking = async_block! {
+            match listen {
+                Some(address) => listen_on(&self.store, access, address),
+                None => {
+                    let stdin = io::stdin();
+                    let stdout = io::stdout();
+                    attaca_ssh::serve(&self.store, access, stdin.lock(), stdout.lock())
+                }
+            }
+        };
+
+        ServeOut {
+            blocking: Box::new(blocking),
+        }
+    }
+}
@@ -0,0 +1,486 @@
+use std::{fmt, fs, io::{BufRead, Write}};
+
+use attaca::{batch::{Batch as ObjectBatch, Operation as ObjectOperation}, digest::prelude::*,
+             object::{CommitRef, ObjectRef, TreeBuilder, TreeRef},
+             path::ObjectPath, store::prelude::*};
+use capnp::{message, serialize_packed};
+use failure::*;
+use futures::prelude::*;
+use leveldb::{kv::KV, options::{ReadOptions, WriteOptions}};
+
+use Repository;
+use db::Key;
+use plumbing::{self, commit::CommitTree};
+use state::{Head, State};
+
+use stash_capnp::*;
+
+/// Shelve changes to the virtual workspace, and later restore them.
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(name = "stash")]
+pub enum StashArgs {
+    #[structopt(name = "push")]
+    Push(StashPushArgs),
+
+    #[structopt(name = "list")]
+    List(StashListArgs),
+
+    #[structopt(name = "apply")]
+    Apply(StashApplyArgs),
+
+    #[structopt(name = "pop")]
+    Pop(StashApplyArgs),
+
+    #[structopt(name = "drop")]
+    Drop(StashDropArgs),
+}
+
+/// Save the virtual workspace to the stash and reset it to the previous commit.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "push")]
+pub struct StashPushArgs {
+    /// Describe the stashed changes.
+    #[structopt(short = "m", long = "message")]
+    pub message: Option<String>,
+
+    /// Also stash the local workspace, and reset it to the previous commit. Without this, local
+    /// files are left untouched.
+    #[structopt(short = "w", long = "workspace")]
+    pub workspace: bool,
+}
+
+/// List stashed changes, most recent first.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "list")]
+pub struct StashListArgs {}
+
+/// Restore stashed changes on top of the virtual workspace.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "apply")]
+pub struct StashApplyArgs {
+    /// Index of the stash entry to restore, as shown by `stash list`.
+    #[structopt(name = "INDEX", default_value = "0")]
+    pub index: usize,
+}
+
+/// Discard stashed changes.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "drop")]
+pub struct StashDropArgs {
+    /// Index of the stash entry to discard, as shown by `stash list`.
+    #[structopt(name = "INDEX", default_value = "0")]
+    pub index: usize,
+}
+
+#[must_use = "StashOut contains futures which must be driven to completion!"]
+pub struct StashOut<'r> {
+    pub blocking: Box<Future<Item = (), Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for StashOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StashOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+/// A single entry in the stash.
+///
+/// The stashed virtual workspace is kept as a commit whose only parent is the commit it was
+/// stashed on top of. If the local workspace was stashed too, its tree is kept alongside.
+pub struct StashEntry<B: Backend> {
+    commit_id: OwnedLocalId<B>,
+    workspace_id: Option<OwnedLocalId<B>>,
+
+    pub message: String,
+    pub timestamp: String,
+}
+
+impl<B: Backend> fmt::Debug for StashEntry<B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StashEntry")
+            .field("message", &self.message)
+            .field("timestamp", &self.timestamp)
+            .field("workspace", &self.workspace_id.is_some())
+            .finish()
+    }
+}
+
+impl<B: Backend> StashEntry<B> {
+    /// Whether this entry includes changes to the local workspace.
+    pub fn has_workspace(&self) -> bool {
+        self.workspace_id.is_some()
+    }
+
+    /// Resolve the stashed commit and, if present, the stashed workspace tree.
+    fn resolve(
+        &self,
+        store: &Store<B>,
+    ) -> impl Future<Item = (CommitRef<Handle<B>>, Option<TreeRef<Handle<B>>>), Error = Error> {
+        use std::borrow::Borrow;
+
+        let commit_id: &LocalId<B> = self.commit_id.borrow();
+        let future_commit = CommitRef::new(commit_id).resolve_id(store);
+        let future_workspace = self.workspace_id.as_ref().map(|id| {
+            let id: &LocalId<B> = id.borrow();
+            TreeRef::new(id).resolve_id(store)
+        });
+
+        async_block! {
+            let commit_ref = await!(future_commit)?
+                .ok_or_else(|| format_err!("Stashed commit is missing from the store!"))?;
+            let maybe_workspace_ref = match future_workspace {
+                Some(future_workspace) => Some(
+                    await!(future_workspace)?
+                        .ok_or_else(|| format_err!("Stashed workspace is missing from the store!"))?,
+                ),
+                None => None,
+            };
+            Ok((commit_ref, maybe_workspace_ref))
+        }
+    }
+
+    /// Resolve the objects which this entry keeps alive.
+    pub(crate) fn roots(
+        &self,
+        store: &Store<B>,
+    ) -> impl Future<Item = Vec<ObjectRef<Handle<B>>>, Error = Error> {
+        self.resolve(store).map(|(commit_ref, maybe_workspace_ref)| {
+            let mut roots = vec![ObjectRef::Commit(commit_ref)];
+            roots.extend(maybe_workspace_ref.map(ObjectRef::Tree));
+            roots
+        })
+    }
+}
+
+fn decode_stash<B: Backend, R: BufRead>(reader: &mut R) -> Result<Vec<StashEntry<B>>, Error> {
+    let message_reader = serialize_packed::read_message(reader, message::ReaderOptions::new())?;
+    let stash_reader = message_reader.get_root::<stash::Reader>()?;
+
+    stash_reader
+        .get_entries()?
+        .iter()
+        .map(|entry| {
+            let commit_id = <B as Backend>::Id::from_bytes(entry.get_commit_id()?);
+            let workspace_id = match entry.get_workspace().which()? {
+                stash_entry::workspace::None(()) => None,
+                stash_entry::workspace::Some(bytes_res) => {
+                    Some(<B as Backend>::Id::from_bytes(bytes_res?))
+                }
+            };
+
+            Ok(StashEntry {
+                commit_id,
+                workspace_id,
+                message: String::from(entry.get_message()?),
+                timestamp: String::from(entry.get_timestamp()?),
+            })
+        })
+        .collect()
+}
+
+fn encode_stash<B: Backend, W: Write>(
+    entries: &[StashEntry<B>],
+    writer: &mut W,
+) -> Result<(), Error> {
+    use std::borrow::Borrow;
+
+    let mut message = message::Builder::new_default();
+
+    {
+        let stash_builder = message.init_root::<stash::Builder>();
+        let mut entries_builder = stash_builder.init_entries(entries.len() as u32);
+        for (i, entry) in entries.iter().enumerate() {
+            let mut entry_builder = entries_builder.borrow().get(i as u32);
+            entry_builder.set_commit_id(entry.commit_id.borrow().as_ref());
+            {
+                let mut workspace_builder = entry_builder.borrow().get_workspace();
+                match entry.workspace_id {
+                    Some(ref id) => workspace_builder.set_some(id.borrow().as_ref()),
+                    None => workspace_builder.set_none(()),
+                }
+            }
+            entry_builder.set_message(&entry.message);
+            entry_builder.set_timestamp(&entry.timestamp);
+        }
+    }
+
+    serialize_packed::write_message(writer, &message)?;
+
+    Ok(())
+}
+
+/// Remove whatever is at `path` in the local workspace.
+fn remove_local<B: Backend>(this: &Repository<B>, path: &ObjectPath) -> Result<(), Error> {
+    let absolute_path = path.with_base(&*this.path);
+    if absolute_path.exists() {
+        let file_type = absolute_path.symlink_metadata()?.file_type();
+        if file_type.is_dir() {
+            fs::remove_dir_all(&absolute_path)?;
+        } else {
+            fs::remove_file(&absolute_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+impl<B: Backend> Repository<B> {
+    pub fn get_stash(&self) -> Result<Vec<StashEntry<B>>, Error> {
+        match self.db
+            .read()
+            .unwrap()
+            .get(ReadOptions::new(), &Key::stash())?
+        {
+            Some(bytes) => decode_stash(&mut &bytes[..]),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn set_stash(&self, entries: &[StashEntry<B>]) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        encode_stash(entries, &mut buf)?;
+        self.db
+            .read()
+            .unwrap()
+            .put(WriteOptions::new(), &Key::stash(), &buf)?;
+
+        Ok(())
+    }
+
+    pub fn stash<'r>(&'r mut self, args: StashArgs) -> StashOut<'r> {
+        match args {
+            StashArgs::Push(push_args) => self.stash_push(push_args),
+            StashArgs::List(StashListArgs {}) => {
+                // TODO log this somehow instead of just printlning it.
+                let blocking = self.get_stash().map(|entries| {
+                    for (i, entry) in entries.iter().enumerate() {
+                        let workspace = if entry.has_workspace() {
+                            " (with workspace)"
+                        } else {
+                            ""
+                        };
+                        println!("stash@{}: {} {}{}", i, entry.timestamp, entry.message, workspace);
+                    }
+                });
+
+                StashOut {
+                    blocking: Box::new(blocking.into_future()),
+                }
+            }
+            StashArgs::Apply(apply_args) => self.stash_apply(apply_args.index, false),
+            StashArgs::Pop(apply_args) => self.stash_apply(apply_args.index, true),
+            StashArgs::Drop(StashDropArgs { index }) => {
+                let blocking = self.get_stash().and_then(|mut entries| {
+                    ensure!(index < entries.len(), "No stash entry at index {}", index);
+                    entries.remove(index);
+                    self.set_stash(&entries)
+                });
+
+                StashOut {
+                    blocking: Box::new(blocking.into_future()),
+                }
+            }
+        }
+    }
+
+    pub fn stash_push<'r>(&'r mut self, args: StashPushArgs) -> StashOut<'r> {
+        let blocking = async_block! {
+            let state = self.get_state()?;
+            let head_ref = await!(plumbing::resolve_head_opt(self))?
+                .ok_or_else(|| format_err!("No previous commit to reset to; nothing to stash!"))?;
+            let head_tree = await!(self.tree_of(&head_ref))?;
+
+            let candidate = state.candidate.clone().unwrap_or_else(|| head_tree.clone());
+            let workspace = if args.workspace {
+                match await!(self.process((*self.path).clone(), ObjectPath::new()))? {
+                    Some(ObjectRef::Tree(tree_ref)) => Some(tree_ref),
+                    _ => bail!("Local workspace is not a directory!"),
+                }
+            } else {
+                None
+            };
+
+            ensure!(
+                candidate != head_tree || workspace.as_ref().map_or(false, |ws| ws != &head_tree),
+                "No changes to stash!"
+            );
+
+            let message = match args.message {
+                Some(message) => message,
+                None => match state.head {
+                    Head::Branch(ref branch) => format!("WIP on {}", branch),
+                    _ => "WIP on detached HEAD".to_owned(),
+                },
+            };
+
+            let mut inputs = CommitTree::new(candidate, Some(head_ref));
+            inputs.message = Some(message.clone());
+            let (commit_ref, commit) = await!(plumbing::commit::commit_tree(self, inputs))?;
+            let timestamp = commit.as_timestamp().to_rfc3339();
+
+            let commit_id = await!(commit_ref.id())?.into_inner();
+            let workspace_id = match workspace {
+                Some(ref tree_ref) => Some(await!(tree_ref.id())?.into_inner()),
+                None => None,
+            };
+
+            let mut entries = self.get_stash()?;
+            entries.insert(
+                0,
+                StashEntry {
+                    commit_id,
+                    workspace_id,
+                    message,
+                    timestamp,
+                },
+            );
+            self.set_stash(&entries)?;
+
+            if workspace.is_some() {
+                await!(plumbing::checkout::checkout_path_from_tree(
+                    self,
+                    head_tree.clone(),
+                    ObjectPath::new(),
+                ))?;
+            }
+
+            // The stashed virtual workspace takes any intent-to-add placeholders with it.
+            self.set_state(&State {
+                candidate: Some(head_tree),
+                intent_to_add: Default::default(),
+                ..state
+            })?;
+
+            Ok(())
+        };
+
+        StashOut {
+            blocking: Box::new(blocking),
+        }
+    }
+
+    /// Restore a stash entry on top of the current virtual workspace (and, if the entry has one,
+    /// the local workspace.) The changes are re-applied as a staged batch; if any path stashed
+    /// has also been changed in the virtual workspace since, nothing is applied and the
+    /// conflicting paths are reported. If `pop` is set, the entry is dropped once applied.
+    pub fn stash_apply<'r>(&'r mut self, index: usize, pop: bool) -> StashOut<'r> {
+        let blocking = async_block! {
+            let mut entries = self.get_stash()?;
+            ensure!(index < entries.len(), "No stash entry at index {}", index);
+
+            let (commit_ref, maybe_workspace_tree) = await!(entries[index].resolve(&self.store))?;
+            let commit = await!(commit_ref.fetch())?;
+            let base_ref = commit
+                .as_parents()
+                .first()
+                .cloned()
+                .ok_or_else(|| format_err!("Stashed commit has no base commit!"))?;
+            let base_tree = await!(self.tree_of(&base_ref))?;
+            let stash_tree = commit.as_subtree().clone();
+
+            // As in `stash_push`, a missing virtual workspace is the same as HEAD's tree.
+            let current_tree = match self.get_state()?.candidate {
+                Some(candidate) => candidate,
+                None => match await!(self.head_tree())? {
+                    Some(head_tree) => head_tree,
+                    None => await!(TreeBuilder::new().as_tree().send(&self.store))?,
+                },
+            };
+
+            let base = self.hierarchy(base_tree.clone())?;
+            let current = self.hierarchy(current_tree)?;
+
+            // Apply to the virtual workspace whatever changed between the base commit and the
+            // stashed tree, provided that the virtual workspace hasn't since diverged from both.
+            let mut conflicts = Vec::new();
+            let mut object_batch = ObjectBatch::<B>::new();
+            let stashed = self.hierarchy(stash_tree.clone())?;
+            let changes = await!(Self::compare_subtrees(base_tree.clone(), stash_tree).collect())?;
+            for change in changes {
+                let path = change.as_path().clone();
+                let (base_obj, stashed_obj, current_obj) = await!(
+                    base.get(path.clone())
+                        .join3(stashed.get(path.clone()), current.get(path.clone()))
+                )?;
+
+                if current_obj != base_obj && current_obj != stashed_obj {
+                    conflicts.push(path);
+                    continue;
+                }
+
+                let operation = match stashed_obj {
+                    Some(objref) => ObjectOperation::Add(path, objref),
+                    None => ObjectOperation::Delete(path),
+                };
+                object_batch = object_batch.add(operation)?;
+            }
+
+            // Changes to the local workspace are checked the same way, against the virtual
+            // workspace, since we can't know the state of local files without hashing them.
+            let mut workspace_changes = Vec::new();
+            if let Some(workspace_tree) = maybe_workspace_tree {
+                let workspace = self.hierarchy(workspace_tree.clone())?;
+                let changes =
+                    await!(Self::compare_subtrees(base_tree, workspace_tree).collect())?;
+                for change in changes {
+                    let path = change.as_path().clone();
+                    let (base_obj, workspace_obj, current_obj) = await!(
+                        base.get(path.clone())
+                            .join3(workspace.get(path.clone()), current.get(path.clone()))
+                    )?;
+
+                    if current_obj != base_obj && current_obj != workspace_obj {
+                        conflicts.push(path);
+                        continue;
+                    }
+
+                    workspace_changes.push((path, workspace_obj));
+                }
+            }
+
+            if !conflicts.is_empty() {
+                conflicts.sort();
+                conflicts.dedup();
+                let listing = conflicts
+                    .iter()
+                    .map(|path| format!("\n    {}", path))
+                    .collect::<String>();
+                bail!(
+                    "Stash entry {} conflicts with changes in the virtual workspace; nothing was \
+                     applied. Conflicting paths:{}",
+                    index,
+                    listing
+                );
+            }
+
+            // The local files about to be overwritten or removed must match the virtual
+            // workspace, or whatever the user changed in them would be lost.
+            let affected = workspace_changes.iter().map(|&(ref path, _)| path.clone()).collect();
+            await!(self.ensure_unchanged_locally(affected, "applying the stash"))?;
+
+            let mut checkouts = Vec::new();
+            for (path, maybe_objref) in workspace_changes {
+                match maybe_objref {
+                    Some(objref) => checkouts.push((path, objref)),
+                    None => remove_local(self, &path)?,
+                }
+            }
+            await!(plumbing::checkout::checkout_paths_from_objects(self, checkouts))?;
+
+            await!(self.stage_objects(object_batch)).context("Error while staging stashed changes")?;
+
+            if pop {
+                entries.remove(index);
+                self.set_stash(&entries)?;
+            }
+
+            Ok(())
+        };
+
+        StashOut {
+            blocking: Box::new(blocking),
+        }
+    }
+}
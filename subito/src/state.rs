@@ -1,6 +1,6 @@
-use std::{collections::HashMap, io::{BufRead, Write}};
+use std::{collections::{BTreeSet, HashMap}, io::{BufRead, Write}, path::Path};
 
-use attaca::{digest::prelude::*, object::{CommitRef, TreeRef}, store::prelude::*};
+use attaca::{digest::prelude::*, object::{CommitRef, TreeRef}, path::ObjectPath, store::prelude::*};
 use capnp::{message, serialize_packed};
 use failure::*;
 use futures::{stream, prelude::*};
@@ -50,11 +50,176 @@ impl<H> Head<H> {
     }
 }
 
+/// A merge in progress. This is kept in the repository state so that conflicts can be resolved
+/// over several commands; it is cleared by committing or by aborting the merge.
+#[derive(Debug, Clone)]
+pub struct MergeState<H> {
+    /// The commit which was HEAD when the merge began.
+    pub ours: CommitRef<H>,
+
+    /// The commit being merged into HEAD.
+    pub theirs: CommitRef<H>,
+
+    /// The merge base of `ours` and `theirs`, if they have any common ancestor.
+    pub base: Option<CommitRef<H>>,
+
+    /// The virtual workspace as it was before the merge began, restored by aborting the merge.
+    pub candidate: Option<TreeRef<H>>,
+
+    /// Paths which were changed differently on both sides and have not yet been staged.
+    pub conflicts: BTreeSet<ObjectPath>,
+}
+
+impl<H> MergeState<H> {
+    pub fn is_resolved(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+
+    /// Mark every conflict at or beneath `path` as resolved.
+    pub fn resolve(&mut self, path: &ObjectPath) {
+        self.conflicts.retain(|conflict| !conflict.starts_with(path));
+    }
+}
+
+impl<B: Backend> MergeState<Handle<B>> {
+    fn id(self) -> impl Future<Item = MergeState<OwnedLocalId<B>>, Error = Error> {
+        let MergeState {
+            ours,
+            theirs,
+            base,
+            candidate,
+            conflicts,
+        } = self;
+
+        async_block! {
+            let ours = await!(ours.id())?;
+            let theirs = await!(theirs.id())?;
+            let base = match base {
+                Some(base) => Some(await!(base.id())?),
+                None => None,
+            };
+            let candidate = match candidate {
+                Some(candidate) => Some(await!(candidate.id())?),
+                None => None,
+            };
+
+            Ok(MergeState {
+                ours,
+                theirs,
+                base,
+                candidate,
+                conflicts,
+            })
+        }
+    }
+}
+
+#[async]
+fn resolve_merge<B: Backend>(
+    raw: MergeState<OwnedLocalId<B>>,
+    store: Store<B>,
+) -> Result<MergeState<Handle<B>>, Error> {
+    let missing = || format_err!("Missing merge ref!");
+    let ours = await!(raw.ours.resolve_id(&store))?.ok_or_else(&missing)?;
+    let theirs = await!(raw.theirs.resolve_id(&store))?.ok_or_else(&missing)?;
+    let base = match raw.base {
+        Some(base) => Some(await!(base.resolve_id(&store))?.ok_or_else(&missing)?),
+        None => None,
+    };
+    let candidate = match raw.candidate {
+        Some(candidate) => Some(await!(candidate.resolve_id(&store))?.ok_or_else(&missing)?),
+        None => None,
+    };
+
+    Ok(MergeState {
+        ours,
+        theirs,
+        base,
+        candidate,
+        conflicts: raw.conflicts,
+    })
+}
+
+/// A rebase in progress. While the commits being rebased are replayed, HEAD is detached at the
+/// last of them to have been replayed; a replay which conflicts is left as a merge in progress
+/// (with `theirs` being the commit replayed) and the rebase stops until it is continued or
+/// aborted.
+#[derive(Debug, Clone)]
+pub struct RebaseState<H> {
+    /// The commit which the rebased commits are replayed on top of.
+    pub onto: CommitRef<H>,
+
+    /// The commit which was HEAD when the rebase began, restored by aborting the rebase.
+    pub orig_head: CommitRef<H>,
+
+    /// The branch being rebased, which is moved to the last replayed commit once the rebase is
+    /// finished, or `None` if HEAD was detached.
+    pub branch: Option<Name>,
+
+    /// Commits yet to be replayed, oldest first.
+    pub todo: Vec<CommitRef<H>>,
+}
+
+impl<B: Backend> RebaseState<Handle<B>> {
+    fn id(self) -> impl Future<Item = RebaseState<OwnedLocalId<B>>, Error = Error> {
+        let RebaseState {
+            onto,
+            orig_head,
+            branch,
+            todo,
+        } = self;
+
+        async_block! {
+            let onto = await!(onto.id())?;
+            let orig_head = await!(orig_head.id())?;
+            let mut todo_ids = Vec::new();
+            for commit_ref in todo {
+                todo_ids.push(await!(commit_ref.id())?);
+            }
+
+            Ok(RebaseState {
+                onto,
+                orig_head,
+                branch,
+                todo: todo_ids,
+            })
+        }
+    }
+}
+
+#[async]
+fn resolve_rebase<B: Backend>(
+    raw: RebaseState<OwnedLocalId<B>>,
+    store: Store<B>,
+) -> Result<RebaseState<Handle<B>>, Error> {
+    let missing = || format_err!("Missing rebase ref!");
+    let onto = await!(raw.onto.resolve_id(&store))?.ok_or_else(&missing)?;
+    let orig_head = await!(raw.orig_head.resolve_id(&store))?.ok_or_else(&missing)?;
+    let mut todo = Vec::new();
+    for commit_id in raw.todo {
+        todo.push(await!(commit_id.resolve_id(&store))?.ok_or_else(&missing)?);
+    }
+
+    Ok(RebaseState {
+        onto,
+        orig_head,
+        branch: raw.branch,
+        todo,
+    })
+}
+
 #[derive(Debug, Clone)]
 pub struct State<H> {
     pub candidate: Option<TreeRef<H>>,
     pub head: Head<H>,
     pub remote_refs: HashMap<Name, HashMap<Name, CommitRef<H>>>,
+    pub merge: Option<MergeState<H>>,
+
+    /// Paths marked with `stage --intent-to-add`. Each has an empty placeholder in the virtual
+    /// workspace until its contents are staged.
+    pub intent_to_add: BTreeSet<ObjectPath>,
+
+    pub rebase: Option<RebaseState<H>>,
 }
 
 impl<H> Default for State<H> {
@@ -63,10 +228,20 @@ impl<H> Default for State<H> {
             candidate: None,
             head: Head::Empty,
             remote_refs: HashMap::new(),
+            merge: None,
+            intent_to_add: BTreeSet::new(),
+            rebase: None,
         }
     }
 }
 
+impl<H> State<H> {
+    /// Clear any intent-to-add marks at or beneath `path`, once something has been staged there.
+    pub fn resolve_intent(&mut self, path: &ObjectPath) {
+        self.intent_to_add.retain(|intent| !intent.starts_with(path));
+    }
+}
+
 #[async]
 fn resolve_refs<B: Backend>(
     raw: Vec<(Name, Vec<(Name, OwnedLocalId<B>)>)>,
@@ -92,12 +267,15 @@ impl<B: Backend> State<Handle<B>> {
     where
         R: BufRead,
     {
-        use state_capnp::state::{self, candidate, head};
+        use state_capnp::{merge_state, rebase_state, state::{self, candidate, head, merge, rebase}};
 
         async_block! {
             let future_head;
             let future_candidate;
             let future_remote_refs;
+            let future_merge;
+            let future_rebase;
+            let intent_to_add;
 
             {
                 let message_reader =
@@ -137,6 +315,70 @@ impl<B: Backend> State<Handle<B>> {
                     })
                     .collect::<Result<Vec<_>, Error>>()?;
 
+                let merge_id = match state.get_merge().which()? {
+                    merge::None(()) => None,
+                    merge::Some(merge_res) => {
+                        let merge = merge_res?;
+                        let base = match merge.get_base().which()? {
+                            merge_state::base::None(()) => None,
+                            merge_state::base::Some(bytes_res) => {
+                                Some(CommitRef::new(LocalId::<B>::from_bytes(bytes_res?)))
+                            }
+                        };
+                        let candidate = match merge.get_candidate().which()? {
+                            merge_state::candidate::None(()) => None,
+                            merge_state::candidate::Some(bytes_res) => {
+                                Some(TreeRef::new(LocalId::<B>::from_bytes(bytes_res?)))
+                            }
+                        };
+                        let conflicts = merge
+                            .get_conflicts()?
+                            .iter()
+                            .map(|text_res| Ok(ObjectPath::from_path(Path::new(text_res?))?))
+                            .collect::<Result<BTreeSet<_>, Error>>()?;
+
+                        Some(MergeState {
+                            ours: CommitRef::new(LocalId::<B>::from_bytes(merge.get_ours()?)),
+                            theirs: CommitRef::new(LocalId::<B>::from_bytes(merge.get_theirs()?)),
+                            base,
+                            candidate,
+                            conflicts,
+                        })
+                    }
+                };
+
+                let rebase_id = match state.get_rebase().which()? {
+                    rebase::None(()) => None,
+                    rebase::Some(rebase_res) => {
+                        let rebase = rebase_res?;
+                        let branch = match rebase.get_branch().which()? {
+                            rebase_state::branch::None(()) => None,
+                            rebase_state::branch::Some(name) => Some(name?.parse()?),
+                        };
+                        let todo = rebase
+                            .get_todo()?
+                            .iter()
+                            .map(|bytes_res| {
+                                Ok(CommitRef::new(LocalId::<B>::from_bytes(bytes_res?)))
+                            })
+                            .collect::<Result<Vec<_>, Error>>()?;
+                        let orig_head = LocalId::<B>::from_bytes(rebase.get_orig_head()?);
+
+                        Some(RebaseState {
+                            onto: CommitRef::new(LocalId::<B>::from_bytes(rebase.get_onto()?)),
+                            orig_head: CommitRef::new(orig_head),
+                            branch,
+                            todo,
+                        })
+                    }
+                };
+
+                intent_to_add = state
+                    .get_intent_to_add()?
+                    .iter()
+                    .map(|text_res| Ok(ObjectPath::from_path(Path::new(text_res?))?))
+                    .collect::<Result<BTreeSet<_>, Error>>()?;
+
                 future_head = head_id
                     .resolve_id(&store)
                     .and_then(|rs| rs.ok_or_else(|| format_err!("Head does not exist!")));
@@ -145,15 +387,24 @@ impl<B: Backend> State<Handle<B>> {
                         .and_then(|rs| rs.ok_or_else(|| format_err!("Candidate does not exist!")))
                 });
                 future_remote_refs = resolve_refs(remote_refs, store.clone());
+                future_merge = merge_id.map(|raw| resolve_merge(raw, store.clone()));
+                future_rebase = rebase_id.map(|raw| resolve_rebase(raw, store.clone()));
             }
 
-            let (candidate, head, remote_refs) =
-                await!(future_candidate.join3(future_head, future_remote_refs))?;
+            let (candidate, head, remote_refs, merge, rebase) = await!(future_candidate.join5(
+                future_head,
+                future_remote_refs,
+                future_merge,
+                future_rebase
+            ))?;
 
             Ok(State {
                 candidate,
                 head,
                 remote_refs,
+                merge,
+                intent_to_add,
+                rebase,
             })
         }
     }
@@ -187,6 +438,16 @@ impl<B: Backend> State<Handle<B>> {
                 remote_refs.insert(remote_name, branches);
             }
 
+            let merge = match state.merge {
+                Some(merge_state) => Some(await!(merge_state.id())?),
+                None => None,
+            };
+
+            let rebase = match state.rebase {
+                Some(rebase_state) => Some(await!(rebase_state.id())?),
+                None => None,
+            };
+
             let mut message = message::Builder::new_default();
 
             {
@@ -207,7 +468,9 @@ impl<B: Backend> State<Handle<B>> {
                         use std::borrow::Borrow;
                         match head {
                             Head::Empty => head_builder.set_empty(()),
-                            Head::Detached(id) => head_builder.set_detached(id.as_inner().borrow().as_bytes()),
+                            Head::Detached(id) => {
+                                head_builder.set_detached(id.as_inner().borrow().as_bytes())
+                            }
                             Head::Branch(branch) => head_builder.set_branch(&*branch),
                         }
                     }
@@ -218,12 +481,92 @@ impl<B: Backend> State<Handle<B>> {
                     for (i, (remote_name, branches)) in remote_refs.into_iter().enumerate() {
                         let mut remote_builder = remote_refs_builder.borrow().get(i as u32);
                         remote_builder.set_name(&remote_name);
-                        let mut remote_branches_builder = remote_builder.init_branches(branches.len() as u32);
+                        let mut remote_branches_builder =
+                            remote_builder.init_branches(branches.len() as u32);
                         for (i, (branch_name, commit_id)) in branches.into_iter().enumerate() {
-                            let mut branch_builder = remote_branches_builder.borrow().get(i as u32);
+                            let mut branch_builder =
+                                remote_branches_builder.borrow().get(i as u32);
                             branch_builder.set_name(&branch_name);
-                            branch_builder.set_commit_id({ use std::borrow::Borrow; commit_id.borrow().as_bytes() });
+                            branch_builder.set_commit_id({
+                                use std::borrow::Borrow;
+                                commit_id.borrow().as_bytes()
+                            });
+                        }
+                    }
+                }
+                {
+                    use std::borrow::Borrow;
+
+                    let mut merge_builder = state_builder.borrow().get_merge();
+                    match merge {
+                        Some(merge_state) => {
+                            let mut merge_state_builder = merge_builder.init_some();
+                            let ours = merge_state.ours.as_inner().borrow().as_bytes();
+                            merge_state_builder.set_ours(ours);
+                            let theirs = merge_state.theirs.as_inner().borrow().as_bytes();
+                            merge_state_builder.set_theirs(theirs);
+                            {
+                                let mut base_builder = merge_state_builder.borrow().get_base();
+                                match merge_state.base {
+                                    Some(id) => {
+                                        base_builder.set_some(id.as_inner().borrow().as_bytes())
+                                    }
+                                    None => base_builder.set_none(()),
+                                }
+                            }
+                            {
+                                let mut candidate_builder =
+                                    merge_state_builder.borrow().get_candidate();
+                                match merge_state.candidate {
+                                    Some(id) => candidate_builder
+                                        .set_some(id.as_inner().borrow().as_bytes()),
+                                    None => candidate_builder.set_none(()),
+                                }
+                            }
+                            let conflicts_len = merge_state.conflicts.len() as u32;
+                            let mut conflicts_builder =
+                                merge_state_builder.init_conflicts(conflicts_len);
+                            for (i, conflict) in merge_state.conflicts.iter().enumerate() {
+                                conflicts_builder.set(i as u32, &conflict.to_string());
+                            }
+                        }
+                        None => merge_builder.set_none(()),
+                    }
+                }
+                {
+                    let mut intent_builder =
+                        state_builder.init_intent_to_add(state.intent_to_add.len() as u32);
+                    for (i, path) in state.intent_to_add.iter().enumerate() {
+                        intent_builder.set(i as u32, &path.to_string());
+                    }
+                }
+                {
+                    use std::borrow::Borrow;
+
+                    let mut rebase_builder = state_builder.borrow().get_rebase();
+                    match rebase {
+                        Some(rebase_state) => {
+                            let mut rebase_state_builder = rebase_builder.init_some();
+                            let onto = rebase_state.onto.as_inner().borrow().as_bytes();
+                            rebase_state_builder.set_onto(onto);
+                            let orig_head = rebase_state.orig_head.as_inner().borrow().as_bytes();
+                            rebase_state_builder.set_orig_head(orig_head);
+                            {
+                                let mut branch_builder =
+                                    rebase_state_builder.borrow().get_branch();
+                                match rebase_state.branch {
+                                    Some(branch) => branch_builder.set_some(&*branch),
+                                    None => branch_builder.set_none(()),
+                                }
+                            }
+                            let mut todo_builder =
+                                rebase_state_builder.init_todo(rebase_state.todo.len() as u32);
+                            for (i, commit_id) in rebase_state.todo.iter().enumerate() {
+                                let id = commit_id.as_inner().borrow().as_bytes();
+                                todo_builder.set(i as u32, id);
+                            }
                         }
+                        None => rebase_builder.set_none(()),
                     }
                 }
             }
@@ -1,4 +1,4 @@
-use std::{fmt, borrow::Borrow};
+use std::{fmt, borrow::Borrow, collections::BTreeMap, io::{self, Write}};
 
 use attaca::{digest::prelude::*, object::{CommitRef, ObjectRef, TreeRef}, path::ObjectPath,
              store::prelude::*};
@@ -8,28 +8,168 @@ use hex;
 use itertools::{EitherOrBoth, Itertools};
 
 use Repository;
+use abbrev::Abbreviator;
+use diff_workspace::{DiffWorkspaceArgs, WorkspaceStatus};
+use plumbing;
+use refs;
 use state::{Head, State};
+use syntax::Name;
+use xattrs;
+
+/// Version of the `--porcelain` output format.
+///
+/// The output begins with a header line, `# porcelain v1`, naming the version. A script may ask for
+/// a particular version with `--porcelain-version`, which fails if this version of `subito` can't
+/// produce it.
+///
+/// After the header, version 1 emits one record per changed path, in path order. Each record is a
+/// two-character code `XY`, a single space, and the path, followed by a terminator (`\n`, or `\0`
+/// if `-z` is given.) `X` is the status of the path in the virtual workspace relative to the
+/// previous commit and `Y` is the status of the path in the local workspace relative to the
+/// virtual workspace. Each is one of:
+///
+/// - `' '`: unmodified
+/// - `A`: added
+/// - `M`: modified
+/// - `D`: deleted
+/// - `R`: renamed
+///
+/// A path marked with `stage --intent-to-add` has the code ` A`: its placeholder is in the virtual
+/// workspace, but its contents have not been staged.
+///
+/// A file removed from one path and added at another with the same contents is a rename. For
+/// renames, the record is `R  <to>` followed by `" -> <from>"` when newline-terminated, or by a
+/// terminator and `<from>` when NUL-terminated.
+///
+/// Paths are always `/`-separated and relative to the repository root. In newline-terminated
+/// records, a path is quoted if it needs to be (see `quote_path`); NUL-terminated records never
+/// quote paths. Any change to this format will bump the version.
+pub const PORCELAIN_VERSION: u32 = 1;
+
+/// The header line which begins the output of `status --short`.
+///
+/// The header is `## ` followed by one of:
+///
+/// - `<branch>` for a branch which follows no remote-tracking branch (see
+///   `Repository::upstream_of`);
+/// - `<branch>...<remote>/<branch>` for a branch which does, followed by ` [ahead N]`,
+///   ` [behind M]` or ` [ahead N, behind M]` unless the two are at the same commit;
+/// - `HEAD (detached at <commit>)`, with the commit abbreviated, when HEAD is detached;
+/// - `HEAD (no commits yet)` in a repository with no HEAD at all.
+///
+/// The records after the header are those of `--porcelain` (see `PORCELAIN_VERSION`),
+/// newline-terminated.
+pub fn write_branch_header<W: Write>(
+    mut writer: W,
+    head: &Head<String>,
+    tracking: Option<&TrackingStatus>,
+    abbreviator: &Abbreviator,
+) -> io::Result<()> {
+    match *head {
+        Head::Empty => writeln!(writer, "## HEAD (no commits yet)"),
+        Head::Detached(ref commit_ref) => writeln!(
+            writer,
+            "## HEAD (detached at {})",
+            abbreviator.abbreviate(commit_ref.as_inner())
+        ),
+        Head::Branch(ref branch) => {
+            write!(writer, "## {}", branch)?;
+            if let Some(tracking) = tracking {
+                write!(writer, "...{}/{}", tracking.remote, tracking.remote_branch)?;
+                match (tracking.ahead, tracking.behind) {
+                    (0, 0) => {}
+                    (ahead, 0) => write!(writer, " [ahead {}]", ahead)?,
+                    (0, behind) => write!(writer, " [behind {}]", behind)?,
+                    (ahead, behind) => write!(writer, " [ahead {}, behind {}]", ahead, behind)?,
+                }
+            }
+            writeln!(writer)
+        }
+    }
+}
 
 /// Compare the virtual workspace to the previous commit.
 #[derive(Default, Debug, StructOpt, Builder)]
 #[structopt(name = "status")]
-pub struct StatusArgs {}
+pub struct StatusArgs {
+    /// Give the output in a stable, easy-to-parse format for scripts. See `PORCELAIN_VERSION`.
+    #[structopt(long = "porcelain")]
+    pub porcelain: bool,
+
+    /// The version of the `--porcelain` format to give the output in. Only the latest version, 1,
+    /// is supported; asking for it makes sure that a script notices if that ever changes.
+    #[structopt(long = "porcelain-version", name = "VERSION",
+                raw(requires = r#""porcelain""#))]
+    pub porcelain_version: Option<u32>,
+
+    /// Give the output in the short format: a header line with the branch and how far it is ahead
+    /// of or behind its upstream, followed by the records of `--porcelain`. See
+    /// `write_branch_header`.
+    #[structopt(short = "s", long = "short", raw(conflicts_with = r#""porcelain""#))]
+    pub short: bool,
+
+    /// Terminate porcelain records with NUL instead of newline.
+    #[structopt(short = "z", raw(requires = r#""porcelain""#))]
+    pub nul_terminated: bool,
+}
 
 #[must_use = "StatusOut contains futures which must be driven to completion!"]
 pub struct StatusOut<'r> {
     pub head: Box<Future<Item = Head<String>, Error = Error> + 'r>,
     pub candidate: Box<Future<Item = Option<String>, Error = Error> + 'r>,
-    pub staged: Box<Stream<Item = Change, Error = Error> + 'r>,
+
+    /// The changes staged in the virtual workspace, with renames detected, in path order.
+    pub staged: Box<Stream<Item = StagedRecord, Error = Error> + 'r>,
+    pub merge: Box<Future<Item = Option<MergeStatus>, Error = Error> + 'r>,
+
+    /// Paths marked with intent-to-add whose contents have not yet been staged. These are not
+    /// included in `staged`.
+    pub intent_to_add: Box<Future<Item = Vec<ObjectPath>, Error = Error> + 'r>,
+
+    /// How the current branch compares to its upstream, if HEAD is on a branch which has one.
+    pub tracking: Box<Future<Item = Option<TrackingStatus>, Error = Error> + 'r>,
+
+    /// Every path which differs between the previous commit, the virtual workspace and the local
+    /// workspace, in path order. The local workspace is only walked if this is polled.
+    pub entries: Box<Stream<Item = (ObjectPath, PathStatus), Error = Error> + 'r>,
 }
 
 impl<'r> fmt::Debug for StatusOut<'r> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("StatusOut")
             .field("staged", &"OPAQUE")
+            .field("merge", &"OPAQUE")
+            .field("intent_to_add", &"OPAQUE")
+            .field("tracking", &"OPAQUE")
+            .field("entries", &"OPAQUE")
             .finish()
     }
 }
 
+/// A summary of the merge in progress, if any.
+#[derive(Debug, Clone)]
+pub struct MergeStatus {
+    /// The hex digest of the commit being merged into HEAD.
+    pub theirs: String,
+
+    /// Conflicted paths which have not yet been staged.
+    pub conflicts: Vec<ObjectPath>,
+}
+
+/// How a branch compares to the remote-tracking branch it follows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackingStatus {
+    pub branch: Name,
+    pub remote: Name,
+    pub remote_branch: Name,
+
+    /// Commits on the branch which are not on the remote-tracking branch.
+    pub ahead: usize,
+
+    /// Commits on the remote-tracking branch which are not on the branch.
+    pub behind: usize,
+}
+
 #[derive(Debug, Clone)]
 pub enum Change {
     Added(ObjectPath),
@@ -37,8 +177,280 @@ pub enum Change {
     Removed(ObjectPath),
 }
 
+impl Change {
+    pub fn as_path(&self) -> &ObjectPath {
+        match *self {
+            Change::Added(ref path) | Change::Modified(ref path) | Change::Removed(ref path) => {
+                path
+            }
+        }
+    }
+
+    /// The single-character porcelain code for this change.
+    pub fn code(&self) -> char {
+        match *self {
+            Change::Added(_) => 'A',
+            Change::Modified(_) => 'M',
+            Change::Removed(_) => 'D',
+        }
+    }
+
+    /// Write this change as a version 1 porcelain record for a staged change. See
+    /// `PORCELAIN_VERSION` for a description of the format.
+    pub fn write_porcelain<W: Write>(&self, mut writer: W, nul_terminated: bool) -> io::Result<()> {
+        if nul_terminated {
+            write!(writer, "{}  {}\0", self.code(), self.as_path())
+        } else {
+            writeln!(writer, "{}  {}", self.code(), quote_path(self.as_path()))
+        }
+    }
+}
+
+/// A staged change as `status` reports it: either a change to a single path, or a file which was
+/// moved from one path to another with its contents unchanged.
+#[derive(Debug, Clone)]
+pub enum StagedRecord {
+    Change(Change),
+    Renamed { from: ObjectPath, to: ObjectPath },
+}
+
+impl StagedRecord {
+    /// The path this record is reported under; for a rename, the path the file was moved to.
+    pub fn as_path(&self) -> &ObjectPath {
+        match *self {
+            StagedRecord::Change(ref change) => change.as_path(),
+            StagedRecord::Renamed { ref to, .. } => to,
+        }
+    }
+
+    /// Write this record as a version 1 porcelain record. See `PORCELAIN_VERSION` for a
+    /// description of the format.
+    pub fn write_porcelain<W: Write>(&self, mut writer: W, nul_terminated: bool) -> io::Result<()> {
+        match *self {
+            StagedRecord::Change(ref change) => change.write_porcelain(writer, nul_terminated),
+            StagedRecord::Renamed { ref from, ref to } if nul_terminated => {
+                write!(writer, "R  {}\0{}\0", to, from)
+            }
+            StagedRecord::Renamed { ref from, ref to } => {
+                writeln!(writer, "R  {} -> {}", quote_path(to), quote_path(from))
+            }
+        }
+    }
+}
+
+/// Quote a path for a newline-terminated porcelain record, if it needs to be. A path containing a
+/// double quote, a backslash, a control character, or ` -> ` (which would make a rename record
+/// ambiguous) is written in double quotes, with `"` and `\` escaped by a backslash and control
+/// characters written as `\n`, `\t` or three-digit octal escapes. Any other path is written as it
+/// is.
+pub fn quote_path(path: &ObjectPath) -> String {
+    let path = path.to_string();
+    let needs_quotes = path.contains(" -> ")
+        || path.chars().any(|c| c == '"' || c == '\\' || c.is_control());
+    if !needs_quotes {
+        return path;
+    }
+
+    let mut quoted = String::with_capacity(path.len() + 2);
+    quoted.push('"');
+    for c in path.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let mut buf = [0; 4];
+                for byte in c.encode_utf8(&mut buf).bytes() {
+                    quoted.push_str(&format!("\\{:03o}", byte));
+                }
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// Write the header line which begins `--porcelain` output. See `PORCELAIN_VERSION`.
+pub fn write_porcelain_header<W: Write>(mut writer: W, nul_terminated: bool) -> io::Result<()> {
+    let terminator = if nul_terminated { '\0' } else { '\n' };
+    write!(writer, "# porcelain v{}{}", PORCELAIN_VERSION, terminator)
+}
+
+/// How a path differs between the previous commit, the virtual workspace and the local workspace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathStatus {
+    /// Changed in the virtual workspace, and the same locally: ready to be committed.
+    Staged,
+
+    /// Changed in the virtual workspace, and changed again locally since it was staged.
+    StagedAndModified,
+
+    /// Unchanged in the virtual workspace, but changed locally.
+    Modified,
+
+    /// Present locally, but neither staged nor in the previous commit.
+    Untracked,
+
+    /// In the virtual workspace, but missing locally.
+    Deleted,
+
+    /// In the previous commit, when nothing at all has been staged: with no virtual workspace,
+    /// every path of the previous commit would be unstaged.
+    WouldBeUnstaged,
+}
+
+impl PathStatus {
+    /// Classify a path from its staged change, if any, and its state in the local workspace
+    /// relative to the virtual workspace, if it's anywhere locally or staged. Returns `None` for a
+    /// path which is the same everywhere.
+    pub fn classify(staged: Option<&Change>, local: Option<WorkspaceStatus>) -> Option<Self> {
+        match (staged, local) {
+            (Some(_), None) | (Some(_), Some(WorkspaceStatus::Unchanged)) => {
+                Some(PathStatus::Staged)
+            }
+            (Some(_), Some(WorkspaceStatus::Added))
+            | (Some(_), Some(WorkspaceStatus::Modified)) => Some(PathStatus::StagedAndModified),
+            (_, Some(WorkspaceStatus::Deleted)) => Some(PathStatus::Deleted),
+            (None, Some(WorkspaceStatus::Added)) => Some(PathStatus::Untracked),
+            (None, Some(WorkspaceStatus::Modified)) => Some(PathStatus::Modified),
+            (None, Some(WorkspaceStatus::Unchanged)) | (None, None) => None,
+        }
+    }
+}
+
+impl fmt::Display for PathStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match *self {
+            PathStatus::Staged => "staged",
+            PathStatus::StagedAndModified => "staged, then modified",
+            PathStatus::Modified => "modified",
+            PathStatus::Untracked => "untracked",
+            PathStatus::Deleted => "deleted",
+            PathStatus::WouldBeUnstaged => "would be unstaged",
+        };
+        f.pad(description)
+    }
+}
+
+/// Write a version 1 porcelain record for a path marked with intent-to-add.
+pub fn write_intent_porcelain<W: Write>(
+    mut writer: W,
+    path: &ObjectPath,
+    nul_terminated: bool,
+) -> io::Result<()> {
+    if nul_terminated {
+        write!(writer, " A {}\0", path)
+    } else {
+        writeln!(writer, " A {}", quote_path(path))
+    }
+}
+
 impl<B: Backend> Repository<B> {
-    pub fn status<'r>(&'r self, _args: StatusArgs) -> StatusOut<'r> {
+    /// Compare a branch to the remote-tracking branch it follows. Returns `None` if it follows
+    /// none, or if the branch has no commits yet.
+    pub fn tracking_status<'r>(
+        &'r self,
+        branch: Name,
+    ) -> Box<Future<Item = Option<TrackingStatus>, Error = Error> + 'r> {
+        let blocking = async_block! {
+            let (remote, remote_branch) = match self.upstream_of(&branch)? {
+                Some(upstream) => upstream,
+                None => return Ok(None),
+            };
+            let local = match await!(plumbing::resolve_local_opt(self, branch.clone()))? {
+                Some(local) => local,
+                None => return Ok(None),
+            };
+            let upstream =
+                await!(plumbing::resolve_remote(self, remote.clone(), remote_branch.clone()))?;
+            let (ahead, behind) = await!(plumbing::merge::ahead_behind(local, upstream))?;
+
+            Ok(Some(TrackingStatus {
+                branch,
+                remote,
+                remote_branch,
+                ahead,
+                behind,
+            }))
+        };
+
+        Box::new(blocking)
+    }
+
+    /// Pair up files removed from HEAD and added to the virtual workspace with the same contents,
+    /// as renames. Each removed file is paired with at most one added file, the first in path
+    /// order; anything left unpaired is reported as it is. Records come out in path order.
+    fn detect_renames<'r>(
+        &'r self,
+        candidate: Option<TreeRef<Handle<B>>>,
+        changes: Vec<Change>,
+    ) -> Box<Future<Item = Vec<StagedRecord>, Error = Error> + 'r> {
+        let blocking = async_block! {
+            let (mut added, mut removed): (Vec<_>, Vec<_>) = changes
+                .iter()
+                .filter_map(|change| match *change {
+                    Change::Added(ref path) => Some((path.clone(), true)),
+                    Change::Removed(ref path) => Some((path.clone(), false)),
+                    Change::Modified(_) => None,
+                })
+                .partition(|&(_, is_added)| is_added);
+
+            let mut renames = Vec::new();
+            let maybe_trees = match (candidate, added.is_empty() || removed.is_empty()) {
+                (Some(candidate), false) => await!(self.head_tree())?.map(|head| (head, candidate)),
+                _ => None,
+            };
+            if let Some((head_tree, candidate)) = maybe_trees {
+                let head = self.hierarchy(head_tree)?;
+                let staged = self.hierarchy(candidate)?;
+                added.sort();
+                removed.sort();
+
+                let mut sources = Vec::new();
+                for (path, _) in removed {
+                    match await!(head.get(path.clone()))? {
+                        Some(ObjectRef::Tree(_)) | Some(ObjectRef::Commit(_)) | None => {}
+                        Some(object_ref) => sources.push((path, object_ref)),
+                    }
+                }
+
+                for (path, _) in added {
+                    let object_ref = match await!(staged.get(path.clone()))? {
+                        Some(ObjectRef::Tree(_)) | Some(ObjectRef::Commit(_)) | None => continue,
+                        Some(object_ref) => object_ref,
+                    };
+                    let maybe_source = sources
+                        .iter()
+                        .position(|&(_, ref source)| *source == object_ref);
+                    if let Some(i) = maybe_source {
+                        let (from, _) = sources.remove(i);
+                        renames.push((from, path));
+                    }
+                }
+            }
+
+            let mut records = changes
+                .into_iter()
+                .filter(|change| match *change {
+                    Change::Added(ref path) => !renames.iter().any(|&(_, ref to)| to == path),
+                    Change::Removed(ref path) => !renames.iter().any(|&(ref from, _)| from == path),
+                    Change::Modified(_) => true,
+                })
+                .map(StagedRecord::Change)
+                .chain(renames.into_iter().map(|(from, to)| StagedRecord::Renamed { from, to }))
+                .collect::<Vec<_>>();
+            records.sort_by(|a, b| a.as_path().cmp(b.as_path()));
+
+            Ok(records)
+        };
+
+        Box::new(blocking)
+    }
+
+    pub fn status<'r>(&'r self, args: StatusArgs) -> StatusOut<'r> {
         let blocking = self.get_state().compat().into_future();
         let shared = blocking.shared();
 
@@ -66,25 +478,142 @@ impl<B: Backend> Repository<B> {
                 Ok(maybe_cand_hex)
             }
         };
+        let merge = {
+            let shared = shared.clone();
+            async_block! {
+                let maybe_merge = await!(shared)?.merge.clone();
+                match maybe_merge {
+                    Some(merge_state) => {
+                        let theirs_id = await!(merge_state.theirs.id())?;
+                        Ok(Some(MergeStatus {
+                            theirs: hex::encode(theirs_id.as_inner().borrow().as_bytes()),
+                            conflicts: merge_state.conflicts.into_iter().collect(),
+                        }))
+                    }
+                    None => Ok(None),
+                }
+            }
+        };
+        let intent_to_add = {
+            let shared = shared.clone();
+            async_block! {
+                Ok(await!(shared)?.intent_to_add.iter().cloned().collect())
+            }
+        };
+        let tracking = {
+            let shared = shared.clone();
+            async_block! {
+                let branch = match await!(shared)?.head {
+                    Head::Branch(ref branch) => branch.clone(),
+                    Head::Empty | Head::Detached(_) => return Ok(None),
+                };
+                await!(self.tracking_status(branch))
+            }
+        };
         let staged = async_stream_block! {
+            if let Some(version) = args.porcelain_version {
+                ensure!(
+                    version == PORCELAIN_VERSION,
+                    "Unsupported porcelain version {}; only version {} is supported.",
+                    version,
+                    PORCELAIN_VERSION
+                );
+            }
+
             let shared_state = await!(shared)?;
+            let intents = shared_state.intent_to_add.clone();
             let staged_changes = Self::staged_changes(self.store.clone(), (*shared_state).clone());
+            let mut changes = Vec::new();
             #[async]
             for change in staged_changes {
-                stream_yield!(change);
+                let is_placeholder = match change {
+                    Change::Added(ref path) => intents.contains(path),
+                    _ => false,
+                };
+
+                if !is_placeholder {
+                    changes.push(change);
+                }
+            }
+
+            let candidate = shared_state.candidate.clone();
+            for record in await!(self.detect_renames(candidate, changes))? {
+                stream_yield!(record);
             }
             Ok(())
         };
 
+        let entries = async_stream_block! {
+            let state = self.get_state()?;
+            let intents = state.intent_to_add.clone();
+
+            // Without a virtual workspace, every staged change is a path of the previous commit
+            // which has simply never been staged, whatever has happened to it locally.
+            let no_candidate = state.candidate.is_none();
+            let classify = move |staged: Option<&Change>, local| match staged {
+                Some(_) if no_candidate => Some(PathStatus::WouldBeUnstaged),
+                _ => PathStatus::classify(staged, local),
+            };
+
+            // Staged changes come out of `staged_changes` in no particular order.
+            let mut staged = BTreeMap::new();
+            #[async]
+            for change in Self::staged_changes(self.store.clone(), state) {
+                let is_placeholder = match change {
+                    Change::Added(ref path) => intents.contains(path),
+                    _ => false,
+                };
+
+                if !is_placeholder {
+                    staged.insert(change.as_path().clone(), change);
+                }
+            }
+
+            let local_entries = self.diff_workspace(DiffWorkspaceArgs::default()).entries;
+            let local = await!(local_entries.collect())?;
+            let merged = Itertools::merge_join_by(
+                staged.into_iter(),
+                local.into_iter(),
+                |&(ref staged_path, _), &(ref local_path, _)| staged_path.cmp(local_path),
+            );
+
+            for either_or_both in merged {
+                let (path, maybe_status) = match either_or_both {
+                    EitherOrBoth::Left((path, change)) => {
+                        let status = classify(Some(&change), None);
+                        (path, status)
+                    }
+                    EitherOrBoth::Right((path, local_status)) => {
+                        let status = classify(None, Some(local_status));
+                        (path, status)
+                    }
+                    EitherOrBoth::Both((path, change), (_, local_status)) => {
+                        let status = classify(Some(&change), Some(local_status));
+                        (path, status)
+                    }
+                };
+
+                if let Some(status) = maybe_status {
+                    stream_yield!((path, status));
+                }
+            }
+
+            Ok(())
+        };
+
         StatusOut {
             head: Box::new(head),
             candidate: Box::new(candidate),
             staged: Box::new(staged),
+            merge: Box::new(merge),
+            intent_to_add: Box::new(intent_to_add),
+            tracking: Box::new(tracking),
+            entries: Box::new(entries),
         }
     }
 
     #[async_stream(item = self::Change)]
-    fn compare_subtrees(
+    pub(crate) fn compare_subtrees(
         head_ref: TreeRef<Handle<B>>,
         candidate_ref: TreeRef<Handle<B>>,
     ) -> Result<(), Error> {
@@ -134,6 +663,10 @@ impl<B: Backend> Repository<B> {
                             // candidate subtree to be commits.
                             (ObjectRef::Commit(_), _) | (_, ObjectRef::Commit(_)) => unreachable!(),
 
+                            // Stores keep a single handle per digest, so equal refs are equal
+                            // trees, and nothing beneath them can differ.
+                            (ObjectRef::Tree(ref head_tree), ObjectRef::Tree(ref cand_tree))
+                                if head_tree == cand_tree => {}
                             (ObjectRef::Tree(head_tree), ObjectRef::Tree(cand_tree)) => {
                                 queue.push(
                                     future::ok(child_path)
@@ -182,13 +715,14 @@ impl<B: Backend> Repository<B> {
         //    the element is possibly modified or simply unchanged.)
         // 2. Both HEAD and the candidate tree are `None`. In this case there are no changes
         //    staged for commit.
-        // 3. HEAD is `Some` but the candidate tree is `None`. This case is absurd.
+        // 3. HEAD is `Some` but the candidate tree is `None`. Nothing at all is staged, so every
+        //    file in HEAD would be removed by the next commit.
         // 4. The candidate tree is `Some` but HEAD is `None`. In this case, everything in the
         //    candidate tree is newly added.
         let maybe_head = match state.head {
             Head::Empty => None,
             Head::Detached(commit_ref) => Some(commit_ref),
-            Head::Branch(branch) => await!(store.load_branches())?
+            Head::Branch(branch) => await!(refs::load_heads(&store))?
                 .get(branch.as_str())
                 .cloned()
                 .map(CommitRef::new),
@@ -200,7 +734,9 @@ impl<B: Backend> Repository<B> {
 
                 #[async]
                 for change in Self::compare_subtrees(head_subtree, candidate_ref) {
-                    stream_yield!(change);
+                    if !xattrs::is_manifest(change.as_path()) {
+                        stream_yield!(change);
+                    }
                 }
 
                 Ok(())
@@ -209,13 +745,23 @@ impl<B: Backend> Repository<B> {
                 let candidate_tree = await!(candidate_ref.fetch())?;
 
                 for (name, _) in candidate_tree.into_iter() {
-                    stream_yield!(Change::Added(ObjectPath::new().push_front(name)));
+                    if name != xattrs::MANIFEST_FILE {
+                        stream_yield!(Change::Added(ObjectPath::new().push_front(name)));
+                    }
                 }
 
                 Ok(())
             }
             (None, None) => Ok(()),
-            (Some(_), None) => bail!("None tree with left HEAD!"),
+            (Some(head_ref), None) => {
+                let head_subtree = await!(head_ref.fetch())?.as_subtree().clone();
+
+                for (path, _) in await!(Self::staged_files(head_subtree))? {
+                    stream_yield!(Change::Removed(path));
+                }
+
+                Ok(())
+            }
         }
     }
 }
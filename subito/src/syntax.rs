@@ -3,6 +3,8 @@ use std::{fmt, borrow::Cow, ops::Deref, str::FromStr, sync::Arc};
 use failure::*;
 use regex::Regex;
 
+use refs::{HEADS, REMOTES, TAGS};
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Name(Arc<String>);
 
@@ -50,9 +52,14 @@ impl Name {
     }
 }
 
+/// A ref, written either by its short name (`master`, `origin/master`) or by its fully qualified
+/// name (`refs/heads/master`, `refs/tags/v1`, `refs/remotes/origin/master`). A short name which
+/// isn't a branch is resolved as a tag, so tags only need their fully qualified names when a
+/// branch has the same name.
 #[derive(Debug, Clone)]
 pub enum Ref {
     Local(Name),
+    Tag(Name),
     Remote(Name, Name),
     Head,
 }
@@ -61,6 +68,7 @@ impl fmt::Display for Ref {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Ref::Local(ref name) => name.fmt(f),
+            Ref::Tag(ref name) => write!(f, "{}{}", TAGS, name),
             Ref::Remote(ref remote, ref name) => write!(f, "{}/{}", remote, name),
             Ref::Head => f.pad("HEAD"),
         }
@@ -73,6 +81,18 @@ impl FromStr for Ref {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s == "HEAD" {
             Ok(Ref::Head)
+        } else if s.starts_with(HEADS) {
+            Ok(Ref::Local(s[HEADS.len()..].parse()?))
+        } else if s.starts_with(TAGS) {
+            Ok(Ref::Tag(s[TAGS.len()..].parse()?))
+        } else if s.starts_with(REMOTES) {
+            let mut split = s[REMOTES.len()..].splitn(2, '/');
+            let remote = split.next().unwrap().parse()?;
+            let name = split
+                .next()
+                .ok_or_else(|| format_err!("could not parse {} as a ref!", s))?
+                .parse()?;
+            Ok(Ref::Remote(remote, name))
         } else {
             lazy_static! {
                 static ref RE: Regex = Regex::new(r"^(?:(?P<r>\w+)/)?(?P<b>\w+)$").unwrap();
@@ -88,3 +108,143 @@ impl FromStr for Ref {
         }
     }
 }
+
+/// A single step of ancestry navigation from a commit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ancestry {
+    /// `^N`: the Nth parent of the commit, counting from one. `^0` is the commit itself.
+    Parent(usize),
+
+    /// `~N`: the Nth generation ancestor, following only first parents.
+    Ancestor(usize),
+}
+
+impl fmt::Display for Ancestry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Ancestry::Parent(n) => write!(f, "^{}", n),
+            Ancestry::Ancestor(n) => write!(f, "~{}", n),
+        }
+    }
+}
+
+/// A revision: a ref or an abbreviated digest, followed by any number of ancestry steps, for
+/// example `HEAD~2`, `origin/master^2` or `3fa9c1~`.
+#[derive(Debug, Clone)]
+pub struct Rev {
+    /// The ref to start from. Bare names which are not branches are also tried as digest
+    /// abbreviations when resolved.
+    pub base: Ref,
+    pub ancestry: Vec<Ancestry>,
+}
+
+impl fmt::Display for Rev {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.base)?;
+        for step in &self.ancestry {
+            write!(f, "{}", step)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<Ref> for Rev {
+    fn from(base: Ref) -> Self {
+        Rev {
+            base,
+            ancestry: Vec::new(),
+        }
+    }
+}
+
+impl FromStr for Rev {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        lazy_static! {
+            static ref STEP: Regex = Regex::new(r"([~^])(\d*)").unwrap();
+        }
+
+        let split = s.find(|c| c == '~' || c == '^').unwrap_or(s.len());
+        let (base, steps) = s.split_at(split);
+        let base = base.parse()?;
+
+        let mut ancestry = Vec::new();
+        let mut consumed = 0;
+        for cap in STEP.captures_iter(steps) {
+            let whole = cap.get(0).unwrap();
+            ensure!(
+                whole.start() == consumed,
+                "could not parse {} as a revision!",
+                s
+            );
+            consumed = whole.end();
+
+            let n = match cap.get(2).unwrap().as_str() {
+                "" => 1,
+                digits => digits.parse()?,
+            };
+            ancestry.push(match &cap[1] {
+                "^" => Ancestry::Parent(n),
+                _ => Ancestry::Ancestor(n),
+            });
+        }
+        ensure!(
+            consumed == steps.len(),
+            "could not parse {} as a revision!",
+            s
+        );
+
+        Ok(Rev { base, ancestry })
+    }
+}
+
+/// The commits `log` shows: those reachable from a single revision, or a range written as one of
+///
+/// - `A..B`: commits reachable from `B` but not from `A`. This is what `B` has which `A` doesn't,
+///   e.g. "what's new on this branch" when `A` is the branch it was forked from. If `A` is not an
+///   ancestor of `B`, the commits only `A` can reach are simply not shown.
+/// - `A...B`: the symmetric difference, commits reachable from either `A` or `B` but not from
+///   both. Whatever lies beneath their merge bases is left out, so this is what has happened on
+///   both sides since they diverged.
+///
+/// Either side of a range may be left empty to mean `HEAD`, so `master..` is everything on `HEAD`
+/// which isn't on `master`.
+#[derive(Debug, Clone)]
+pub enum RevRange {
+    Rev(Rev),
+    Between(Rev, Rev),
+    Symmetric(Rev, Rev),
+}
+
+impl fmt::Display for RevRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RevRange::Rev(ref rev) => write!(f, "{}", rev),
+            RevRange::Between(ref from, ref to) => write!(f, "{}..{}", from, to),
+            RevRange::Symmetric(ref left, ref right) => write!(f, "{}...{}", left, right),
+        }
+    }
+}
+
+impl FromStr for RevRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn side(s: &str) -> Result<Rev, Error> {
+            if s.is_empty() {
+                Ok(Rev::from(Ref::Head))
+            } else {
+                s.parse()
+            }
+        }
+
+        if let Some(i) = s.find("...") {
+            Ok(RevRange::Symmetric(side(&s[..i])?, side(&s[i + 3..])?))
+        } else if let Some(i) = s.find("..") {
+            Ok(RevRange::Between(side(&s[..i])?, side(&s[i + 2..])?))
+        } else {
+            Ok(RevRange::Rev(s.parse()?))
+        }
+    }
+}
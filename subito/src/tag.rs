@@ -0,0 +1,89 @@
+use std::fmt;
+
+use attaca::store::prelude::*;
+use failure::*;
+use futures::prelude::*;
+
+use Repository;
+use plumbing;
+use refs;
+use syntax::{Name, Ref, Rev};
+
+/// Create, list or delete tags. A tag names a commit, and unlike a branch, never moves on its own.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "tag")]
+pub struct TagArgs {
+    /// The tag to create or delete. Without a tag, tags are listed.
+    #[structopt(name = "TAG")]
+    pub name: Option<Name>,
+
+    /// The commit to tag. Defaults to HEAD.
+    #[structopt(name = "REV")]
+    pub rev: Option<Rev>,
+
+    /// List tags, sorted by name.
+    #[structopt(short = "l", long = "list", raw(conflicts_with_all = r#"&["TAG", "delete"]"#))]
+    pub list: bool,
+
+    /// Delete the tag.
+    #[structopt(short = "d", long = "delete", raw(requires = r#""TAG""#))]
+    pub delete: bool,
+
+    /// Move the tag to REV if it already exists, rather than failing.
+    #[structopt(short = "f", long = "force", raw(conflicts_with_all = r#"&["list", "delete"]"#))]
+    pub force: bool,
+}
+
+#[must_use = "TagOut contains futures which must be driven to completion!"]
+pub struct TagOut<'r> {
+    /// With `--list` (or no tag), the name of every tag, sorted.
+    pub blocking: Box<Future<Item = Vec<Name>, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for TagOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TagOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    pub fn tag<'r>(&'r mut self, args: TagArgs) -> TagOut<'r> {
+        let blocking = async_block! {
+            let tags = await!(refs::load_tags(&self.store))?;
+
+            let name = match args.name {
+                Some(name) => name,
+                None => {
+                    let mut names = tags.keys()
+                        .map(|name| Name::from_string(name.clone()))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    names.sort();
+                    return Ok(names);
+                }
+            };
+
+            let mut new_tags = tags.clone();
+            if args.delete {
+                ensure!(new_tags.remove(name.as_str()).is_some(), "no such tag {}", name);
+            } else {
+                ensure!(
+                    args.force || !tags.contains_key(name.as_str()),
+                    "tag {} already exists",
+                    name
+                );
+                let rev = args.rev.unwrap_or_else(|| Rev::from(Ref::Head));
+                let commit_ref = await!(plumbing::resolve_rev(self, rev))?;
+                new_tags.insert(name.into_string(), commit_ref.into_inner());
+            }
+            await!(refs::swap_tags(&self.store, tags, new_tags))?;
+
+            Ok(Vec::new())
+        };
+
+        TagOut {
+            blocking: Box::new(blocking),
+        }
+    }
+}
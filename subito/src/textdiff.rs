@@ -0,0 +1,396 @@
+//! Line- and word-level textual diffs, used to render changes between two blobs.
+
+use std::{cmp, iter, fmt::Write};
+
+/// The number of leading bytes inspected when deciding whether a blob is binary.
+const BINARY_SNIFF_LEN: usize = 8000;
+
+const COLOR_OLD: &'static str = "\x1b[31m";
+const COLOR_NEW: &'static str = "\x1b[32m";
+const COLOR_FRAG: &'static str = "\x1b[36m";
+const COLOR_RESET: &'static str = "\x1b[m";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Granularity {
+    Line,
+    Word,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RenderOptions {
+    /// Number of unchanged lines to show around each change.
+    pub context: usize,
+    pub granularity: Granularity,
+    pub color: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            context: 3,
+            granularity: Granularity::Line,
+            color: false,
+        }
+    }
+}
+
+/// Heuristically determine whether some data is binary: it is if it contains a NUL byte near the
+/// start or is not valid UTF-8.
+pub fn is_binary(data: &[u8]) -> bool {
+    data[..cmp::min(data.len(), BINARY_SNIFF_LEN)].contains(&0)
+        || ::std::str::from_utf8(data).is_err()
+}
+
+/// Count the lines added and removed by the shortest edit script from `old` to `new`, as
+/// `(added, removed)`. A changed line counts as both a removal and an addition.
+pub fn line_counts(old: &[&str], new: &[&str]) -> (usize, usize) {
+    myers(old, new)
+        .into_iter()
+        .fold((0, 0), |(added, removed), op| match op {
+            Op::Equal => (added, removed),
+            Op::Delete => (added, removed + 1),
+            Op::Insert => (added + 1, removed),
+        })
+}
+
+/// Compute a shortest edit script transforming `a` into `b` using the linear-space variant of
+/// Myers' O(ND) algorithm.
+pub fn myers<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Op> {
+    let max = a.len() + b.len();
+    let mut forward = vec![0; 2 * max + 3];
+    let mut backward = vec![0; 2 * max + 3];
+    let mut ops = Vec::with_capacity(max);
+    diff_between(a, b, &mut forward, &mut backward, &mut ops);
+    ops
+}
+
+/// Append a shortest edit script transforming `a` into `b` to `ops`. The problem is split at the
+/// middle of an optimal path, so only the two `V` vectors are kept rather than a trace of every
+/// round of the search.
+fn diff_between<T: PartialEq>(
+    a: &[T],
+    b: &[T],
+    forward: &mut [isize],
+    backward: &mut [isize],
+    ops: &mut Vec<Op>,
+) {
+    let prefix = a.iter().zip(b).take_while(|&(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|&(x, y)| x == y)
+        .count();
+    let a_mid = &a[prefix..a.len() - suffix];
+    let b_mid = &b[prefix..b.len() - suffix];
+
+    ops.extend(iter::repeat(Op::Equal).take(prefix));
+    if a_mid.is_empty() {
+        ops.extend(iter::repeat(Op::Insert).take(b_mid.len()));
+    } else if b_mid.is_empty() {
+        ops.extend(iter::repeat(Op::Delete).take(a_mid.len()));
+    } else {
+        let (x, y) = middle_snake(a_mid, b_mid, forward, backward);
+        diff_between(&a_mid[..x], &b_mid[..y], forward, backward, ops);
+        diff_between(&a_mid[x..], &b_mid[y..], forward, backward, ops);
+    }
+    ops.extend(iter::repeat(Op::Equal).take(suffix));
+}
+
+/// Find a point at which an optimal path from `a` to `b` can be split, by running the forward and
+/// reverse searches simultaneously until they overlap. `a` and `b` must both be non-empty and
+/// share no common prefix or suffix; the point then always has at least one edit on either side.
+fn middle_snake<T: PartialEq>(
+    a: &[T],
+    b: &[T],
+    forward: &mut [isize],
+    backward: &mut [isize],
+) -> (usize, usize) {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    let offset = (forward.len() / 2) as isize;
+    let at = |k: isize| (k + offset) as usize;
+
+    // `forward[at(k)]` is the furthest-reaching x coordinate on diagonal k from the start, and
+    // `backward[at(k)]` the furthest-reaching distance from the end on the reversed diagonal k.
+    forward[at(1)] = 0;
+    backward[at(1)] = 0;
+
+    for d in 0..(n + m + 1) / 2 + 1 {
+        let mut k = -d;
+        while k <= d {
+            let mut x = if k == -d || (k != d && forward[at(k - 1)] < forward[at(k + 1)]) {
+                forward[at(k + 1)]
+            } else {
+                forward[at(k - 1)] + 1
+            };
+            let (start_x, start_y) = (x, x - k);
+            let mut y = start_y;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[at(k)] = x;
+
+            if odd && (k - delta).abs() < d && x + backward[at(delta - k)] >= n {
+                return (start_x as usize, start_y as usize);
+            }
+
+            k += 2;
+        }
+
+        let mut k = -d;
+        while k <= d {
+            let mut x = if k == -d || (k != d && backward[at(k - 1)] < backward[at(k + 1)]) {
+                backward[at(k + 1)]
+            } else {
+                backward[at(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[(n - x - 1) as usize] == b[(m - y - 1) as usize] {
+                x += 1;
+                y += 1;
+            }
+            backward[at(k)] = x;
+
+            if !odd && (k - delta).abs() <= d && x + forward[at(delta - k)] >= n {
+                return ((n - x) as usize, (m - y) as usize);
+            }
+
+            k += 2;
+        }
+    }
+
+    unreachable!("an edit script always exists")
+}
+
+/// A contiguous region of changes, along with surrounding context.
+#[derive(Debug, Clone)]
+pub struct Hunk<'a> {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    pub lines: Vec<(Op, &'a str)>,
+}
+
+impl<'a> Hunk<'a> {
+    fn header(&self) -> String {
+        // As in `diff -u`, an empty range is reported as starting at the line before it.
+        fn start(start: usize, len: usize) -> usize {
+            if len == 0 {
+                start
+            } else {
+                start + 1
+            }
+        }
+
+        format!(
+            "@@ -{},{} +{},{} @@",
+            start(self.old_start, self.old_len),
+            self.old_len,
+            start(self.new_start, self.new_len),
+            self.new_len
+        )
+    }
+
+    /// Render this hunk, including its header, according to `options`.
+    pub fn render(&self, options: &RenderOptions) -> String {
+        let mut out = String::new();
+
+        if options.color {
+            out.push_str(COLOR_FRAG);
+            out.push_str(&self.header());
+            out.push_str(COLOR_RESET);
+        } else {
+            out.push_str(&self.header());
+        }
+        out.push('\n');
+
+        match options.granularity {
+            Granularity::Line => for &(op, line) in &self.lines {
+                let (prefix, color) = match op {
+                    Op::Equal => (' ', None),
+                    Op::Delete => ('-', Some(COLOR_OLD)),
+                    Op::Insert => ('+', Some(COLOR_NEW)),
+                };
+
+                match color {
+                    Some(color) if options.color => {
+                        write!(out, "{}{}{}{}\n", color, prefix, line, COLOR_RESET).unwrap()
+                    }
+                    _ => write!(out, "{}{}\n", prefix, line).unwrap(),
+                }
+            },
+            Granularity::Word => {
+                let old = self.lines
+                    .iter()
+                    .filter(|&&(op, _)| op != Op::Insert)
+                    .map(|&(_, line)| line)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let new = self.lines
+                    .iter()
+                    .filter(|&&(op, _)| op != Op::Delete)
+                    .map(|&(_, line)| line)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                out.push_str(&render_words(&old, &new, options.color));
+                out.push('\n');
+            }
+        }
+
+        out
+    }
+}
+
+/// Group an edit script over lines into hunks with `context` lines of surrounding context.
+pub fn hunks<'a>(old: &[&'a str], new: &[&'a str], context: usize) -> Vec<Hunk<'a>> {
+    let mut script = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    for op in myers(old, new) {
+        script.push((op, i, j));
+        match op {
+            Op::Equal => {
+                i += 1;
+                j += 1;
+            }
+            Op::Delete => i += 1,
+            Op::Insert => j += 1,
+        }
+    }
+
+    let next_change = |from: usize| {
+        script[from..]
+            .iter()
+            .position(|&(op, _, _)| op != Op::Equal)
+            .map(|p| p + from)
+    };
+
+    let mut hunks = Vec::new();
+    let mut pos = 0;
+
+    while let Some(first_change) = next_change(pos) {
+        let start = cmp::max(pos, first_change.saturating_sub(context));
+
+        // Extend the hunk for as long as the gap between runs of changes is small enough that
+        // their contexts would overlap.
+        let mut end = first_change;
+        loop {
+            while end < script.len() && script[end].0 != Op::Equal {
+                end += 1;
+            }
+
+            match next_change(end) {
+                Some(change) if change - end <= 2 * context => end = change,
+                _ => break,
+            }
+        }
+
+        let stop = cmp::min(end + context, script.len());
+        let entries = &script[start..stop];
+        let lines = entries
+            .iter()
+            .map(|&(op, i, j)| match op {
+                Op::Equal | Op::Delete => (op, old[i]),
+                Op::Insert => (op, new[j]),
+            })
+            .collect::<Vec<_>>();
+
+        hunks.push(Hunk {
+            old_start: entries[0].1,
+            old_len: entries.iter().filter(|e| e.0 != Op::Insert).count(),
+            new_start: entries[0].2,
+            new_len: entries.iter().filter(|e| e.0 != Op::Delete).count(),
+            lines,
+        });
+
+        pos = stop;
+    }
+
+    hunks
+}
+
+/// Split text into alternating runs of whitespace and non-whitespace.
+fn tokenize(s: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut in_space = None;
+
+    for (i, c) in s.char_indices() {
+        let is_space = c.is_whitespace();
+        if in_space.map_or(false, |prev| prev != is_space) {
+            tokens.push(&s[start..i]);
+            start = i;
+        }
+        in_space = Some(is_space);
+    }
+
+    if start < s.len() {
+        tokens.push(&s[start..]);
+    }
+
+    tokens
+}
+
+/// Render a word-level diff of two pieces of text. Removed words are shown as `[-word-]` and
+/// added words as `{+word+}`, or in red and green respectively if `color` is set.
+pub fn render_words(old: &str, new: &str, color: bool) -> String {
+    fn flush(out: &mut String, op: Op, run: &mut String, color: bool) {
+        if run.is_empty() {
+            return;
+        }
+
+        match (op, color) {
+            (Op::Equal, _) => out.push_str(run),
+            (Op::Delete, true) => write!(out, "{}{}{}", COLOR_OLD, run, COLOR_RESET).unwrap(),
+            (Op::Delete, false) => write!(out, "[-{}-]", run).unwrap(),
+            (Op::Insert, true) => write!(out, "{}{}{}", COLOR_NEW, run, COLOR_RESET).unwrap(),
+            (Op::Insert, false) => write!(out, "{{+{}+}}", run).unwrap(),
+        }
+
+        run.clear();
+    }
+
+    let old_tokens = tokenize(old);
+    let new_tokens = tokenize(new);
+    let mut out = String::new();
+    let mut run = String::new();
+    let mut run_op = Op::Equal;
+    let (mut i, mut j) = (0, 0);
+
+    for op in myers(&old_tokens, &new_tokens) {
+        if op != run_op {
+            flush(&mut out, run_op, &mut run, color);
+            run_op = op;
+        }
+
+        match op {
+            Op::Equal => {
+                run.push_str(old_tokens[i]);
+                i += 1;
+                j += 1;
+            }
+            Op::Delete => {
+                run.push_str(old_tokens[i]);
+                i += 1;
+            }
+            Op::Insert => {
+                run.push_str(new_tokens[j]);
+                j += 1;
+            }
+        }
+    }
+    flush(&mut out, run_op, &mut run, color);
+
+    out
+}
@@ -0,0 +1,51 @@
+//! How much the frontend should print.
+//!
+//! Verbosity is chosen once for the whole program with the global `-q`/`--quiet` and
+//! `-v`/`--verbose` flags, rather than by each command. What each level means for a command is
+//! up to the frontend, within the following contract:
+//!
+//! - `commit`: `Quiet` prints nothing; `Normal` prints the new commit's abbreviated digest and
+//!   the first line of its message; `Verbose` prints the full digest instead; `VeryVerbose` also
+//!   prints the author, date and parents.
+//! - `stage` and `unstage`: `Quiet` prints nothing; `Normal` shows progress if stderr is a
+//!   terminal; `Verbose` and above also print each path as it is processed.
+//! - `clone`: `Quiet` prints nothing; `Normal` shows progress if stderr is a terminal; `Verbose`
+//!   and above always show progress.
+//!
+//! Errors are always reported, whatever the verbosity. Explicit `--progress` and `--no-progress`
+//! flags take precedence over the progress behavior above.
+
+/// Levels of verbosity, from least to most output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Verbosity {
+    /// `-q`: print nothing but errors.
+    Quiet,
+
+    /// The default.
+    Normal,
+
+    /// `-v`
+    Verbose,
+
+    /// `-vv` or more.
+    VeryVerbose,
+}
+
+impl Default for Verbosity {
+    fn default() -> Self {
+        Verbosity::Normal
+    }
+}
+
+impl Verbosity {
+    /// Find the verbosity from whether `-q` was given and how many times `-v` was. `-q` wins if
+    /// both were.
+    pub fn from_flags(quiet: bool, verbose: u64) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::VeryVerbose,
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! A reusable walk over every entry beneath a tree, with pruning.
+//!
+//! The walk is depth-first. All the entries of a tree are visited, in name order, before anything
+//! beneath them, and subtrees are then walked in the same order. Each distinct subtree is fetched
+//! and descended into only once: if the same subtree turns up under several paths (vendored copies,
+//! say), the visitor sees it as an entry under each of them, but only sees its contents beneath the
+//! first.
+
+use std::{fmt, collections::HashSet};
+
+use attaca::{object::{ObjectRef, TreeRef}, path::ObjectPath, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+
+use Repository;
+
+/// What a `walk_tree` visitor wants done after seeing an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Walk {
+    /// Carry on, descending into the entry if it is a tree.
+    Continue,
+
+    /// Skip everything beneath the entry. This makes no difference for entries which aren't trees.
+    Prune,
+}
+
+#[must_use = "WalkTreeOut contains futures which must be driven to completion!"]
+pub struct WalkTreeOut<'r> {
+    /// Finishes once every entry has been visited, or at the first error from the visitor or the
+    /// store.
+    pub blocking: Box<Future<Item = (), Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for WalkTreeOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WalkTreeOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    /// Walk every entry beneath `root`, calling `visitor` with the path of each (relative to
+    /// `root`) and the object it refers to. The visitor returns a future, so it may fetch objects
+    /// itself; the walk waits for it before going on, and descends into the entry unless it
+    /// resolves to `Walk::Prune`. See the module documentation for the order of the walk.
+    pub fn walk_tree<'r, V, F>(&'r self, root: TreeRef<Handle<B>>, visitor: V) -> WalkTreeOut<'r>
+    where
+        V: FnMut(&ObjectPath, &ObjectRef<Handle<B>>) -> F + 'r,
+        F: IntoFuture<Item = Walk, Error = Error> + 'r,
+    {
+        let blocking = async_block! {
+            let mut visitor = visitor;
+            let mut walked = HashSet::new();
+            let mut stack = vec![(ObjectPath::new(), root)];
+
+            while let Some((tree_path, tree_ref)) = stack.pop() {
+                if !walked.insert(tree_ref.as_inner().clone()) {
+                    continue;
+                }
+
+                let tree = await!(tree_ref.fetch())?;
+                let mut subtrees = Vec::new();
+                for (name, object_ref) in tree {
+                    let path = tree_path.push_back(name);
+                    let step = visitor(&path, &object_ref).into_future();
+                    let walk = await!(step)?;
+
+                    if let (Walk::Continue, ObjectRef::Tree(subtree_ref)) = (walk, object_ref) {
+                        subtrees.push((path, subtree_ref));
+                    }
+                }
+
+                // Push in reverse so that subtrees are popped, and so walked, in name order.
+                stack.extend(subtrees.into_iter().rev());
+            }
+
+            Ok(())
+        };
+
+        WalkTreeOut {
+            blocking: Box::new(blocking),
+        }
+    }
+}
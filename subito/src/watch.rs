@@ -0,0 +1,148 @@
+use std::{fmt, thread, collections::BTreeSet, path::{Path, PathBuf}, sync::mpsc as std_mpsc,
+          time::Duration};
+
+use attaca::store::prelude::*;
+use failure::*;
+use futures::{prelude::*, sync::mpsc};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use notify::{self, DebouncedEvent, RecursiveMode, Watcher};
+
+use Repository;
+use candidate::{BatchOp, IGNORE_FILE};
+
+/// Keep the virtual workspace synchronized with the local workspace as files change.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "watch")]
+pub struct WatchArgs {
+    /// How long to wait for changes to settle before staging them, in milliseconds.
+    #[structopt(long = "debounce", default_value = "200")]
+    pub debounce_ms: u64,
+}
+
+impl Default for WatchArgs {
+    fn default() -> Self {
+        Self { debounce_ms: 200 }
+    }
+}
+
+/// A set of paths which were staged together in response to filesystem events.
+#[derive(Debug, Clone)]
+pub struct WatchUpdate {
+    pub paths: Vec<PathBuf>,
+}
+
+#[must_use = "WatchOut contains futures which must be driven to completion!"]
+pub struct WatchOut<'r> {
+    pub updates: Box<Stream<Item = WatchUpdate, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for WatchOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WatchOut")
+            .field("updates", &"OPAQUE")
+            .finish()
+    }
+}
+
+fn build_ignore(root: &Path) -> Result<Gitignore, Error> {
+    let mut builder = GitignoreBuilder::new(root);
+
+    for name in &[".gitignore", ".ignore", IGNORE_FILE] {
+        let path = root.join(name);
+        if path.exists() {
+            if let Some(err) = builder.add(&path) {
+                bail!(err);
+            }
+        }
+    }
+
+    Ok(builder.build()?)
+}
+
+fn is_ignored(root: &Path, ignore: &Gitignore, path: &Path) -> bool {
+    match path.strip_prefix(root) {
+        Ok(relative) => {
+            relative.starts_with(".attaca")
+                || ignore
+                    .matched_path_or_any_parents(relative, path.is_dir())
+                    .is_ignore()
+        }
+        Err(_) => true,
+    }
+}
+
+fn event_paths(event: DebouncedEvent) -> Vec<PathBuf> {
+    match event {
+        DebouncedEvent::Create(path)
+        | DebouncedEvent::Write(path)
+        | DebouncedEvent::Chmod(path)
+        | DebouncedEvent::Remove(path) => vec![path],
+        DebouncedEvent::Rename(from, to) => vec![from, to],
+        DebouncedEvent::NoticeWrite(_)
+        | DebouncedEvent::NoticeRemove(_)
+        | DebouncedEvent::Rescan
+        | DebouncedEvent::Error(..) => Vec::new(),
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    /// Watch the local workspace for changes, staging changed and removed paths as they occur.
+    ///
+    /// Rapid changes are debounced and coalesced into a single batch, which is staged through the
+    /// same path as `stage`. Each batch which is applied is yielded from `WatchOut::updates`. The
+    /// stream does not end unless an error occurs; drop it to stop watching.
+    pub fn watch<'r>(&'r mut self, args: WatchArgs) -> WatchOut<'r> {
+        let updates = async_stream_block! {
+            let root = (*self.path).clone();
+            let ignore = build_ignore(&root)?;
+
+            let (raw_tx, raw_rx) = std_mpsc::channel();
+            let mut watcher = notify::watcher(raw_tx, Duration::from_millis(args.debounce_ms))?;
+            watcher.watch(&root, RecursiveMode::Recursive)?;
+
+            // `notify` delivers events over a blocking channel. Forward them onto a futures
+            // channel from a separate thread, draining everything which has already arrived so
+            // that bursts of events become a single batch.
+            let (tx, rx) = mpsc::unbounded();
+            thread::spawn(move || {
+                while let Ok(first) = raw_rx.recv() {
+                    let mut events = vec![first];
+                    events.extend(raw_rx.try_iter());
+
+                    if tx.unbounded_send(events).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            #[async]
+            for events in rx.map_err(|()| format_err!("Watcher channel closed unexpectedly")) {
+                let paths = events
+                    .into_iter()
+                    .flat_map(event_paths)
+                    .filter(|path| !is_ignored(&root, &ignore, path))
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .collect::<Vec<_>>();
+
+                if paths.is_empty() {
+                    continue;
+                }
+
+                let batch = paths.clone().into_iter().map(BatchOp::stage);
+                await!(self.stage_batch(batch)).context("Error while staging watched paths")?;
+
+                stream_yield!(WatchUpdate { paths });
+            }
+
+            // Keep the watcher alive for as long as we're receiving events from it.
+            drop(watcher);
+
+            Ok(())
+        };
+
+        WatchOut {
+            updates: Box::new(updates),
+        }
+    }
+}
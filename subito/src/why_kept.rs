@@ -0,0 +1,205 @@
+//! Find out what keeps an object alive.
+//!
+//! An object stays in the store for as long as it is reachable from one of the repository's roots
+//! (see `plumbing::roots`). For each root an object is reachable from, `why-kept` reports the
+//! shortest route from that root to the object, written like a revision followed by a path:
+//! `refs/heads/master~2^2:src/lib.rs` is the file `src/lib.rs` in the tree of the second parent of
+//! the grandparent of `master`.
+
+use std::{fmt, collections::{HashMap, HashSet, VecDeque}};
+
+use attaca::{digest::{prelude::*, Sha3Digest}, object::ObjectRef, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+use hex;
+
+use Repository;
+use plumbing;
+
+/// Show which refs, stash entries or other roots keep an object reachable, and how.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "why-kept")]
+pub struct WhyKeptArgs {
+    /// The full hex digest of the object.
+    #[structopt(name = "DIGEST")]
+    pub digest: String,
+}
+
+/// A single step from an object to one it references.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// From a commit to its parent with the given index, counting from zero.
+    Parent(usize),
+
+    /// From a commit to its tree.
+    Subtree,
+
+    /// From a tree to its entry with the given name.
+    Entry(String),
+
+    /// From a large blob to its chunk with the given index, counting from zero.
+    Chunk(usize),
+}
+
+/// A root which keeps an object alive, and the route from it to the object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeptBy {
+    pub root: String,
+    pub steps: Vec<Step>,
+}
+
+impl fmt::Display for KeptBy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.root)?;
+
+        let mut first_parents = 0;
+        let mut in_tree = false;
+        let mut in_path = false;
+        for step in &self.steps {
+            if first_parents > 0 && *step != Step::Parent(0) {
+                write!(f, "~{}", first_parents)?;
+                first_parents = 0;
+            }
+
+            match *step {
+                Step::Parent(0) => first_parents += 1,
+                Step::Parent(i) => write!(f, "^{}", i + 1)?,
+                Step::Subtree => {
+                    f.write_str(":")?;
+                    in_tree = true;
+                }
+                Step::Entry(ref name) => {
+                    // Roots which are trees, such as the virtual workspace, have no `Subtree` step
+                    // to introduce the path.
+                    if !in_tree {
+                        f.write_str(":")?;
+                        in_tree = true;
+                    } else if in_path {
+                        f.write_str("/")?;
+                    }
+                    f.write_str(name)?;
+                    in_path = true;
+                }
+                Step::Chunk(i) => write!(f, " (chunk {})", i)?,
+            }
+        }
+        if first_parents > 0 {
+            write!(f, "~{}", first_parents)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[must_use = "WhyKeptOut contains futures which must be driven to completion!"]
+pub struct WhyKeptOut<'r> {
+    /// Every root the object is reachable from. Empty if the object is not in use.
+    pub kept_by: Box<Future<Item = Vec<KeptBy>, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for WhyKeptOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WhyKeptOut")
+            .field("kept_by", &"OPAQUE")
+            .finish()
+    }
+}
+
+/// Walk breadth-first from `root`, recording how each object was first reached, until `target`
+/// turns up. Returns the steps from `root` to `target`, or `None` if it isn't reachable.
+#[async]
+fn route<B: Backend>(
+    root: ObjectRef<Handle<B>>,
+    target: Handle<B>,
+) -> Result<Option<Vec<Step>>, Error> {
+    let mut came_from = HashMap::new();
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::new();
+
+    visited.insert(root.as_inner().clone());
+    queue.push_back(root);
+
+    while let Some(object_ref) = queue.pop_front() {
+        let handle = object_ref.as_inner().clone();
+
+        if handle == target {
+            let mut steps = Vec::new();
+            let mut current = handle;
+            while let Some((parent, step)) = came_from.remove(&current) {
+                steps.push(step);
+                current = parent;
+            }
+            steps.reverse();
+            return Ok(Some(steps));
+        }
+
+        let children = match object_ref {
+            ObjectRef::Small(_) => Vec::new(),
+            ObjectRef::Large(large_ref) => await!(large_ref.fetch())?
+                .into_iter()
+                .enumerate()
+                .map(|(i, (_, child_ref))| (Step::Chunk(i), child_ref))
+                .collect(),
+            ObjectRef::Tree(tree_ref) => await!(tree_ref.fetch())?
+                .into_iter()
+                .map(|(name, child_ref)| (Step::Entry(name), child_ref))
+                .collect(),
+            ObjectRef::Commit(commit_ref) => {
+                let commit = await!(commit_ref.fetch())?;
+                let subtree_ref = ObjectRef::Tree(commit.as_subtree().clone());
+                let mut children = vec![(Step::Subtree, subtree_ref)];
+                children.extend(
+                    commit
+                        .as_parents()
+                        .iter()
+                        .cloned()
+                        .enumerate()
+                        .map(|(i, parent_ref)| (Step::Parent(i), ObjectRef::Commit(parent_ref))),
+                );
+                children
+            }
+        };
+
+        for (step, child_ref) in children {
+            if visited.insert(child_ref.as_inner().clone()) {
+                came_from.insert(child_ref.as_inner().clone(), (handle.clone(), step));
+                queue.push_back(child_ref);
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+impl<B: Backend> Repository<B> {
+    /// Find every root `args.digest` is reachable from, with the shortest route from each. Roots
+    /// are walked separately, so this visits shared history once per root.
+    pub fn why_kept<'r>(&'r self, args: WhyKeptArgs) -> WhyKeptOut<'r> {
+        let kept_by = async_block! {
+            let hex_digest = args.digest;
+            let bytes = hex::decode(&hex_digest)
+                .map_err(|_| format_err!("{} is not a hex digest", hex_digest))?;
+            ensure!(
+                bytes.len() == Sha3Digest::SIGNATURE.size,
+                "{} is not a full digest ({} hex digits are needed)",
+                hex_digest,
+                2 * Sha3Digest::SIGNATURE.size
+            );
+            let target = await!(self.store.resolve_digest(Sha3Digest::from_bytes(&bytes)))?
+                .ok_or_else(|| format_err!("No object {} is in the store", hex_digest))?;
+
+            let mut kept_by = Vec::new();
+            for (root, root_ref) in await!(plumbing::roots(self))? {
+                if let Some(steps) = await!(route(root_ref, target.clone()))? {
+                    kept_by.push(KeptBy { root, steps });
+                }
+            }
+
+            Ok(kept_by)
+        };
+
+        WhyKeptOut {
+            kept_by: Box::new(kept_by),
+        }
+    }
+}
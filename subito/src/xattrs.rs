@@ -0,0 +1,427 @@
+//! Extended attributes.
+//!
+//! With `Config::xattrs` set, staging records the extended attributes of files and directories,
+//! and checkout restores them. Tree entries have no room for metadata, so the attributes of the
+//! entries of a directory are kept beside them, in a manifest named `.attacaxattrs` in the same
+//! tree. Manifests only ever live in trees: checkout doesn't write them out as files, and they're
+//! not compared against the local workspace.
+//!
+//! The name `.attacaxattrs` is reserved: staging refuses a local file or directory which has it,
+//! since checkout would take it for a manifest. Manifests are left out of status, diffs, the
+//! renames `log --follow` looks for, and merges, which combine them entry by entry rather than
+//! reporting conflicts in them.
+//!
+//! Filesystems and platforms without extended attributes are treated as though nothing had any:
+//! staging records nothing, and checkout restores nothing. Attributes which can't be read for lack
+//! of permission are treated the same way. Symlinks are skipped, as are attributes whose names
+//! aren't UTF-8. Checkout only adds attributes; it never removes any a file already
+//! had, since some are set by the system rather than the user.
+//!
+//! Changing only the attributes of a file doesn't change its modification time, so a directory
+//! which the cache considers unchanged is reused as it was, attributes and all. Staging the
+//! changed file itself always records its attributes afresh.
+
+use std::{collections::{BTreeMap, BTreeSet, btree_map}, ffi::OsStr, io::{self, Write}, path::Path,
+          str};
+
+use attaca::{batch::Operation as ObjectOperation, hierarchy::Hierarchy,
+             object::{ObjectRef, SmallBuilder}, path::ObjectPath, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+use nix::libc;
+use xattr;
+
+use Repository;
+use candidate::OpKind;
+use diff;
+
+/// The name of the manifest recording the extended attributes of the other entries of a tree.
+pub const MANIFEST_FILE: &'static str = ".attacaxattrs";
+
+/// The extended attributes of a single file or directory, by name.
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+/// Whether `path` names a manifest.
+pub fn is_manifest(path: &ObjectPath) -> bool {
+    path.to_path().file_name() == Some(OsStr::new(MANIFEST_FILE))
+}
+
+/// Refuse to stage a local file or directory at `path` if it has the name reserved for manifests.
+pub fn ensure_not_reserved(path: &ObjectPath) -> Result<(), Error> {
+    ensure!(
+        !is_manifest(path),
+        "Cannot stage {}: the name {} is reserved for manifests of extended attributes. Rename \
+         it, or ignore it.",
+        path,
+        MANIFEST_FILE
+    );
+
+    Ok(())
+}
+
+fn is_unsupported(error: &io::Error) -> bool {
+    match error.raw_os_error() {
+        Some(code) => code == libc::ENOTSUP || code == libc::EOPNOTSUPP,
+        None => false,
+    }
+}
+
+fn is_denied(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(libc::EACCES)
+}
+
+/// Read the extended attributes of a file or directory. They're empty if it has none, if its
+/// filesystem doesn't support them, or if they can't be read for lack of permission; a single
+/// attribute which can't be read for lack of permission is left out.
+pub fn read(path: &Path) -> Result<Xattrs, Error> {
+    let mut xattrs = Xattrs::new();
+    if !xattr::SUPPORTED_PLATFORM {
+        return Ok(xattrs);
+    }
+
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(ref error) if is_unsupported(error) || is_denied(error) => return Ok(xattrs),
+        Err(error) => {
+            return Err(error
+                .context(format!("Error listing extended attributes of {}", path.display()))
+                .into())
+        }
+    };
+
+    for name in names {
+        let name = match name.into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+
+        // The attribute may have been removed since it was listed.
+        let maybe_value = match xattr::get(path, &name) {
+            Ok(maybe_value) => maybe_value,
+            Err(ref error) if is_denied(error) => continue,
+            Err(error) => {
+                return Err(error
+                    .context(format!(
+                        "Error reading extended attribute {} of {}",
+                        name,
+                        path.display()
+                    ))
+                    .into())
+            }
+        };
+        if let Some(value) = maybe_value {
+            xattrs.insert(name, value);
+        }
+    }
+
+    Ok(xattrs)
+}
+
+/// Set extended attributes on a file or directory. Does nothing if its filesystem doesn't support
+/// them.
+pub fn apply(path: &Path, xattrs: &Xattrs) -> Result<(), Error> {
+    if !xattr::SUPPORTED_PLATFORM {
+        return Ok(());
+    }
+
+    for (name, value) in xattrs {
+        match xattr::set(path, name, value) {
+            Ok(()) => {}
+            Err(ref error) if is_unsupported(error) => return Ok(()),
+            Err(error) => {
+                return Err(error
+                    .context(format!(
+                        "Error setting extended attribute {} of {}",
+                        name,
+                        path.display()
+                    ))
+                    .into())
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Set the extended attributes recorded in `manifest` on the entries of the directory `dir`.
+/// Entries which are missing, or have become symlinks, are skipped.
+pub fn restore(dir: &Path, manifest: &Manifest) -> Result<(), Error> {
+    for (name, xattrs) in manifest.iter() {
+        let path = dir.join(name);
+        match path.symlink_metadata() {
+            Ok(ref metadata) if !metadata.file_type().is_symlink() => apply(&path, xattrs)?,
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// The extended attributes of the entries of a tree, by entry name. Entries without any aren't
+/// listed.
+///
+/// Encoded, a manifest is a sequence of netstrings, three for each attribute: the name of the
+/// entry, the name of the attribute and its value.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    entries: BTreeMap<String, Xattrs>,
+}
+
+fn write_netstring(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write!(buf, "{}:", bytes.len()).unwrap();
+    buf.extend_from_slice(bytes);
+    buf.push(b',');
+}
+
+fn read_netstring<'a>(data: &mut &'a [u8]) -> Result<&'a [u8], Error> {
+    let colon = data.iter()
+        .position(|&b| b == b':')
+        .ok_or_else(|| format_err!("Malformed extended attribute manifest: missing length"))?;
+    let len = str::from_utf8(&data[..colon])?
+        .parse::<usize>()
+        .context("Malformed extended attribute manifest: bad length")?;
+    let rest = &data[colon + 1..];
+    ensure!(
+        rest.len() > len && rest[len] == b',',
+        "Malformed extended attribute manifest: truncated netstring"
+    );
+
+    *data = &rest[len + 1..];
+    Ok(&rest[..len])
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Xattrs> {
+        self.entries.get(name)
+    }
+
+    /// Record the attributes of an entry, or forget the entry if it has none.
+    pub fn set(&mut self, name: String, xattrs: Xattrs) {
+        if xattrs.is_empty() {
+            self.entries.remove(&name);
+        } else {
+            self.entries.insert(name, xattrs);
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Xattrs> {
+        self.entries.remove(name)
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<String, Xattrs> {
+        self.entries.iter()
+    }
+
+    /// Three-way merge two manifests against their common base, entry by entry. An entry whose
+    /// attributes were changed differently on both sides keeps those of `ours`.
+    pub fn merge(base: &Self, ours: &Self, theirs: &Self) -> Self {
+        let names = base.entries
+            .keys()
+            .chain(ours.entries.keys())
+            .chain(theirs.entries.keys())
+            .cloned()
+            .collect::<BTreeSet<_>>();
+
+        let mut merged = Self::new();
+        for name in names {
+            let base_xattrs = base.get(&name);
+            let ours_xattrs = ours.get(&name);
+            let theirs_xattrs = theirs.get(&name);
+            let xattrs = if base_xattrs == ours_xattrs {
+                theirs_xattrs
+            } else {
+                ours_xattrs
+            };
+            merged.set(name, xattrs.cloned().unwrap_or_default());
+        }
+
+        merged
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (entry, xattrs) in &self.entries {
+            for (name, value) in xattrs {
+                write_netstring(&mut buf, entry.as_bytes());
+                write_netstring(&mut buf, name.as_bytes());
+                write_netstring(&mut buf, value);
+            }
+        }
+        buf
+    }
+
+    pub fn decode(mut data: &[u8]) -> Result<Self, Error> {
+        let mut manifest = Self::new();
+        while !data.is_empty() {
+            let entry = str::from_utf8(read_netstring(&mut data)?)?.to_owned();
+            let name = str::from_utf8(read_netstring(&mut data)?)?.to_owned();
+            let value = read_netstring(&mut data)?.to_vec();
+            manifest
+                .entries
+                .entry(entry)
+                .or_insert_with(Xattrs::new)
+                .insert(name, value);
+        }
+        Ok(manifest)
+    }
+
+    /// Send the encoded manifest to the store.
+    #[async]
+    pub fn send<B: Backend>(self, store: Store<B>) -> Result<ObjectRef<Handle<B>>, Error> {
+        let mut builder = SmallBuilder::new();
+        builder.extend(self.encode());
+        Ok(ObjectRef::Small(await!(builder.as_small().send(&store))?))
+    }
+
+    /// Fetch a manifest from the store.
+    #[async]
+    pub fn fetch<B: Backend>(object_ref: ObjectRef<Handle<B>>) -> Result<Self, Error> {
+        match await!(diff::read_blob(object_ref))? {
+            Some(data) => Self::decode(&data),
+            None => bail!("Malformed tree: {} is not a file", MANIFEST_FILE),
+        }
+    }
+
+    /// Fetch the manifest of the tree at `dir` in `hierarchy`, if it has one.
+    #[async]
+    pub fn load<B: Backend>(
+        hierarchy: Hierarchy<B>,
+        dir: ObjectPath,
+    ) -> Result<Option<Self>, Error> {
+        match await!(hierarchy.get(dir.push_back(MANIFEST_FILE.to_owned())))? {
+            Some(object_ref) => Ok(Some(await!(Self::fetch(object_ref))?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    /// Operations updating the manifests of the virtual workspace `candidate` for a batch of
+    /// staged and unstaged paths, each given with whether it's still there afterwards. Staged
+    /// paths take their attributes from the local workspace, and unstaged ones from `head`.
+    pub(crate) fn manifest_operations<'r>(
+        &'r self,
+        candidate: Hierarchy<B>,
+        head: Hierarchy<B>,
+        targets: Vec<(ObjectPath, OpKind, bool)>,
+    ) -> impl Future<Item = Vec<ObjectOperation<B>>, Error = Error> + 'r {
+        async_block! {
+            let mut manifests = BTreeMap::new();
+
+            for (path, kind, present) in targets {
+                let as_path = path.to_path();
+                let (parent, name) =
+                    match (as_path.parent(), as_path.file_name().and_then(OsStr::to_str)) {
+                        (Some(parent), Some(name)) => {
+                            (ObjectPath::from_path(parent)?, name.to_owned())
+                        }
+                        _ => continue,
+                    };
+                if name == MANIFEST_FILE {
+                    continue;
+                }
+
+                if !manifests.contains_key(&parent) {
+                    let original = await!(Manifest::load(candidate.clone(), parent.clone()))?;
+                    let current = original.clone().unwrap_or_default();
+                    manifests.insert(parent.clone(), (original, current));
+                }
+
+                let xattrs = match (kind, present) {
+                    (_, false) => Xattrs::new(),
+                    (OpKind::Stage, true) => {
+                        let absolute_path = path.with_base(&*self.path);
+                        if absolute_path.symlink_metadata()?.file_type().is_symlink() {
+                            Xattrs::new()
+                        } else {
+                            read(&absolute_path)?
+                        }
+                    }
+                    (OpKind::Unstage, true) => {
+                        await!(Manifest::load(head.clone(), parent.clone()))?
+                            .and_then(|mut manifest| manifest.remove(&name))
+                            .unwrap_or_default()
+                    }
+                };
+                manifests.get_mut(&parent).unwrap().1.set(name, xattrs);
+            }
+
+            let mut operations = Vec::new();
+            for (parent, (original, current)) in manifests {
+                let manifest_path = parent.push_back(MANIFEST_FILE.to_owned());
+                match original {
+                    Some(ref original) if *original == current => {}
+                    Some(_) if current.is_empty() => {
+                        operations.push(ObjectOperation::Delete(manifest_path));
+                    }
+                    None if current.is_empty() => {}
+                    _ => {
+                        let object_ref = await!(current.send(self.store.clone()))?;
+                        operations.push(ObjectOperation::Add(manifest_path, object_ref));
+                    }
+                }
+            }
+
+            Ok(operations)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xattrs(pairs: &[(&str, &str)]) -> Xattrs {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn manifest_round_trips() {
+        let mut manifest = Manifest::new();
+        manifest.set("file".to_owned(), xattrs(&[("user.a", "1"), ("user.empty", "")]));
+        manifest.set("with:colon,comma".to_owned(), xattrs(&[("user.b", "2:3,\0")]));
+        manifest.set("none".to_owned(), Xattrs::new());
+
+        let decoded = Manifest::decode(&manifest.encode()).unwrap();
+        assert_eq!(decoded, manifest);
+        assert!(decoded.get("none").is_none());
+        assert!(Manifest::decode(&Manifest::new().encode()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn manifest_decode_rejects_truncated_data() {
+        let mut manifest = Manifest::new();
+        manifest.set("file".to_owned(), xattrs(&[("user.a", "value")]));
+        let encoded = manifest.encode();
+        assert!(Manifest::decode(&encoded[..encoded.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn manifest_merge_keeps_changes_from_both_sides() {
+        let mut base = Manifest::new();
+        base.set("a".to_owned(), xattrs(&[("user.x", "base")]));
+        base.set("".to_owned(), xattrs(&[("user.x", "base")]));
+
+        let mut ours = base.clone();
+        ours.set("a".to_owned(), xattrs(&[("user.x", "ours")]));
+        let mut theirs = base.clone();
+        theirs.set("".to_owned(), Xattrs::new());
+        theirs.set("c".to_owned(), xattrs(&[("user.x", "theirs")]));
+
+        let mut expected = Manifest::new();
+        expected.set("a".to_owned(), xattrs(&[("user.x", "ours")]));
+        expected.set("c".to_owned(), xattrs(&[("user.x", "theirs")]));
+        assert_eq!(Manifest::merge(&base, &ours, &theirs), expected);
+    }
+}